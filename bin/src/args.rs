@@ -1,15 +1,243 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use ir::pass::Pass;
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  Success
+  1  Runtime or code generation errors, invalid IR, unformatted files
+  2  Wrong usage or an invalid holymer.toml
+  3  A file couldn't be read or written
+  4  Syntax errors
+  5  Type errors
+With several files, the status is that of the first failure.";
 
 #[derive(Debug, Parser)]
+#[command(after_help = EXIT_STATUS)]
 pub struct Args {
-    /// The path to the file to be compiled.
-    #[arg(required = true)]
-    pub file: String,
-    /// Only run the type checker.
-    #[arg(short = 'c', long = "check")]
-    pub typecheck: bool,
+    #[command(subcommand)]
+    pub command: Command,
+    #[command(flatten)]
+    pub common: Common,
+}
+
+// Options shared by every command
+#[derive(Debug, clap::Args)]
+pub struct Common {
+    /// When to color the reports.
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, global = true)]
+    pub color: ColorChoice,
+    /// How to format the reports: with the source they point at, or one
+    /// line each.
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human, global = true)]
+    pub message_format: MessageFormat,
+    /// Stop reporting errors after this many.
+    #[arg(long, value_name = "N", global = true)]
+    pub error_limit: Option<usize>,
+    /// Print the program to stderr after these stages.
+    #[arg(long, value_enum, value_delimiter = ',', global = true)]
+    pub print_after: Vec<Stage>,
+    /// Report how long each stage took on stderr.
+    #[arg(long, global = true)]
+    pub time_passes: bool,
+    /// With `build` and `emit`, write a map from the output back to the
+    /// source to this path: a Source Map v3 for JavaScript, a line table
+    /// for assembly.
+    #[arg(long, value_name = "PATH", global = true)]
+    pub source_map: Option<String>,
+    /// Read the file as textual IR, as printed by `hc emit ir`, instead
+    /// of source code.
+    #[arg(long, global = true)]
+    pub from_ir: bool,
+    /// Check that the IR is well formed after every stage that produces
+    /// it, reporting what is wrong.
+    #[arg(long, global = true)]
+    pub verify_ir: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color when stderr is a terminal and `NO_COLOR` isn't set.
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    /// Reports with the source they point at.
+    Human,
+    /// One `file:line:column: kind: message` line per report.
+    Short,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Emit {
+    /// The tokens and their spans.
+    Tokens,
+    /// The syntax tree.
+    Ast,
+    /// The syntax tree with types.
+    Typed,
+    /// The IR, as lowered from the typed syntax tree.
+    Ir,
+    /// The IR after optimizations.
+    OptIr,
+    /// x86-64 assembly for Linux.
+    Asm,
+    /// An ES2020 module.
+    Js,
+    /// A Lua 5.4 chunk.
+    Lua,
+    /// A WebAssembly module in the binary format.
+    Wasm,
+    /// A WebAssembly module in the text format.
+    Wat,
+}
+
+/// A stage of the compiler that can be printed after
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Stage {
+    Lex,
+    Parse,
+    Typecheck,
+    Lower,
+    Anf,
+    Closure,
+    Inline,
+    Fold,
+    Dce,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Lex       => "lex",
+            Stage::Parse     => "parse",
+            Stage::Typecheck => "typecheck",
+            Stage::Lower     => "lower",
+            _ => self.pass().map_or("", |p| p.name()),
+        }
+    }
+
+    /// The IR pass the stage is, if it is one
+    pub fn pass(&self) -> Option<Pass> {
+        match self {
+            Stage::Anf     => Some(Pass::Anf),
+            Stage::Closure => Some(Pass::Closure),
+            Stage::Inline  => Some(Pass::Inline),
+            Stage::Fold    => Some(Pass::Fold),
+            Stage::Dce     => Some(Pass::Dce),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Check files for errors without compiling them.
+    Check {
+        /// The files to be checked, `-` for stdin.
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+        /// Check them again whenever they or the modules they import
+        /// change. Only on Linux.
+        #[arg(short, long)]
+        watch: bool,
+    },
+    /// Compile files, or the project of the closest `holymer.toml`.
+    Build {
+        /// The files to be compiled, `-` for stdin.
+        #[arg(value_name = "FILE")]
+        files: Vec<String>,
+        /// The target to compile for, `x86_64-linux` unless the manifest
+        /// says otherwise.
+        #[arg(long, value_enum)]
+        target: Option<Target>,
+        /// The path of the output, named after the file by default.
+        #[arg(short = 'o', long = "output")]
+        output: Option<String>,
+    },
+    /// Interpret files, reporting runtime errors where they happen.
+    Run {
+        /// The files to be run one after the other, `-` for stdin.
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+        /// Run them again whenever they or the modules they import change.
+        /// Only on Linux.
+        #[arg(short, long)]
+        watch: bool,
+    },
+    /// Print the result of a stage of the compiler, or the program in
+    /// another language.
+    Emit {
+        /// What to print.
+        #[arg(value_enum)]
+        what: Emit,
+        /// The files to be compiled, `-` for stdin.
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+    },
+    /// Print files in the standard style.
+    Fmt {
+        /// The files to be formatted, `-` for stdin.
+        #[arg(required = true, value_name = "FILE")]
+        files: Vec<String>,
+        /// Overwrite the files instead of printing them.
+        #[arg(short, long, conflicts_with = "check")]
+        write: bool,
+        /// Only check that the files are formatted, failing if they aren't.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Evaluate expressions interactively.
+    Repl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Target {
+    /// An executable for Linux on x86-64, assembled and linked by the
+    /// system's `cc`.
+    #[value(name = "x86_64-linux")]
+    X86_64Linux,
+    /// An ES2020 module.
+    Js,
+    /// A Lua 5.4 chunk.
+    Lua,
+    /// A WebAssembly module.
+    Wasm,
+}
+
+impl Target {
+    /// The extension of what is built for the target
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "",
+            Target::Js   => "js",
+            Target::Lua  => "lua",
+            Target::Wasm => "wasm",
+        }
+    }
 }
 
 pub fn get_args() -> Args {
     Args::parse()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_map() {
+        for args in [
+            &["hc", "build", "--source-map", "out.map", "a.hlm"][..],
+            &["hc", "build", "a.hlm", "--target", "js", "--source-map", "out.map"],
+            &["hc", "emit", "js", "a.hlm", "--source-map", "out.map"],
+            &["hc", "--source-map", "out.map", "emit", "asm", "a.hlm"],
+        ] {
+            let args = Args::try_parse_from(args).unwrap();
+            assert_eq!(args.common.source_map.as_deref(), Some("out.map"));
+        }
+        assert!(Args::try_parse_from(["hc", "emit", "js", "a.hlm", "--source-map"]).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+//! A line editor for the REPL, with the usual keys and a history kept in
+//! `~/.hc_history`. When stdin isn't a terminal, lines are read as is.
+
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Read, Write},
+    path::PathBuf,
+};
+
+pub struct Editor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+    terminal: bool,
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    // Ctrl-U, deleting everything before the cursor
+    Kill,
+    // Ctrl-C, starting over on a new line
+    Interrupt,
+    // Ctrl-D
+    Eof,
+    Other,
+}
+
+/// Puts the terminal in raw mode for as long as it lives
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        // SAFETY: the termios struct is plain data filled in by tcgetattr
+        unsafe {
+            let mut t: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut t) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let original = t;
+            libc::cfmakeraw(&mut t);
+            // Keep turning `\n` into `\r\n` on output
+            t.c_oflag |= libc::OPOST;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &t) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self(original))
+        }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `enable`
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSADRAIN, &self.0);
+        }
+    }
+}
+
+fn byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut b = [0];
+    Ok((input.read(&mut b)? == 1).then_some(b[0]))
+}
+
+/// Read a key press, or `None` at the end of the input
+fn key(input: &mut impl Read) -> io::Result<Option<Key>> {
+    let Some(b) = byte(input)? else { return Ok(None) };
+    Ok(Some(match b {
+        b'\r' | b'\n' => Key::Enter,
+        127 | 8 => Key::Backspace,
+        1  => Key::Home,
+        2  => Key::Left,
+        3  => Key::Interrupt,
+        4  => Key::Eof,
+        5  => Key::End,
+        6  => Key::Right,
+        14 => Key::Down,
+        16 => Key::Up,
+        21 => Key::Kill,
+        // Escape sequences, `ESC [ A` or `ESC [ 3 ~`
+        27 => {
+            if !matches!(byte(input)?, Some(b'[' | b'O')) {
+                return Ok(Some(Key::Other));
+            }
+            let mut code = byte(input)?;
+            let mut n = None;
+            while let Some(d @ b'0'..=b'9') = code {
+                n = Some(n.unwrap_or(0) * 10 + (d - b'0') as u32);
+                code = byte(input)?;
+            }
+            match (code, n) {
+                (Some(b'A'), _) => Key::Up,
+                (Some(b'B'), _) => Key::Down,
+                (Some(b'C'), _) => Key::Right,
+                (Some(b'D'), _) => Key::Left,
+                (Some(b'H'), _) | (Some(b'~'), Some(1 | 7)) => Key::Home,
+                (Some(b'F'), _) | (Some(b'~'), Some(4 | 8)) => Key::End,
+                (Some(b'~'), Some(3)) => Key::Delete,
+                _ => Key::Other,
+            }
+        }
+        b if b < 0x20 => Key::Other,
+        b => {
+            // The rest of a UTF-8 sequence
+            let len = b.leading_ones().max(1) as usize;
+            let mut bytes = vec![b];
+            for _ in 1..len {
+                bytes.extend(byte(input)?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    }))
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        let path = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".hc_history"));
+        let history = path.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|s| s.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Self { history, path, terminal: io::stdin().is_terminal() && io::stdout().is_terminal() }
+    }
+
+    /// Read a line, or `None` at the end of the input
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        if !self.terminal {
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()));
+        }
+        let line = {
+            let _raw = RawMode::enable()?;
+            self.edit(prompt)?
+        };
+        if let Some(line) = &line {
+            self.remember(line);
+        }
+        Ok(line)
+    }
+
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if let Some(path) = &self.path {
+            // Losing the history isn't worth stopping for
+            let _ = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", line));
+        }
+    }
+
+    fn edit(&self, prompt: &str) -> io::Result<Option<String>> {
+        let mut stdin = io::stdin().lock();
+        let mut stdout = io::stdout().lock();
+        let mut line: Vec<char> = vec![];
+        let mut cursor = 0;
+        // Where we are in the history, and the line that was being written
+        // before going through it
+        let mut entry = self.history.len();
+        let mut draft = vec![];
+        loop {
+            write!(stdout, "\r{}{}\x1b[K", prompt, line.iter().collect::<String>())?;
+            if cursor < line.len() {
+                write!(stdout, "\x1b[{}D", line.len() - cursor)?;
+            }
+            stdout.flush()?;
+
+            let Some(key) = key(&mut stdin)? else { return Ok(None) };
+            match key {
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Enter => {
+                    writeln!(stdout)?;
+                    return Ok(Some(line.into_iter().collect()));
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Delete if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Home => cursor = 0,
+                Key::End => cursor = line.len(),
+                Key::Up | Key::Down => {
+                    let next = match key {
+                        Key::Up => entry.checked_sub(1),
+                        _ => (entry < self.history.len()).then_some(entry + 1),
+                    };
+                    if let Some(next) = next {
+                        if entry == self.history.len() {
+                            draft = line.clone();
+                        }
+                        entry = next;
+                        line = match self.history.get(next) {
+                            Some(old) => old.chars().collect(),
+                            None => draft.clone(),
+                        };
+                        cursor = line.len();
+                    }
+                }
+                Key::Kill => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                Key::Interrupt => {
+                    writeln!(stdout, "^C")?;
+                    line.clear();
+                    cursor = 0;
+                    entry = self.history.len();
+                }
+                Key::Eof if line.is_empty() => {
+                    writeln!(stdout)?;
+                    return Ok(None);
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Loading the modules of a program, starting from its entry file.
+//!
+//! `import util.math;` is looked up as `util/math.hlm` in each of the roots
+//! in turn: the source directories of a project, or else the directory of
+//! the entry file.
+
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use ariadne::{Color, ReportKind};
+use ir::text::{self, SexprType};
+use syntax::{
+    expr::Span,
+    module::{alias, path_name, sort, Module, ModulePath},
+    source::FileId,
+};
+use typing::memo::Memo;
+
+use crate::{args::Emit, name, parse, relative, Failure, Pipeline};
+
+pub struct Loaded<'src> {
+    pub file: FileId,
+    /// Its path if it's imported, else its own `module` declaration or file
+    pub name: String,
+    pub module: Module<'src>,
+    /// The name each import is referred to by, the module it is if it
+    /// could be loaded, and where it is imported
+    pub imports: Vec<(&'src str, Option<usize>, Span)>,
+    /// Whether it has errors that keep it from being type checked
+    pub broken: bool,
+}
+
+pub struct Loader<'src> {
+    roots: Vec<PathBuf>,
+    pub modules: Vec<Loaded<'src>>,
+    // The modules loaded by their canonical path, `None` for those that
+    // couldn't be, so that their errors are only reported once
+    paths: HashMap<PathBuf, Option<usize>>,
+    // How many files had errors
+    pub failed: usize,
+}
+
+impl<'src> Loader<'src> {
+    pub fn new(roots: Vec<PathBuf>) -> Self {
+        Self { roots, modules: vec![], paths: HashMap::new(), failed: 0 }
+    }
+
+    /// Load a file and everything it imports, or stdin for `-`. Returns the
+    /// module it is if it could be read and parsed. `emit` is only for the
+    /// file itself, its imports are always parsed quietly
+    pub fn load(&mut self, pipe: &mut Pipeline<'src>, path: &str, emit: Emit) -> Option<usize> {
+        let key = (path != "-").then(|| std::fs::canonicalize(path).unwrap_or_else(|_| path.into()));
+        if let Some(m) = key.as_ref().and_then(|k| self.paths.get(k)) {
+            return *m;
+        }
+        let Some(text) = pipe.read_file(path) else {
+            self.failed += 1;
+            return None;
+        };
+        let stem = Path::new(name(path)).file_stem().unwrap_or_default().to_string_lossy().to_string();
+        self.add(pipe, name(path), key, text, emit, Err(stem))
+    }
+
+    /// Parse a module and load its imports.
+    /// `expected` is the path it is imported with, or the name to give it
+    /// if it isn't imported and doesn't declare one
+    fn add(
+        &mut self,
+        pipe: &mut Pipeline<'src>,
+        path: &str,
+        key: Option<PathBuf>,
+        text: String,
+        emit: Emit,
+        expected: Result<&ModulePath<'src>, String>,
+    ) -> Option<usize> {
+        let errors = pipe.reporter.errors();
+        if let Some((loaded, key)) = pipe.loaded.as_mut().zip(key.as_ref()) {
+            loaded.insert(key.clone());
+        }
+        let (file, _) = pipe.add_source(path, text);
+        let Some((_, module)) = parse(pipe, file, emit) else {
+            if let Some(key) = key {
+                self.paths.insert(key, None);
+            }
+            // Not a failure when the output was asked for
+            if pipe.reporter.errors() > errors {
+                self.failed += 1;
+            }
+            return None;
+        };
+
+        let name = match (&expected, &module.name) {
+            (Ok(path), Some((declared, span))) if declared != *path => {
+                let msg = format!("imported as `{}`", path_name(path));
+                let title = format!("this is module `{}`, not `{}`", path_name(declared), path_name(path));
+                pipe.error_at(*span, title, msg);
+                pipe.fail(Failure::Type);
+                path_name(path)
+            }
+            (Ok(path), _) => path_name(path),
+            (Err(_), Some((declared, _))) => path_name(declared),
+            (Err(stem), None) => stem.clone(),
+        };
+        let m = self.modules.len();
+        if let Some(key) = &key {
+            self.paths.insert(key.clone(), Some(m));
+        }
+        let paths = module.imports.clone();
+        self.modules.push(Loaded { file, name, module, imports: vec![], broken: false });
+
+        // Errors in the modules it imports are theirs
+        let mut ok = pipe.reporter.errors() == errors;
+        let mut imports: Vec<(&'src str, Option<usize>, Span)> = vec![];
+        for (path, span) in &paths {
+            let alias = alias(path);
+            if imports.iter().any(|(a, _, _)| *a == alias) {
+                let msg = format!("another import is already called `{}`", alias);
+                error(pipe, *span, format!("`{}` is imported twice", alias), msg);
+                ok = false;
+                continue;
+            }
+            match self.import(pipe, path, *span) {
+                Ok(import) => imports.push((alias, import, *span)),
+                Err(()) => ok = false,
+            }
+        }
+        self.modules[m].imports = imports;
+        if !ok {
+            self.modules[m].broken = true;
+            self.failed += 1;
+        }
+        Some(m)
+    }
+
+    /// Find an imported module and load it. Fails if there is no such
+    /// module, and gives `None` if it has errors of its own
+    fn import(
+        &mut self,
+        pipe: &mut Pipeline<'src>,
+        path: &ModulePath<'src>,
+        span: Span,
+    ) -> Result<Option<usize>, ()> {
+        let file = self.roots.iter()
+            .map(|root| root.join(path.join("/")).with_extension("hlm"))
+            .find(|f| f.is_file());
+        let Some(file) = file else {
+            let roots = self.roots.iter().map(|r| relative(r)).collect::<Vec<_>>();
+            let msg = format!("looked for {}.hlm in {}", path.join("/"), roots.join(", "));
+            error(pipe, span, format!("module `{}` not found", path_name(path)), msg);
+            return Err(());
+        };
+        let key = std::fs::canonicalize(&file).unwrap_or_else(|_| file.clone());
+        if let Some(m) = self.paths.get(&key) {
+            return Ok(*m);
+        }
+        let Some(text) = pipe.read_file(&relative(&file)) else {
+            self.paths.insert(key, None);
+            self.failed += 1;
+            return Ok(None);
+        };
+        Ok(self.add(pipe, &relative(&file), Some(key), text, Emit::Ir, Ok(path)))
+    }
+
+    /// How many files were loaded or tried to be, besides stdin
+    pub fn files(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// The modules in the order they are compiled in, each one after the
+    /// ones it imports, or `None` if they import each other in a cycle
+    pub fn order(&mut self, pipe: &mut Pipeline) -> Option<Vec<usize>> {
+        let imports = self.modules.iter()
+            .map(|m| m.imports.iter().filter_map(|(_, i, _)| *i).collect())
+            .collect::<Vec<_>>();
+        let cycle = match sort(&imports) {
+            Ok(order) => return Some(order),
+            Err(cycle) => cycle,
+        };
+        let names = cycle.iter()
+            .chain(&cycle[..1])
+            .map(|&m| format!("`{}`", self.modules[m].name))
+            .collect::<Vec<_>>();
+        // Every import of the cycle is labelled, in the file it is in
+        let labels = cycle.iter()
+            .zip(cycle.iter().cycle().skip(1))
+            .enumerate()
+            .map(|(i, (&m, &next))| {
+                let (_, _, span) = self.modules[m].imports.iter().find(|(_, import, _)| *import == Some(next)).unwrap();
+                (format!("{} imports {}", names[i], names[i + 1]), *span, Color::Red)
+            })
+            .collect::<Vec<(_, Span, _)>>();
+        let title = format!("import cycle: {}", names.join(" -> "));
+        pipe.report(ReportKind::Error, labels[0].1, title, labels);
+        pipe.fail(Failure::Type);
+        self.failed += 1;
+        None
+    }
+}
+
+fn error(pipe: &mut Pipeline, span: Span, title: String, msg: String) {
+    pipe.error_at(span, title, msg);
+    pipe.fail(Failure::Type);
+}
+
+// The first line of the saved types, to ignore those of other versions of
+// the format or of the compiler
+const HEADER: &str = concat!("hc types 2 ", env!("CARGO_PKG_VERSION"));
+
+/// Read the types saved by [`save_types`]. Types that are missing or can't
+/// be read are none, as they are only there to save time
+pub fn load_types(path: &Path) -> Memo {
+    let mut memo = Memo::default();
+    let Ok(text) = std::fs::read_to_string(path) else { return memo };
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return memo;
+    }
+    for line in lines {
+        let (fingerprint, types) = line.split_once(' ').unwrap_or((line, ""));
+        let Ok(fingerprint) = u64::from_str_radix(fingerprint, 16) else { continue };
+        if let Ok(types) = text::parse_types(FileId::default(), types) {
+            memo.insert(fingerprint, types);
+        }
+    }
+    memo
+}
+
+/// Save the types of the groups of items checked since the memo was last
+/// cleared, one group per line
+pub fn save_types(memo: &Memo, path: &Path) -> std::io::Result<()> {
+    let mut out = format!("{}\n", HEADER);
+    let mut groups = memo.used().collect::<Vec<_>>();
+    groups.sort_by_key(|(fingerprint, _)| *fingerprint);
+    for (fingerprint, types) in groups {
+        out += &format!("{:016x}", fingerprint);
+        types.iter().for_each(|t| out += &format!(" {}", SexprType(t)));
+        out.push('\n');
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, out)
+}
@@ -1,87 +1,667 @@
-use ariadne::{sources, Color, Label, Report, ReportKind};
-use chumsky::{Parser, prelude::Input};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use ir::lower_expr;
-use syntax::parser::{lexer, exprs_parser};
-use typing::infer::{infer_exprs, InferErrorKind};
+use ariadne::{Color, ReportKind};
+use clap::ValueEnum;
+
+use com::{
+    session::{self, Diagnostic, Phase, Session, Severity, TypedModule, TypedProgram},
+    source_map::SourceMap,
+    x86_64,
+};
+use ir::{pretty::pretty, text, Expr};
+use syntax::{
+    expr::{Span, Token},
+    module::Module,
+    pretty::{comments, pretty as pretty_source},
+    source::{FileId, Texts},
+};
+use typing::infer::Interface;
+
+use args::{Args, Command, Emit, Stage, Target};
+use load::{Loaded, Loader};
+use manifest::Manifest;
+use report::Reporter;
 
 pub mod args;
+mod line;
+mod load;
+pub mod manifest;
+pub mod report;
+mod repl;
+#[cfg(target_os = "linux")]
+mod watch;
 
-fn main() {
-    let args = args::get_args();
-    let filename = args.file.clone();
-    let src = std::fs::read_to_string(&args.file).expect("file not found");
+/// Why `hc` failed, which is its exit status. With several files, it is
+/// the first failure
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Failure {
+    /// Runtime and code generation errors, invalid IR, unformatted files
+    Error = 1,
+    /// Wrong arguments, as clap exits with on its own
+    Usage = 2,
+    /// A file couldn't be read or written
+    Io = 3,
+    Syntax = 4,
+    Type = 5,
+}
 
-    // Lexing & parsing
-    let (ts, errs) = lexer().parse(&src).into_output_errors();
+/// Runs the stages of the compiler with a [`Session`], reporting what goes
+/// wrong and printing and timing the stages as asked
+struct Pipeline<'src> {
+    reporter: Reporter,
+    session: Session<'src>,
+    failure: Option<Failure>,
+    from_ir: bool,
+    print_after: Vec<Stage>,
+    time_passes: bool,
+    source_map: Option<String>,
+    // The files loaded or tried to be, for `--watch` to watch them
+    loaded: Option<HashSet<PathBuf>>,
+}
 
-    let (ast, parse_errs) = if let Some(tokens) = &ts {
-        let (ast, parse_errs) = exprs_parser()
-            .map_with_span(|ast, span| (ast, span))
-            .parse(tokens.as_slice().spanned((src.len()..src.len()).into()))
-            .into_output_errors();
+impl<'src> Pipeline<'src> {
+    fn new(args: &Args, texts: &'src Texts) -> Self {
+        let mut session = Session::new(texts);
+        session.verify_ir = args.common.verify_ir;
+        let print_after = args.common.print_after.clone();
+        if print_after.iter().any(|s| s.pass().is_some() || *s == Stage::Lower) {
+            let print_after = print_after.clone();
+            session.after = Some(Box::new(move |stage, irs| {
+                if print_after.iter().any(|s| s.name() == stage) {
+                    eprint!("// after {}\n{}", stage, pretty(irs));
+                }
+            }));
+        }
+        Self {
+            reporter: Reporter::new(&args.common),
+            session,
+            failure: None,
+            from_ir: args.common.from_ir,
+            print_after,
+            time_passes: args.common.time_passes,
+            source_map: args.common.source_map.clone(),
+            loaded: None,
+        }
+    }
 
-        (ast, parse_errs)
-    } else {
-        (None, vec![])
-    };
+    /// Print the result of a stage to stderr if asked to
+    fn print<T: Display>(&self, stage: Stage, items: impl IntoIterator<Item = T>) {
+        if self.print_after.contains(&stage) {
+            eprintln!("// after {}", stage.name());
+            items.into_iter().for_each(|i| eprintln!("{}", i));
+        }
+    }
 
-    // Typecheck if there are no lexing or parsing errors
-    if let Some(ast) = ast.filter(|_| errs.len() + parse_errs.len() == 0) {
-        let (ast, e) = infer_exprs(ast.0);
-        // If there is an error, print it
-        if !e.is_empty() {
-            e.into_iter()
-                .for_each(|e| {
-                    let mut r = Report::build(ReportKind::Error, filename.clone(), e.span.start)
-                        .with_message(e.title.to_string());
-
-                    for (msg, kind, span) in e.labels {
-                        r = r.with_label(
-                            Label::new((filename.clone(), span.into_range()))
-                                .with_message(msg.to_string())
-                                .with_color(match kind {
-                                    InferErrorKind::Error => Color::Red,
-                                    InferErrorKind::Hint => Color::Blue,
-                                }),
-                        );
-                    }
+    /// Lower the entry module of a program and the modules it needs into
+    /// one program, each module after the ones it imports
+    fn lower(&mut self, program: Program<'src>) -> Option<Vec<Expr<'src>>> {
+        let needed = program.needed();
+        let Program { loader, typed } = program;
+        // Where each module is in the program lowered
+        let mut index = HashMap::new();
+        let mut modules = vec![];
+        for (m, mut module) in typed.into_iter().filter(|(m, _)| needed.contains(m)) {
+            let imports = loader.modules[m].imports.iter();
+            module.imports = imports.map(|(alias, i, _)| (*alias, index[&i.unwrap()])).collect();
+            index.insert(m, modules.len());
+            modules.push(module);
+        }
+        self.session.lower(TypedProgram::new(modules)).map_err(|errs| self.diagnose(errs)).ok()
+    }
+
+    /// Optimize a program, reporting the warnings of the optimizer
+    fn optimize(&mut self, irs: Vec<Expr<'src>>) -> Option<Vec<Expr<'src>>> {
+        let irs = self.session.optimize(irs);
+        let warnings = self.session.warnings();
+        self.diagnose(warnings);
+        irs.map_err(|errs| self.diagnose(errs)).ok()
+    }
+
+    /// Report something at a span of the sources
+    fn report(&mut self, kind: ReportKind<'static>, span: Span, message: impl Display, labels: Vec<(String, Span, Color)>) {
+        self.reporter.report(&self.session.sources, kind, span, message, labels);
+    }
 
-                    r
-                        .finish()
-                        .print(sources([(filename.clone(), src.clone())]))
-                        .unwrap()
+    fn error_at(&mut self, span: Span, title: impl Display, message: impl Display) {
+        self.reporter.error_at(&self.session.sources, span, title, message);
+    }
+
+    /// Report a diagnostic of the session
+    fn show(&mut self, d: Diagnostic) {
+        let color = |severity| match severity {
+            Severity::Error => Color::Red,
+            Severity::Warning => Color::Yellow,
+            Severity::Hint => Color::Blue,
+        };
+        let kind = match d.severity {
+            Severity::Error => ReportKind::Error,
+            Severity::Warning => ReportKind::Warning,
+            Severity::Hint => ReportKind::Advice,
+        };
+        match d.span {
+            Some(span) => {
+                let labels = d.labels.into_iter().map(|(msg, span, s)| (msg, span, color(s))).collect();
+                self.report(kind, span, d.title, labels);
+            }
+            None => self.reporter.error(d.title),
+        }
+    }
+
+    /// Report diagnostics, failing with the errors among them
+    fn diagnose(&mut self, diagnostics: Vec<Diagnostic>) {
+        for d in diagnostics {
+            if d.severity == Severity::Error {
+                self.fail(match d.phase {
+                    Phase::Syntax => Failure::Syntax,
+                    Phase::Type => Failure::Type,
+                    _ => Failure::Error,
                 });
-        // Else go to the next stage
+            }
+            self.show(d);
+        }
+    }
+
+    /// Report the timings and what the error limit left out
+    fn finish(&self) {
+        self.reporter.finish();
+        if !self.time_passes {
+            return;
+        }
+        let timings = &self.session.timings;
+        let total: Duration = timings.iter().map(|(_, d)| *d).sum();
+        for (name, d) in timings.iter().chain([&("total", total)]) {
+            eprintln!("{:>10.3}ms  {}", d.as_secs_f64() * 1000.0, name);
+        }
+    }
+
+    /// Remember that something failed, to exit with it once every file
+    /// is done
+    fn fail(&mut self, failure: Failure) {
+        self.failure.get_or_insert(failure);
+    }
+
+    /// Stop right away
+    fn exit(&mut self, failure: Failure) -> ! {
+        self.fail(failure);
+        self.finish();
+        std::process::exit(self.failure.map_or(0, |f| f as i32))
+    }
+
+    /// Report an error that stops the command before it starts
+    fn usage(&mut self, message: impl Display) -> ! {
+        self.reporter.error(message);
+        self.exit(Failure::Usage)
+    }
+
+    /// Read a file, or stdin for `-`
+    fn read_file(&mut self, path: &str) -> Option<String> {
+        let read = if path == "-" {
+            std::io::read_to_string(std::io::stdin())
         } else {
-            if args.typecheck {
-                ast.iter().for_each(|node| println!("{:?}", node.0));
-                return;
+            std::fs::read_to_string(path)
+        };
+        read.map_err(|e| {
+            self.reporter.error(format!("could not read {}: {}", name(path), e));
+            self.fail(Failure::Io);
+        }).ok()
+    }
+
+    /// Add the text of a file to the sources of the session
+    fn add_source(&mut self, path: &str, text: String) -> (FileId, &'src str) {
+        let file = self.session.add_source(name(path), text);
+        (file, self.session.sources.get(file).text)
+    }
+
+    /// Read a file and add it to the sources
+    fn read_source(&mut self, path: &str) -> Option<(FileId, &'src str)> {
+        let text = self.read_file(path)?;
+        Some(self.add_source(path, text))
+    }
+
+    fn write_file(&mut self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> bool {
+        std::fs::write(&path, contents).map_err(|e| {
+            self.reporter.error(format!("could not write {}: {}", path.as_ref().display(), e));
+            self.fail(Failure::Io);
+        }).is_ok()
+    }
+}
+
+/// What to call a file in reports
+fn name(path: &str) -> &str {
+    if path == "-" { "<stdin>" } else { path }
+}
+
+/// The tokens of a source file and its syntax tree
+type Parsed<'src> = (Vec<(Token<'src>, Span)>, Module<'src>);
+
+/// The modules of a program, the first one being its entry, and those that
+/// could be type checked in the order they are compiled in, whose imports
+/// are only filled in once it is known which modules are lowered
+struct Program<'src> {
+    loader: Loader<'src>,
+    typed: Vec<(usize, TypedModule<'src>)>,
+}
+
+impl Program<'_> {
+    /// The entry module and those it imports, directly or not
+    fn needed(&self) -> HashSet<usize> {
+        let mut needed = HashSet::from([0]);
+        let mut stack = vec![0];
+        while let Some(m) = stack.pop() {
+            for (_, i, _) in &self.loader.modules[m].imports {
+                if let Some(i) = *i {
+                    if needed.insert(i) {
+                        stack.push(i);
+                    }
+                }
+            }
+        }
+        needed
+    }
+}
+
+/// Where the modules imported by a file are, when it isn't part of a
+/// project: next to it
+fn roots(path: &str) -> Vec<PathBuf> {
+    let dir = Path::new(path).parent().filter(|_| path != "-");
+    vec![dir.map_or_else(PathBuf::new, Path::to_path_buf)]
+}
+
+/// Lex and parse a source file, reporting every error on the way.
+/// Returns the tokens and the syntax tree only if there were no errors and
+/// `emit` asks for a later stage, otherwise the output is printed here.
+fn parse<'src>(pipe: &mut Pipeline<'src>, file: FileId, emit: Emit) -> Option<Parsed<'src>> {
+    if emit == Emit::Tokens {
+        match pipe.session.lex(file) {
+            Ok(tokens) => tokens.iter().for_each(|(t, s)| println!("{:?} {}", s, t)),
+            Err(errs) => pipe.diagnose(errs),
+        }
+        return None;
+    }
+    let (tokens, ast) = pipe.session.parse(file).map_err(|errs| pipe.diagnose(errs)).ok()?;
+    pipe.print(Stage::Lex, tokens.iter().map(|(t, s)| format!("{:?} {}", s, t)));
+    pipe.print(Stage::Parse, ast.items.iter().map(|node| format!("{:?}", node.0)));
+    if emit == Emit::Ast {
+        ast.items.iter().for_each(|node| println!("{:?}", node.0));
+        return None;
+    }
+    Some((tokens, ast))
+}
+
+/// Run the front end on a program: its entry file, every module it
+/// imports, and `others` to check along with them. Every error on the way
+/// is reported, and modules are type checked as long as the ones they
+/// import have no errors. Returns the program unless `emit` asks for an
+/// earlier stage, whose output is printed here
+fn typecheck<'src>(
+    pipe: &mut Pipeline<'src>,
+    roots: Vec<PathBuf>,
+    entry: &str,
+    others: &[String],
+    emit: Emit,
+) -> Option<Program<'src>> {
+    let mut loader = Loader::new(roots);
+    if loader.load(pipe, entry, emit).is_none() && loader.failed == 0 {
+        return None;
+    }
+    for path in others {
+        loader.load(pipe, path, Emit::Ir);
+    }
+
+    let mut interfaces: HashMap<usize, Interface> = HashMap::new();
+    let mut typed = vec![];
+    for m in loader.order(pipe).unwrap_or_default() {
+        let Loaded { file, module, imports, broken, .. } = &loader.modules[m];
+        if *broken || imports.iter().any(|(_, i, _)| i.is_none_or(|i| !interfaces.contains_key(&i))) {
+            continue;
+        }
+        let imports = imports.iter().map(|(alias, i, _)| (*alias, interfaces[&i.unwrap()].clone())).collect();
+        match pipe.session.check_module(*file, module.clone(), imports) {
+            Ok((items, interface)) => {
+                interfaces.insert(m, interface.clone());
+                typed.push((m, TypedModule { file: *file, items, interface, imports: vec![] }));
+            }
+            Err(errs) => {
+                pipe.diagnose(errs);
+                loader.failed += 1;
+            }
+        }
+    }
+
+    if loader.failed == 0 {
+        let nodes = || typed.iter().flat_map(|(_, m)| &m.items).map(|node| format!("{:?}", node.0));
+        pipe.print(Stage::Typecheck, nodes());
+        if emit == Emit::Typed {
+            nodes().for_each(|node| println!("{}", node));
+            return None;
+        }
+    }
+    Some(Program { loader, typed })
+}
+
+/// Read the IR of a program, either by running the front end on source
+/// code or from textual IR
+fn read_ir<'src>(pipe: &mut Pipeline<'src>, path: &str, emit: Emit) -> Option<Vec<Expr<'src>>> {
+    if !pipe.from_ir {
+        let program = typecheck(pipe, roots(path), path, &[], emit)?;
+        if program.loader.failed > 0 {
+            return None;
+        }
+        return pipe.lower(program);
+    }
+    let (file, src) = pipe.read_source(path)?;
+    match pipe.session.time("read", || text::parse(file, src)) {
+        Ok(irs) => match pipe.session.verify("read", &irs) {
+            Ok(()) => Some(irs),
+            Err(errs) => {
+                pipe.diagnose(errs);
+                None
             }
-            // ast.iter().for_each(|node| println!("{:?}", node.0));
-            let irs = ast.into_iter().map(|node| lower_expr(node.0)).collect::<Vec<_>>();
-            irs.iter().for_each(|ir| println!("{}", ir));
+        },
+        Err(e) => {
+            pipe.error_at(e.span, format!("invalid IR: {}", e.message), e.message);
+            pipe.fail(Failure::Syntax);
+            None
         }
+    }
+}
+
+fn check(pipe: &mut Pipeline, path: &str) {
+    if pipe.from_ir {
+        read_ir(pipe, path, Emit::Ir);
+    } else {
+        typecheck(pipe, roots(path), path, &[], Emit::Ir);
+    }
+}
+
+/// Compile a file and write what it builds to `output`
+fn build(pipe: &mut Pipeline, path: &str, target: Target, output: &Path) {
+    let Some(irs) = read_ir(pipe, path, Emit::Asm) else { return };
+    codegen(pipe, irs, target, output);
+}
+
+/// Optimize a program and generate code for it, writing it to `output`
+fn codegen<'src>(pipe: &mut Pipeline<'src>, irs: Vec<Expr<'src>>, target: Target, output: &Path) {
+    if pipe.source_map.is_some() && !matches!(target, Target::Js | Target::X86_64Linux) {
+        pipe.usage("source maps can only be written for the `js` and `x86_64-linux` targets");
+    }
+    let Some(irs) = pipe.optimize(irs) else { return };
+    let out = match target {
+        Target::X86_64Linux => session::Target::Asm,
+        Target::Js   => session::Target::Js,
+        Target::Lua  => session::Target::Lua,
+        Target::Wasm => session::Target::Wasm,
+    };
+    let code = match pipe.session.codegen(&irs, out) {
+        Ok(mut out) => {
+            if let Some((path, map)) = pipe.source_map.clone().zip(out.source_map) {
+                write_source_map(pipe, target == Target::Js, &path, map, &mut out.code);
+            }
+            out.code
+        }
+        Err(e) => return pipe.diagnose(vec![e]),
     };
+    if let Some(dir) = output.parent().filter(|d| !d.as_os_str().is_empty()) {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            pipe.reporter.error(format!("could not create {}: {}", dir.display(), e));
+            return pipe.fail(Failure::Io);
+        }
+    }
+    if target != Target::X86_64Linux {
+        pipe.write_file(output, code);
+        return;
+    }
+    let asm = String::from_utf8(code).unwrap();
+    if let Err(e) = pipe.session.time("assemble", || x86_64::build(&asm, output)) {
+        pipe.reporter.error(e);
+        pipe.fail(Failure::Error);
+    }
+}
+
+/// A path as it is shown in reports, relative to the current directory
+fn relative(path: &Path) -> String {
+    let cwd = std::env::current_dir().unwrap_or_default();
+    match path.strip_prefix(&cwd).unwrap_or(path) {
+        p if p.as_os_str().is_empty() => ".".to_string(),
+        p => p.display().to_string(),
+    }
+}
+
+/// Every module under a directory
+fn modules(pipe: &mut Pipeline, dir: &Path, out: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            pipe.reporter.error(format!("could not read {}: {}", relative(dir), e));
+            return pipe.fail(Failure::Io);
+        }
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            modules(pipe, &path, out);
+        } else if path.extension().is_some_and(|ext| ext == "hlm") {
+            out.push(path);
+        }
+    }
+}
 
-    // Report lex & parse errors
-    errs.into_iter()
-        .map(|e| e.map_token(|c| c.to_string()))
-        .chain(
-            parse_errs
-                .into_iter()
-                .map(|e| e.map_token(|tok| tok.to_string())),
-        )
-        .for_each(|e| {
-            Report::build(ReportKind::Error, filename.clone(), e.span().start)
-                .with_message(e.to_string())
-                .with_label(
-                    Label::new((filename.clone(), e.span().into_range()))
-                        .with_message(e.reason().to_string())
-                        .with_color(Color::Red),
-                )
-                .finish()
-                .print(sources([(filename.clone(), src.clone())]))
-                .unwrap()
-        });
-}
\ No newline at end of file
+/// Build the project of a manifest. Every module in its source directories
+/// is type checked so that all of their errors are reported at once, and
+/// the entry module is compiled with those it imports to
+/// `<output-dir>/<target>/<name>`. The types of the modules are kept in
+/// `<output-dir>/cache` for the next build to only check what changed
+fn build_project(pipe: &mut Pipeline, path: &Path, target: Option<Target>) {
+    let Some((file, src)) = pipe.read_source(&relative(path)) else { return };
+    let manifest = match Manifest::parse(file, src) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            pipe.error_at(e.span, format!("invalid manifest: {}", e.message), e.message);
+            return pipe.fail(Failure::Usage);
+        }
+    };
+    let root = path.parent().unwrap();
+    let target = target.unwrap_or(manifest.target);
+    pipe.session.opt_level = manifest.opt_level;
+
+    let mut others = vec![];
+    for dir in &manifest.source_dirs {
+        modules(pipe, &root.join(dir), &mut others);
+    }
+    others.sort();
+    let others = others.iter().map(|m| relative(m)).collect::<Vec<_>>();
+    let roots = manifest.source_dirs.iter().map(|dir| root.join(dir)).collect();
+    let entry = relative(&root.join(&manifest.entry));
+    let cache = root.join(manifest.cache());
+    pipe.session.memo = Some(load::load_types(&cache));
+    let program = typecheck(pipe, roots, &entry, &others, Emit::Asm);
+    // Not worth failing the build over
+    let _ = load::save_types(&pipe.session.memo.take().unwrap(), &cache);
+    let Some(program) = program else { return };
+
+    if program.loader.failed > 0 {
+        pipe.reporter.error(format!(
+            "could not build `{}`: {} of its {} modules have errors",
+            manifest.name, program.loader.failed, program.loader.files(),
+        ));
+        return;
+    }
+    if let Some(irs) = pipe.lower(program) {
+        codegen(pipe, irs, target, &root.join(manifest.output(target)));
+    }
+}
+
+/// Interpret a program, printing the value of every top-level expression
+/// that isn't a definition
+fn run(pipe: &mut Pipeline, path: &str) {
+    let Some(irs) = read_ir(pipe, path, Emit::Ir) else { return };
+    // Each file runs on its own
+    pipe.session.forget();
+    let result = pipe.session.eval_each(&irs, |e, v| if !e.is_define() {
+        println!("{}", v);
+    });
+    if let Err(e) = result {
+        pipe.diagnose(vec![e]);
+    }
+}
+
+/// Write the source map of the output to `path`: a Source Map v3 linked to
+/// from the output for JavaScript, a line table otherwise
+fn write_source_map(pipe: &mut Pipeline, js: bool, path: &str, map: SourceMap, out: &mut Vec<u8>) {
+    if js {
+        out.extend(format!("//# sourceMappingURL={}\n", path).bytes());
+        let json = map.to_json(&pipe.session.sources);
+        pipe.write_file(path, json)
+    } else {
+        let table = map.line_table(&pipe.session.sources);
+        pipe.write_file(path, table)
+    };
+}
+
+fn emit(pipe: &mut Pipeline, emit: Emit, path: &str) {
+    let Some(irs) = read_ir(pipe, path, emit) else { return };
+    if emit == Emit::Ir {
+        irs.iter().for_each(|ir| println!("{}", ir));
+        return;
+    }
+    let Some(irs) = pipe.optimize(irs) else { return };
+    let target = match emit {
+        Emit::Js   => session::Target::Js,
+        Emit::Lua  => session::Target::Lua,
+        Emit::Wasm => session::Target::Wasm,
+        Emit::Wat  => session::Target::Wat,
+        Emit::Asm  => session::Target::Asm,
+        _ => {
+            let out = pipe.session.time("codegen", || irs.iter().map(|ir| format!("{}\n", ir)).collect::<String>());
+            return print!("{}", out);
+        }
+    };
+    match pipe.session.codegen(&irs, target) {
+        Ok(mut out) => {
+            if let Some((path, map)) = pipe.source_map.clone().zip(out.source_map) {
+                write_source_map(pipe, emit == Emit::Js, &path, map, &mut out.code);
+            }
+            std::io::stdout().write_all(&out.code).unwrap()
+        }
+        Err(e) => pipe.diagnose(vec![e]),
+    }
+}
+
+/// Print a file in the standard style, or check that it already is
+fn fmt(pipe: &mut Pipeline, path: &str, write: bool, check: bool) {
+    let Some((file, src)) = pipe.read_source(path) else { return };
+    let Some((tokens, module)) = parse(pipe, file, Emit::Ir) else { return };
+    // Comments aren't in the syntax tree, so they would be lost
+    if let Some(&span) = comments(file, src, &tokens).first() {
+        let msg = "formatting would remove this comment";
+        pipe.error_at(span, format!("can't format {}: it has comments", name(path)), msg);
+        pipe.fail(Failure::Error);
+        return;
+    }
+    let out = pretty_source(&module, src);
+    if check {
+        if out != src {
+            pipe.reporter.error(format!("{} is not formatted", name(path)));
+            pipe.fail(Failure::Error);
+        }
+    } else if write {
+        if out != src {
+            pipe.write_file(path, out);
+        }
+    } else {
+        print!("{}", out);
+    }
+}
+
+/// Run `f` on the files, then again whenever they change. Files are
+/// watched with inotify, so only on Linux
+fn watch(pipe: &mut Pipeline, args: &Args, files: &[String], f: impl Fn(&mut Pipeline, &str)) -> ! {
+    #[cfg(target_os = "linux")]
+    {
+        watch::watch(pipe, args, files, f)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (args, files, f);
+        pipe.usage("`--watch` is only supported on Linux")
+    }
+}
+
+fn main() {
+    // Die quietly when the output is closed early, as in `hc ... | head`,
+    // instead of panicking on the next print
+    #[cfg(unix)]
+    // SAFETY: done before anything else runs, there are no other threads
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+    }
+
+    let args = args::get_args();
+    let texts = Texts::new();
+    let mut pipe = Pipeline::new(&args, &texts);
+
+    if args.common.source_map.is_some() {
+        match &args.command {
+            Command::Build { files, .. } | Command::Emit { files, .. } if files.len() > 1 => {
+                pipe.usage("`--source-map` can only be given with a single file")
+            }
+            Command::Build { .. } | Command::Emit { .. } => {}
+            _ => pipe.usage("`--source-map` can only be given to `hc build` and `hc emit`"),
+        }
+    }
+
+    match &args.command {
+        Command::Check { files, watch: true } => watch(&mut pipe, &args, files, check),
+        Command::Check { files, watch: false } => files.iter().for_each(|f| check(&mut pipe, f)),
+        Command::Build { files, target, output } => {
+            if output.is_some() && files.len() != 1 {
+                pipe.usage("`-o` can only be given with a single file");
+            }
+            if files.is_empty() {
+                match std::env::current_dir().ok().and_then(|dir| manifest::find(&dir)) {
+                    Some(path) => build_project(&mut pipe, &path, *target),
+                    None => pipe.usage(format!(
+                        "no files to build, and no {} here or in a parent directory", manifest::FILENAME,
+                    )),
+                }
+            }
+            let target = target.unwrap_or(Target::X86_64Linux);
+            for f in files {
+                let stem = Path::new(if f == "-" { "out" } else { f });
+                let output = output.as_ref().map_or_else(|| stem.with_extension(target.extension()), PathBuf::from);
+                build(&mut pipe, f, target, &output);
+            }
+        }
+        Command::Run { files, watch: true } => watch(&mut pipe, &args, files, run),
+        Command::Run { files, watch: false } => files.iter().for_each(|f| run(&mut pipe, f)),
+        Command::Emit { what, files } => {
+            if pipe.from_ir && matches!(what, Emit::Tokens | Emit::Ast | Emit::Typed) {
+                pipe.usage(format!("can't emit {} from IR", what.to_possible_value().unwrap().get_name()));
+            }
+            if pipe.source_map.is_some() && !matches!(what, Emit::Js | Emit::Asm) {
+                pipe.usage("source maps can only be written for `hc emit js` and `hc emit asm`");
+            }
+            files.iter().for_each(|f| emit(&mut pipe, *what, f));
+        }
+        Command::Fmt { files, write, check } => {
+            if pipe.from_ir {
+                pipe.usage("only source code can be formatted");
+            }
+            if *write && files.iter().any(|f| f == "-") {
+                pipe.usage("stdin can't be formatted in place");
+            }
+            files.iter().for_each(|f| fmt(&mut pipe, f, *write, *check));
+        }
+        // Finished by the REPL, which makes a pipeline of its own
+        Command::Repl => return repl::repl(&args),
+    }
+    pipe.finish();
+    if let Some(failure) = pipe.failure {
+        std::process::exit(failure as i32);
+    }
+}
@@ -0,0 +1,348 @@
+//! The `holymer.toml` manifest of a project, for `hc build` without files.
+//!
+//! ```toml
+//! [package]
+//! name = "hello"
+//! entry = "src/main.hlm"      # the default
+//! source-dirs = ["src"]       # the default
+//!
+//! [build]
+//! target = "x86_64-linux"     # the default
+//! opt-level = 1               # the default, 0 turns the optimizer off
+//! output-dir = "target"       # the default
+//! ```
+//!
+//! Only the part of TOML this needs is read: tables, and keys with
+//! strings, integers, booleans or arrays of them on a single line.
+
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use syntax::{expr::Span, source::FileId};
+
+use crate::args::Target;
+
+pub const FILENAME: &str = "holymer.toml";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub entry: PathBuf,
+    pub source_dirs: Vec<PathBuf>,
+    pub target: Target,
+    pub opt_level: u8,
+    pub output_dir: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestError {
+    pub message: String,
+    pub span: Span,
+}
+
+macro_rules! bail {
+    ($span:expr, $($arg:tt)*) => {
+        return Err(ManifestError { message: format!($($arg)*), span: $span })
+    };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<(Value, Span)>),
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Str(_)   => "a string",
+            Value::Int(_)   => "an integer",
+            Value::Bool(_)  => "a boolean",
+            Value::Array(_) => "an array",
+        }
+    }
+}
+
+/// A `key = value` line, in the table it is under
+struct Entry {
+    table: String,
+    key: String,
+    key_span: Span,
+    value: Value,
+    span: Span,
+}
+
+/// Reads values from a line, `pos` being the offset of the line in the
+/// whole file
+struct Reader<'a> {
+    file: FileId,
+    src: &'a str,
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn rest(&self) -> &str {
+        &self.src[self.pos..]
+    }
+
+    fn skip_spaces(&mut self) {
+        self.pos += self.rest().len() - self.rest().trim_start_matches([' ', '\t']).len();
+    }
+
+    fn at_end(&self) -> bool {
+        self.rest().is_empty() || self.rest().starts_with('#')
+    }
+
+    fn here(&self) -> Span {
+        let end = self.pos + self.rest().chars().next().map_or(0, char::len_utf8);
+        Span::new(self.file, self.pos, end)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.rest().starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn bare(&mut self) -> &str {
+        let len = self.rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest().len());
+        self.pos += len;
+        &self.src[self.pos - len..self.pos]
+    }
+
+    fn value(&mut self) -> Result<(Value, Span), ManifestError> {
+        let start = self.pos;
+        let value = if self.eat('"') {
+            let mut s = String::new();
+            loop {
+                let Some(c) = self.rest().chars().next() else {
+                    bail!(Span::new(self.file, start, self.pos), "unterminated string")
+                };
+                self.pos += c.len_utf8();
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        let escaped = match self.rest().chars().next() {
+                            Some('"')  => '"',
+                            Some('\\') => '\\',
+                            Some('n')  => '\n',
+                            Some('t')  => '\t',
+                            _ => bail!(self.here(), "unknown escape in string"),
+                        };
+                        self.pos += 1;
+                        s.push(escaped);
+                    }
+                    c => s.push(c),
+                }
+            }
+            Value::Str(s)
+        } else if self.eat('[') {
+            let mut items = vec![];
+            loop {
+                self.skip_spaces();
+                if self.eat(']') {
+                    break;
+                }
+                items.push(self.value()?);
+                self.skip_spaces();
+                if !self.eat(',') {
+                    self.skip_spaces();
+                    if !self.eat(']') {
+                        bail!(self.here(), "expected `,` or `]`");
+                    }
+                    break;
+                }
+            }
+            Value::Array(items)
+        } else {
+            match self.bare() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "" => bail!(self.here(), "expected a value"),
+                word => match word.replace('_', "").parse() {
+                    Ok(i) => Value::Int(i),
+                    Err(_) => bail!(Span::new(self.file, start, self.pos), "expected a value, found `{}`", word),
+                },
+            }
+        };
+        Ok((value, Span::new(self.file, start, self.pos)))
+    }
+}
+
+fn entries(file: FileId, src: &str) -> Result<Vec<Entry>, ManifestError> {
+    let mut entries: Vec<Entry> = vec![];
+    let mut table = String::new();
+    let mut offset = 0;
+    for line in src.split_inclusive('\n') {
+        let mut r = Reader { file, src: &src[..offset + line.trim_end_matches(['\n', '\r']).len()], pos: offset };
+        offset += line.len();
+        r.skip_spaces();
+        if r.at_end() {
+            continue;
+        }
+        if r.eat('[') {
+            r.skip_spaces();
+            table = r.bare().to_string();
+            r.skip_spaces();
+            if table.is_empty() || !r.eat(']') {
+                bail!(r.here(), "expected a table name and `]`");
+            }
+        } else {
+            let start = r.pos;
+            let key = r.bare().to_string();
+            let key_span = Span::new(file, start, r.pos);
+            if key.is_empty() {
+                bail!(r.here(), "expected a key");
+            }
+            r.skip_spaces();
+            if !r.eat('=') {
+                bail!(r.here(), "expected `=` after `{}`", key);
+            }
+            r.skip_spaces();
+            let (value, span) = r.value()?;
+            if entries.iter().any(|e| e.table == table && e.key == key) {
+                bail!(key_span, "`{}` is given more than once", key);
+            }
+            entries.push(Entry { table: table.clone(), key, key_span, value, span });
+        }
+        r.skip_spaces();
+        if !r.at_end() {
+            bail!(Span::new(file, r.pos, r.src.len()), "expected the end of the line");
+        }
+    }
+    Ok(entries)
+}
+
+fn string(value: &Value, span: Span, key: &str) -> Result<String, ManifestError> {
+    match value {
+        Value::Str(s) => Ok(s.clone()),
+        v => bail!(span, "`{}` should be a string, not {}", key, v.kind()),
+    }
+}
+
+impl Manifest {
+    /// Read a manifest, the source of `file`
+    pub fn parse(file: FileId, src: &str) -> Result<Self, ManifestError> {
+        let mut name = None;
+        let mut manifest = Manifest {
+            name: String::new(),
+            entry: PathBuf::from("src/main.hlm"),
+            source_dirs: vec![PathBuf::from("src")],
+            target: Target::X86_64Linux,
+            opt_level: 1,
+            output_dir: PathBuf::from("target"),
+        };
+        for Entry { table, key, key_span, value, span } in entries(file, src)? {
+            match (table.as_str(), key.as_str()) {
+                ("package", "name") => {
+                    let s = string(&value, span, &key)?;
+                    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        bail!(span, "package names can only have letters, digits, `_` and `-`");
+                    }
+                    name = Some(s);
+                }
+                ("package", "entry") => manifest.entry = string(&value, span, &key)?.into(),
+                ("package", "source-dirs") => {
+                    let Value::Array(dirs) = value else {
+                        bail!(span, "`{}` should be an array, not {}", key, value.kind())
+                    };
+                    manifest.source_dirs = dirs.iter()
+                        .map(|(v, span)| string(v, *span, &key).map(PathBuf::from))
+                        .collect::<Result<_, _>>()?;
+                }
+                ("build", "target") => {
+                    let s = string(&value, span, &key)?;
+                    manifest.target = Target::from_str(&s, false).map_err(|_| {
+                        let targets = Target::value_variants().iter()
+                            .map(|t| format!("`{}`", t.to_possible_value().unwrap().get_name()))
+                            .collect::<Vec<_>>();
+                        ManifestError {
+                            message: format!("unknown target `{}`, expected one of {}", s, targets.join(", ")),
+                            span,
+                        }
+                    })?;
+                }
+                ("build", "opt-level") => match value {
+                    Value::Int(i @ (0 | 1)) => manifest.opt_level = i as u8,
+                    _ => bail!(span, "`opt-level` should be 0 or 1"),
+                },
+                ("build", "output-dir") => manifest.output_dir = string(&value, span, &key)?.into(),
+                ("", _) => bail!(key_span, "`{}` should be in a table", key),
+                (table, key) => bail!(key_span, "unknown key `{}` in [{}]", key, table),
+            }
+        }
+        manifest.name = name.ok_or_else(|| ManifestError {
+            message: "the package has no `name` in [package]".to_string(),
+            span: Span::new(file, 0, 0),
+        })?;
+        Ok(manifest)
+    }
+
+    /// The path the entry module is built to for a target, relative to the
+    /// project
+    pub fn output(&self, target: Target) -> PathBuf {
+        self.output_dir
+            .join(target.to_possible_value().unwrap().get_name())
+            .join(&self.name)
+            .with_extension(target.extension())
+    }
+
+    /// Where the types of the modules are kept from one build to the next,
+    /// relative to the project
+    pub fn cache(&self) -> PathBuf {
+        self.output_dir.join("cache").join("types")
+    }
+}
+
+/// Find the manifest in a directory or the closest of its parents
+pub fn find(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|d| d.join(FILENAME)).find(|p| p.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let src = "\
+# A project
+[package]
+name = \"hello\"
+source-dirs = [\"src\", \"lib\",]
+
+[build]
+target = \"js\"  # for the web
+opt-level = 0
+";
+        let m = Manifest::parse(FileId::default(), src).unwrap();
+        assert_eq!(m.name, "hello");
+        assert_eq!(m.entry, PathBuf::from("src/main.hlm"));
+        assert_eq!(m.source_dirs, [PathBuf::from("src"), PathBuf::from("lib")]);
+        assert_eq!(m.target, Target::Js);
+        assert_eq!(m.opt_level, 0);
+        assert_eq!(m.output(m.target), PathBuf::from("target/js/hello.js"));
+        assert_eq!(m.output(Target::X86_64Linux), PathBuf::from("target/x86_64-linux/hello"));
+        assert_eq!(m.cache(), PathBuf::from("target/cache/types"));
+    }
+
+    #[test]
+    fn test_errors() {
+        fn error(src: &str) -> (String, &str) {
+            let e = Manifest::parse(FileId::default(), src).unwrap_err();
+            (e.message, &src[e.span.into_range()])
+        }
+        assert_eq!(error("[package]\nname = hello"), ("expected a value, found `hello`".to_string(), "hello"));
+        assert_eq!(error("[package]\nname = \"a\"\nversion = 1"), ("unknown key `version` in [package]".to_string(), "version"));
+        assert_eq!(error("[build]\ntarget = \"z80\""), ("unknown target `z80`, expected one of `x86_64-linux`, `js`, `lua`, `wasm`".to_string(), "\"z80\""));
+        assert_eq!(error("[package]\nname = \"a\" \"b\""), ("expected the end of the line".to_string(), "\"b\""));
+        assert_eq!(error("[package]\nentry = \"main.hlm\"").0, "the package has no `name` in [package]");
+    }
+}
@@ -0,0 +1,191 @@
+//! `hc repl`. Definitions persist from one input to the next, and the value
+//! of every other expression is printed with its type.
+//!
+//! Every input is checked by the session of the pipeline, which keeps it as
+//! a file of the sources, so that errors in functions defined by earlier
+//! inputs are still reported where they are. `:reload` starts over with a
+//! new pipeline, which frees the text of the inputs before it.
+
+use com::session::{Diagnostic, TypedProgram};
+use ir::{eval::Value, ExprKind};
+use syntax::{pretty::pretty_type, source::Texts};
+
+use crate::{args::Args, line::Editor, Pipeline};
+
+const HELP: &str = "\
+:type <expr>   print the type of an expression
+:ir <expr>     print the IR of an expression
+:load <file>   run a file, keeping its definitions
+:reload        start over, loading the same files again
+:help          print this message
+:quit          exit";
+
+#[derive(Default)]
+struct Repl {
+    // The files loaded so far, for `:reload`
+    loaded: Vec<String>,
+    // Whether to start over
+    reload: bool,
+}
+
+/// Report what went wrong with an input. It isn't a failure of `hc`, as the
+/// next input can do better
+fn report(pipe: &mut Pipeline, diagnostics: Vec<Diagnostic>) -> Vec<String> {
+    diagnostics.into_iter().for_each(|d| pipe.show(d));
+    vec![]
+}
+
+impl Repl {
+    /// Run an input, returning what to print
+    fn input(&mut self, pipe: &mut Pipeline, text: &str) -> Vec<String> {
+        let text = text.trim();
+        if let Some(command) = text.strip_prefix(':') {
+            let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+            return self.command(pipe, command, arg.trim());
+        }
+        let program = pipe.session.check(text);
+        self.run(pipe, program)
+    }
+
+    /// Evaluate an input or a loaded file, keeping its definitions
+    fn run<'src>(&mut self, pipe: &mut Pipeline<'src>, program: Result<TypedProgram<'src>, Vec<Diagnostic>>) -> Vec<String> {
+        let irs = match program.and_then(|p| pipe.session.lower(p)) {
+            Ok(irs) => irs,
+            Err(errs) => return report(pipe, errs),
+        };
+        let mut out = vec![];
+        let result = pipe.session.eval_each(&irs, |e, v| out.push(match (&e.kind, v) {
+            (ExprKind::Define { var, .. }, _) => format!("{} : {}", var.name, pretty_type(&var.ty)),
+            (_, Value::Str(s)) => format!("it : Str = {:?}", s),
+            (_, v) => format!("it : {} = {}", pretty_type(&e.ty), v),
+        }));
+        if let Err(e) = result {
+            report(pipe, vec![e]);
+        }
+        out
+    }
+
+    fn command(&mut self, pipe: &mut Pipeline, command: &str, arg: &str) -> Vec<String> {
+        match command {
+            "type" | "t" => match pipe.session.scratch(|s| s.check(arg)) {
+                Ok(program) => program.modules[0].items.iter()
+                    .map(|(e, _)| format!("{} : {}", arg, pretty_type(&e.ty())))
+                    .collect(),
+                Err(errs) => report(pipe, errs),
+            },
+            "ir" => match pipe.session.scratch(|s| s.check(arg).and_then(|p| s.lower(p))) {
+                Ok(irs) => irs.iter().map(|ir| ir.to_string()).collect(),
+                Err(errs) => report(pipe, errs),
+            },
+            "load" | "l" => {
+                let text = match std::fs::read_to_string(arg) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        pipe.reporter.error(format!("could not read {}: {}", arg, e));
+                        return vec![];
+                    }
+                };
+                if !self.loaded.iter().any(|f| f == arg) {
+                    self.loaded.push(arg.to_string());
+                }
+                let file = pipe.session.add_source(arg, text);
+                let program = pipe.session.check_file(file);
+                self.run(pipe, program)
+            }
+            "reload" | "r" => {
+                self.reload = true;
+                vec![]
+            }
+            "help" | "h" | "?" => vec![HELP.to_string()],
+            _ => {
+                pipe.reporter.error(format!("unknown command `:{}`, see `:help`", command));
+                vec![]
+            }
+        }
+    }
+
+    /// Start over with a new pipeline, loading the same files again
+    fn reload(&mut self, pipe: &mut Pipeline) -> Vec<String> {
+        self.reload = false;
+        let loaded = std::mem::take(&mut self.loaded);
+        loaded.iter().flat_map(|file| self.command(pipe, "load", file)).collect()
+    }
+}
+
+pub fn repl(args: &Args) {
+    let mut editor = Editor::new();
+    let mut repl = Repl::default();
+    loop {
+        let texts = Texts::new();
+        let mut pipe = Pipeline::new(args, &texts);
+        repl.reload(&mut pipe).iter().for_each(|out| println!("{}", out));
+        while !repl.reload {
+            let line = match editor.read_line("> ") {
+                Ok(Some(line)) => line,
+                Ok(None) => return pipe.finish(),
+                Err(e) => {
+                    pipe.reporter.error(e);
+                    return pipe.finish();
+                }
+            };
+            match line.trim() {
+                "" => continue,
+                ":quit" | ":q" => return pipe.finish(),
+                _ => repl.input(&mut pipe, &line).iter().for_each(|out| println!("{}", out)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::Args;
+
+    fn args() -> Args {
+        Args::parse_from(["hc", "--color", "never", "repl"])
+    }
+
+    #[test]
+    fn test_session() {
+        let texts = Texts::new();
+        let mut pipe = Pipeline::new(&args(), &texts);
+        let mut s = Repl::default();
+        let mut input = |text: &str| s.input(&mut pipe, text);
+
+        assert_eq!(input("let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1"), ["fact : (Int) -> Int"]);
+        assert_eq!(input("fact(5)"), ["it : Int = 120"]);
+        assert_eq!(input("let x = 2; \"a\""), ["x : Int", "it : Str = \"a\""]);
+        assert_eq!(input(":type fact(x) == 2"), ["fact(x) == 2 : Bool"]);
+        assert_eq!(input(":ir x + 1"), ["(+ x%2 1)"]);
+        // Nothing is kept from inputs with errors
+        assert!(input("let y = fact(true)").is_empty());
+        assert!(input("y").is_empty());
+        assert_eq!(input("fact(3) - x"), ["it : Int = 4"]);
+        assert!(input("10 / (x - 2)").is_empty());
+        assert!(input(":what").is_empty());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("hc-test-reload-{}.hlm", std::process::id()));
+        std::fs::write(&path, "let z = 3;").unwrap();
+        let path = path.to_str().unwrap();
+        let mut s = Repl::default();
+        let texts = Texts::new();
+        let mut pipe = Pipeline::new(&args(), &texts);
+        assert_eq!(s.input(&mut pipe, &format!(":load {}", path)), ["z : Int"]);
+        assert!(s.input(&mut pipe, ":reload").is_empty());
+        assert!(s.reload);
+
+        // The files loaded are loaded again by a new pipeline
+        let texts = Texts::new();
+        let mut pipe = Pipeline::new(&args(), &texts);
+        assert_eq!(s.reload(&mut pipe), ["z : Int"]);
+        assert!(!s.reload);
+        assert_eq!(s.input(&mut pipe, "z"), ["it : Int = 3"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
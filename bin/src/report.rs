@@ -0,0 +1,140 @@
+//! Reporting errors and warnings on stderr, the way the common options ask.
+//!
+//! Reports are given the files that were read, which the spans of what they
+//! report point into, so that a report can have labels in several files.
+
+use std::{collections::HashMap, fmt::{self, Display}, io::IsTerminal};
+
+use ariadne::{Color, Config, Label, Report, ReportKind, Source};
+use syntax::{expr::Span, source::{FileId, Sources}};
+
+use crate::args::{ColorChoice, Common, MessageFormat};
+
+pub struct Reporter {
+    color: bool,
+    format: MessageFormat,
+    limit: Option<usize>,
+    errors: usize,
+}
+
+/// The sources the way ariadne reads them, made as it asks for them
+struct Cache<'a, 'src> {
+    sources: &'a Sources<'src>,
+    made: HashMap<FileId, Source>,
+}
+
+impl ariadne::Cache<FileId> for Cache<'_, '_> {
+    fn fetch(&mut self, id: &FileId) -> Result<&Source, Box<dyn fmt::Debug + '_>> {
+        let text = self.sources.get(*id).text;
+        Ok(self.made.entry(*id).or_insert_with(|| Source::from(text)))
+    }
+
+    fn display<'a>(&self, id: &'a FileId) -> Option<Box<dyn Display + 'a>> {
+        Some(Box::new(self.sources.get(*id).path.clone()))
+    }
+}
+
+impl Reporter {
+    pub fn new(common: &Common) -> Self {
+        let color = match common.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        };
+        Self { color, format: common.message_format, limit: common.error_limit, errors: 0 }
+    }
+
+    /// How many errors were reported, including those left out by the limit
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    // Count an error, returning whether it is still under the limit
+    fn count(&mut self, kind: ReportKind) -> bool {
+        if kind != ReportKind::Error {
+            return true;
+        }
+        self.errors += 1;
+        self.limit.is_none_or(|limit| self.errors <= limit)
+    }
+
+    /// Report something at a span, with labels pointing at the relevant
+    /// parts of the sources, which can be in other files
+    pub fn report(
+        &mut self,
+        sources: &Sources<'_>,
+        kind: ReportKind<'static>,
+        span: Span,
+        message: impl Display,
+        labels: Vec<(String, Span, Color)>,
+    ) {
+        if !self.count(kind) {
+            return;
+        }
+        match self.format {
+            MessageFormat::Human => {
+                let mut r = Report::build(kind, span.file, span.start)
+                    .with_config(Config::default().with_color(self.color))
+                    .with_message(message);
+                for (msg, span, color) in labels {
+                    r = r.with_label(
+                        Label::new((span.file, span.into_range()))
+                            .with_message(msg)
+                            .with_color(color),
+                    );
+                }
+                r.finish()
+                    .eprint(Cache { sources, made: HashMap::new() })
+                    .unwrap();
+            }
+            MessageFormat::Short => {
+                let (line, column) = position(sources, span);
+                let file = &sources.get(span.file).path;
+                eprintln!("{}:{}:{}: {}: {}", file, line, column, kind.to_string().to_lowercase(), message);
+            }
+        }
+    }
+
+    /// Report an error with a single label saying the same as the message
+    pub fn error_at(&mut self, sources: &Sources<'_>, span: Span, title: impl Display, message: impl Display) {
+        let labels = vec![(message.to_string(), span, Color::Red)];
+        self.report(sources, ReportKind::Error, span, title, labels);
+    }
+
+    /// Report an error that isn't about a place in a file
+    pub fn error(&mut self, message: impl Display) {
+        if self.count(ReportKind::Error) {
+            eprintln!("error: {}", message);
+        }
+    }
+
+    /// Say how many errors the limit left out, if any
+    pub fn finish(&self) {
+        if let Some(hidden) = self.limit.map(|limit| self.errors.saturating_sub(limit)).filter(|n| *n > 0) {
+            eprintln!("note: {} more error{} not shown", hidden, if hidden == 1 { "" } else { "s" });
+        }
+    }
+}
+
+/// The line and column a span starts at, counted from 1
+fn position(sources: &Sources, span: Span) -> (usize, usize) {
+    let (line, before) = sources.get(span.file).locate(span.start);
+    (line + 1, before.chars().count() + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position() {
+        let mut sources = Sources::new();
+        sources.add("a.hlm", "x");
+        let file = sources.add("b.hlm", "ab\nλcd\n");
+        let at = |offset| position(&sources, Span::new(file, offset, offset));
+        assert_eq!(at(0), (1, 1));
+        assert_eq!(at(3), (2, 1));
+        assert_eq!(at(6), (2, 3));
+        assert_eq!(at(100), (3, 1));
+    }
+}
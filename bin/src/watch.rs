@@ -0,0 +1,247 @@
+//! `--watch`: checking or running files again whenever they change.
+//!
+//! The directories of the files are watched with inotify rather than the
+//! files themselves, as editors often save by writing another file and
+//! renaming it over the old one. Every run has a pipeline of its own, so
+//! that the text read by the one before is freed; only the [`Memo`] of the
+//! types of the items that didn't change is kept from one to the next.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr},
+    io::{self, IsTerminal},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use syntax::source::Texts;
+use typing::memo::Memo;
+
+use crate::{args::Args, Failure, Pipeline};
+
+struct Inotify {
+    fd: i32,
+    // The directories watched, by their watch descriptor
+    dirs: HashMap<i32, PathBuf>,
+}
+
+impl Inotify {
+    fn new() -> io::Result<Self> {
+        // SAFETY: no memory is involved
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, dirs: HashMap::new() })
+    }
+
+    /// Watch a directory for files being written, created, moved in or
+    /// deleted
+    fn watch(&mut self, dir: &Path) -> io::Result<()> {
+        if self.dirs.values().any(|d| d == dir) {
+            return Ok(());
+        }
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE;
+        // SAFETY: the path is a C string that outlives the call
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Wait for files to change and return their paths. Saving a file
+    /// often changes several, so those that change right after the first
+    /// one are waited for too
+    fn wait(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        self.read(&mut changed)?;
+        while self.ready(50)? {
+            self.read(&mut changed)?;
+        }
+        Ok(changed)
+    }
+
+    /// Whether there are events to read within `ms` milliseconds
+    fn ready(&self, ms: i32) -> io::Result<bool> {
+        let mut fd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        // SAFETY: the pointer is to one pollfd, as the count says
+        match unsafe { libc::poll(&mut fd, 1, ms) } {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n > 0),
+        }
+    }
+
+    fn read(&self, changed: &mut Vec<PathBuf>) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        // SAFETY: the buffer is writable for as long as it is said to be
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        parse_events(&buf[..n as usize], &self.dirs, changed);
+        Ok(())
+    }
+}
+
+/// The paths of the files that events read from inotify are about, from
+/// the directories watched by their watch descriptor
+fn parse_events(buf: &[u8], dirs: &HashMap<i32, PathBuf>, changed: &mut Vec<PathBuf>) {
+    // The events are read field by field, as the buffer isn't aligned for
+    // `inotify_event`
+    let header = std::mem::size_of::<libc::inotify_event>();
+    let mut i = 0;
+    while i + header <= buf.len() {
+        let wd = i32::from_ne_bytes(buf[i..i + 4].try_into().unwrap());
+        let len = u32::from_ne_bytes(buf[i + 12..i + 16].try_into().unwrap()) as usize;
+        if i + header + len > buf.len() {
+            break;
+        }
+        // The name is padded with NULs
+        let name = buf[i + header..i + header + len].split(|&b| b == 0).next().unwrap_or_default();
+        if let Some(dir) = dirs.get(&wd).filter(|_| !name.is_empty()) {
+            changed.push(dir.join(OsStr::from_bytes(name)));
+        }
+        i += header + len;
+    }
+}
+
+/// Whether a file changing is a reason to go again: it is one of the files
+/// `watched`, or a new module may be what an import was missing
+fn relevant(watched: &HashSet<PathBuf>, path: &Path) -> bool {
+    watched.contains(path) || path.extension().is_some_and(|ext| ext == "hlm")
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is owned by this and not used after
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Run `f` on every file, then again whenever one of them or a module
+/// they import changes, until `hc` is interrupted. `pipe` reports what
+/// goes wrong with watching, and each run gets a new pipeline made from
+/// `args`
+pub fn watch(pipe: &mut Pipeline, args: &Args, files: &[String], f: impl Fn(&mut Pipeline, &str)) -> ! {
+    if files.iter().any(|f| f == "-") {
+        pipe.usage("stdin can't be watched");
+    }
+    let mut inotify = Inotify::new().unwrap_or_else(|e| {
+        pipe.reporter.error(format!("could not watch files: {}", e));
+        pipe.exit(Failure::Io)
+    });
+    let mut memo = Memo::default();
+    loop {
+        if io::stderr().is_terminal() {
+            eprint!("\x1b[2J\x1b[H");
+        }
+        memo.clear();
+        let texts = Texts::new();
+        let mut run = Pipeline::new(args, &texts);
+        run.loaded = Some(HashSet::new());
+        run.session.memo = Some(memo);
+        let start = Instant::now();
+        files.iter().for_each(|file| f(&mut run, file));
+        run.finish();
+        status(&run, start);
+        memo = run.session.memo.take().unwrap();
+
+        // Watched anew every time, as the imports may have changed
+        let watched = files.iter()
+            .filter_map(|f| std::fs::canonicalize(f).ok())
+            .chain(run.loaded.take().unwrap())
+            .collect::<HashSet<_>>();
+        for dir in watched.iter().filter_map(|f| f.parent()) {
+            if let Err(e) = inotify.watch(dir) {
+                pipe.reporter.error(format!("could not watch {}: {}", dir.display(), e));
+                pipe.exit(Failure::Io);
+            }
+        }
+        loop {
+            match inotify.wait() {
+                Ok(changed) if changed.iter().any(|path| relevant(&watched, path)) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    pipe.reporter.error(format!("could not watch files: {}", e));
+                    pipe.exit(Failure::Io);
+                }
+            }
+        }
+    }
+}
+
+/// Say in one line how the run went
+fn status(pipe: &Pipeline, start: Instant) {
+    let elapsed = start.elapsed().as_secs_f64() * 1000.0;
+    if pipe.failure.is_some() {
+        let errors = pipe.reporter.errors();
+        eprintln!("{} error{}, watching for changes", errors, if errors == 1 { "" } else { "s" });
+        return;
+    }
+    let memo = pipe.session.memo.as_ref().unwrap();
+    match memo.checked {
+        0 => eprintln!("ok in {:.1}ms, watching for changes", elapsed),
+        n => eprintln!(
+            "ok: {} of {} item{} checked again in {:.1}ms, watching for changes",
+            n - memo.reused, n, if n == 1 { "" } else { "s" }, elapsed,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An event as inotify lays it out, its name padded to `len` bytes
+    fn event(buf: &mut Vec<u8>, wd: i32, name: &str, len: u32) {
+        buf.extend(wd.to_ne_bytes());
+        buf.extend(libc::IN_CLOSE_WRITE.to_ne_bytes());
+        buf.extend(0u32.to_ne_bytes());
+        buf.extend(len.to_ne_bytes());
+        buf.extend(name.as_bytes());
+        buf.resize(buf.len() + len as usize - name.len(), 0);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let dirs = HashMap::from([(1, PathBuf::from("/src")), (2, PathBuf::from("/lib"))]);
+        let mut buf = vec![];
+        event(&mut buf, 1, "main.hlm", 16);
+        // About the directory itself
+        event(&mut buf, 2, "", 0);
+        // From a directory no longer watched
+        event(&mut buf, 3, "gone.hlm", 16);
+        event(&mut buf, 2, "math.hlm.swp", 16);
+        let mut changed = vec![];
+        parse_events(&buf, &dirs, &mut changed);
+        assert_eq!(changed, [PathBuf::from("/src/main.hlm"), PathBuf::from("/lib/math.hlm.swp")]);
+
+        // An event cut short by the end of the buffer is left out, whether
+        // it ends in its header or in its name
+        let mut changed = vec![];
+        parse_events(&buf[..10], &dirs, &mut changed);
+        assert!(changed.is_empty());
+        let header = std::mem::size_of::<libc::inotify_event>();
+        parse_events(&buf[..header + 4], &dirs, &mut changed);
+        assert!(changed.is_empty());
+        parse_events(&buf[..2 * header + 16 + 4], &dirs, &mut changed);
+        assert_eq!(changed, [PathBuf::from("/src/main.hlm")]);
+    }
+
+    #[test]
+    fn test_relevant() {
+        let watched = HashSet::from([PathBuf::from("/src/main.hlm"), PathBuf::from("/src/notes")]);
+        assert!(relevant(&watched, Path::new("/src/main.hlm")));
+        assert!(relevant(&watched, Path::new("/src/notes")));
+        // A module that may now be found by an import
+        assert!(relevant(&watched, Path::new("/lib/new.hlm")));
+        assert!(!relevant(&watched, Path::new("/src/main.hlm.swp")));
+        assert!(!relevant(&watched, Path::new("/src/.main.hlm~")));
+    }
+}
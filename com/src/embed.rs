@@ -0,0 +1,254 @@
+//! Embedding Holymer in a Rust program: the Rust types that have Holymer
+//! counterparts, so that programs can call Rust functions given to them
+//! with [`Session::register`], and Rust can call the functions programs
+//! define with [`Session::call`].
+//!
+//! ```
+//! use com::session::Session;
+//! use syntax::source::Texts;
+//!
+//! let texts = Texts::new();
+//! let mut session = Session::new(&texts);
+//! session.register("len", |s: String| s.len() as i64);
+//! session.load("let twice = fun (s Str) -> len(s) * 2;").unwrap();
+//! assert_eq!(session.call::<_, i64>("twice", ("abc".to_string(),)).unwrap(), 6);
+//! ```
+//!
+//! [`Session::register`]: crate::session::Session::register
+//! [`Session::call`]: crate::session::Session::call
+
+use std::{fmt::Display, rc::Rc};
+
+use ir::eval::{EvalError, Native, Value};
+use syntax::{pretty::pretty_type, ty::Type};
+
+/// A Rust type whose values are values of a Holymer type
+pub trait HostValue: Sized {
+    fn ty() -> Type;
+    fn into_value<'src>(self) -> Value<'src>;
+    /// Fails if the value is of another type
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError>;
+}
+
+fn mismatch<T: HostValue>(v: &Value) -> EvalError {
+    EvalError::new(format!("expected a value of type {}, found {}", pretty_type(&T::ty()), v))
+}
+
+impl HostValue for () {
+    fn ty() -> Type {
+        Type::Unit
+    }
+
+    fn into_value<'src>(self) -> Value<'src> {
+        Value::Unit
+    }
+
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError> {
+        match v {
+            Value::Unit => Ok(()),
+            v => Err(mismatch::<Self>(&v)),
+        }
+    }
+}
+
+impl HostValue for i64 {
+    fn ty() -> Type {
+        Type::Int
+    }
+
+    fn into_value<'src>(self) -> Value<'src> {
+        Value::Int(self)
+    }
+
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError> {
+        match v {
+            Value::Int(i) => Ok(i),
+            v => Err(mismatch::<Self>(&v)),
+        }
+    }
+}
+
+impl HostValue for bool {
+    fn ty() -> Type {
+        Type::Bool
+    }
+
+    fn into_value<'src>(self) -> Value<'src> {
+        Value::Bool(self)
+    }
+
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError> {
+        match v {
+            Value::Bool(b) => Ok(b),
+            v => Err(mismatch::<Self>(&v)),
+        }
+    }
+}
+
+impl HostValue for String {
+    fn ty() -> Type {
+        Type::Str
+    }
+
+    fn into_value<'src>(self) -> Value<'src> {
+        Value::Str(Rc::from(self))
+    }
+
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError> {
+        match v {
+            Value::Str(s) => Ok(s.to_string()),
+            v => Err(mismatch::<Self>(&v)),
+        }
+    }
+}
+
+impl<T: HostValue> HostValue for Vec<T> {
+    fn ty() -> Type {
+        Type::Array(Box::new(T::ty()))
+    }
+
+    fn into_value<'src>(self) -> Value<'src> {
+        Value::Array(self.into_iter().map(T::into_value).collect())
+    }
+
+    fn from_value(v: Value<'_>) -> Result<Self, EvalError> {
+        match v {
+            Value::Array(vs) => vs.iter().cloned().map(T::from_value).collect(),
+            v => Err(mismatch::<Self>(&v)),
+        }
+    }
+}
+
+/// What a Rust function given to programs returns: a value, or the error
+/// to make the program fail with
+pub trait HostResult {
+    fn ty() -> Type;
+    fn into_result<'src>(self) -> Result<Value<'src>, EvalError>;
+}
+
+impl<T: HostValue> HostResult for T {
+    fn ty() -> Type {
+        T::ty()
+    }
+
+    fn into_result<'src>(self) -> Result<Value<'src>, EvalError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: HostValue, E: Display> HostResult for Result<T, E> {
+    fn ty() -> Type {
+        T::ty()
+    }
+
+    fn into_result<'src>(self) -> Result<Value<'src>, EvalError> {
+        self.map(T::into_value).map_err(|e| EvalError::new(e.to_string()))
+    }
+}
+
+/// The arguments of a call from Rust, as a tuple
+pub trait HostArgs {
+    fn types() -> Vec<Type>;
+    fn into_values<'src>(self) -> Vec<Value<'src>>;
+}
+
+/// A Rust function that programs can call. `Args` is the tuple of the
+/// types of its arguments
+pub trait HostFn<Args, R>: 'static {
+    /// The Holymer type of the function
+    fn ty() -> Type;
+    fn into_native<'src>(self, name: &str) -> Native<'src>;
+}
+
+macro_rules! tuples {
+    ($($arg:ident)*) => {
+        impl<$($arg: HostValue),*> HostArgs for ($($arg,)*) {
+            fn types() -> Vec<Type> {
+                vec![$($arg::ty()),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn into_values<'src>(self) -> Vec<Value<'src>> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_value()),*]
+            }
+        }
+
+        impl<F, R, $($arg),*> HostFn<($($arg,)*), R> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: HostResult,
+            $($arg: HostValue),*
+        {
+            fn ty() -> Type {
+                Type::Func(<($($arg,)*)>::types(), Box::new(R::ty()))
+            }
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn into_native<'src>(self, name: &str) -> Native<'src> {
+                let arity = <($($arg,)*)>::types().len();
+                Native::new(name, arity, move |args| {
+                    // The arity was checked, and the types by the type checker
+                    let mut args = args.into_iter();
+                    $(let $arg = $arg::from_value(args.next().unwrap())?;)*
+                    self($($arg),*).into_result()
+                })
+            }
+        }
+    };
+}
+
+tuples!();
+tuples!(A);
+tuples!(A B);
+tuples!(A B C);
+tuples!(A B C D);
+
+#[cfg(test)]
+mod tests {
+    use syntax::source::Texts;
+
+    use crate::session::{Phase, Session};
+
+    #[test]
+    fn test_embed() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        s.register("len", |s: String| s.len() as i64);
+        s.register("words", |s: String| s.split_whitespace().map(str::to_string).collect::<Vec<_>>());
+        s.register("count", |ws: Vec<String>| ws.len() as i64);
+        s.register("positive", |n: i64| if n > 0 { Ok(n) } else { Err(format!("{} is not positive", n)) });
+        s.load("
+            let wordcount = fun (s Str) -> count(words(s));
+            let long = fun (s Str) -> len(s) > 3;
+            let inv = fun (n Int) -> 100 / positive(n);
+        ").unwrap();
+
+        assert_eq!(s.call::<_, i64>("wordcount", ("to be or not".to_string(),)).unwrap(), 4);
+        assert!(s.call::<_, bool>("long", ("four".to_string(),)).unwrap());
+        assert_eq!(s.call::<_, Vec<String>>("words", ("a b".to_string(),)).unwrap(), ["a", "b"]);
+        assert_eq!(s.call::<_, i64>("count", (vec!["x".to_string()],)).unwrap(), 1);
+        assert_eq!(s.call::<_, i64>("inv", (4,)).unwrap(), 25);
+
+        let e = s.call::<_, i64>("inv", (-1,)).unwrap_err();
+        assert_eq!((e.phase, e.title.as_str()), (Phase::Runtime, "runtime error: -1 is not positive"));
+        assert!(e.span.is_some());
+        let e = s.call::<_, bool>("inv", (1,)).unwrap_err();
+        assert_eq!((e.phase, e.title.as_str()), (Phase::Type, "`inv` has type (Int) -> Int, not (Int) -> Bool"));
+        assert_eq!(s.call::<_, i64>("nothing", ()).unwrap_err().title, "`nothing` is not defined");
+
+        // Generic functions are called at the types asked for
+        s.load("let id = fun (x) -> x; let first = fun (x, y) -> x;").unwrap();
+        assert_eq!(s.call::<_, i64>("id", (7,)).unwrap(), 7);
+        assert_eq!(s.call::<_, String>("id", ("a".to_string(),)).unwrap(), "a");
+        assert!(s.call::<_, bool>("first", (true, 1)).unwrap());
+        let e = s.call::<_, bool>("id", (1,)).unwrap_err();
+        assert_eq!(e.title, "`id` has type (A) -> A, not (Int) -> Bool");
+
+        // The functions given stay after forgetting the programs
+        s.forget();
+        assert!(s.call::<_, i64>("inv", (4,)).is_err());
+        s.load("let twice = fun (s Str) -> len(s) * 2;").unwrap();
+        assert_eq!(s.call::<_, i64>("twice", ("abc".to_string(),)).unwrap(), 6);
+    }
+}
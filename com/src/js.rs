@@ -0,0 +1,381 @@
+//! JavaScript backend, emitting an ES2020 module.
+//!
+//! Integers are `BigInt`s truncated back to 64 bits after every operation
+//! that can overflow, unit is `undefined`. Top-level definitions become
+//! exports and the remaining top-level expressions are printed by the
+//! exported `main` function. Functions and literals are exported as
+//! constants, other definitions are only assigned by `main`, in order with
+//! the printing, so that the program's effects happen in source order.
+//! A global defined more than once is a single export that `main` assigns
+//! at each definition, so everything referring to it sees the latest one.
+
+use ir::{BinOp, Expr, ExprKind, Lit, Type, UnOp, Var, VarId};
+
+use crate::{
+    source_map::{extract, mark, SourceMap},
+    BackendError,
+};
+
+const PRELUDE: &str = "\
+const $int = (x) => BigInt.asIntN(64, x);
+const $show = (x) => x === undefined ? \"()\"
+    : typeof x === \"function\" ? \"<function>\"
+    : String(x);
+";
+
+// Reserved words, plus names the generated module uses itself
+const RESERVED: [&str; 50] = [
+    "await", "break", "case", "catch", "class", "const", "continue",
+    "debugger", "default", "delete", "do", "else", "enum", "export",
+    "extends", "false", "finally", "for", "function", "if", "implements",
+    "import", "in", "instanceof", "interface", "let", "new", "null",
+    "package", "private", "protected", "public", "return", "static",
+    "super", "switch", "this", "throw", "true", "try", "typeof", "var",
+    "void", "while", "with", "yield", "arguments", "eval", "undefined",
+    "main",
+];
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(BackendError::new(format!($($arg)*)))
+    };
+}
+
+/// Turn a Holymer identifier into a valid JavaScript one
+fn mangle(name: &str) -> String {
+    let name = name.replace('\'', "$");
+    if RESERVED.contains(&name.as_str()) {
+        format!("${}", name)
+    } else {
+        name
+    }
+}
+
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+struct Emitter<'src> {
+    // Local variables in scope, with their names in the source and what
+    // they are called in the output
+    scopes: Vec<Vec<(VarId, &'src str, String)>>,
+    // The same for top-level definitions, which are only renamed when
+    // modules define globals of the same name
+    globals: Vec<(VarId, &'src str, String)>,
+    fresh: usize,
+}
+
+impl<'src> Emitter<'src> {
+    /// Bind a local variable, renaming it if it would shadow another one
+    /// (`let x = x + 1 in ...` must still see the outer `x`)
+    fn bind(&mut self, var: &Var<'src>) -> String {
+        let shadows = self.globals.iter().chain(self.scopes.iter().flatten()).any(|(_, n, _)| *n == var.name);
+        let js = if shadows {
+            self.fresh += 1;
+            format!("{}${}", mangle(var.name), self.fresh)
+        } else {
+            mangle(var.name)
+        };
+        self.scopes.last_mut().unwrap().push((var.id, var.name, js.clone()));
+        js
+    }
+
+    fn var(&self, var: &Var) -> String {
+        self.scopes.iter().flatten().chain(&self.globals)
+            .find(|(id, _, _)| *id == var.id)
+            .map(|(_, _, js)| js.clone())
+            .unwrap_or_else(|| mangle(var.name))
+    }
+
+    /// Emit an expression, marked with its span for the source map
+    fn expr(&mut self, e: &Expr<'src>, depth: usize) -> Result<String, BackendError> {
+        let js = match &e.kind {
+            ExprKind::Lit(Lit::Unit)    => "undefined".to_string(),
+            ExprKind::Lit(Lit::Bool(b)) => b.to_string(),
+            ExprKind::Lit(Lit::Int(i))  => format!("{}n", i),
+            ExprKind::Lit(Lit::Str(s))  => string(s),
+            ExprKind::Var(x) => self.var(x),
+            ExprKind::If { cond, t, f } => format!(
+                "({} ? {} : {})",
+                self.expr(cond, depth)?, self.expr(t, depth)?, self.expr(f, depth)?
+            ),
+            ExprKind::Lambda { params, body } => {
+                self.scopes.push(vec![]);
+                let params = params.iter()
+                    .map(|p| self.bind(p))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let body = if is_statement(body) {
+                    let mut lines = vec![];
+                    self.ret(body, depth + 1, &mut lines)?;
+                    format!("{{\n{}\n{}}}", lines.join("\n"), indent(depth))
+                } else {
+                    self.expr(body, depth)?
+                };
+                self.scopes.pop();
+                format!("(({}) => {})", params, body)
+            }
+            ExprKind::Let { .. } | ExprKind::Define { .. } | ExprKind::Block { .. } => {
+                // Only expressible as statements, so wrap them in a
+                // function that is called immediately
+                let mut lines = vec![];
+                self.ret(e, depth + 1, &mut lines)?;
+                format!("(() => {{\n{}\n{}}})()", lines.join("\n"), indent(depth))
+            }
+            ExprKind::Unary(UnOp::Neg, x) => format!("$int(-{})", self.expr(x, depth)?),
+            ExprKind::Unary(UnOp::Not, x) => format!("!{}", self.expr(x, depth)?),
+            ExprKind::Binary(op, l, r) => {
+                let unit = l.ty == Type::Unit;
+                let (l, r) = (self.expr(l, depth)?, self.expr(r, depth)?);
+                match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div => format!("$int({} {} {})", l, op, r),
+                    BinOp::Eq | BinOp::Ne => format!("({} {}= {})", l, op, r),
+                    // `undefined` is unordered, even with itself, while unit
+                    // is equal to itself
+                    BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge if unit => {
+                        format!("({}, {}, {})", l, r, matches!(op, BinOp::Le | BinOp::Ge))
+                    }
+                    _ => format!("({} {} {})", l, op, r),
+                }
+            }
+            ExprKind::Call { func, args } => {
+                let f = match func.kind {
+                    ExprKind::Var(_) => self.expr(func, depth)?,
+                    _ => format!("({})", self.expr(func, depth)?),
+                };
+                let args = args.iter()
+                    .map(|a| self.expr(a, depth))
+                    .collect::<Result<Vec<_>, _>>()?;
+                format!("{}({})", f, args.join(", "))
+            }
+            ExprKind::Closure { .. } | ExprKind::EnvGet { .. } => {
+                bail!("closure-converted IR is not supported by the JavaScript backend")
+            }
+        };
+        Ok(mark(e.span) + &js)
+    }
+
+    /// Emit statements that return the value of an expression
+    fn ret(&mut self, e: &Expr<'src>, depth: usize, out: &mut Vec<String>) -> Result<(), BackendError> {
+        let ind = indent(depth);
+        match &e.kind {
+            ExprKind::If { cond, t, f } => {
+                out.push(format!("{}if ({}) {{", ind, self.expr(cond, depth)?));
+                self.ret(t, depth + 1, out)?;
+                out.push(format!("{}}} else {{", ind));
+                self.ret(f, depth + 1, out)?;
+                out.push(format!("{}}}", ind));
+            }
+            ExprKind::Let { var, value, body } => {
+                let value = self.expr(value, depth)?;
+                self.scopes.push(vec![]);
+                out.push(format!("{}const {} = {};", ind, self.bind(var), value));
+                self.ret(body, depth, out)?;
+                self.scopes.pop();
+            }
+            ExprKind::Define { .. } => {
+                self.define(e, depth, out)?;
+                out.push(format!("{}return undefined;", ind));
+            }
+            ExprKind::Block { exprs, void } => {
+                self.scopes.push(vec![]);
+                for (i, e) in exprs.iter().enumerate() {
+                    if i + 1 == exprs.len() && !void {
+                        self.ret(e, depth, out)?;
+                    } else if e.is_define() {
+                        self.define(e, depth, out)?;
+                    } else {
+                        out.push(format!("{}{};", ind, self.expr(e, depth)?));
+                    }
+                }
+                if exprs.is_empty() || *void {
+                    out.push(format!("{}return undefined;", ind));
+                }
+                self.scopes.pop();
+            }
+            _ => out.push(format!("{}return {};", ind, self.expr(e, depth)?)),
+        }
+        Ok(())
+    }
+
+    /// Emit a `define` inside a block as a constant
+    fn define(&mut self, e: &Expr<'src>, depth: usize, out: &mut Vec<String>) -> Result<(), BackendError> {
+        let ExprKind::Define { var, value } = &e.kind else { bail!("expected a define") };
+        // Only lambdas can refer to themselves
+        let (name, value) = if value.is_lambda() {
+            (self.bind(var), self.expr(value, depth)?)
+        } else {
+            let value = self.expr(value, depth)?;
+            (self.bind(var), value)
+        };
+        out.push(format!("{}const {} = {};", indent(depth), name, value));
+        Ok(())
+    }
+}
+
+// Whether the expression reads better as a function body than as an
+// immediately called function
+fn is_statement(e: &Expr) -> bool {
+    matches!(e.kind, ExprKind::Let { .. } | ExprKind::Define { .. } | ExprKind::Block { .. } | ExprKind::If { .. })
+}
+
+/// Compile a program into an ES2020 module
+pub fn compile(program: &[Expr]) -> Result<String, BackendError> {
+    compile_with_map(program).map(|(js, _)| js)
+}
+
+/// Compile a program into an ES2020 module, with the map from the module
+/// back to the source of the program
+pub fn compile_with_map(program: &[Expr]) -> Result<(String, SourceMap), BackendError> {
+    // What each global is called, and those defined more than once
+    let mut globals: Vec<(VarId, &str, String)> = vec![];
+    let mut redefined = vec![];
+    for e in program {
+        if let ExprKind::Define { var, .. } = &e.kind {
+            if globals.iter().any(|(id, _, _)| *id == var.id) {
+                redefined.push(var.id);
+                continue;
+            }
+            // Modules can each define a global of the same name
+            let mut name = mangle(var.name);
+            if globals.iter().any(|(_, _, js)| *js == name) {
+                name = format!("{}$g{}", name, globals.len());
+            }
+            globals.push((var.id, var.name, name));
+        }
+    }
+
+    let mut em = Emitter { scopes: vec![], globals, fresh: 0 };
+    let mut out = vec![PRELUDE.to_string()];
+    let mut main = vec![];
+    let mut declared = vec![];
+
+    for e in program {
+        em.scopes = vec![vec![]];
+        match &e.kind {
+            ExprKind::Define { var, value } => {
+                let name = em.var(var);
+                let constant = matches!(value.kind, ExprKind::Lambda { .. } | ExprKind::Lit(_));
+                if constant && !redefined.contains(&var.id) {
+                    let value = em.expr(value, 0)?;
+                    out.push(format!("export const {} = {};", name, value));
+                } else {
+                    let value = em.expr(value, 1)?;
+                    if !declared.contains(&var.id) {
+                        out.push(format!("export let {};", name));
+                        declared.push(var.id);
+                    }
+                    main.push(format!("    {} = {};", name, value));
+                }
+            }
+            _ => main.push(format!("    console.log($show({}));", em.expr(e, 1)?)),
+        }
+    }
+
+    out.push(String::new());
+    out.push("export function main() {".to_string());
+    out.extend(main);
+    out.push("}".to_string());
+    Ok(extract(&(out.join("\n") + "\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{eval, example, interpret, lower, optimize, KITCHEN_SINK};
+    use syntax::source::Texts;
+    use syntax::{expr::Span, source::FileId};
+
+    // Run a module with node, or return `None` if node isn't installed
+    fn run(js: &str) -> Option<String> {
+        // Modules can't call their own exports from the outside, so
+        // append the call to `main`
+        interpret("node", &["--input-type=module", "-"], &format!("{}main();\n", js))
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let texts = Texts::new();
+        let js = compile(&lower(&texts, &example("factorial"))).unwrap();
+        assert_eq!(js, format!("{}
+export const factorial = ((n) => {{
+    if ((n > 1n)) {{
+        return $int(n * factorial($int(n - 1n)));
+    }} else {{
+        return 1n;
+    }}
+}});
+
+export function main() {{
+    console.log($show(factorial(5n)));
+}}
+", PRELUDE));
+    }
+
+    #[test]
+    fn test_same_name_globals() {
+        // Globals of two modules, both called `x`
+        let program = ir::text::parse(FileId::default(), "(define (x%0 Int) 1) (define (x%1 Int) (+ x%0 1)) x%1").unwrap();
+        let js = compile(&program).unwrap();
+        assert!(js.contains("export const x = 1n;\nexport let x$g1;"), "{}", js);
+        assert!(js.contains("    x$g1 = $int(x + 1n);"), "{}", js);
+        if let Some(out) = run(&js) {
+            assert_eq!(out, "2\n");
+        }
+    }
+
+    #[test]
+    fn test_effect_order() {
+        let texts = Texts::new();
+        // The division by zero happens after the first line is printed
+        let js = compile(&lower(&texts, "1; let x = 10 / 0; x;")).unwrap();
+        assert!(js.contains("\
+export function main() {
+    console.log($show(1n));
+    x = $int(10n / 0n);
+    console.log($show(x));
+}"), "{}", js);
+    }
+
+    #[test]
+    fn test_node() {
+        let texts = Texts::new();
+        for src in [example("factorial"), example("simple"), KITCHEN_SINK.to_string()] {
+            for program in [lower(&texts, &src), optimize(&texts, &src)] {
+                let js = compile(&program).unwrap();
+                if let Some(out) = run(&js) {
+                    assert_eq!(out, eval(&texts, &src));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_source_map() {
+        let texts = Texts::new();
+        let src = "let f = fun (n Int) -> 10 / n;\nf(0);";
+        let (js, map) = compile_with_map(&lower(&texts, src)).unwrap();
+        let start = src.find("10 / n").unwrap();
+        let m = map.mappings.iter().find(|m| m.span == Span::new(FileId::default(), start, start + 6)).unwrap();
+        let line = js.lines().nth(m.line).unwrap();
+        assert_eq!(&line[m.column..], "$int(10n / n));");
+        assert!(map.mappings.iter().all(|m| m.span.end <= src.len()));
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+use ir::{Expr, ExprKind, Lit};
+
+pub mod embed;
+pub mod js;
+pub mod lua;
+pub mod session;
+pub mod source_map;
+pub mod wasm;
+pub mod x86_64;
+
+/// An error produced while generating code for a backend
+#[derive(Clone, Debug)]
+pub struct BackendError {
+    pub message: String,
+}
+
+impl BackendError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self { message: message.into() }
+    }
+}
+
+impl Display for BackendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Collect the string literals of an expression
+pub(crate) fn collect_strings<'src>(e: &Expr<'src>, out: &mut BTreeSet<&'src str>) {
+    if let ExprKind::Lit(Lit::Str(s)) = e.kind {
+        out.insert(s);
+    }
+    e.children().into_iter().for_each(|c| collect_strings(c, out));
+}
+
+#[cfg(test)]
+mod testing;
@@ -0,0 +1,635 @@
+//! Lua backend, emitting a Lua 5.4 chunk.
+//!
+//! Integers map to Lua's 64-bit integers, which already wrap, and unit is
+//! `nil`. Lua has no expression forms for `if`, `let` or blocks, so those
+//! are emitted as statements assigning a temporary that the surrounding
+//! expression then reads. Top-level definitions become locals of the chunk,
+//! or fields of a table once there are too many of them, and the chunk
+//! returns them in a table together with a `main` function printing the
+//! remaining top-level expressions. A global defined more than once is
+//! assigned again at each definition, so everything referring to it sees
+//! the latest one.
+
+use ir::{BinOp, Expr, ExprKind, Lit, Type, UnOp, Var, VarId};
+
+use crate::BackendError;
+
+// `//` and `%` round towards negative infinity, while division truncates
+const PRELUDE: &str = "\
+local function hl0_div(a, b)
+    if b == 0 then error(\"division by zero\", 0) end
+    local q = a // b
+    if a % b ~= 0 and (a < 0) ~= (b < 0) then q = q + 1 end
+    return q
+end
+
+local function hl0_mod(a, b)
+    if b == 0 then error(\"division by zero\", 0) end
+    local r = a % b
+    if r ~= 0 and (r < 0) ~= (a < 0) then r = r - b end
+    return r
+end
+
+-- Booleans and nil can't be ordered, so they are compared as integers
+local function hl0_ord(x)
+    if x then return 1 else return 0 end
+end
+
+local function hl0_print(x)
+    if x == nil then
+        print(\"()\")
+    elseif type(x) == \"function\" then
+        print(\"<function>\")
+    else
+        print(tostring(x))
+    end
+end
+";
+
+// Keywords, plus names that change how the chunk itself behaves or that
+// it uses itself
+const RESERVED: [&str; 24] = [
+    "and", "break", "do", "else", "elseif", "end", "false", "for",
+    "function", "goto", "if", "in", "local", "nil", "not", "or", "repeat",
+    "return", "then", "true", "until", "while", "_ENV", "main",
+];
+
+// How many globals are locals of the chunk. Lua allows 200 locals per
+// function, so the globals after those are fields of the table `hl0_g`
+const LOCALS: usize = 150;
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(BackendError::new(format!($($arg)*)))
+    };
+}
+
+/// Turn a Holymer identifier into a valid Lua one. Holymer identifiers
+/// have no digits, so the generated names can't clash with them
+fn mangle(name: &str) -> String {
+    let name = name.replace('\'', "_1");
+    if RESERVED.contains(&name.as_str()) {
+        format!("{}_0", name)
+    } else {
+        name
+    }
+}
+
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            // Padded, so that a digit after it isn't read as part of it
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\{:03}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+// Where the value of a statement goes
+enum Dest {
+    Return,
+    Assign(String),
+}
+
+struct Emitter<'src> {
+    // Local variables in scope, with their names in the source and what
+    // they are called in the output
+    scopes: Vec<Vec<(VarId, &'src str, String)>>,
+    // The same for top-level definitions, which are only renamed when
+    // modules define globals of the same name
+    globals: Vec<(VarId, &'src str, String)>,
+    fresh: usize,
+}
+
+impl<'src> Emitter<'src> {
+    fn temp(&mut self) -> String {
+        self.fresh += 1;
+        format!("hl0_t{}", self.fresh)
+    }
+
+    /// Bind a local variable, renaming it if it would shadow another one.
+    /// Passes can introduce distinct variables with the same name, and the
+    /// inner one must not hide the outer one from code that refers to it
+    fn bind(&mut self, var: &Var<'src>) -> String {
+        let shadows = self.globals.iter().chain(self.scopes.iter().flatten()).any(|(_, n, _)| *n == var.name);
+        let lua = if shadows {
+            self.fresh += 1;
+            format!("hl{}_{}", self.fresh, mangle(var.name))
+        } else {
+            mangle(var.name)
+        };
+        self.scopes.last_mut().unwrap().push((var.id, var.name, lua.clone()));
+        lua
+    }
+
+    fn var(&self, var: &Var) -> String {
+        self.scopes.iter().flatten().chain(&self.globals)
+            .find(|(id, _, _)| *id == var.id)
+            .map(|(_, _, lua)| lua.clone())
+            .unwrap_or_else(|| mangle(var.name))
+    }
+
+    fn params(&mut self, params: &[Var<'src>]) -> String {
+        params.iter()
+            .map(|p| self.bind(p))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Emit an expression, pushing the statements that have to run before
+    /// it onto `out`
+    fn expr(&mut self, e: &Expr<'src>, depth: usize, out: &mut Vec<String>) -> Result<String, BackendError> {
+        let ind = indent(depth);
+        Ok(match &e.kind {
+            ExprKind::Lit(Lit::Unit)    => "nil".to_string(),
+            ExprKind::Lit(Lit::Bool(b)) => b.to_string(),
+            ExprKind::Lit(Lit::Int(i64::MIN)) => "math.mininteger".to_string(),
+            ExprKind::Lit(Lit::Int(i)) if *i < 0 => format!("({})", i),
+            ExprKind::Lit(Lit::Int(i))  => i.to_string(),
+            ExprKind::Lit(Lit::Str(s))  => string(s),
+            ExprKind::Var(x) => self.var(x),
+            ExprKind::If { .. } | ExprKind::Let { .. } | ExprKind::Define { .. } | ExprKind::Block { .. } => {
+                let t = self.temp();
+                out.push(format!("{}local {}", ind, t));
+                self.stmt(e, &Dest::Assign(t.clone()), depth, out)?;
+                t
+            }
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), l, r) => {
+                let l = self.expr(l, depth, out)?;
+                let mut pre = vec![];
+                let r = self.expr(r, depth + 1, &mut pre)?;
+                let op = if *op == BinOp::And { "and" } else { "or" };
+                if pre.is_empty() {
+                    format!("({} {} {})", l, op, r)
+                } else {
+                    // Only evaluate the right side when needed
+                    let t = self.temp();
+                    out.push(format!("{}local {} = {}", ind, t, l));
+                    let cond = if op == "and" { t.clone() } else { format!("not {}", t) };
+                    out.push(format!("{}if {} then", ind, cond));
+                    out.extend(pre);
+                    out.push(format!("{}    {} = {}", ind, t, r));
+                    out.push(format!("{}end", ind));
+                    t
+                }
+            }
+            ExprKind::Lambda { params, body } => {
+                self.scopes.push(vec![]);
+                let mut lines = vec![format!("function({})", self.params(params))];
+                self.stmt(body, &Dest::Return, depth + 1, &mut lines)?;
+                self.scopes.pop();
+                lines.push(format!("{}end", ind));
+                lines.join("\n")
+            }
+            ExprKind::Unary(UnOp::Neg, x) => format!("(-{})", self.expr(x, depth, out)?),
+            ExprKind::Unary(UnOp::Not, x) => format!("(not {})", self.expr(x, depth, out)?),
+            ExprKind::Binary(op @ (BinOp::Div | BinOp::Rem), l, r) => {
+                let args = self.operands(&[l, r], depth, out)?;
+                let f = if *op == BinOp::Div { "hl0_div" } else { "hl0_mod" };
+                format!("{}({}, {})", f, args[0], args[1])
+            }
+            ExprKind::Binary(op, l, r) => {
+                let ordinal = matches!(l.ty, Type::Bool | Type::Unit)
+                    && matches!(op, BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge);
+                let mut args = self.operands(&[l, r], depth, out)?;
+                if ordinal {
+                    for a in &mut args {
+                        *a = format!("hl0_ord({})", a);
+                    }
+                }
+                let op = if *op == BinOp::Ne { "~=" } else { op.symbol() };
+                format!("({} {} {})", args[0], op, args[1])
+            }
+            ExprKind::Call { func, args } => {
+                let all = std::iter::once(&**func).chain(args).collect::<Vec<_>>();
+                let mut args = self.operands(&all, depth, out)?;
+                let f = args.remove(0);
+                let f = match f.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.') {
+                    true => f,
+                    false => format!("({})", f),
+                };
+                format!("{}({})", f, args.join(", "))
+            }
+            ExprKind::Closure { .. } | ExprKind::EnvGet { .. } => {
+                bail!("closure-converted IR is not supported by the Lua backend")
+            }
+        })
+    }
+
+    /// Emit expressions that are evaluated left to right. If the statements
+    /// of a later one would run before an earlier one, the earlier one is
+    /// stored in a temporary first
+    fn operands(&mut self, es: &[&Expr<'src>], depth: usize, out: &mut Vec<String>) -> Result<Vec<String>, BackendError> {
+        let mut values: Vec<String> = vec![];
+        for e in es {
+            let mut pre = vec![];
+            let v = self.expr(e, depth, &mut pre)?;
+            if !pre.is_empty() {
+                for prev in values.iter_mut() {
+                    if !is_atom(prev) {
+                        let t = self.temp();
+                        out.push(format!("{}local {} = {}", indent(depth), t, prev));
+                        *prev = t;
+                    }
+                }
+            }
+            out.extend(pre);
+            values.push(v);
+        }
+        Ok(values)
+    }
+
+    /// Emit statements that give the value of an expression to `dest`
+    fn stmt(&mut self, e: &Expr<'src>, dest: &Dest, depth: usize, out: &mut Vec<String>) -> Result<(), BackendError> {
+        let ind = indent(depth);
+        let scoped = matches!(dest, Dest::Assign(_));
+        match &e.kind {
+            ExprKind::If { cond, t, f } => {
+                let c = self.expr(cond, depth, out)?;
+                out.push(format!("{}if {} then", ind, c));
+                self.stmt(t, dest, depth + 1, out)?;
+                out.push(format!("{}else", ind));
+                self.stmt(f, dest, depth + 1, out)?;
+                out.push(format!("{}end", ind));
+            }
+            ExprKind::Let { var, value, body } => {
+                // A returning statement ends the function anyway, so
+                // only assignments need their own scope
+                let depth = if scoped {
+                    out.push(format!("{}do", ind));
+                    depth + 1
+                } else {
+                    depth
+                };
+                let value = self.expr(value, depth, out)?;
+                self.scopes.push(vec![]);
+                out.push(format!("{}local {} = {}", indent(depth), self.bind(var), value));
+                self.stmt(body, dest, depth, out)?;
+                self.scopes.pop();
+                if scoped {
+                    out.push(format!("{}end", ind));
+                }
+            }
+            ExprKind::Define { .. } if scoped => {
+                out.push(format!("{}do", ind));
+                self.scopes.push(vec![]);
+                self.define(e, depth + 1, out)?;
+                self.scopes.pop();
+                out.push(format!("{}end", ind));
+            }
+            ExprKind::Define { .. } => self.define(e, depth, out)?,
+            ExprKind::Block { exprs, void } => {
+                let depth = if scoped {
+                    out.push(format!("{}do", ind));
+                    depth + 1
+                } else {
+                    depth
+                };
+                self.scopes.push(vec![]);
+                for (i, e) in exprs.iter().enumerate() {
+                    if i + 1 == exprs.len() && !void {
+                        self.stmt(e, dest, depth, out)?;
+                    } else if e.is_define() {
+                        self.define(e, depth, out)?;
+                    } else {
+                        let v = self.expr(e, depth, out)?;
+                        out.push(format!("{}local _ = {}", indent(depth), v));
+                    }
+                }
+                self.scopes.pop();
+                if scoped {
+                    out.push(format!("{}end", ind));
+                }
+            }
+            _ => self.value(e, dest, depth, out)?,
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, e: &Expr<'src>, dest: &Dest, depth: usize, out: &mut Vec<String>) -> Result<(), BackendError> {
+        let v = self.expr(e, depth, out)?;
+        out.push(match dest {
+            Dest::Return => format!("{}return {}", indent(depth), v),
+            Dest::Assign(t) => format!("{}{} = {}", indent(depth), t, v),
+        });
+        Ok(())
+    }
+
+    /// Emit a `define` inside a block as a local
+    fn define(&mut self, e: &Expr<'src>, depth: usize, out: &mut Vec<String>) -> Result<(), BackendError> {
+        let ExprKind::Define { var, value } = &e.kind else { bail!("expected a define") };
+        // Only lambdas can refer to themselves, which `local function`
+        // takes care of
+        if value.is_lambda() {
+            let name = self.bind(var);
+            let func = self.function(value, &name, depth)?;
+            out.push(format!("{}local {}", indent(depth), func));
+        } else {
+            let value = self.expr(value, depth, out)?;
+            out.push(format!("{}local {} = {}", indent(depth), self.bind(var), value));
+        }
+        Ok(())
+    }
+
+    /// Emit `function name(params) ... end` for a lambda
+    fn function(&mut self, l: &Expr<'src>, name: &str, depth: usize) -> Result<String, BackendError> {
+        let ExprKind::Lambda { params, body } = &l.kind else { bail!("expected a lambda") };
+        self.scopes.push(vec![]);
+        let mut lines = vec![format!("function {}({})", name, self.params(params))];
+        self.stmt(body, &Dest::Return, depth + 1, &mut lines)?;
+        self.scopes.pop();
+        lines.push(format!("{}end", indent(depth)));
+        Ok(lines.join("\n"))
+    }
+}
+
+// Whether an emitted expression can be evaluated later with the same result
+fn is_atom(s: &str) -> bool {
+    s.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        || (s.starts_with('"') && s.ends_with('"'))
+}
+
+/// Compile a program into a Lua 5.4 chunk
+pub fn compile(program: &[Expr]) -> Result<String, BackendError> {
+    // What each global is called, with the name it is exported as
+    let mut bindings: Vec<(String, String)> = vec![];
+    let mut globals: Vec<(VarId, &str, String)> = vec![];
+    for e in program {
+        if let ExprKind::Define { var, .. } = &e.kind {
+            if globals.iter().any(|(id, _, _)| *id == var.id) {
+                continue;
+            }
+            // Modules can each define a global of the same name
+            let mut name = mangle(var.name);
+            if bindings.iter().any(|(_, export)| *export == name) {
+                name = format!("hlg{}_{}", bindings.len(), name);
+            }
+            // `main` is taken by the chunk's own export
+            let export = if name == mangle(var.name) && var.name != "main" {
+                var.name.to_string()
+            } else {
+                name.clone()
+            };
+            if bindings.len() >= LOCALS {
+                name = format!("hl0_g.{}", name);
+            }
+            globals.push((var.id, var.name, name.clone()));
+            bindings.push((name, export));
+        }
+    }
+
+    let mut em = Emitter { scopes: vec![], globals, fresh: 0 };
+    let mut out = vec![PRELUDE.to_string()];
+    // Declared up front so that functions can refer to later definitions
+    if !bindings.is_empty() {
+        let names = bindings.iter().take(LOCALS).map(|(g, _)| g.as_str()).collect::<Vec<_>>();
+        out.push(format!("local {}", names.join(", ")));
+    }
+    if bindings.len() > LOCALS {
+        out.push("local hl0_g = {}".to_string());
+    }
+    let mut main = vec![];
+
+    for e in program {
+        match &e.kind {
+            ExprKind::Define { var, value } if value.is_lambda() => {
+                let name = em.var(var);
+                out.push(em.function(value, &name, 0)?);
+            }
+            ExprKind::Define { var, value } => {
+                let name = em.var(var);
+                // The statements computing the value are scoped, so that
+                // their temporaries don't add up to too many locals
+                let mut pre = vec![];
+                let value = em.expr(value, 1, &mut pre)?;
+                if pre.is_empty() {
+                    out.push(format!("{} = {}", name, value));
+                } else {
+                    out.push("do".to_string());
+                    out.extend(pre);
+                    out.push(format!("    {} = {}", name, value));
+                    out.push("end".to_string());
+                }
+            }
+            _ => {
+                // Scoped for the same reason
+                let mut pre = vec![];
+                let v = em.expr(e, 2, &mut pre)?;
+                if pre.is_empty() {
+                    main.push(format!("    hl0_print({})", v));
+                } else {
+                    main.push("    do".to_string());
+                    main.extend(pre);
+                    main.push(format!("        hl0_print({})", v));
+                    main.push("    end".to_string());
+                }
+            }
+        }
+    }
+
+    out.push(String::new());
+    out.push("local function main()".to_string());
+    out.extend(main);
+    out.push("end".to_string());
+    out.push(String::new());
+
+    // Renamed globals are exported under their new name
+    let exports = bindings.iter()
+        .map(|(g, export)| format!("    [{}] = {},", string(export), g))
+        .chain(std::iter::once("    main = main,".to_string()))
+        .collect::<Vec<_>>();
+    out.push("return {".to_string());
+    out.extend(exports);
+    out.push("}".to_string());
+    Ok(out.join("\n") + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{eval, example, interpret, lower, KITCHEN_SINK};
+    use syntax::source::Texts;
+
+    // Run a chunk with lua, or return `None` if it isn't installed
+    fn run(lua: &str) -> Option<String> {
+        interpret("lua", &["-e", &format!("load({})().main()", string(lua))], "")
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let texts = Texts::new();
+        let lua = compile(&lower(&texts, &example("factorial"))).unwrap();
+        assert_eq!(lua, format!("{}
+local factorial
+function factorial(n)
+    if (n > 1) then
+        return (n * factorial((n - 1)))
+    else
+        return 1
+    end
+end
+
+local function main()
+    hl0_print(factorial(5))
+end
+
+return {{
+    [\"factorial\"] = factorial,
+    main = main,
+}}
+", PRELUDE));
+    }
+
+    #[test]
+    fn test_values() {
+        let texts = Texts::new();
+        // Blocks in the middle of an expression are hoisted into statements
+        let lua = compile(&lower(&texts, "1 + { let x = 2; x * 3 }")).unwrap();
+        assert!(lua.contains("\
+local function main()
+    do
+        local hl0_t1
+        do
+            local x = 2
+            hl0_t1 = (x * 3)
+        end
+        hl0_print((1 + hl0_t1))
+    end
+end"));
+    }
+
+    #[test]
+    fn test_strings() {
+        let texts = Texts::new();
+        // Without padding, `\12` would be a single character
+        let src = "\"\u{1}2\";";
+        let lua = compile(&lower(&texts, src)).unwrap();
+        assert!(lua.contains("hl0_print(\"\\0012\")"), "{}", lua);
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+
+    #[test]
+    fn test_shadowing() {
+        let texts = Texts::new();
+        // After inlining, the body of `f` refers to the global `y` from
+        // inside the scope of the local one
+        let src = "let y = 1; let f = fun (a Int) -> a + y; { let y = 5; f(y) };";
+        let program = ir::inline::inline(lower(&texts, src), ir::inline::MAX_INLINE_SIZE);
+        let lua = compile(&program).unwrap();
+        assert!(lua.contains("\
+            local hl2_y = 5
+            hl0_t1 = (hl2_y + y)"), "{}", lua);
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+
+    #[test]
+    fn test_lua() {
+        let texts = Texts::new();
+        for src in [example("factorial"), example("simple"), KITCHEN_SINK.to_string()] {
+            let lua = compile(&lower(&texts, &src)).unwrap();
+            if let Some(out) = run(&lua) {
+                assert_eq!(out, eval(&texts, &src));
+            }
+        }
+    }
+
+    #[test]
+    fn test_main() {
+        let texts = Texts::new();
+        let src = "let main = fun (x Int) -> x + 1; main(1);";
+        let lua = compile(&lower(&texts, src)).unwrap();
+        assert!(lua.contains("    [\"main_0\"] = main_0,\n    main = main,"), "{}", lua);
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+
+    #[test]
+    fn test_golden() {
+        let texts = Texts::new();
+        // Division truncates and the remainder has the sign of the
+        // dividend, and the redefinition of `x` reads the first one before
+        // assigning it
+        let src = "let x = 7; let half = fun (n Int) -> n / 2; let x = half(x) % -2 + { let y = x; y * -7 / 2 }; x;";
+        let lua = compile(&lower(&texts, src)).unwrap();
+        assert_eq!(lua, format!("{}
+local x, half
+x = 7
+function half(n)
+    return hl0_div(n, 2)
+end
+do
+    local hl0_t2 = hl0_mod(half(x), (-2))
+    local hl0_t1
+    do
+        local y = x
+        hl0_t1 = hl0_div((y * (-7)), 2)
+    end
+    x = (hl0_t2 + hl0_t1)
+end
+
+local function main()
+    hl0_print(x)
+end
+
+return {{
+    [\"x\"] = x,
+    [\"half\"] = half,
+    main = main,
+}}
+", PRELUDE));
+        // Flooring would give -1 and -25
+        assert_eq!(eval(&texts, src), "-23\n");
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+
+    #[test]
+    fn test_many_globals() {
+        let texts = Texts::new();
+        // Identifiers can't have digits, so the globals are `gaa`, `gab`...
+        let name = |i: usize| format!("g{}{}", (b'a' + (i / 26) as u8) as char, (b'a' + (i % 26) as u8) as char);
+        let src = (0..LOCALS + 2)
+            .map(|i| format!("let {} = {};", name(i), i))
+            .collect::<String>() + &format!("{};", name(LOCALS + 1));
+        let lua = compile(&lower(&texts, &src)).unwrap();
+        let last = format!("hl0_g.{}", name(LOCALS + 1));
+        assert!(lua.contains("local hl0_g = {}\ngaa = 0\n"), "{}", lua);
+        assert!(lua.contains(&format!("{} = {}\n", last, LOCALS + 1)), "{}", lua);
+        assert!(lua.contains(&format!("hl0_print({})", last)), "{}", lua);
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, &src));
+        }
+    }
+
+    #[test]
+    fn test_many_expressions() {
+        let texts = Texts::new();
+        // Each of them needs a temporary, which goes out of scope after it
+        // is printed
+        let src = "let n = 1;".to_string() + &"if n > 0 then n else 0;".repeat(250);
+        let lua = compile(&lower(&texts, &src)).unwrap();
+        assert!(lua.contains("    do\n        local hl0_t250\n"), "{}", lua);
+        assert!(lua.contains("        hl0_print(hl0_t250)\n    end\nend\n"), "{}", lua);
+        if let Some(out) = run(&lua) {
+            assert_eq!(out, eval(&texts, &src));
+        }
+    }
+}
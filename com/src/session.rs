@@ -0,0 +1,715 @@
+//! The compiler as a library. A [`Session`] runs the stages of the compiler
+//! on source code and gives back what went wrong as [`Diagnostic`]s instead
+//! of printing it, so that `hc`, its REPL and the tests share one driver.
+//!
+//! ```
+//! use com::session::{Session, Target};
+//! use syntax::source::Texts;
+//!
+//! let texts = Texts::new();
+//! let mut session = Session::new(&texts);
+//! let program = session.check("let sq = fun (x Int) -> x * x; sq(7);").unwrap();
+//! let irs = session.lower(program).unwrap();
+//! assert_eq!(session.eval(&irs).unwrap()[0].to_string(), "49");
+//! assert!(session.emit(irs, Target::Js).is_ok());
+//! ```
+//!
+//! What is checked with [`Session::check`] builds on what was checked,
+//! lowered and evaluated before, as the inputs of a REPL do. The modules of
+//! a larger program are checked on their own with [`Session::check_module`]
+//! and lowered together.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use chumsky::{prelude::Input, Parser};
+use ir::{
+    eval::{apply, EvalError, Evaluator, Value},
+    fold::FoldWarning,
+    pass::PassManager,
+    verify::{verify, VerifyError},
+    Expr, Lowerer,
+};
+use syntax::{
+    expr::{Span, Token},
+    module::Module,
+    parser::{exprs_parser, lexer, module_parser},
+    pretty::pretty_type,
+    source::{FileId, Sources, Texts},
+    ty::Type,
+};
+use typing::{
+    infer::{instance_of, Infer, InferError, InferErrorKind, Interface},
+    memo::Memo,
+    typed::TExpr,
+};
+
+use crate::{
+    embed::{HostArgs, HostFn, HostValue},
+    js, lua, source_map::SourceMap, wasm, x86_64, BackendError,
+};
+
+/// How serious a diagnostic is, or what a label of one points out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Hint,
+}
+
+/// The stage of the compiler a diagnostic comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Syntax,
+    Type,
+    /// Invalid IR, found with [`Session::verify_ir`], and what the optimizer
+    /// warns about
+    Ir,
+    Codegen,
+    Runtime,
+}
+
+/// An error or a warning about a program
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub phase: Phase,
+    pub title: String,
+    /// Where it is, unless it isn't about a place in the sources
+    pub span: Option<Span>,
+    /// What to say about the parts of the sources it is about, which can
+    /// be in several files
+    pub labels: Vec<(String, Span, Severity)>,
+}
+
+impl Diagnostic {
+    /// An error with a single label
+    pub fn error(phase: Phase, span: Span, title: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            phase,
+            title: title.into(),
+            span: Some(span),
+            labels: vec![(message.into(), span, Severity::Error)],
+        }
+    }
+
+    fn invalid_ir(stage: &str, e: VerifyError) -> Self {
+        Self::error(Phase::Ir, e.span, format!("invalid IR after {}: {}", stage, e.message), e.message)
+    }
+
+    /// A runtime error, at `at` if the evaluator doesn't say where
+    fn runtime(e: EvalError, at: Option<Span>) -> Self {
+        let title = format!("runtime error: {}", e.message);
+        match e.span.or(at) {
+            Some(span) => Self::error(Phase::Runtime, span, title, e.message),
+            None => Self::unplaced(Phase::Runtime, title),
+        }
+    }
+
+    /// An error that isn't about a place in the sources
+    fn unplaced(phase: Phase, title: String) -> Self {
+        Self { severity: Severity::Error, phase, title, span: None, labels: vec![] }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.title)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl From<InferError> for Diagnostic {
+    fn from(e: InferError) -> Self {
+        let labels = e.labels.into_iter()
+            .map(|(msg, kind, span)| (msg, span, match kind {
+                InferErrorKind::Error => Severity::Error,
+                InferErrorKind::Hint => Severity::Hint,
+            }))
+            .collect();
+        Self { severity: Severity::Error, phase: Phase::Type, title: e.title, span: Some(e.span), labels }
+    }
+}
+
+impl From<FoldWarning> for Diagnostic {
+    fn from(w: FoldWarning) -> Self {
+        let labels = vec![(w.message.clone(), w.span, Severity::Warning)];
+        Self { severity: Severity::Warning, phase: Phase::Ir, title: w.message, span: Some(w.span), labels }
+    }
+}
+
+impl From<BackendError> for Diagnostic {
+    fn from(e: BackendError) -> Self {
+        Self::unplaced(Phase::Codegen, e.message)
+    }
+}
+
+/// What [`Session::codegen`] generates code in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// An ES2020 module
+    Js,
+    /// A Lua 5.4 chunk
+    Lua,
+    /// A WebAssembly module in the binary format
+    Wasm,
+    /// A WebAssembly module in the text format
+    Wat,
+    /// x86-64 assembly for Linux, see [`x86_64::build`] to assemble it
+    Asm,
+}
+
+/// Generated code
+pub struct Output {
+    pub code: Vec<u8>,
+    /// The map back to the sources, for the targets that have one
+    pub source_map: Option<SourceMap>,
+}
+
+/// A type checked module
+pub struct TypedModule<'src> {
+    pub file: FileId,
+    pub items: Vec<(TExpr<'src>, Span)>,
+    pub interface: Interface<'src>,
+    /// The modules it imports, by the name they are referred to with and
+    /// their position in the program
+    pub imports: Vec<(&'src str, usize)>,
+}
+
+/// Type checked modules, each one after the modules it imports
+pub struct TypedProgram<'src> {
+    pub modules: Vec<TypedModule<'src>>,
+    // Checked by `Session::check`, so lowered in the scope of the session
+    in_session: bool,
+}
+
+impl<'src> TypedProgram<'src> {
+    pub fn new(modules: Vec<TypedModule<'src>>) -> Self {
+        Self { modules, in_session: false }
+    }
+}
+
+type Tokens<'src> = Vec<(Token<'src>, Span)>;
+type Items<'src> = Vec<(TExpr<'src>, Span)>;
+
+/// Runs the stages of the compiler, keeping the sources they read and what
+/// the programs checked so far define
+pub struct Session<'src> {
+    /// Every file read, which the spans of diagnostics point into. Their
+    /// text is kept in the session's [`Texts`], as syntax trees and the IR
+    /// keep referring to it
+    pub sources: Sources<'src>,
+    /// 0 to skip the optimizer
+    pub opt_level: u8,
+    /// Check the IR after every stage, giving what is wrong with it as
+    /// diagnostics
+    pub verify_ir: bool,
+    /// How long each stage took
+    pub timings: Vec<(&'static str, Duration)>,
+    /// The types of the definitions of the modules checked before, for
+    /// [`Session::check_module`] to only check those that changed
+    pub memo: Option<Memo>,
+    /// Called with the IR after lowering and after every pass, with the
+    /// name of the stage
+    #[allow(clippy::type_complexity)]
+    pub after: Option<Box<dyn FnMut(&'static str, &[Expr<'src>])>>,
+    warnings: Vec<Diagnostic>,
+    texts: &'src Texts,
+    infer: Infer<'src>,
+    lowerer: Lowerer<'src>,
+    evaluator: Evaluator<'src>,
+    // The Rust functions given to programs, to give them again after
+    // `forget`
+    natives: Vec<(&'src str, Type, Value<'src>)>,
+    // How many programs were checked from a string, to name them
+    inputs: usize,
+}
+
+impl<'src> Session<'src> {
+    /// A session keeping the text of the sources it reads in `texts`,
+    /// which frees it once the session and what it made are dropped
+    pub fn new(texts: &'src Texts) -> Self {
+        Self {
+            sources: Sources::new(),
+            opt_level: 1,
+            verify_ir: false,
+            timings: vec![],
+            memo: None,
+            after: None,
+            warnings: vec![],
+            texts,
+            infer: Infer::new(),
+            lowerer: Lowerer::new(),
+            evaluator: Evaluator::new(),
+            natives: vec![],
+            inputs: 0,
+        }
+    }
+
+    /// Add a file to the sources, `path` being what to call it
+    pub fn add_source(&mut self, path: impl Into<String>, text: impl Into<String>) -> FileId {
+        let text = self.texts.add(&text.into());
+        self.sources.add(path, text)
+    }
+
+    /// Run a stage of the compiler, timing it
+    pub fn time<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let out = f();
+        self.timings.push((name, start.elapsed()));
+        out
+    }
+
+    /// The warnings since the last call
+    pub fn warnings(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Lex a file, with the tokens the lexer recovered if there are errors
+    fn tokens(&mut self, file: FileId) -> (Option<Tokens<'src>>, Vec<Diagnostic>) {
+        let src = self.sources.get(file).text;
+        let (tokens, errs) = self.time("lex", || lexer(file).parse(src).into_output_errors());
+        // The lexer's spans don't have the file, as it reads text
+        let errs = errs.into_iter()
+            .map(|e| {
+                let span = Span::new(file, e.span().start, e.span().end);
+                Diagnostic::error(Phase::Syntax, span, e.to_string(), e.reason().to_string())
+            })
+            .collect();
+        (tokens, errs)
+    }
+
+    pub fn lex(&mut self, file: FileId) -> Result<Tokens<'src>, Vec<Diagnostic>> {
+        match self.tokens(file) {
+            (Some(tokens), errs) if errs.is_empty() => Ok(tokens),
+            (_, errs) => Err(errs),
+        }
+    }
+
+    /// Lex and parse a file with `parser`, giving the errors of both
+    fn parse_with<T>(
+        &mut self,
+        file: FileId,
+        parser: impl for<'tokens> FnOnce(&'tokens [(Token<'src>, Span)], Span) -> (Option<T>, Vec<Diagnostic>),
+    ) -> Result<(Tokens<'src>, T), Vec<Diagnostic>> {
+        let len = self.sources.get(file).text.len();
+        let (tokens, mut errs) = self.tokens(file);
+        let Some(tokens) = tokens else { return Err(errs) };
+        let (out, parse_errs) = self.time("parse", || parser(&tokens, Span::new(file, len, len)));
+        errs.extend(parse_errs);
+        match out {
+            Some(out) if errs.is_empty() => Ok((tokens, out)),
+            _ => Err(errs),
+        }
+    }
+
+    /// Lex and parse a module, giving its tokens and its syntax tree
+    pub fn parse(&mut self, file: FileId) -> Result<(Tokens<'src>, Module<'src>), Vec<Diagnostic>> {
+        self.parse_with(file, |tokens, eoi| {
+            let (module, errs) = module_parser().parse(tokens.spanned(eoi)).into_output_errors();
+            (module, errs.into_iter().map(syntax_error).collect())
+        })
+    }
+
+    /// Type check a program after those checked before, whose definitions
+    /// it can use. It is added to the sources as `<input N>`
+    pub fn check(&mut self, source: &str) -> Result<TypedProgram<'src>, Vec<Diagnostic>> {
+        self.inputs += 1;
+        let file = self.add_source(format!("<input {}>", self.inputs), source);
+        self.check_file(file)
+    }
+
+    /// [`Session::check`] a file of the sources. Nothing is kept from a
+    /// program with errors
+    pub fn check_file(&mut self, file: FileId) -> Result<TypedProgram<'src>, Vec<Diagnostic>> {
+        let (_, ast) = self.parse_with(file, |tokens, eoi| {
+            let (ast, errs) = exprs_parser().parse(tokens.spanned(eoi)).into_output_errors();
+            (ast, errs.into_iter().map(syntax_error).collect())
+        })?;
+        let mut infer = std::mem::take(&mut self.infer);
+        let (items, errs) = self.time("typecheck", || infer.infer_more(ast));
+        self.infer = infer;
+        if !errs.is_empty() {
+            return Err(errs.into_iter().map(Diagnostic::from).collect());
+        }
+        let module = TypedModule { file, items, interface: Interface::default(), imports: vec![] };
+        Ok(TypedProgram { modules: vec![module], in_session: true })
+    }
+
+    /// Type check a module on its own, with the interfaces of the modules
+    /// it imports by the name they are referred to with. Gives its typed
+    /// items and its own interface
+    pub fn check_module(
+        &mut self,
+        file: FileId,
+        module: Module<'src>,
+        imports: Vec<(&'src str, Interface<'src>)>,
+    ) -> Result<(Items<'src>, Interface<'src>), Vec<Diagnostic>> {
+        let mut infer = Infer::new();
+        for (alias, interface) in imports {
+            infer.import(alias, interface);
+        }
+        let src = self.sources.get(file).text;
+        let mut memo = self.memo.take();
+        let (items, interface, errs) = self.time("typecheck", || match &mut memo {
+            Some(memo) => infer.infer_module_memo(module, src, memo),
+            None => infer.infer_module(module),
+        });
+        self.memo = memo;
+        match errs.is_empty() {
+            true => Ok((items, interface)),
+            false => Err(errs.into_iter().map(Diagnostic::from).collect()),
+        }
+    }
+
+    /// Check a program with [`verify`] if [`Session::verify_ir`] asks to,
+    /// `stage` being what made it
+    pub fn verify(&self, stage: &'static str, irs: &[Expr<'src>]) -> Result<(), Vec<Diagnostic>> {
+        if !self.verify_ir {
+            return Ok(());
+        }
+        match verify(irs) {
+            errs if errs.is_empty() => Ok(()),
+            errs => Err(errs.into_iter().map(|e| Diagnostic::invalid_ir(stage, e)).collect()),
+        }
+    }
+
+    /// Lower a program into the IR. Its modules each get a scope of their
+    /// own, except for a program from [`Session::check`], which is lowered
+    /// in the scope of the ones before it
+    pub fn lower(&mut self, program: TypedProgram<'src>) -> Result<Vec<Expr<'src>>, Vec<Diagnostic>> {
+        let scope = std::mem::take(&mut self.lowerer);
+        let TypedProgram { modules, in_session } = program;
+        let mut lowerer = scope.clone();
+        let (irs, lowerer) = self.time("lower", || {
+            let mut exports: Vec<HashMap<_, _>> = vec![];
+            let mut irs = vec![];
+            for module in modules {
+                if !in_session {
+                    lowerer = lowerer.next_module();
+                    for (alias, i) in &module.imports {
+                        lowerer.import(alias, exports[*i].clone());
+                    }
+                }
+                irs.extend(lowerer.lower_program(module.items));
+                exports.push(lowerer.exports(&module.interface));
+            }
+            (irs, lowerer)
+        });
+        self.lowerer = if in_session { lowerer } else { lowerer.resume(scope) };
+        if let Some(after) = &mut self.after {
+            after("lower", &irs);
+        }
+        self.verify("lower", &irs)?;
+        Ok(irs)
+    }
+
+    /// Optimize a program as much as `opt_level` says. The warnings of the
+    /// optimizer are kept for [`Session::warnings`]
+    pub fn optimize(&mut self, irs: Vec<Expr<'src>>) -> Result<Vec<Expr<'src>>, Vec<Diagnostic>> {
+        let mut pm = match self.opt_level {
+            0 => PassManager::new(vec![]),
+            _ => PassManager::optimize(),
+        };
+        // Given as diagnostics instead of panicking
+        pm.verify &= !self.verify_ir;
+        let Self { timings, verify_ir, after, .. } = self;
+        let mut invalid = vec![];
+        let (irs, warnings) = pm.run(irs, |pass, program, elapsed| {
+            timings.push((pass.name(), elapsed));
+            if let Some(after) = after {
+                after(pass.name(), program);
+            }
+            if *verify_ir {
+                invalid.extend(verify(program).into_iter().map(|e| Diagnostic::invalid_ir(pass.name(), e)));
+            }
+        });
+        self.warnings.extend(warnings.into_iter().map(Diagnostic::from));
+        match invalid.is_empty() {
+            true => Ok(irs),
+            false => Err(invalid),
+        }
+    }
+
+    /// Generate code for an optimized program
+    pub fn codegen(&mut self, irs: &[Expr<'src>], target: Target) -> Result<Output, Diagnostic> {
+        let code = self.time("codegen", || match target {
+            Target::Js   => js::compile_with_map(irs).map(|(js, map)| (js.into_bytes(), Some(map))),
+            Target::Lua  => lua::compile(irs).map(|lua| (lua.into_bytes(), None)),
+            Target::Wasm => wasm::compile(irs).map(|m| (wasm::encode::encode(&m), None)),
+            Target::Wat  => wasm::compile(irs).map(|m| (m.to_string().into_bytes(), None)),
+            Target::Asm  => x86_64::compile_with_map(irs).map(|(asm, map)| (asm.into_bytes(), Some(map))),
+        });
+        let (code, source_map) = code?;
+        Ok(Output { code, source_map })
+    }
+
+    /// Optimize a program and generate code for it
+    pub fn emit(&mut self, irs: Vec<Expr<'src>>, target: Target) -> Result<Output, Vec<Diagnostic>> {
+        let irs = self.optimize(irs)?;
+        self.codegen(&irs, target).map_err(|e| vec![e])
+    }
+
+    /// Evaluate the top-level expressions of a program one after the other,
+    /// after those evaluated before, giving each one and its value to `f`
+    pub fn eval_each(
+        &mut self,
+        irs: &[Expr<'src>],
+        mut f: impl FnMut(&Expr<'src>, Value<'src>),
+    ) -> Result<(), Diagnostic> {
+        let mut evaluator = std::mem::take(&mut self.evaluator);
+        let result = self.time("eval", || irs.iter().try_for_each(|e| {
+            let v = evaluator.eval(e).map_err(|err| Diagnostic::runtime(err, Some(e.span)))?;
+            f(e, v);
+            Ok(())
+        }));
+        self.evaluator = evaluator;
+        result
+    }
+
+    /// Evaluate a program, giving the values of the top-level expressions
+    /// that aren't definitions
+    pub fn eval(&mut self, irs: &[Expr<'src>]) -> Result<Vec<Value<'src>>, Diagnostic> {
+        let mut values = vec![];
+        self.eval_each(irs, |e, v| if !e.is_define() {
+            values.push(v);
+        })?;
+        Ok(values)
+    }
+
+    /// Run `f` and then forget what it defined, e.g. to type check an
+    /// expression without keeping it
+    pub fn scratch<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let (infer, lowerer) = (self.infer.clone(), self.lowerer.clone());
+        let out = f(self);
+        self.infer = infer;
+        self.lowerer = lowerer;
+        out
+    }
+
+    /// Forget everything the programs checked so far define, keeping the
+    /// sources and the Rust functions given to them. The text of the
+    /// sources is only freed with the session's [`Texts`], so start a new
+    /// session with new `Texts` instead to also free that
+    pub fn forget(&mut self) {
+        self.infer = Infer::new();
+        self.lowerer = Lowerer::new();
+        self.evaluator = Evaluator::new();
+        for i in 0..self.natives.len() {
+            self.define_native(i);
+        }
+    }
+
+    fn define_native(&mut self, i: usize) {
+        let (name, ty, value) = &self.natives[i];
+        self.infer.define(name, ty.clone());
+        let var = self.lowerer.global(name, ty.clone());
+        self.evaluator.define(var.id, value.clone());
+    }
+
+    /// Give the programs checked with [`Session::check`] a Rust function
+    /// to call as `name`, its Holymer type being that of its arguments and
+    /// result. Only the evaluator can run programs that call it
+    pub fn register<Args, R, F: HostFn<Args, R>>(&mut self, name: &'src str, f: F) {
+        let value = Value::Native(Rc::new(f.into_native(name)));
+        self.natives.push((name, F::ty(), value));
+        self.define_native(self.natives.len() - 1);
+    }
+
+    /// Check, lower and evaluate a program after those before, for what it
+    /// defines to be called with [`Session::call`]
+    pub fn load(&mut self, source: &str) -> Result<(), Vec<Diagnostic>> {
+        let program = self.check(source)?;
+        let irs = self.lower(program)?;
+        self.eval(&irs).map_err(|e| vec![e])?;
+        Ok(())
+    }
+
+    /// Call a function defined by the programs evaluated so far, or given
+    /// to them, with a tuple of arguments. A generic function is called at
+    /// the types of the arguments and the result
+    pub fn call<Args: HostArgs, R: HostValue>(&mut self, name: &str, args: Args) -> Result<R, Diagnostic> {
+        let expected = Type::Func(Args::types(), Box::new(R::ty()));
+        let Some(ty) = self.infer.type_of(name) else {
+            return Err(Diagnostic::unplaced(Phase::Type, format!("`{}` is not defined", name)));
+        };
+        if !instance_of(&expected, &ty) {
+            let title = format!("`{}` has type {}, not {}", name, pretty_type(&ty), pretty_type(&expected));
+            return Err(Diagnostic::unplaced(Phase::Type, title));
+        }
+        let f = self.lowerer.var(name).and_then(|var| self.evaluator.get(var.id));
+        let Some(f) = f else {
+            let title = format!("`{}` has no value, as its definition wasn't evaluated", name);
+            return Err(Diagnostic::unplaced(Phase::Runtime, title));
+        };
+        let v = self.time("eval", || apply(f, args.into_values()));
+        v.and_then(R::from_value).map_err(|e| Diagnostic::runtime(e, None))
+    }
+}
+
+fn syntax_error(e: chumsky::error::Rich<'_, Token<'_>, Span>) -> Diagnostic {
+    Diagnostic::error(Phase::Syntax, *e.span(), e.to_string(), e.reason().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(s: &mut Session, source: &str) -> Vec<String> {
+        let program = s.check(source).unwrap();
+        let irs = s.lower(program).unwrap();
+        s.eval(&irs).unwrap().iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_add_source() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        let a = s.add_source("a.hlm", "1;");
+        let b = s.add_source("b.hlm", String::from("2;"));
+        assert_eq!((s.sources.get(a).path.as_str(), s.sources.get(a).text), ("a.hlm", "1;"));
+        assert_eq!(s.sources.get(b).text, "2;");
+    }
+
+    #[test]
+    fn test_parse() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        let file = s.add_source("m.hlm", "import a; pub let x = a.y;");
+        let (tokens, module) = s.parse(file).unwrap();
+        assert!(!tokens.is_empty());
+        assert_eq!(module.imports.len(), 1);
+        let file = s.add_source("bad.hlm", "let = 1;");
+        assert_eq!(s.parse(file).unwrap_err()[0].phase, Phase::Syntax);
+        let file = s.add_source("bad.hlm", "\"");
+        assert_eq!(s.lex(file).unwrap_err()[0].phase, Phase::Syntax);
+    }
+
+    #[test]
+    fn test_check() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        assert!(s.check("let double = fun (x Int) -> x * 2;").is_ok());
+        // Later programs use what earlier ones define
+        assert!(s.check("double(21);").is_ok());
+        let errs = s.check("y + 1; 1 + true;").err().unwrap();
+        assert_eq!(errs.iter().map(|e| (e.phase, e.title.as_str())).collect::<Vec<_>>(), [
+            (Phase::Type, "Undefined value"),
+            (Phase::Type, "Type mismatch"),
+        ]);
+        assert_eq!(s.sources.get(errs[0].span.unwrap().file).path, "<input 3>");
+        assert_eq!(s.check("let = 1;").err().unwrap()[0].phase, Phase::Syntax);
+    }
+
+    #[test]
+    fn test_check_module() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        let file = s.add_source("a.hlm", "pub let inc = fun (x Int) -> x + 1; let hidden = 1;");
+        let (_, module) = s.parse(file).unwrap();
+        let (items, interface) = s.check_module(file, module, vec![]).unwrap();
+        assert_eq!(items.len(), 2);
+        let file = s.add_source("b.hlm", "import a; a.inc(1);");
+        let (_, module) = s.parse(file).unwrap();
+        assert!(s.check_module(file, module, vec![("a", interface.clone())]).is_ok());
+        let file = s.add_source("c.hlm", "import a; a.hidden;");
+        let (_, module) = s.parse(file).unwrap();
+        assert!(s.check_module(file, module, vec![("a", interface)]).is_err());
+    }
+
+    #[test]
+    fn test_lower() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        s.verify_ir = true;
+        let program = s.check("let x = 1; x + 1;").unwrap();
+        assert_eq!(s.lower(program).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        s.verify_ir = true;
+        let stages = Rc::new(std::cell::RefCell::new(vec![]));
+        let seen = stages.clone();
+        s.after = Some(Box::new(move |stage, _| seen.borrow_mut().push(stage)));
+        let program = s.check("1 + 2;").unwrap();
+        let irs = s.lower(program).unwrap();
+        let irs = s.optimize(irs).unwrap();
+        assert_eq!(s.eval(&irs).unwrap()[0].to_string(), "3");
+        assert_eq!(stages.borrow()[0], "lower");
+        assert!(stages.borrow().len() > 1);
+
+        // Only lowered at level 0
+        s.opt_level = 0;
+        stages.borrow_mut().clear();
+        let program = s.check("1 + 2;").unwrap();
+        let irs = s.lower(program).unwrap();
+        assert!(s.optimize(irs).is_ok());
+        assert_eq!(*stages.borrow(), ["lower"]);
+    }
+
+    #[test]
+    fn test_emit() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        let program = s.check("let sq = fun (x Int) -> x * x; sq(3);").unwrap();
+        let irs = s.lower(program).unwrap();
+        let out = s.codegen(&irs, Target::Lua).unwrap();
+        assert!(out.source_map.is_none());
+        let out = s.emit(irs, Target::Js).unwrap();
+        assert!(out.source_map.is_some());
+    }
+
+    #[test]
+    fn test_eval() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        assert!(values(&mut s, "let double = fun (x Int) -> x * 2;").is_empty());
+        assert_eq!(values(&mut s, "double(21); double(1) == 2;"), ["42", "true"]);
+        let program = s.check("1 / 0;").unwrap();
+        let irs = s.lower(program).unwrap();
+        assert_eq!(s.eval(&irs).unwrap_err().phase, Phase::Runtime);
+    }
+
+    #[test]
+    fn test_eval_each() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        let program = s.check("let x = 2; x * 3;").unwrap();
+        let irs = s.lower(program).unwrap();
+        let mut seen = vec![];
+        s.eval_each(&irs, |e, v| seen.push((e.is_define(), v.to_string()))).unwrap();
+        // Definitions are of the unit type
+        assert_eq!(seen, [(true, "()".to_string()), (false, "6".to_string())]);
+    }
+
+    #[test]
+    fn test_scratch() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        // What `scratch` defines is gone afterwards
+        assert!(s.scratch(|s| s.check("let y = 1;")).is_ok());
+        assert!(s.check("y;").is_err());
+    }
+
+    #[test]
+    fn test_forget() {
+        let texts = Texts::new();
+        let mut s = Session::new(&texts);
+        assert!(values(&mut s, "let x = 1;").is_empty());
+        s.forget();
+        assert!(s.check("x;").is_err());
+        // The sources are kept, so the spans of earlier programs still
+        // point into them
+        assert_eq!(s.sources.iter().count(), 2);
+        assert_eq!(values(&mut s, "let x = true; x;"), ["true"]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Source maps, relating positions in the output of a backend to the spans
+//! of the program they come from.
+//!
+//! Backends put a marker with the span of an expression in front of the
+//! code they emit for it, and [`extract`] takes the markers back out of the
+//! output, recording where they were. Lines and columns are counted from
+//! 0, columns in UTF-16 code units as Source Map v3 wants them.
+
+use ir::Span;
+use syntax::source::{FileId, SourceFile, Sources};
+
+const START: char = '\u{1}';
+const END: char = '\u{2}';
+
+/// The code at a position of the output comes from a span of the source
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub mappings: Vec<Mapping>,
+}
+
+/// The marker for the code of an expression with the span
+pub(crate) fn mark(span: Span) -> String {
+    format!("{}{}:{}:{}{}", START, span.file.0, span.start, span.end, END)
+}
+
+/// Take the markers out of the output of a backend. Lines with nothing but
+/// markers are removed, the code on the next line is what they mark
+pub(crate) fn extract(text: &str) -> (String, SourceMap) {
+    let mut out = String::with_capacity(text.len());
+    let mut map = SourceMap::default();
+    let mut pending = vec![];
+    let mut line = 0;
+    for (i, text_line) in text.split('\n').enumerate() {
+        let mut clean = String::new();
+        let mut column = 0;
+        let mut rest = text_line;
+        while let Some(start) = rest.find(START) {
+            clean.push_str(&rest[..start]);
+            column += rest[..start].encode_utf16().count();
+            let end = rest[start..].find(END).expect("unterminated source map marker") + start;
+            let mut parts = rest[start + 1..end].split(':').map(|n| n.parse::<usize>().unwrap());
+            let mut next = || parts.next().unwrap();
+            let span = Span::new(FileId(next() as u32), next(), next());
+            pending.push((column, span));
+            rest = &rest[end + 1..];
+        }
+        clean.push_str(rest);
+        if clean.is_empty() && text_line.contains(START) {
+            // The marks are for the next line
+            pending.iter_mut().for_each(|(c, _)| *c = 0);
+            continue;
+        }
+        for (column, span) in pending.drain(..) {
+            // Only the innermost expression starting somewhere is kept
+            match map.mappings.last_mut() {
+                Some(m) if m.line == line && m.column == column => m.span = span,
+                _ => map.mappings.push(Mapping { line, column, span }),
+            }
+        }
+        if i != 0 {
+            out.push('\n');
+        }
+        out.push_str(&clean);
+        line += 1;
+    }
+    (out, map)
+}
+
+/// The line and column where a span starts
+fn position(sources: &Sources, span: Span) -> (usize, usize) {
+    let (line, before) = sources.get(span.file).locate(span.start);
+    (line, before.encode_utf16().count())
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Append a number in the base 64 VLQ encoding of source maps
+fn vlq(out: &mut String, n: i64) {
+    const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut n = if n < 0 { ((-n) << 1) | 1 } else { n << 1 };
+    loop {
+        let digit = n & 0b11111;
+        n >>= 5;
+        let continued = if n > 0 { 0b100000 } else { 0 };
+        out.push(DIGITS[(digit | continued) as usize] as char);
+        if n == 0 {
+            return;
+        }
+    }
+}
+
+impl SourceMap {
+    /// The map in the Source Map v3 format, listing every file of the
+    /// sources with their contents
+    pub fn to_json(&self, sources: &Sources) -> String {
+        let mut mappings = String::new();
+        let (mut line, mut column) = (0, 0);
+        let (mut file, mut src_line, mut src_column) = (0, 0, 0);
+        for m in &self.mappings {
+            if m.line != line {
+                mappings.push_str(&";".repeat(m.line - line));
+                (line, column) = (m.line, 0);
+            } else if !mappings.is_empty() {
+                mappings.push(',');
+            }
+            let (l, c) = position(sources, m.span);
+            vlq(&mut mappings, m.column as i64 - column as i64);
+            vlq(&mut mappings, m.span.file.0 as i64 - file as i64);
+            vlq(&mut mappings, l as i64 - src_line as i64);
+            vlq(&mut mappings, c as i64 - src_column as i64);
+            (column, file, src_line, src_column) = (m.column, m.span.file.0, l, c);
+        }
+        let list = |f: for<'a> fn(&'a SourceFile) -> &'a str| sources.iter()
+            .map(|(_, file)| json_string(f(file)))
+            .collect::<Vec<_>>()
+            .join(",");
+        format!(
+            "{{\"version\":3,\"sources\":[{}],\"sourcesContent\":[{}],\"names\":[],\"mappings\":\"{}\"}}\n",
+            list(|f| &f.path), list(|f| f.text), mappings,
+        )
+    }
+
+    /// One line for each mapping, with the line and column in the output
+    /// then the file, line and column in the source, counted from 1
+    pub fn line_table(&self, sources: &Sources) -> String {
+        self.mappings.iter()
+            .map(|m| {
+                let (l, c) = position(sources, m.span);
+                let path = &sources.get(m.span.file).path;
+                format!("{}:{} {}:{}:{}\n", m.line + 1, m.column + 1, path, l + 1, c + 1)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(start: usize, end: usize) -> Span {
+        Span::new(FileId(1), start, end)
+    }
+
+    #[test]
+    fn test_extract() {
+        let text = format!("a{}b\n{}\n  {}{}c", mark(span(0, 1)), mark(span(2, 3)), mark(span(4, 9)), mark(span(5, 6)));
+        let (out, map) = extract(&text);
+        assert_eq!(out, "ab\n  c");
+        assert_eq!(map.mappings, [
+            Mapping { line: 0, column: 1, span: span(0, 1) },
+            Mapping { line: 1, column: 0, span: span(2, 3) },
+            Mapping { line: 1, column: 2, span: span(5, 6) },
+        ]);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let mut out = String::new();
+        [0, 1, -1, 16, 123456].into_iter().for_each(|n| vlq(&mut out, n));
+        assert_eq!(out, "ACDgBgkxH");
+
+        let mut sources = Sources::new();
+        sources.add("main.hl", "");
+        sources.add("x.hl", "ab\ncd");
+        let map = SourceMap { mappings: vec![
+            Mapping { line: 0, column: 4, span: span(1, 2) },
+            Mapping { line: 2, column: 1, span: span(3, 4) },
+        ] };
+        let json = map.to_json(&sources);
+        assert!(json.contains("\"sources\":[\"main.hl\",\"x.hl\"]"));
+        assert!(json.contains("\"mappings\":\"ICAC;;CACD\""));
+        assert_eq!(map.line_table(&sources), "1:5 x.hl:1:2\n3:2 x.hl:2:1\n");
+    }
+}
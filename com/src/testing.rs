@@ -0,0 +1,122 @@
+//! Helpers shared by the backend tests.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use ir::Expr;
+use syntax::source::Texts;
+
+use crate::session::Session;
+
+// The IR the helpers give refers to the text of the program, kept in the
+// `Texts` of the test
+
+/// Run the front end on a well-typed program and lower it into the IR
+pub fn lower<'t>(texts: &'t Texts, src: &str) -> Vec<Expr<'t>> {
+    let mut s = Session::new(texts);
+    let program = s.check(src).expect("type checking failed");
+    s.lower(program).expect("lowering failed")
+}
+
+/// Lower a well-typed program and run the optimizations on it
+pub fn optimize<'t>(texts: &'t Texts, src: &str) -> Vec<Expr<'t>> {
+    Session::new(texts).optimize(lower(texts, src)).expect("optimizing failed")
+}
+
+/// The output of a program according to the reference evaluator
+pub fn eval(texts: &Texts, src: &str) -> String {
+    Session::new(texts).eval(&lower(texts, src)).expect("evaluation failed")
+        .into_iter()
+        .map(|v| format!("{}\n", v))
+        .collect()
+}
+
+/// Tell whoever runs the tests that part of one was skipped. Written to
+/// stderr directly, as the test harness would otherwise capture it
+pub fn skip(why: &str) {
+    let _ = writeln!(std::io::stderr(), "warning: skipped running a compiled program, {}", why);
+}
+
+/// Run an interpreter such as node with `args` and `stdin`, and return what
+/// it printed, or `None` if it isn't installed
+pub fn interpret(interpreter: &str, args: &[&str], stdin: &str) -> Option<String> {
+    let mut child = match Command::new(interpreter)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn() {
+        Ok(child) => child,
+        Err(_) => {
+            skip(&format!("{} is not installed", interpreter));
+            return None;
+        }
+    };
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    Some(String::from_utf8(out.stdout).unwrap())
+}
+
+pub fn example(name: &str) -> String {
+    let path = format!("{}/../example/{}.hlm", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read_to_string(path).unwrap()
+}
+
+/// A program exercising closures, spilling, stack arguments, strings,
+/// redefined globals, matches and ordering of booleans and unit
+pub const KITCHEN_SINK: &str = "
+let adder = fun (n Int) -> fun (x Int) -> x + n;
+let addfive = adder(5);
+addfive(10);
+let many = fun (a Int, b Int, c Int, d Int, e Int, f Int, g Int, h Int) -> a - b + c - d + e - f + g * h;
+many(1, 2, 3, 4, 5, 6, 7, 8);
+(1 + (2 * (3 + (4 * (5 + (6 * (7 + (8 * 9))))))));
+{
+    let fib = fun (n Int) Int -> if n < 2 then n else fib(n - 1) + fib(n - 2);
+    fib(15)
+};
+let x = 3 in let y = x * 2 in fun (z Int) -> x + y + z;
+let x = 4 in { let x = x + 1; let f = fun (y Int) -> x * y; f(x) };
+let succ = fun (n Int) -> n + 1;
+let succ = succ(41) in succ;
+9223372036854775807 + 1;
+\"hello\" < \"world\";
+\"b\" == \"b\";
+-9223372036854775807 / -1;
+7 % -1;
+!(1 == 2) && true || false;
+{ 1; 2; };
+if 3 > 2 then \"yes\" else \"no\";
+let k = 10 in { let y = k + 1; let g = fun (u Int) -> y * k + u; g(1) };
+let r = 1;
+let h = fun (x Int) -> x + r;
+let r = r * 10;
+h(1);
+r;
+let describe = fun (n Int, flag Bool) -> match n, flag {
+    0, _ -> \"zero\",
+    1 | 2, true -> \"small and set\",
+    k, false -> if k > 100 then \"big\" else \"unset\",
+    _, _ -> \"other\",
+};
+describe(0, false);
+describe(2, true);
+describe(2, false);
+describe(-3, true);
+describe(500, false);
+match \"b\" { \"a\" -> 1, \"b\" | \"c\" -> 2, _ -> 3 };
+match () { () -> true };
+() < (); () <= (); () > (); () >= ();
+false < true; true <= false; true > false; false >= false;
+let a = 1;
+let mk = fun (x Int) -> fun (u Int) -> x;
+let g = mk(a);
+let a = a + 1;
+g(0);
+let f = fun (u Int) -> a * u;
+a;
+f(3);
+";
@@ -0,0 +1,524 @@
+use std::collections::{BTreeSet, HashMap};
+
+use ir::{closure::free_vars, BinOp, Expr, ExprKind, Lit, Type, UnOp, Var, VarId};
+
+use crate::{collect_strings, BackendError};
+
+use super::{Export, ExportKind, Func, Global, Import, Instr, Module, ValType};
+
+const I64: ValType = ValType::I64;
+/// The imported `hl.print` function
+const PRINT: u32 = 0;
+/// The bump allocator, the first defined function
+const ALLOC: u32 = 1;
+/// The string comparison, the second one
+const STRCMP: u32 = 2;
+/// The global holding the next free address
+const HEAP: u32 = 0;
+/// Where string literals start in memory, so no value is a null pointer
+const DATA_OFFSET: u32 = 8;
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(BackendError::new(format!($($arg)*)))
+    };
+}
+
+// A function being generated
+struct Frame<'src> {
+    params: u32,
+    locals: Vec<ValType>,
+    scopes: Vec<Vec<(VarId, u32)>>,
+    captures: Vec<Var<'src>>,
+    body: Vec<Instr>,
+}
+
+impl<'src> Frame<'src> {
+    fn new(params: u32) -> Self {
+        Self {
+            params,
+            locals: vec![],
+            scopes: vec![vec![]],
+            captures: vec![],
+            body: vec![],
+        }
+    }
+
+    fn local(&mut self) -> u32 {
+        self.locals.push(I64);
+        self.params + self.locals.len() as u32 - 1
+    }
+
+    fn lookup(&self, id: VarId) -> Option<u32> {
+        self.scopes.iter()
+            .flat_map(|s| s.iter())
+            .find(|(v, _)| *v == id)
+            .map(|(_, l)| *l)
+    }
+
+    fn is_local(&self, id: VarId) -> bool {
+        self.lookup(id).is_some() || self.captures.iter().any(|c| c.id == id)
+    }
+}
+
+// A lambda waiting for its body to be generated
+struct Pending<'src> {
+    index: u32,
+    params: Vec<Var<'src>>,
+    body: Expr<'src>,
+    captures: Vec<Var<'src>>,
+}
+
+struct Codegen<'src> {
+    m: Module,
+    // Defined functions, filled in once they are generated
+    funcs: Vec<Option<Func>>,
+    globals: HashMap<VarId, u32>,
+    strings: HashMap<&'src str, i64>,
+    pending: Vec<Pending<'src>>,
+    f: Frame<'src>,
+}
+
+impl<'src> Codegen<'src> {
+    fn emit(&mut self, i: Instr) {
+        self.f.body.push(i);
+    }
+
+    /// The type of a closure taking `arity` arguments
+    fn closure_ty(&mut self, arity: usize) -> u32 {
+        self.m.ty(vec![I64; arity + 1], vec![I64])
+    }
+
+    /// Reserve a function index
+    fn reserve(&mut self) -> u32 {
+        self.funcs.push(None);
+        (self.m.imports.len() + self.funcs.len()) as u32 - 1
+    }
+
+    fn define(&mut self, index: u32, name: String, ty: u32, body: Vec<Instr>, locals: Vec<ValType>) {
+        let i = index as usize - self.m.imports.len();
+        self.funcs[i] = Some(Func { name, ty, locals, body });
+    }
+
+    fn load_var(&mut self, var: &Var<'src>) -> Result<(), BackendError> {
+        if let Some(l) = self.f.lookup(var.id) {
+            self.emit(Instr::LocalGet(l));
+        } else if let Some(i) = self.f.captures.iter().position(|c| c == var) {
+            // The closure itself is the first parameter
+            self.emit(Instr::LocalGet(0));
+            self.emit(Instr::I32WrapI64);
+            self.emit(Instr::I64Load(8 * (i as u32 + 1)));
+        } else if let Some(g) = self.globals.get(&var.id) {
+            self.emit(Instr::GlobalGet(*g));
+        } else {
+            bail!("`{}` is not defined", var.name);
+        }
+        Ok(())
+    }
+
+    /// Generate code that leaves the value of an expression on the stack
+    fn expr(&mut self, e: &Expr<'src>) -> Result<(), BackendError> {
+        match &e.kind {
+            ExprKind::Lit(l) => {
+                let v = match l {
+                    Lit::Unit    => 0,
+                    Lit::Bool(b) => *b as i64,
+                    Lit::Int(i)  => *i,
+                    Lit::Str(s)  => self.strings[s],
+                };
+                self.emit(Instr::I64Const(v));
+            }
+            ExprKind::Var(x) => self.load_var(x)?,
+            ExprKind::If { cond, t, f } => {
+                self.expr(cond)?;
+                self.emit(Instr::I32WrapI64);
+                self.emit(Instr::If(Some(I64)));
+                self.expr(t)?;
+                self.emit(Instr::Else);
+                self.expr(f)?;
+                self.emit(Instr::End);
+            }
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), l, r) => {
+                self.expr(l)?;
+                self.emit(Instr::I32WrapI64);
+                self.emit(Instr::If(Some(I64)));
+                if *op == BinOp::And {
+                    self.expr(r)?;
+                    self.emit(Instr::Else);
+                    self.emit(Instr::I64Const(0));
+                } else {
+                    self.emit(Instr::I64Const(1));
+                    self.emit(Instr::Else);
+                    self.expr(r)?;
+                }
+                self.emit(Instr::End);
+            }
+            ExprKind::Let { var, value, body } => {
+                self.expr(value)?;
+                let l = self.f.local();
+                self.emit(Instr::LocalSet(l));
+                self.f.scopes.push(vec![(var.id, l)]);
+                self.expr(body)?;
+                self.f.scopes.pop();
+            }
+            ExprKind::Define { var, value } => {
+                let l = self.f.local();
+                if value.is_lambda() {
+                    // Bind the name first so that a recursive lambda
+                    // can capture itself
+                    self.f.scopes.last_mut().unwrap().push((var.id, l));
+                    self.lambda(value, Some(var.id))?;
+                } else {
+                    self.expr(value)?;
+                    self.f.scopes.last_mut().unwrap().push((var.id, l));
+                }
+                self.emit(Instr::LocalSet(l));
+                self.emit(Instr::I64Const(0));
+            }
+            ExprKind::Lambda { .. } => self.lambda(e, None)?,
+            ExprKind::Block { exprs, void } => {
+                self.f.scopes.push(vec![]);
+                for (i, e) in exprs.iter().enumerate() {
+                    self.expr(e)?;
+                    if i + 1 < exprs.len() || *void {
+                        self.emit(Instr::Drop);
+                    }
+                }
+                if exprs.is_empty() || *void {
+                    self.emit(Instr::I64Const(0));
+                }
+                self.f.scopes.pop();
+            }
+            ExprKind::Unary(UnOp::Neg, x) => {
+                self.emit(Instr::I64Const(0));
+                self.expr(x)?;
+                self.emit(Instr::I64Sub);
+            }
+            ExprKind::Unary(UnOp::Not, x) => {
+                self.expr(x)?;
+                self.emit(Instr::I64Eqz);
+                self.emit(Instr::I64ExtendI32U);
+            }
+            ExprKind::Binary(BinOp::Div, l, r) => {
+                // i64::MIN / -1 traps, so -1 is handled separately
+                let (a, b) = (self.f.local(), self.f.local());
+                self.expr(l)?;
+                self.emit(Instr::LocalSet(a));
+                self.expr(r)?;
+                self.emit(Instr::LocalSet(b));
+                self.emit(Instr::LocalGet(b));
+                self.emit(Instr::I64Const(-1));
+                self.emit(Instr::I64Eq);
+                self.emit(Instr::If(Some(I64)));
+                self.emit(Instr::I64Const(0));
+                self.emit(Instr::LocalGet(a));
+                self.emit(Instr::I64Sub);
+                self.emit(Instr::Else);
+                self.emit(Instr::LocalGet(a));
+                self.emit(Instr::LocalGet(b));
+                self.emit(Instr::I64DivS);
+                self.emit(Instr::End);
+            }
+            ExprKind::Binary(op, l, r) => {
+                let strings = l.ty == Type::Str;
+                self.expr(l)?;
+                self.expr(r)?;
+                if strings && op.is_comparison() {
+                    // Strings are compared by their contents, then the
+                    // sign of the difference is compared with zero
+                    self.emit(Instr::Call(STRCMP));
+                    self.emit(Instr::I64Const(0));
+                }
+                self.emit(match op {
+                    BinOp::Add => Instr::I64Add,
+                    BinOp::Sub => Instr::I64Sub,
+                    BinOp::Mul => Instr::I64Mul,
+                    BinOp::Rem => Instr::I64RemS,
+                    BinOp::Eq  => Instr::I64Eq,
+                    BinOp::Ne  => Instr::I64Ne,
+                    BinOp::Lt  => Instr::I64LtS,
+                    BinOp::Le  => Instr::I64LeS,
+                    BinOp::Gt  => Instr::I64GtS,
+                    BinOp::Ge  => Instr::I64GeS,
+                    _ => unreachable!("handled above"),
+                });
+                if op.is_comparison() {
+                    self.emit(Instr::I64ExtendI32U);
+                }
+            }
+            ExprKind::Call { func, args } => {
+                let t = self.f.local();
+                self.expr(func)?;
+                self.emit(Instr::LocalSet(t));
+                self.emit(Instr::LocalGet(t));
+                for a in args {
+                    self.expr(a)?;
+                }
+                self.emit(Instr::LocalGet(t));
+                self.emit(Instr::I32WrapI64);
+                self.emit(Instr::I64Load(0));
+                self.emit(Instr::I32WrapI64);
+                let ty = self.closure_ty(args.len());
+                self.emit(Instr::CallIndirect(ty));
+            }
+            ExprKind::Closure { .. } | ExprKind::EnvGet { .. } => {
+                bail!("closure-converted IR is not supported by the WebAssembly backend")
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a closure for a lambda, if `this` is given the closure
+    /// captures itself as that variable
+    fn lambda(&mut self, l: &Expr<'src>, this: Option<VarId>) -> Result<(), BackendError> {
+        let ExprKind::Lambda { params, body } = &l.kind else { bail!("expected a lambda") };
+        let captures = free_vars(l).into_iter()
+            .filter(|x| self.f.is_local(x.id))
+            .collect::<Vec<_>>();
+
+        let index = self.reserve();
+        let slot = self.m.elems.len() as i64;
+        self.m.elems.push(index);
+        self.pending.push(Pending {
+            index,
+            params: params.clone(),
+            body: (**body).clone(),
+            captures: captures.clone(),
+        });
+
+        let t = self.f.local();
+        self.emit(Instr::I64Const(8 * (captures.len() as i64 + 1)));
+        self.emit(Instr::Call(ALLOC));
+        self.emit(Instr::LocalSet(t));
+        self.emit(Instr::LocalGet(t));
+        self.emit(Instr::I32WrapI64);
+        self.emit(Instr::I64Const(slot));
+        self.emit(Instr::I64Store(0));
+        for (i, c) in captures.iter().enumerate() {
+            self.emit(Instr::LocalGet(t));
+            self.emit(Instr::I32WrapI64);
+            if Some(c.id) == this {
+                self.emit(Instr::LocalGet(t));
+            } else {
+                self.load_var(c)?;
+            }
+            self.emit(Instr::I64Store(8 * (i as u32 + 1)));
+        }
+        self.emit(Instr::LocalGet(t));
+        Ok(())
+    }
+
+    fn function(&mut self, p: Pending<'src>) -> Result<(), BackendError> {
+        self.f = Frame::new(p.params.len() as u32 + 1);
+        self.f.captures = p.captures;
+        for (i, param) in p.params.iter().enumerate() {
+            self.f.scopes[0].push((param.id, i as u32 + 1));
+        }
+        self.expr(&p.body)?;
+        let ty = self.closure_ty(p.params.len());
+        let f = std::mem::replace(&mut self.f, Frame::new(0));
+        self.define(p.index, format!("hl_fn{}", p.index), ty, f.body, f.locals);
+        Ok(())
+    }
+
+    /// Generate the bump allocator, `(size: i64) -> i64`
+    fn alloc(&mut self) {
+        use Instr::*;
+        let body = vec![
+            GlobalGet(HEAP),
+            GlobalGet(HEAP), LocalGet(0), I32WrapI64, I32Add, GlobalSet(HEAP),
+            // Grow the memory if the heap went past its end
+            GlobalGet(HEAP), MemorySize, I32Const(16), I32Shl, I32GtU,
+            If(None),
+                GlobalGet(HEAP), I32Const(16), I32ShrU, I32Const(1), I32Add,
+                MemorySize, I32Sub, MemoryGrow, Drop,
+            End,
+            I64ExtendI32U,
+        ];
+        let ty = self.m.ty(vec![I64], vec![I64]);
+        let index = self.reserve();
+        self.define(index, "hl_alloc".to_string(), ty, body, vec![]);
+    }
+
+    /// Generate the string comparison, `(a: i64, b: i64) -> i64`, giving
+    /// the difference of the first bytes that differ
+    fn strcmp(&mut self) {
+        use Instr::*;
+        let (a, b, ca, cb) = (0, 1, 2, 3);
+        let body = vec![
+            Loop(None),
+                LocalGet(a), I32WrapI64, I64Load8U(0), LocalSet(ca),
+                LocalGet(b), I32WrapI64, I64Load8U(0), LocalSet(cb),
+                LocalGet(a), I64Const(1), I64Add, LocalSet(a),
+                LocalGet(b), I64Const(1), I64Add, LocalSet(b),
+                // Go on while the bytes are the same and not the end
+                LocalGet(ca), LocalGet(cb), I64Eq,
+                If(Some(ValType::I32)),
+                    LocalGet(ca), I64Const(0), I64Ne,
+                Else,
+                    I32Const(0),
+                End,
+                BrIf(0),
+            End,
+            LocalGet(ca), LocalGet(cb), I64Sub,
+        ];
+        let ty = self.m.ty(vec![I64, I64], vec![I64]);
+        let index = self.reserve();
+        self.define(index, "hl_strcmp".to_string(), ty, body, vec![I64, I64]);
+    }
+}
+
+/// The `kind` argument of `hl.print` for a value of the given type
+fn print_kind(ty: &Type) -> i32 {
+    match ty {
+        Type::Int  => 1,
+        Type::Bool => 2,
+        Type::Str  => 3,
+        Type::Func(_, _) => 4,
+        _ => 0,
+    }
+}
+
+/// Compile a program into a module
+pub fn compile(program: &[Expr]) -> Result<Module, BackendError> {
+    let mut m = Module::default();
+    let print = m.ty(vec![I64, ValType::I32], vec![]);
+    m.imports.push(Import { module: "hl".to_string(), name: "print".to_string(), ty: print });
+
+    // String literals are laid out once each, NUL-terminated
+    let mut strings = BTreeSet::new();
+    program.iter().for_each(|e| collect_strings(e, &mut strings));
+    let strings = strings.into_iter()
+        .map(|s| {
+            let addr = (DATA_OFFSET as usize + m.data.len()) as i64;
+            m.data.extend(s.as_bytes());
+            m.data.push(0);
+            (s, addr)
+        })
+        .collect::<HashMap<_, _>>();
+    m.data_offset = DATA_OFFSET;
+    let heap = (DATA_OFFSET as usize + m.data.len() + 7) & !7;
+    m.memory = heap as u32 / 65536 + 1;
+    m.globals.push(Global {
+        name: "hl_heap".to_string(),
+        ty: ValType::I32,
+        mutable: true,
+        init: Instr::I32Const(heap as i32),
+    });
+
+    let mut globals = HashMap::new();
+    let mut defines = vec![];
+    for e in program {
+        if let ExprKind::Define { var, value } = &e.kind {
+            defines.push((var, value));
+            if globals.contains_key(&var.id) {
+                continue;
+            }
+            // Modules can each define a global of the same name, and the
+            // module exports `main` and `memory` itself, so clashing globals
+            // are exported under the name and the index of their global
+            let mut name = var.name.to_string();
+            if m.globals.iter().any(|g| g.name == name) || ["main", "memory"].contains(&var.name) {
+                name = format!("{}${}", name, m.globals.len());
+            }
+            globals.insert(var.id, m.globals.len() as u32);
+            m.globals.push(Global {
+                name,
+                ty: I64,
+                mutable: true,
+                init: Instr::I64Const(0),
+            });
+        }
+    }
+    // Functions and literals defined once are set when the module is
+    // instantiated, other definitions by `main` in order with the printing
+    let early = |var: &Var, value: &Expr| {
+        matches!(value.kind, ExprKind::Lambda { .. } | ExprKind::Lit(_))
+            && defines.iter().filter(|(v, _)| v.id == var.id).count() == 1
+    };
+
+    let mut cg = Codegen {
+        m,
+        funcs: vec![],
+        globals,
+        strings,
+        pending: vec![],
+        f: Frame::new(0),
+    };
+    cg.alloc();
+    cg.strcmp();
+
+    let start = cg.reserve();
+    for (var, value) in defines.iter().filter(|(var, value)| early(var, value)) {
+        cg.expr(value)?;
+        cg.emit(Instr::GlobalSet(cg.globals[&var.id]));
+    }
+    let unit = cg.m.ty(vec![], vec![]);
+    let f = std::mem::replace(&mut cg.f, Frame::new(0));
+    cg.define(start, "hl_start".to_string(), unit, f.body, f.locals);
+    cg.m.start = Some(start);
+
+    let main = cg.reserve();
+    for e in program {
+        match &e.kind {
+            ExprKind::Define { var, value } if early(var, value) => (),
+            ExprKind::Define { var, value } => {
+                cg.expr(value)?;
+                cg.emit(Instr::GlobalSet(cg.globals[&var.id]));
+            }
+            _ => {
+                cg.expr(e)?;
+                cg.emit(Instr::I32Const(print_kind(&e.ty)));
+                cg.emit(Instr::Call(PRINT));
+            }
+        }
+    }
+    let f = std::mem::replace(&mut cg.f, Frame::new(0));
+    cg.define(main, "hl_main".to_string(), unit, f.body, f.locals);
+    cg.m.exports.push(Export { name: "main".to_string(), kind: ExportKind::Func, index: main });
+    cg.m.exports.push(Export { name: "memory".to_string(), kind: ExportKind::Memory, index: 0 });
+
+    // Export top-level functions as plain wasm functions calling the
+    // closure, and everything else as a global. A global defined more than
+    // once is exported as what its last definition makes it
+    let mut exported = vec![];
+    for (var, value) in defines.iter().rev() {
+        if exported.contains(&var.id) {
+            continue;
+        }
+        exported.push(var.id);
+        let g = cg.globals[&var.id];
+        match &value.kind {
+            ExprKind::Lambda { params, .. } => {
+                let arity = params.len();
+                let mut body = vec![Instr::GlobalGet(g)];
+                body.extend((0..arity as u32).map(Instr::LocalGet));
+                body.extend([
+                    Instr::GlobalGet(g),
+                    Instr::I32WrapI64,
+                    Instr::I64Load(0),
+                    Instr::I32WrapI64,
+                    Instr::CallIndirect(cg.closure_ty(arity)),
+                ]);
+                let ty = cg.m.ty(vec![I64; arity], vec![I64]);
+                let index = cg.reserve();
+                let name = cg.m.globals[g as usize].name.clone();
+                cg.define(index, name.clone(), ty, body, vec![]);
+                cg.m.exports.push(Export { name, kind: ExportKind::Func, index });
+            }
+            _ => {
+                let name = cg.m.globals[g as usize].name.clone();
+                cg.m.exports.push(Export { name, kind: ExportKind::Global, index: g });
+            }
+        }
+    }
+
+    while let Some(p) = cg.pending.pop() {
+        cg.function(p)?;
+    }
+
+    let mut m = cg.m;
+    m.funcs = cg.funcs.into_iter().map(|f| f.unwrap()).collect();
+    m.table = m.elems.len() as u32;
+    Ok(m)
+}
@@ -0,0 +1,213 @@
+//! Binary encoding of a [`Module`].
+
+use super::{ExportKind, Instr, Module, ValType};
+
+pub fn u32(out: &mut Vec<u8>, mut v: u32) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub fn i64(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        // Done once the rest is only sign bits and the sign bit of this
+        // byte agrees with them
+        if (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, s: &str) {
+    u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn valtype(out: &mut Vec<u8>, t: ValType) {
+    out.push(match t {
+        ValType::I32 => 0x7f,
+        ValType::I64 => 0x7e,
+    });
+}
+
+fn vec<T>(out: &mut Vec<u8>, items: &[T], mut f: impl FnMut(&mut Vec<u8>, &T)) {
+    u32(out, items.len() as u32);
+    items.iter().for_each(|i| f(out, i));
+}
+
+fn section(out: &mut Vec<u8>, id: u8, body: Vec<u8>) {
+    out.push(id);
+    u32(out, body.len() as u32);
+    out.extend(body);
+}
+
+pub fn instr(out: &mut Vec<u8>, i: &Instr) {
+    use Instr::*;
+    match i {
+        Unreachable => out.push(0x00),
+        Loop(t) | If(t) => {
+            out.push(if matches!(i, Loop(_)) { 0x03 } else { 0x04 });
+            match t {
+                Some(t) => valtype(out, *t),
+                None => out.push(0x40),
+            }
+        }
+        Else => out.push(0x05),
+        End  => out.push(0x0b),
+        BrIf(l) => { out.push(0x0d); u32(out, *l); }
+        Call(f) => { out.push(0x10); u32(out, *f); }
+        CallIndirect(t) => { out.push(0x11); u32(out, *t); out.push(0x00); }
+        Drop => out.push(0x1a),
+        LocalGet(i)  => { out.push(0x20); u32(out, *i); }
+        LocalSet(i)  => { out.push(0x21); u32(out, *i); }
+        LocalTee(i)  => { out.push(0x22); u32(out, *i); }
+        GlobalGet(i) => { out.push(0x23); u32(out, *i); }
+        GlobalSet(i) => { out.push(0x24); u32(out, *i); }
+        // Alignment hint of 2^3 bytes
+        I64Load(o)  => { out.push(0x29); u32(out, 3); u32(out, *o); }
+        I64Load8U(o) => { out.push(0x31); u32(out, 0); u32(out, *o); }
+        I64Store(o) => { out.push(0x37); u32(out, 3); u32(out, *o); }
+        MemorySize => out.extend([0x3f, 0x00]),
+        MemoryGrow => out.extend([0x40, 0x00]),
+        I32Const(v) => { out.push(0x41); i64(out, *v as i64); }
+        I64Const(v) => { out.push(0x42); i64(out, *v); }
+        I32Eqz => out.push(0x45),
+        I32GtU => out.push(0x4b),
+        I64Eqz => out.push(0x50),
+        I64Eq  => out.push(0x51),
+        I64Ne  => out.push(0x52),
+        I64LtS => out.push(0x53),
+        I64GtS => out.push(0x55),
+        I64LeS => out.push(0x57),
+        I64GeS => out.push(0x59),
+        I32Add  => out.push(0x6a),
+        I32Sub  => out.push(0x6b),
+        I32Shl  => out.push(0x74),
+        I32ShrU => out.push(0x76),
+        I64Add  => out.push(0x7c),
+        I64Sub  => out.push(0x7d),
+        I64Mul  => out.push(0x7e),
+        I64DivS => out.push(0x7f),
+        I64RemS => out.push(0x81),
+        I32WrapI64    => out.push(0xa7),
+        I64ExtendI32U => out.push(0xad),
+    }
+}
+
+/// Encode a module into the binary format
+pub fn encode(m: &Module) -> Vec<u8> {
+    let mut out = b"\0asm".to_vec();
+    out.extend([1, 0, 0, 0]);
+
+    let mut s = vec![];
+    vec(&mut s, &m.types, |out, t| {
+        out.push(0x60);
+        vec(out, &t.params, |out, t| valtype(out, *t));
+        vec(out, &t.results, |out, t| valtype(out, *t));
+    });
+    section(&mut out, 1, s);
+
+    let mut s = vec![];
+    vec(&mut s, &m.imports, |out, i| {
+        name(out, &i.module);
+        name(out, &i.name);
+        out.push(0x00);
+        u32(out, i.ty);
+    });
+    section(&mut out, 2, s);
+
+    let mut s = vec![];
+    vec(&mut s, &m.funcs, |out, f| u32(out, f.ty));
+    section(&mut out, 3, s);
+
+    let mut s = vec![];
+    u32(&mut s, 1);
+    s.extend([0x70, 0x00]);
+    u32(&mut s, m.table);
+    section(&mut out, 4, s);
+
+    let mut s = vec![];
+    u32(&mut s, 1);
+    s.push(0x00);
+    u32(&mut s, m.memory);
+    section(&mut out, 5, s);
+
+    let mut s = vec![];
+    vec(&mut s, &m.globals, |out, g| {
+        valtype(out, g.ty);
+        out.push(g.mutable as u8);
+        instr(out, &g.init);
+        instr(out, &Instr::End);
+    });
+    section(&mut out, 6, s);
+
+    let mut s = vec![];
+    vec(&mut s, &m.exports, |out, e| {
+        name(out, &e.name);
+        out.push(match e.kind {
+            ExportKind::Func   => 0x00,
+            ExportKind::Table  => 0x01,
+            ExportKind::Memory => 0x02,
+            ExportKind::Global => 0x03,
+        });
+        u32(out, e.index);
+    });
+    section(&mut out, 7, s);
+
+    if let Some(start) = m.start {
+        let mut s = vec![];
+        u32(&mut s, start);
+        section(&mut out, 8, s);
+    }
+
+    let mut s = vec![];
+    u32(&mut s, 1);
+    u32(&mut s, 0);
+    instr(&mut s, &Instr::I32Const(0));
+    instr(&mut s, &Instr::End);
+    vec(&mut s, &m.elems, |out, f| u32(out, *f));
+    section(&mut out, 9, s);
+
+    let mut s = vec![];
+    vec(&mut s, &m.funcs, |out, f| {
+        let mut body = vec![];
+        // Locals are run-length encoded
+        let mut runs: Vec<(u32, ValType)> = vec![];
+        for t in &f.locals {
+            match runs.last_mut() {
+                Some((n, last)) if last == t => *n += 1,
+                _ => runs.push((1, *t)),
+            }
+        }
+        vec(&mut body, &runs, |out, (n, t)| {
+            u32(out, *n);
+            valtype(out, *t);
+        });
+        f.body.iter().for_each(|i| instr(&mut body, i));
+        instr(&mut body, &Instr::End);
+        u32(out, body.len() as u32);
+        out.extend(body);
+    });
+    section(&mut out, 10, s);
+
+    let mut s = vec![];
+    u32(&mut s, 1);
+    u32(&mut s, 0);
+    instr(&mut s, &Instr::I32Const(m.data_offset as i32));
+    instr(&mut s, &Instr::End);
+    u32(&mut s, m.data.len() as u32);
+    s.extend(&m.data);
+    section(&mut out, 11, s);
+
+    out
+}
@@ -0,0 +1,269 @@
+//! WebAssembly backend.
+//!
+//! Every value is an `i64`: integers as is, booleans are 0 or 1, unit is 0,
+//! strings are addresses of NUL-terminated literals in linear memory and
+//! functions are addresses of closures laid out as `[table index,
+//! captures...]`. A closure of arity `n` is a function of type
+//! `(env, arg1, ..., argn) -> i64` called through the function table.
+//!
+//! The module imports `hl.print(value: i64, kind: i32)` for printing. Its
+//! start function sets the globals that are defined once as a function or
+//! a literal, and the exported `main` runs the other definitions and prints
+//! the remaining top-level expressions, in source order. A global defined
+//! more than once is set again at each definition.
+
+mod codegen;
+pub mod encode;
+pub mod validate;
+mod wat;
+
+pub use codegen::compile;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValType {
+    I32,
+    I64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instr {
+    Unreachable,
+    Loop(Option<ValType>),
+    If(Option<ValType>),
+    Else,
+    End,
+    BrIf(u32),
+    Call(u32),
+    CallIndirect(u32),
+    Drop,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    // Memory accesses take a static offset
+    I64Load(u32),
+    I64Load8U(u32),
+    I64Store(u32),
+    MemorySize,
+    MemoryGrow,
+    I32Const(i32),
+    I64Const(i64),
+    I32Eqz,
+    I32GtU,
+    I64Eqz,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64GtS,
+    I64LeS,
+    I64GeS,
+    I32Add,
+    I32Sub,
+    I32Shl,
+    I32ShrU,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64RemS,
+    I32WrapI64,
+    I64ExtendI32U,
+}
+
+#[derive(Clone, Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Func {
+    pub name: String,
+    pub ty: u32,
+    // Locals declared after the parameters
+    pub locals: Vec<ValType>,
+    pub body: Vec<Instr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Global {
+    pub name: String,
+    pub ty: ValType,
+    pub mutable: bool,
+    pub init: Instr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportKind {
+    Func,
+    Table,
+    Memory,
+    Global,
+}
+
+#[derive(Clone, Debug)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub index: u32,
+}
+
+/// A module with a single function table and a single linear memory
+#[derive(Clone, Debug, Default)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub funcs: Vec<Func>,
+    pub table: u32,
+    pub memory: u32,
+    pub globals: Vec<Global>,
+    pub exports: Vec<Export>,
+    pub start: Option<u32>,
+    // Function indices placed in the table starting at 0
+    pub elems: Vec<u32>,
+    // Bytes placed in memory at `data_offset`
+    pub data: Vec<u8>,
+    pub data_offset: u32,
+}
+
+impl Module {
+    /// The index of a function type, adding it if it doesn't exist yet
+    pub fn ty(&mut self, params: Vec<ValType>, results: Vec<ValType>) -> u32 {
+        let ty = FuncType { params, results };
+        match self.types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                self.types.push(ty);
+                self.types.len() as u32 - 1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{eval, example, interpret, lower, optimize, KITCHEN_SINK};
+    use syntax::source::Texts;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    // Instantiates a module with an `hl.print` that formats values like
+    // the evaluator and calls `main`, then the exported function named by
+    // the second argument (if any) with the remaining arguments
+    const HOST: &str = r#"
+const bytes = require("fs").readFileSync(process.argv[1]);
+let memory;
+const show = (v, k) => {
+    switch (k) {
+        case 0: return "()";
+        case 1: return v.toString();
+        case 2: return v !== 0n ? "true" : "false";
+        case 3: {
+            const mem = new Uint8Array(memory.buffer);
+            let end = Number(v);
+            while (mem[end] !== 0) end++;
+            return new TextDecoder().decode(mem.subarray(Number(v), end));
+        }
+        default: return "<function>";
+    }
+};
+WebAssembly.instantiate(bytes, { hl: { print: (v, k) => console.log(show(v, k)) } })
+    .then(({ instance }) => {
+        memory = instance.exports.memory;
+        instance.exports.main();
+        const [f, ...args] = process.argv.slice(2);
+        if (f) console.log(instance.exports[f](...args.map(BigInt)).toString());
+    });
+"#;
+
+    // Run a module with node, or return `None` if node isn't installed
+    fn run(wasm: &[u8], call: &[&str]) -> Option<String> {
+        let path = std::env::temp_dir().join(format!(
+            "hl-wasm-{}-{}.wasm",
+            std::process::id(),
+            RUNS.fetch_add(1, Ordering::Relaxed),
+        ));
+        std::fs::write(&path, wasm).unwrap();
+        let args = ["-e", HOST, path.to_str().unwrap()].into_iter().chain(call.iter().copied()).collect::<Vec<_>>();
+        let out = interpret("node", &args, "");
+        std::fs::remove_file(&path).unwrap();
+        out
+    }
+
+    fn build(program: &[ir::Expr]) -> Vec<u8> {
+        let m = compile(program).unwrap();
+        let bytes = encode::encode(&m);
+        if let Err(e) = validate::validate(&bytes) {
+            panic!("{}\n{}", e, m);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_validate() {
+        let texts = Texts::new();
+        for src in [example("factorial"), example("simple"), KITCHEN_SINK.to_string()] {
+            build(&lower(&texts, &src));
+            build(&optimize(&texts, &src));
+        }
+    }
+
+    #[test]
+    fn test_node() {
+        let texts = Texts::new();
+        for src in [example("factorial"), example("simple"), KITCHEN_SINK.to_string()] {
+            for program in [lower(&texts, &src), optimize(&texts, &src)] {
+                if let Some(out) = run(&build(&program), &[]) {
+                    assert_eq!(out, eval(&texts, &src));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_exports() {
+        let texts = Texts::new();
+        let wasm = build(&lower(&texts, &example("factorial")));
+        if let Some(out) = run(&wasm, &["factorial", "10"]) {
+            assert_eq!(out, "120\n3628800\n");
+        }
+    }
+
+    #[test]
+    fn test_strings() {
+        let texts = Texts::new();
+        let src = "\"b\" > \"a\"; \"ab\" < \"b\"; \"a\" < \"ab\"; \"x\" == \"x\"; \"x\" != \"y\"; \"b\" <= \"a\";";
+        // Strings are compared by their contents, not their addresses
+        let m = compile(&lower(&texts, src)).unwrap();
+        assert!(m.to_string().contains("call 2"), "{}", m);
+        if let Some(out) = run(&build(&lower(&texts, src)), &[]) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+
+    #[test]
+    fn test_reserved_exports() {
+        let texts = Texts::new();
+        let src = "let main = fun (x Int) -> x * 2; let memory = 3; main(memory);";
+        let m = compile(&lower(&texts, src)).unwrap();
+        let renamed = |prefix: &str| m.exports.iter()
+            .map(|e| e.name.clone())
+            .find(|n| n.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no export for `{}`\n{}", prefix, m));
+        let main = renamed("main$");
+        renamed("memory$");
+        if let Some(out) = run(&build(&lower(&texts, src)), &[&main, "4"]) {
+            assert_eq!(out, eval(&texts, src) + "8\n");
+        }
+    }
+}
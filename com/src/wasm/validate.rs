@@ -0,0 +1,586 @@
+//! Structural validation of encoded modules.
+//!
+//! Decodes the binary format and checks what the engine would check when
+//! compiling it: section layout, index spaces, exports and the operand
+//! stack typing of every function body. Only the subset of the format the
+//! backend emits is understood, anything else is reported as an error.
+
+use std::collections::HashSet;
+
+use super::ValType;
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(format!($($arg)*))
+    };
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let b = *self.bytes.get(self.pos).ok_or("unexpected end of input")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.bytes.len() {
+            bail!("unexpected end of input");
+        }
+        let s = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(s)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut v: u64 = 0;
+        for i in 0..5 {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as u64) << (7 * i);
+            if b & 0x80 == 0 {
+                return u32::try_from(v).map_err(|_| "integer too large".to_string());
+            }
+        }
+        bail!("integer representation too long")
+    }
+
+    fn signed(&mut self, bits: u32) -> Result<i64, String> {
+        let mut v: i64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= bits + 7 {
+                bail!("integer representation too long");
+            }
+            v |= ((b & 0x7f) as i64).wrapping_shl(shift);
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    v |= -1 << shift;
+                }
+                return Ok(v);
+            }
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let n = self.u32()? as usize;
+        String::from_utf8(self.take(n)?.to_vec()).map_err(|_| "malformed UTF-8 name".to_string())
+    }
+
+    fn valtype(&mut self) -> Result<ValType, String> {
+        match self.byte()? {
+            0x7f => Ok(ValType::I32),
+            0x7e => Ok(ValType::I64),
+            b => bail!("unsupported value type 0x{:02x}", b),
+        }
+    }
+
+    fn vec<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T, String>) -> Result<Vec<T>, String> {
+        let n = self.u32()?;
+        (0..n).map(|_| f(self)).collect()
+    }
+}
+
+#[derive(Clone)]
+struct Type {
+    params: Vec<ValType>,
+    results: Vec<ValType>,
+}
+
+#[derive(Default)]
+struct Ctx {
+    types: Vec<Type>,
+    // Type index of every function, imports first
+    funcs: Vec<u32>,
+    imported: usize,
+    table: Option<u32>,
+    memory: bool,
+    globals: Vec<(ValType, bool)>,
+}
+
+impl Ctx {
+    fn ty(&self, i: u32) -> Result<&Type, String> {
+        self.types.get(i as usize).ok_or(format!("unknown type {}", i))
+    }
+
+    fn func(&self, i: u32) -> Result<&Type, String> {
+        let t = *self.funcs.get(i as usize).ok_or(format!("unknown function {}", i))?;
+        self.ty(t)
+    }
+
+    fn global(&self, i: u32) -> Result<(ValType, bool), String> {
+        self.globals.get(i as usize).copied().ok_or(format!("unknown global {}", i))
+    }
+}
+
+/// Check a constant expression of the given type, as used by global
+/// initializers and segment offsets
+fn const_expr(r: &mut Reader, ty: ValType) -> Result<(), String> {
+    let found = match r.byte()? {
+        0x41 => { r.signed(32)?; ValType::I32 }
+        0x42 => { r.signed(64)?; ValType::I64 }
+        b => bail!("unsupported constant instruction 0x{:02x}", b),
+    };
+    if found != ty {
+        bail!("type mismatch in constant expression");
+    }
+    if r.byte()? != 0x0b {
+        bail!("constant expression required");
+    }
+    Ok(())
+}
+
+struct Frame {
+    height: usize,
+    result: Option<ValType>,
+    is_if: bool,
+    // Branches to a loop go back to its start, so they take no values
+    is_loop: bool,
+    unreachable: bool,
+}
+
+// The operand stack of a function body
+struct Stack {
+    values: Vec<ValType>,
+    frames: Vec<Frame>,
+}
+
+impl Stack {
+    fn push(&mut self, t: ValType) {
+        self.values.push(t);
+    }
+
+    fn pop(&mut self) -> Result<Option<ValType>, String> {
+        let frame = self.frames.last().unwrap();
+        if self.values.len() == frame.height {
+            if frame.unreachable {
+                // Anything can be popped after an unreachable
+                return Ok(None);
+            }
+            bail!("type mismatch: operand stack underflow");
+        }
+        Ok(self.values.pop())
+    }
+
+    fn expect(&mut self, t: ValType) -> Result<(), String> {
+        match self.pop()? {
+            Some(found) if found != t => bail!("type mismatch: expected {}, found {}", t, found),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the current frame ends with exactly its result
+    fn end_frame(&mut self) -> Result<(), String> {
+        if let Some(t) = self.frames.last().unwrap().result {
+            self.expect(t)?;
+        }
+        let frame = self.frames.last().unwrap();
+        if self.values.len() != frame.height {
+            bail!("type mismatch: values remaining on the stack at the end of a block");
+        }
+        Ok(())
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.values.truncate(frame.height);
+        frame.unreachable = true;
+    }
+}
+
+fn body(r: &mut Reader, ctx: &Ctx, ty: &Type) -> Result<(), String> {
+    use ValType::*;
+
+    let mut locals = ty.params.clone();
+    for _ in 0..r.u32()? {
+        let n = r.u32()?;
+        let t = r.valtype()?;
+        if locals.len() + n as usize > 50_000 {
+            bail!("too many locals");
+        }
+        locals.extend(std::iter::repeat_n(t, n as usize));
+    }
+    let local = |i: u32| locals.get(i as usize).copied().ok_or(format!("unknown local {}", i));
+
+    let mut s = Stack {
+        values: vec![],
+        frames: vec![Frame {
+            height: 0,
+            result: ty.results.first().copied(),
+            is_if: false,
+            is_loop: false,
+            unreachable: false,
+        }],
+    };
+
+    loop {
+        match r.byte()? {
+            // unreachable
+            0x00 => s.unreachable(),
+            // loop and if
+            op @ (0x03 | 0x04) => {
+                let result = match r.byte()? {
+                    0x40 => None,
+                    0x7f => Some(I32),
+                    0x7e => Some(I64),
+                    b => bail!("unsupported block type 0x{:02x}", b),
+                };
+                let is_if = op == 0x04;
+                if is_if {
+                    s.expect(I32)?;
+                }
+                s.frames.push(Frame { height: s.values.len(), result, is_if, is_loop: !is_if, unreachable: false });
+            }
+            // else
+            0x05 => {
+                if !s.frames.last().unwrap().is_if || s.frames.len() == 1 {
+                    bail!("else without a matching if");
+                }
+                s.end_frame()?;
+                let frame = s.frames.last_mut().unwrap();
+                frame.is_if = false;
+                frame.unreachable = false;
+            }
+            // end
+            0x0b => {
+                s.end_frame()?;
+                let frame = s.frames.pop().unwrap();
+                if frame.is_if && frame.result.is_some() {
+                    bail!("type mismatch: if with a result needs an else");
+                }
+                if s.frames.is_empty() {
+                    return Ok(());
+                }
+                if let Some(t) = frame.result {
+                    s.push(t);
+                }
+            }
+            // br_if
+            0x0d => {
+                let depth = r.u32()? as usize;
+                if depth >= s.frames.len() {
+                    bail!("unknown label");
+                }
+                s.expect(I32)?;
+                let frame = &s.frames[s.frames.len() - 1 - depth];
+                if let Some(t) = frame.result.filter(|_| !frame.is_loop) {
+                    s.expect(t)?;
+                    s.push(t);
+                }
+            }
+            // call and call_indirect
+            op @ (0x10 | 0x11) => {
+                let callee = if op == 0x10 {
+                    ctx.func(r.u32()?)?.clone()
+                } else {
+                    let t = ctx.ty(r.u32()?)?.clone();
+                    if r.byte()? != 0x00 || ctx.table.is_none() {
+                        bail!("unknown table");
+                    }
+                    s.expect(I32)?;
+                    t
+                };
+                for t in callee.params.iter().rev() {
+                    s.expect(*t)?;
+                }
+                callee.results.iter().for_each(|t| s.push(*t));
+            }
+            // drop
+            0x1a => { s.pop()?; }
+            // local.get, local.set, local.tee
+            0x20 => { let t = local(r.u32()?)?; s.push(t); }
+            0x21 => { let t = local(r.u32()?)?; s.expect(t)?; }
+            0x22 => { let t = local(r.u32()?)?; s.expect(t)?; s.push(t); }
+            // global.get, global.set
+            0x23 => { let (t, _) = ctx.global(r.u32()?)?; s.push(t); }
+            0x24 => {
+                let (t, mutable) = ctx.global(r.u32()?)?;
+                if !mutable {
+                    bail!("global is immutable");
+                }
+                s.expect(t)?;
+            }
+            // i64.load, i64.load8_u, i64.store
+            op @ (0x29 | 0x31 | 0x37) => {
+                if !ctx.memory {
+                    bail!("unknown memory");
+                }
+                let natural = if op == 0x31 { 0 } else { 3 };
+                if r.u32()? > natural {
+                    bail!("alignment must not be larger than natural");
+                }
+                r.u32()?;
+                if op == 0x37 {
+                    s.expect(I64)?;
+                }
+                s.expect(I32)?;
+                if op != 0x37 {
+                    s.push(I64);
+                }
+            }
+            // memory.size, memory.grow
+            op @ (0x3f | 0x40) => {
+                if r.byte()? != 0x00 || !ctx.memory {
+                    bail!("unknown memory");
+                }
+                if op == 0x40 {
+                    s.expect(I32)?;
+                }
+                s.push(I32);
+            }
+            0x41 => { r.signed(32)?; s.push(I32); }
+            0x42 => { r.signed(64)?; s.push(I64); }
+            // i32.eqz
+            0x45 => { s.expect(I32)?; s.push(I32); }
+            // i32 comparisons
+            0x46..=0x4f => { s.expect(I32)?; s.expect(I32)?; s.push(I32); }
+            // i64.eqz
+            0x50 => { s.expect(I64)?; s.push(I32); }
+            // i64 comparisons
+            0x51..=0x5a => { s.expect(I64)?; s.expect(I64)?; s.push(I32); }
+            // i32 binary operators
+            0x6a..=0x78 => { s.expect(I32)?; s.expect(I32)?; s.push(I32); }
+            // i64 binary operators
+            0x7c..=0x8a => { s.expect(I64)?; s.expect(I64)?; s.push(I64); }
+            // i32.wrap_i64
+            0xa7 => { s.expect(I64)?; s.push(I32); }
+            // i64.extend_i32_s, i64.extend_i32_u
+            0xac | 0xad => { s.expect(I32)?; s.push(I64); }
+            b => bail!("unsupported instruction 0x{:02x}", b),
+        }
+    }
+}
+
+/// Validate an encoded module
+pub fn validate(bytes: &[u8]) -> Result<(), String> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(4)? != b"\0asm" {
+        bail!("magic header not detected");
+    }
+    if r.take(4)? != [1, 0, 0, 0] {
+        bail!("unknown binary version");
+    }
+
+    let mut ctx = Ctx::default();
+    let mut declared: Vec<u32> = vec![];
+    let mut codes = None;
+    let mut last = 0;
+
+    while !r.done() {
+        let id = r.byte()?;
+        let size = r.u32()? as usize;
+        let mut s = Reader { bytes: r.take(size)?, pos: 0 };
+        if id != 0 {
+            if id <= last {
+                bail!("unexpected section {}", id);
+            }
+            last = id;
+        }
+
+        match id {
+            // custom
+            0 => { s.name()?; s.pos = s.bytes.len(); }
+            // types
+            1 => {
+                ctx.types = s.vec(|s| {
+                    if s.byte()? != 0x60 {
+                        bail!("malformed function type");
+                    }
+                    let params = s.vec(|s| s.valtype())?;
+                    let results = s.vec(|s| s.valtype())?;
+                    if results.len() > 1 {
+                        bail!("multiple results are not supported");
+                    }
+                    Ok(Type { params, results })
+                })?;
+            }
+            // imports
+            2 => {
+                for _ in 0..s.u32()? {
+                    s.name()?;
+                    s.name()?;
+                    if s.byte()? != 0x00 {
+                        bail!("only function imports are supported");
+                    }
+                    let t = s.u32()?;
+                    ctx.ty(t)?;
+                    ctx.funcs.push(t);
+                }
+                ctx.imported = ctx.funcs.len();
+            }
+            // functions
+            3 => {
+                declared = s.vec(|s| s.u32())?;
+                for t in &declared {
+                    ctx.ty(*t)?;
+                }
+                ctx.funcs.extend(&declared);
+            }
+            // table
+            4 => {
+                for _ in 0..s.u32()? {
+                    if ctx.table.is_some() {
+                        bail!("multiple tables");
+                    }
+                    if s.byte()? != 0x70 {
+                        bail!("only funcref tables are supported");
+                    }
+                    let flags = s.byte()?;
+                    let min = s.u32()?;
+                    if flags == 1 && s.u32()? < min {
+                        bail!("size minimum must not be greater than maximum");
+                    }
+                    ctx.table = Some(min);
+                }
+            }
+            // memory
+            5 => {
+                for _ in 0..s.u32()? {
+                    if ctx.memory {
+                        bail!("multiple memories");
+                    }
+                    let flags = s.byte()?;
+                    let min = s.u32()?;
+                    let max = if flags == 1 { s.u32()? } else { min };
+                    if min > 65536 || max > 65536 {
+                        bail!("memory size must be at most 65536 pages (4GiB)");
+                    }
+                    if max < min {
+                        bail!("size minimum must not be greater than maximum");
+                    }
+                    ctx.memory = true;
+                }
+            }
+            // globals
+            6 => {
+                for _ in 0..s.u32()? {
+                    let t = s.valtype()?;
+                    let mutable = match s.byte()? {
+                        0 => false,
+                        1 => true,
+                        _ => bail!("malformed mutability"),
+                    };
+                    const_expr(&mut s, t)?;
+                    ctx.globals.push((t, mutable));
+                }
+            }
+            // exports
+            7 => {
+                let mut names = HashSet::new();
+                for _ in 0..s.u32()? {
+                    let name = s.name()?;
+                    let kind = s.byte()?;
+                    let index = s.u32()?;
+                    match kind {
+                        0x00 => { ctx.func(index)?; }
+                        0x01 if ctx.table.is_none() || index != 0 => bail!("unknown table {}", index),
+                        0x02 if !ctx.memory || index != 0 => bail!("unknown memory {}", index),
+                        0x01 | 0x02 => {}
+                        0x03 => { ctx.global(index)?; }
+                        b => bail!("malformed export kind 0x{:02x}", b),
+                    }
+                    if !names.insert(name.clone()) {
+                        bail!("duplicate export name `{}`", name);
+                    }
+                }
+            }
+            // start
+            8 => {
+                let t = ctx.func(s.u32()?)?;
+                if !t.params.is_empty() || !t.results.is_empty() {
+                    bail!("start function must have type [] -> []");
+                }
+            }
+            // elements
+            9 => {
+                for _ in 0..s.u32()? {
+                    if s.u32()? != 0 {
+                        bail!("only active segments of table 0 are supported");
+                    }
+                    let size = ctx.table.ok_or("unknown table")?;
+                    const_expr(&mut s, ValType::I32)?;
+                    let funcs = s.vec(|s| s.u32())?;
+                    for f in &funcs {
+                        ctx.func(*f)?;
+                    }
+                    if funcs.len() > size as usize {
+                        bail!("element segment does not fit the table");
+                    }
+                }
+            }
+            // code
+            10 => {
+                let n = s.u32()?;
+                if n as usize != declared.len() {
+                    bail!("function and code section have inconsistent lengths");
+                }
+                for t in &declared {
+                    let size = s.u32()? as usize;
+                    let mut f = Reader { bytes: s.take(size)?, pos: 0 };
+                    let ty = ctx.ty(*t)?.clone();
+                    body(&mut f, &ctx, &ty)?;
+                    if !f.done() {
+                        bail!("section size mismatch in function body");
+                    }
+                }
+                codes = Some(n);
+            }
+            // data
+            11 => {
+                for _ in 0..s.u32()? {
+                    if s.u32()? != 0 || !ctx.memory {
+                        bail!("unknown memory");
+                    }
+                    const_expr(&mut s, ValType::I32)?;
+                    let n = s.u32()? as usize;
+                    s.take(n)?;
+                }
+            }
+            _ => bail!("malformed section id {}", id),
+        }
+
+        if !s.done() {
+            bail!("section size mismatch in section {}", id);
+        }
+    }
+
+    if codes.is_none() && !declared.is_empty() {
+        bail!("function and code section have inconsistent lengths");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects() {
+        assert!(validate(b"\0asm\x02\0\0\0").is_err());
+        // A function of type [] -> [i64] whose body is only `end`
+        let bytes = [
+            0, b'a', b's', b'm', 1, 0, 0, 0,
+            1, 5, 1, 0x60, 0, 1, 0x7e,
+            3, 2, 1, 0,
+            10, 4, 1, 2, 0, 0x0b,
+        ];
+        assert!(validate(&bytes).unwrap_err().contains("underflow"));
+        // The same function returning `i64.const 1`
+        let bytes = [
+            0, b'a', b's', b'm', 1, 0, 0, 0,
+            1, 5, 1, 0x60, 0, 1, 0x7e,
+            3, 2, 1, 0,
+            10, 6, 1, 4, 0, 0x42, 1, 0x0b,
+        ];
+        assert_eq!(validate(&bytes), Ok(()));
+    }
+}
@@ -0,0 +1,160 @@
+//! Text format of a [`Module`].
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use super::{ExportKind, FuncType, Instr, Module, ValType};
+
+impl Display for ValType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            ValType::I32 => write!(f, "i32"),
+            ValType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+impl Display for FuncType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "(func")?;
+        if !self.params.is_empty() {
+            write!(f, " (param")?;
+            self.params.iter().try_for_each(|t| write!(f, " {}", t))?;
+            write!(f, ")")?;
+        }
+        if !self.results.is_empty() {
+            write!(f, " (result")?;
+            self.results.iter().try_for_each(|t| write!(f, " {}", t))?;
+            write!(f, ")")?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        use Instr::*;
+        match self {
+            Unreachable => write!(f, "unreachable"),
+            Loop(None)    => write!(f, "loop"),
+            Loop(Some(t)) => write!(f, "loop (result {})", t),
+            If(None)    => write!(f, "if"),
+            If(Some(t)) => write!(f, "if (result {})", t),
+            Else => write!(f, "else"),
+            End  => write!(f, "end"),
+            BrIf(l) => write!(f, "br_if {}", l),
+            Call(i) => write!(f, "call {}", i),
+            CallIndirect(t) => write!(f, "call_indirect (type {})", t),
+            Drop => write!(f, "drop"),
+            LocalGet(i)  => write!(f, "local.get {}", i),
+            LocalSet(i)  => write!(f, "local.set {}", i),
+            LocalTee(i)  => write!(f, "local.tee {}", i),
+            GlobalGet(i) => write!(f, "global.get {}", i),
+            GlobalSet(i) => write!(f, "global.set {}", i),
+            I64Load(o)  => write!(f, "i64.load offset={}", o),
+            I64Load8U(o) => write!(f, "i64.load8_u offset={}", o),
+            I64Store(o) => write!(f, "i64.store offset={}", o),
+            MemorySize => write!(f, "memory.size"),
+            MemoryGrow => write!(f, "memory.grow"),
+            I32Const(v) => write!(f, "i32.const {}", v),
+            I64Const(v) => write!(f, "i64.const {}", v),
+            I32Eqz => write!(f, "i32.eqz"),
+            I32GtU => write!(f, "i32.gt_u"),
+            I64Eqz => write!(f, "i64.eqz"),
+            I64Eq  => write!(f, "i64.eq"),
+            I64Ne  => write!(f, "i64.ne"),
+            I64LtS => write!(f, "i64.lt_s"),
+            I64GtS => write!(f, "i64.gt_s"),
+            I64LeS => write!(f, "i64.le_s"),
+            I64GeS => write!(f, "i64.ge_s"),
+            I32Add  => write!(f, "i32.add"),
+            I32Sub  => write!(f, "i32.sub"),
+            I32Shl  => write!(f, "i32.shl"),
+            I32ShrU => write!(f, "i32.shr_u"),
+            I64Add  => write!(f, "i64.add"),
+            I64Sub  => write!(f, "i64.sub"),
+            I64Mul  => write!(f, "i64.mul"),
+            I64DivS => write!(f, "i64.div_s"),
+            I64RemS => write!(f, "i64.rem_s"),
+            I32WrapI64    => write!(f, "i32.wrap_i64"),
+            I64ExtendI32U => write!(f, "i64.extend_i32_u"),
+        }
+    }
+}
+
+fn string(bytes: &[u8]) -> String {
+    bytes.iter()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{}", *b as char),
+            0x20..=0x7e => (*b as char).to_string(),
+            _ => format!("\\{:02x}", b),
+        })
+        .collect()
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        writeln!(f, "(module")?;
+        for (i, t) in self.types.iter().enumerate() {
+            writeln!(f, "  (type (;{};) {})", i, t)?;
+        }
+        for i in &self.imports {
+            writeln!(f, "  (import \"{}\" \"{}\" (func ${} (type {})))", i.module, i.name, i.name, i.ty)?;
+        }
+        writeln!(f, "  (table {} funcref)", self.table)?;
+        writeln!(f, "  (memory {})", self.memory)?;
+        for g in &self.globals {
+            let ty = if g.mutable { format!("(mut {})", g.ty) } else { g.ty.to_string() };
+            writeln!(f, "  (global ${} {} ({}))", g.name, ty, g.init)?;
+        }
+        for e in &self.exports {
+            let kind = match e.kind {
+                ExportKind::Func   => "func",
+                ExportKind::Table  => "table",
+                ExportKind::Memory => "memory",
+                ExportKind::Global => "global",
+            };
+            writeln!(f, "  (export \"{}\" ({} {}))", e.name, kind, e.index)?;
+        }
+        if let Some(start) = self.start {
+            writeln!(f, "  (start {})", start)?;
+        }
+        write!(f, "  (elem (i32.const 0) func")?;
+        self.elems.iter().try_for_each(|e| write!(f, " {}", e))?;
+        writeln!(f, ")")?;
+
+        for func in &self.funcs {
+            let ty = &self.types[func.ty as usize];
+            write!(f, "  (func ${} (type {})", func.name, func.ty)?;
+            if !ty.params.is_empty() {
+                write!(f, " (param")?;
+                ty.params.iter().try_for_each(|t| write!(f, " {}", t))?;
+                write!(f, ")")?;
+            }
+            if !ty.results.is_empty() {
+                write!(f, " (result")?;
+                ty.results.iter().try_for_each(|t| write!(f, " {}", t))?;
+                write!(f, ")")?;
+            }
+            if !func.locals.is_empty() {
+                write!(f, " (local")?;
+                func.locals.iter().try_for_each(|t| write!(f, " {}", t))?;
+                write!(f, ")")?;
+            }
+            writeln!(f)?;
+            let mut depth = 2;
+            for i in &func.body {
+                if matches!(i, Instr::Else | Instr::End) {
+                    depth -= 1;
+                }
+                writeln!(f, "{}{}", "  ".repeat(depth), i)?;
+                if matches!(i, Instr::Loop(_) | Instr::If(_) | Instr::Else) {
+                    depth += 1;
+                }
+            }
+            writeln!(f, "  )")?;
+        }
+
+        writeln!(f, "  (data (i32.const {}) \"{}\")", self.data_offset, string(&self.data))?;
+        writeln!(f, ")")
+    }
+}
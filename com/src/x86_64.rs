@@ -0,0 +1,728 @@
+//! x86-64 Linux backend, emitting GNU `as` (AT&T syntax) assembly.
+//!
+//! Every value is a 64-bit word: integers are stored as is, booleans are
+//! 0 or 1, unit is 0, strings are pointers to NUL-terminated literals and
+//! functions are pointers to closures laid out as `[code, captures...]`.
+//!
+//! Closures are called with the closure pointer in `%rdi`, the first five
+//! arguments in `%rsi`, `%rdx`, `%rcx`, `%r8`, `%r9` and the rest on the
+//! stack, everything else follows the System V ABI.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io,
+    path::Path,
+    process::Command,
+};
+
+use ir::{closure::free_vars, BinOp, Expr, ExprKind, Lit, Type, UnOp, Var, VarId};
+
+use crate::{
+    collect_strings,
+    source_map::{extract, mark, SourceMap},
+    BackendError,
+};
+
+const RUNTIME: &str = include_str!("x86_64/runtime.s");
+
+/// Callee-saved registers handed out by the register allocator
+const REGS: [&str; 5] = ["%rbx", "%r12", "%r13", "%r14", "%r15"];
+/// Registers used to pass closure arguments (the closure is in `%rdi`)
+const ARG_REGS: [&str; 5] = ["%rsi", "%rdx", "%rcx", "%r8", "%r9"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Loc {
+    Imm(i64),
+    Reg(&'static str),
+    // Offset from %rbp
+    Stack(i64),
+    // A global variable
+    Global(String),
+    // The address of a label
+    Addr(String),
+}
+
+impl Loc {
+    fn operand(&self) -> String {
+        match self {
+            Loc::Imm(i)    => format!("${}", i),
+            Loc::Reg(r)    => r.to_string(),
+            Loc::Stack(o)  => format!("{}(%rbp)", o),
+            Loc::Global(s) => format!("{}(%rip)", s),
+            Loc::Addr(s)   => format!("{}(%rip)", s),
+        }
+    }
+
+    fn is_mem(&self) -> bool {
+        matches!(self, Loc::Stack(_) | Loc::Global(_))
+    }
+}
+
+// A value produced by an expression, temporaries are given back to the
+// allocator once they are consumed
+struct Val {
+    loc: Loc,
+    temp: bool,
+}
+
+impl Val {
+    fn imm(i: i64) -> Self {
+        Val { loc: Loc::Imm(i), temp: false }
+    }
+}
+
+// A function being generated
+struct Frame<'src> {
+    code: Vec<String>,
+    free_regs: Vec<&'static str>,
+    free_slots: Vec<i64>,
+    slots: i64,
+    scopes: Vec<Vec<(VarId, Loc)>>,
+    captures: Vec<Var<'src>>,
+    closure: Option<Loc>,
+}
+
+impl Frame<'_> {
+    fn new() -> Self {
+        Self {
+            code: vec![],
+            free_regs: REGS.iter().rev().copied().collect(),
+            free_slots: vec![],
+            slots: 0,
+            scopes: vec![vec![]],
+            captures: vec![],
+            closure: None,
+        }
+    }
+
+    fn slot(&mut self) -> Loc {
+        match self.free_slots.pop() {
+            Some(o) => Loc::Stack(o),
+            None => {
+                self.slots += 1;
+                // 8 bytes for %rbp and 40 bytes of saved registers
+                Loc::Stack(-40 - 8 * self.slots)
+            }
+        }
+    }
+
+    /// Allocate a location, preferring registers over stack slots
+    fn alloc(&mut self) -> Loc {
+        match self.free_regs.pop() {
+            Some(r) => Loc::Reg(r),
+            None => self.slot(),
+        }
+    }
+
+    fn free(&mut self, loc: Loc) {
+        match loc {
+            Loc::Reg(r) => self.free_regs.push(r),
+            Loc::Stack(o) if o < 0 => self.free_slots.push(o),
+            _ => {},
+        }
+    }
+
+    fn lookup(&self, id: VarId) -> Option<Loc> {
+        self.scopes.iter()
+            .flat_map(|s| s.iter())
+            .find(|(v, _)| *v == id)
+            .map(|(_, l)| l.clone())
+    }
+
+    fn is_local(&self, id: VarId) -> bool {
+        self.lookup(id).is_some() || self.captures.iter().any(|c| c.id == id)
+    }
+}
+
+// A lambda waiting for its body to be generated
+struct Pending<'src> {
+    label: String,
+    params: Vec<Var<'src>>,
+    body: Expr<'src>,
+    captures: Vec<Var<'src>>,
+}
+
+struct Codegen<'src> {
+    globals: HashMap<VarId, String>,
+    strings: HashMap<&'src str, String>,
+    pending: Vec<Pending<'src>>,
+    text: Vec<String>,
+    labels: usize,
+    lambdas: usize,
+    f: Frame<'src>,
+}
+
+macro_rules! emit {
+    ($self:ident, $($arg:tt)*) => {
+        $self.f.code.push(format!("    {}", format!($($arg)*)))
+    };
+}
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(BackendError::new(format!($($arg)*)))
+    };
+}
+
+impl<'src> Codegen<'src> {
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!(".L{}", self.labels)
+    }
+
+    fn temp(&mut self) -> Val {
+        Val { loc: self.f.alloc(), temp: true }
+    }
+
+    fn release(&mut self, v: Val) {
+        if v.temp {
+            self.f.free(v.loc);
+        }
+    }
+
+    /// Move a value into a location, going through `%rax` if needed
+    fn mov(&mut self, src: &Loc, dst: &Loc) {
+        if src == dst {
+            return;
+        }
+        match src {
+            Loc::Imm(i) if i32::try_from(*i).is_err() => {
+                if let Loc::Reg(_) = dst {
+                    emit!(self, "movabsq ${}, {}", i, dst.operand());
+                } else {
+                    emit!(self, "movabsq ${}, %rax", i);
+                    emit!(self, "movq %rax, {}", dst.operand());
+                }
+            }
+            Loc::Addr(_) => {
+                if let Loc::Reg(_) = dst {
+                    emit!(self, "leaq {}, {}", src.operand(), dst.operand());
+                } else {
+                    emit!(self, "leaq {}, %rax", src.operand());
+                    emit!(self, "movq %rax, {}", dst.operand());
+                }
+            }
+            _ if src.is_mem() && dst.is_mem() => {
+                emit!(self, "movq {}, %rax", src.operand());
+                emit!(self, "movq %rax, {}", dst.operand());
+            }
+            _ => emit!(self, "movq {}, {}", src.operand(), dst.operand()),
+        }
+    }
+
+    fn load(&mut self, src: &Loc, reg: &'static str) {
+        self.mov(src, &Loc::Reg(reg));
+    }
+
+    /// Load a variable into a register
+    fn load_var(&mut self, var: &Var<'src>, reg: &'static str) -> Result<(), BackendError> {
+        if let Some(loc) = self.f.lookup(var.id) {
+            self.load(&loc, reg);
+        } else if let Some(i) = self.f.captures.iter().position(|c| c == var) {
+            let closure = self.f.closure.clone().unwrap();
+            self.load(&closure, "%r11");
+            emit!(self, "movq {}(%r11), {}", 8 * (i + 1), reg);
+        } else if let Some(g) = self.globals.get(&var.id) {
+            let g = Loc::Global(g.clone());
+            self.load(&g, reg);
+        } else {
+            bail!("`{}` is not defined", var.name);
+        }
+        Ok(())
+    }
+
+    fn copy_temp(&mut self, src: Val) -> Val {
+        let dst = self.temp();
+        self.mov(&src.loc, &dst.loc);
+        self.release(src);
+        dst
+    }
+
+    fn expr(&mut self, e: &Expr<'src>) -> Result<Val, BackendError> {
+        self.f.code.push(mark(e.span));
+        match &e.kind {
+            ExprKind::Lit(l) => Ok(match l {
+                Lit::Unit    => Val::imm(0),
+                Lit::Bool(b) => Val::imm(*b as i64),
+                Lit::Int(i)  => Val::imm(*i),
+                Lit::Str(s)  => Val { loc: Loc::Addr(self.strings[s].clone()), temp: false },
+            }),
+            ExprKind::Var(x) => {
+                let dst = self.temp();
+                self.load_var(x, "%rax")?;
+                self.mov(&Loc::Reg("%rax"), &dst.loc);
+                Ok(dst)
+            }
+            ExprKind::If { cond, t, f } => {
+                let (lf, lend) = (self.label(), self.label());
+                let c = self.expr(cond)?;
+                self.load(&c.loc, "%rax");
+                self.release(c);
+                let dst = self.temp();
+                emit!(self, "testq %rax, %rax");
+                emit!(self, "jz {}", lf);
+                let t = self.expr(t)?;
+                self.mov(&t.loc, &dst.loc);
+                self.release(t);
+                emit!(self, "jmp {}", lend);
+                self.f.code.push(format!("{}:", lf));
+                let f = self.expr(f)?;
+                self.mov(&f.loc, &dst.loc);
+                self.release(f);
+                self.f.code.push(format!("{}:", lend));
+                Ok(dst)
+            }
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), l, r) => {
+                let lend = self.label();
+                let l = self.expr(l)?;
+                let dst = self.copy_temp(l);
+                self.load(&dst.loc, "%rax");
+                emit!(self, "testq %rax, %rax");
+                emit!(self, "{} {}", if *op == BinOp::And { "jz" } else { "jnz" }, lend);
+                let r = self.expr(r)?;
+                self.mov(&r.loc, &dst.loc);
+                self.release(r);
+                self.f.code.push(format!("{}:", lend));
+                Ok(dst)
+            }
+            ExprKind::Let { var, value, body } => {
+                let value = self.expr(value)?;
+                let loc = self.f.alloc();
+                self.mov(&value.loc, &loc);
+                self.release(value);
+                self.f.scopes.push(vec![(var.id, loc.clone())]);
+                let body = self.expr(body)?;
+                self.f.scopes.pop();
+                self.f.free(loc);
+                Ok(body)
+            }
+            ExprKind::Define { var, value } => {
+                if let Some(g) = self.globals.get(&var.id) {
+                    // Top-level definition
+                    let g = Loc::Global(g.clone());
+                    let value = self.expr(value)?;
+                    self.load(&value.loc, "%rax");
+                    self.release(value);
+                    self.mov(&Loc::Reg("%rax"), &g);
+                } else {
+                    let loc = self.f.alloc();
+                    let value = if value.is_lambda() {
+                        // Bind the name first so that a recursive lambda
+                        // can capture itself
+                        self.f.scopes.last_mut().unwrap().push((var.id, loc.clone()));
+                        self.lambda(value, Some(var.id))?
+                    } else {
+                        let value = self.expr(value)?;
+                        self.f.scopes.last_mut().unwrap().push((var.id, loc.clone()));
+                        value
+                    };
+                    self.mov(&value.loc, &loc);
+                    self.release(value);
+                }
+                Ok(Val::imm(0))
+            }
+            ExprKind::Lambda { .. } => self.lambda(e, None),
+            ExprKind::Block { exprs, void } => {
+                self.f.scopes.push(vec![]);
+                let mut last = Val::imm(0);
+                for e in exprs {
+                    self.release(last);
+                    last = self.expr(e)?;
+                }
+                for (_, loc) in self.f.scopes.pop().unwrap() {
+                    self.f.free(loc);
+                }
+                if *void {
+                    self.release(last);
+                    Ok(Val::imm(0))
+                } else {
+                    Ok(last)
+                }
+            }
+            ExprKind::Unary(op, x) => {
+                let x = self.expr(x)?;
+                let dst = self.copy_temp(x);
+                self.load(&dst.loc, "%rax");
+                match op {
+                    UnOp::Neg => emit!(self, "negq %rax"),
+                    UnOp::Not => emit!(self, "xorq $1, %rax"),
+                }
+                self.mov(&Loc::Reg("%rax"), &dst.loc);
+                Ok(dst)
+            }
+            ExprKind::Binary(op, l, r) => {
+                let l_ty = l.ty.clone();
+                let l = self.expr(l)?;
+                let r = self.expr(r)?;
+                self.load(&l.loc, "%rax");
+                self.load(&r.loc, "%rcx");
+                self.release(r);
+                self.release(l);
+                self.binary(*op, &l_ty);
+                let dst = self.temp();
+                self.mov(&Loc::Reg("%rax"), &dst.loc);
+                Ok(dst)
+            }
+            ExprKind::Call { func, args } => self.call(func, args),
+            ExprKind::Closure { .. } | ExprKind::EnvGet { .. } => {
+                bail!("closure-converted IR is not supported by the x86-64 backend")
+            }
+        }
+    }
+
+    /// Apply a binary operator to `%rax` and `%rcx`, whose operands are of
+    /// type `ty`, leaving the result in `%rax`
+    fn binary(&mut self, op: BinOp, ty: &Type) {
+        match op {
+            BinOp::Add => emit!(self, "addq %rcx, %rax"),
+            BinOp::Sub => emit!(self, "subq %rcx, %rax"),
+            BinOp::Mul => emit!(self, "imulq %rcx, %rax"),
+            BinOp::Div | BinOp::Rem => {
+                let (lok, ldiv, lend) = (self.label(), self.label(), self.label());
+                emit!(self, "testq %rcx, %rcx");
+                emit!(self, "jnz {}", lok);
+                emit!(self, "call hl_div_zero");
+                self.f.code.push(format!("{}:", lok));
+                // i64::MIN / -1 traps, so -1 is handled separately
+                emit!(self, "cmpq $-1, %rcx");
+                emit!(self, "jne {}", ldiv);
+                if op == BinOp::Div {
+                    emit!(self, "negq %rax");
+                } else {
+                    emit!(self, "xorl %eax, %eax");
+                }
+                emit!(self, "jmp {}", lend);
+                self.f.code.push(format!("{}:", ldiv));
+                emit!(self, "cqto");
+                emit!(self, "idivq %rcx");
+                if op == BinOp::Rem {
+                    emit!(self, "movq %rdx, %rax");
+                }
+                self.f.code.push(format!("{}:", lend));
+            }
+            _ => {
+                let set = match op {
+                    BinOp::Eq => "sete",
+                    BinOp::Ne => "setne",
+                    BinOp::Lt => "setl",
+                    BinOp::Le => "setle",
+                    BinOp::Gt => "setg",
+                    BinOp::Ge => "setge",
+                    _ => unreachable!("short-circuiting operators are handled separately"),
+                };
+                if *ty == Type::Str {
+                    // Strings are compared by their contents, then the
+                    // sign of the difference is compared with zero
+                    emit!(self, "movq %rax, %rdi");
+                    emit!(self, "movq %rcx, %rsi");
+                    emit!(self, "call hl_strcmp");
+                    emit!(self, "xorl %ecx, %ecx");
+                }
+                emit!(self, "cmpq %rcx, %rax");
+                emit!(self, "{} %al", set);
+                emit!(self, "movzbq %al, %rax");
+            }
+        }
+    }
+
+    fn call(&mut self, f: &Expr<'src>, args: &[Expr<'src>]) -> Result<Val, BackendError> {
+        let f = self.expr(f)?;
+        let args = args.iter()
+            .map(|a| self.expr(a))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Arguments that don't fit in registers are pushed in reverse order,
+        // padded so the stack stays 16-byte aligned
+        let stack_args = args.len().saturating_sub(ARG_REGS.len());
+        let pad = stack_args % 2;
+        if pad == 1 {
+            emit!(self, "subq $8, %rsp");
+        }
+        for a in args.iter().skip(ARG_REGS.len()).rev() {
+            match &a.loc {
+                Loc::Reg(_) | Loc::Stack(_) | Loc::Global(_) => emit!(self, "pushq {}", a.loc.operand()),
+                Loc::Imm(i) if i32::try_from(*i).is_ok() => emit!(self, "pushq {}", a.loc.operand()),
+                _ => {
+                    self.load(&a.loc, "%rax");
+                    emit!(self, "pushq %rax");
+                }
+            }
+        }
+        for (a, r) in args.iter().zip(ARG_REGS) {
+            self.load(&a.loc, r);
+        }
+        self.load(&f.loc, "%rdi");
+        emit!(self, "call *(%rdi)");
+        if stack_args + pad > 0 {
+            emit!(self, "addq ${}, %rsp", 8 * (stack_args + pad));
+        }
+
+        args.into_iter().for_each(|a| self.release(a));
+        self.release(f);
+        let dst = self.temp();
+        self.mov(&Loc::Reg("%rax"), &dst.loc);
+        Ok(dst)
+    }
+
+    /// Allocate a closure for a lambda, if `this` is given the closure
+    /// captures itself as that variable
+    fn lambda(&mut self, l: &Expr<'src>, this: Option<VarId>) -> Result<Val, BackendError> {
+        let ExprKind::Lambda { params, body } = &l.kind else { bail!("expected a lambda") };
+        let captures = free_vars(l).into_iter()
+            .filter(|x| self.f.is_local(x.id))
+            .collect::<Vec<_>>();
+
+        self.lambdas += 1;
+        let label = format!("hl_fn{}", self.lambdas);
+        self.pending.push(Pending {
+            label: label.clone(),
+            params: params.clone(),
+            body: (**body).clone(),
+            captures: captures.clone(),
+        });
+
+        emit!(self, "movq ${}, %rdi", 8 * (captures.len() + 1));
+        emit!(self, "call hl_alloc");
+        let dst = self.temp();
+        self.mov(&Loc::Reg("%rax"), &dst.loc);
+        emit!(self, "leaq {}(%rip), %rcx", label);
+        emit!(self, "movq %rcx, (%rax)");
+        // Loading a variable never clobbers %rax
+        for (i, c) in captures.iter().enumerate() {
+            if Some(c.id) == this {
+                emit!(self, "movq %rax, {}(%rax)", 8 * (i + 1));
+            } else {
+                self.load_var(c, "%rcx")?;
+                emit!(self, "movq %rcx, {}(%rax)", 8 * (i + 1));
+            }
+        }
+        Ok(dst)
+    }
+
+    /// Wrap the body of the current frame with a prologue and epilogue
+    fn finish(&mut self, label: &str, result: Option<Val>) {
+        let mut f = std::mem::replace(&mut self.f, Frame::new());
+        if let Some(r) = result {
+            let src = r.loc.operand();
+            match r.loc {
+                Loc::Imm(i) if i32::try_from(i).is_err() => f.code.push(format!("    movabsq ${}, %rax", i)),
+                Loc::Addr(_) => f.code.push(format!("    leaq {}, %rax", src)),
+                _ => f.code.push(format!("    movq {}, %rax", src)),
+            }
+        } else {
+            f.code.push("    xorl %eax, %eax".to_string());
+        }
+
+        // Keep %rsp 16-byte aligned after the 6 pushes
+        let size = 8 * (f.slots + (f.slots + 1) % 2);
+        let mut out = vec![
+            format!("    .globl {}", label),
+            format!("{}:", label),
+            "    pushq %rbp".to_string(),
+            "    movq %rsp, %rbp".to_string(),
+        ];
+        out.extend(REGS.iter().map(|r| format!("    pushq {}", r)));
+        out.push(format!("    subq ${}, %rsp", size));
+        out.extend(f.code);
+        out.push("    leaq -40(%rbp), %rsp".to_string());
+        out.extend(REGS.iter().rev().map(|r| format!("    popq {}", r)));
+        out.push("    popq %rbp".to_string());
+        out.push("    ret".to_string());
+        self.text.push(out.join("\n"));
+    }
+
+    fn function(&mut self, p: Pending<'src>) -> Result<(), BackendError> {
+        let closure = self.f.slot();
+        self.mov(&Loc::Reg("%rdi"), &closure);
+        self.f.closure = Some(closure);
+        self.f.captures = p.captures;
+        for (i, param) in p.params.iter().enumerate() {
+            let loc = match ARG_REGS.get(i) {
+                Some(r) => {
+                    let loc = self.f.slot();
+                    self.mov(&Loc::Reg(r), &loc);
+                    loc
+                }
+                None => Loc::Stack(16 + 8 * (i - ARG_REGS.len()) as i64),
+            };
+            self.f.scopes[0].push((param.id, loc));
+        }
+        let result = self.expr(&p.body)?;
+        self.finish(&p.label, Some(result));
+        Ok(())
+    }
+}
+
+fn print_fn(ty: &Type) -> &'static str {
+    match ty {
+        Type::Int  => "hl_print_int",
+        Type::Bool => "hl_print_bool",
+        Type::Str  => "hl_print_str",
+        Type::Func(_, _) => "hl_print_fn",
+        _ => "hl_print_unit",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'"' | b'\\' => format!("\\{}", b as char),
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\{:03o}", b),
+        })
+        .collect()
+}
+
+/// Compile a program into assembly. The program prints the value of
+/// every top-level expression that isn't a definition.
+pub fn compile(program: &[Expr]) -> Result<String, BackendError> {
+    compile_with_map(program).map(|(asm, _)| asm)
+}
+
+/// Compile a program into assembly, with the map from the assembly back to
+/// the source of the program
+pub fn compile_with_map(program: &[Expr]) -> Result<(String, SourceMap), BackendError> {
+    let mut globals = HashMap::new();
+    let mut strings = BTreeSet::new();
+    for e in program {
+        if let ExprKind::Define { var, .. } = &e.kind {
+            let i = globals.len();
+            globals.entry(var.id).or_insert_with(|| format!("hl_g{}_{}", i, var.name.replace('\'', "_")));
+        }
+        collect_strings(e, &mut strings);
+    }
+
+    let mut data = vec!["    .section .rodata".to_string()];
+    let strings = strings.into_iter()
+        .enumerate()
+        .map(|(i, s)| {
+            data.push(format!("hl_str{}: .asciz \"{}\"", i, escape(s)));
+            (s, format!("hl_str{}", i))
+        })
+        .collect::<HashMap<_, _>>();
+    data.push("    .bss".to_string());
+    data.push("    .p2align 3".to_string());
+    let mut gs = globals.values().collect::<Vec<_>>();
+    gs.sort();
+    data.extend(gs.into_iter().map(|g| format!("{}: .zero 8", g)));
+
+    let mut cg = Codegen {
+        globals,
+        strings,
+        pending: vec![],
+        text: vec![],
+        labels: 0,
+        lambdas: 0,
+        f: Frame::new(),
+    };
+
+    for e in program {
+        let v = cg.expr(e)?;
+        if !e.is_define() {
+            cg.load(&v.loc, "%rdi");
+            emit!(cg, "call {}", print_fn(&e.ty));
+        }
+        cg.release(v);
+    }
+    cg.finish("main", None);
+
+    while let Some(p) = cg.pending.pop() {
+        cg.function(p)?;
+    }
+
+    let mut out = vec!["    .text".to_string()];
+    out.extend(cg.text);
+    out.extend(data);
+    out.push(RUNTIME.to_string());
+    Ok(extract(&out.join("\n")))
+}
+
+/// Assemble and link the output of [`compile`] into an executable with the
+/// system C compiler driver
+pub fn build(asm: &str, output: &Path) -> io::Result<()> {
+    let src = output.with_extension("s");
+    fs::write(&src, asm)?;
+    let status = Command::new("cc")
+        .arg("-o")
+        .arg(output)
+        .arg(&src)
+        .status();
+    fs::remove_file(&src)?;
+    if !status?.success() {
+        return Err(io::Error::other("failed to assemble or link"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{eval, example, lower, optimize, skip, KITCHEN_SINK};
+    use syntax::source::Texts;
+
+    // Compile and run a program, or return `None` if there is no C
+    // compiler driver to link with
+    fn run(name: &str, program: &[Expr]) -> Option<String> {
+        let asm = compile(program).unwrap();
+        let exe = std::env::temp_dir().join(format!("hc-x86_64-{}-{}", name, std::process::id()));
+        if let Err(e) = build(&asm, &exe) {
+            skip(&format!("could not build: {}", e));
+            return None;
+        }
+        let out = Command::new(&exe).output().unwrap();
+        fs::remove_file(&exe).unwrap();
+        assert!(out.status.success());
+        Some(String::from_utf8(out.stdout).unwrap())
+    }
+
+    #[test]
+    fn test_examples() {
+        let texts = Texts::new();
+        for name in ["factorial", "simple"] {
+            let src = example(name);
+            if let Some(out) = run(name, &lower(&texts, &src)) {
+                assert_eq!(out, eval(&texts, &src));
+            }
+        }
+    }
+
+    #[test]
+    fn test_kitchen_sink() {
+        let texts = Texts::new();
+        if let Some(out) = run("sink", &lower(&texts, KITCHEN_SINK)) {
+            assert_eq!(out, eval(&texts, KITCHEN_SINK));
+        }
+        if let Some(out) = run("sink-opt", &optimize(&texts, KITCHEN_SINK)) {
+            assert_eq!(out, eval(&texts, KITCHEN_SINK));
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let texts = Texts::new();
+        let asm = compile(&lower(&texts, "1 / (1 - 1);")).unwrap();
+        let exe = std::env::temp_dir().join(format!("hc-x86_64-div-{}", std::process::id()));
+        match build(&asm, &exe) {
+            Ok(()) => {
+                let out = Command::new(&exe).output().unwrap();
+                fs::remove_file(&exe).unwrap();
+                assert_eq!(out.status.code(), Some(1));
+                assert!(String::from_utf8_lossy(&out.stderr).contains("division by zero"));
+            }
+            Err(e) => skip(&format!("could not build: {}", e)),
+        }
+    }
+
+    #[test]
+    fn test_string_comparison() {
+        let texts = Texts::new();
+        let src = "\"b\" > \"a\"; \"ab\" < \"b\"; \"x\" == \"x\"; \"x\" != \"y\"; \"b\" <= \"a\";";
+        let program = lower(&texts, src);
+        // Strings are compared by their contents, not their addresses
+        assert!(compile(&program).unwrap().contains("call hl_strcmp"));
+        if let Some(out) = run("strings", &program) {
+            assert_eq!(out, eval(&texts, src));
+        }
+    }
+}
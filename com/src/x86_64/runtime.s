@@ -0,0 +1,106 @@
+# Minimal runtime for the x86-64 backend.
+# Every function here follows the System V ABI and is called with an
+# aligned stack by the generated code.
+
+    .section .rodata
+hl_fmt_int:  .asciz "%ld\n"
+hl_fmt_err:  .asciz "runtime error: %s\n"
+hl_str_true:  .asciz "true"
+hl_str_false: .asciz "false"
+hl_str_unit:  .asciz "()"
+hl_str_fn:    .asciz "<function>"
+hl_msg_oom:  .asciz "out of memory"
+hl_msg_div:  .asciz "division by zero"
+
+    .text
+
+# void *hl_alloc(size_t size)
+    .globl hl_alloc
+hl_alloc:
+    pushq %rbp
+    movq %rsp, %rbp
+    call malloc@PLT
+    testq %rax, %rax
+    jz 1f
+    popq %rbp
+    ret
+1:
+    leaq hl_msg_oom(%rip), %rdi
+    call hl_panic
+
+# void hl_panic(const char *msg)
+    .globl hl_panic
+hl_panic:
+    pushq %rbp
+    movq %rsp, %rbp
+    movq %rdi, %rdx
+    movq stderr@GOTPCREL(%rip), %rax
+    movq (%rax), %rdi
+    leaq hl_fmt_err(%rip), %rsi
+    xorl %eax, %eax
+    call fprintf@PLT
+    movl $1, %edi
+    call exit@PLT
+
+# void hl_div_zero(void)
+    .globl hl_div_zero
+hl_div_zero:
+    pushq %rbp
+    movq %rsp, %rbp
+    leaq hl_msg_div(%rip), %rdi
+    call hl_panic
+
+# void hl_print_int(int64_t x)
+    .globl hl_print_int
+hl_print_int:
+    pushq %rbp
+    movq %rsp, %rbp
+    movq %rdi, %rsi
+    leaq hl_fmt_int(%rip), %rdi
+    xorl %eax, %eax
+    call printf@PLT
+    popq %rbp
+    ret
+
+# void hl_print_bool(int64_t b)
+    .globl hl_print_bool
+hl_print_bool:
+    leaq hl_str_false(%rip), %rax
+    leaq hl_str_true(%rip), %rcx
+    testq %rdi, %rdi
+    cmovnz %rcx, %rax
+    movq %rax, %rdi
+    jmp hl_print_str
+
+# void hl_print_unit(int64_t _)
+    .globl hl_print_unit
+hl_print_unit:
+    leaq hl_str_unit(%rip), %rdi
+    jmp hl_print_str
+
+# void hl_print_fn(void *closure)
+    .globl hl_print_fn
+hl_print_fn:
+    leaq hl_str_fn(%rip), %rdi
+    jmp hl_print_str
+
+# void hl_print_str(const char *s)
+    .globl hl_print_str
+hl_print_str:
+    pushq %rbp
+    movq %rsp, %rbp
+    call puts@PLT
+    popq %rbp
+    ret
+
+# int64_t hl_strcmp(const char *a, const char *b)
+    .globl hl_strcmp
+hl_strcmp:
+    pushq %rbp
+    movq %rsp, %rbp
+    call strcmp@PLT
+    movslq %eax, %rax
+    popq %rbp
+    ret
+
+    .section .note.GNU-stack,"",@progbits
@@ -0,0 +1,152 @@
+//! Conversion to A-normal form.
+//!
+//! After the pass, the operands of calls and operators and the conditions of
+//! `if`s are atoms (literals, variables or lambdas), every intermediate
+//! result is named by a `let`, and `&&`/`||` are turned into `if`s, so the
+//! evaluation order is the nesting order of the `let`s.
+
+use crate::{BinOp, Expr, ExprKind, Lit, Var, VarGen};
+
+/// Convert a lowered program to A-normal form
+pub fn anf(program: Vec<Expr>) -> Vec<Expr> {
+    let mut anf = Anf { vars: VarGen::after(&program) };
+    program.into_iter().map(|e| anf.expr(e)).collect()
+}
+
+/// Whether an expression is an atom, that is it can be evaluated without
+/// side effects or evaluating other expressions first
+pub fn is_atom(e: &Expr) -> bool {
+    matches!(e.kind, ExprKind::Lit(_) | ExprKind::Var(_) | ExprKind::Lambda { .. })
+}
+
+/// Whether an expression is in the form produced by [`anf`]
+pub fn is_anf(e: &Expr) -> bool {
+    let mut ok = true;
+    e.walk(&mut |e| ok &= match &e.kind {
+        ExprKind::Unary(_, x) => is_atom(x),
+        ExprKind::Binary(op, l, r) => {
+            !matches!(op, BinOp::And | BinOp::Or) && is_atom(l) && is_atom(r)
+        }
+        ExprKind::Call { func, args } => is_atom(func) && args.iter().all(is_atom),
+        ExprKind::If { cond, .. } => is_atom(cond),
+        ExprKind::Closure { env, .. } => env.iter().all(is_atom),
+        ExprKind::EnvGet { closure, .. } => is_atom(closure),
+        ExprKind::Let { value, .. } => !matches!(value.kind, ExprKind::Let { .. }),
+        _ => true,
+    });
+    ok
+}
+
+struct Anf {
+    vars: VarGen,
+}
+
+type Binds<'src> = Vec<(Var<'src>, Expr<'src>)>;
+
+impl<'src> Anf {
+    /// Convert an expression in a position where it can be wrapped in `let`s
+    fn expr(&mut self, e: Expr<'src>) -> Expr<'src> {
+        let span = e.span;
+        let mut binds = vec![];
+        let body = self.cexp(e, &mut binds);
+        binds.into_iter().rev().fold(body, |body, (var, value)| {
+            let ty = body.ty.clone();
+            Expr::new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty, span)
+        })
+    }
+
+    /// Convert an expression whose operands are atoms, pushing the bindings
+    /// that have to be evaluated before it to `binds`
+    fn cexp(&mut self, e: Expr<'src>, binds: &mut Binds<'src>) -> Expr<'src> {
+        let Expr { kind, ty, span } = e;
+        let kind = match kind {
+            ExprKind::Lit(_) | ExprKind::Var(_) => kind,
+            ExprKind::Lambda { params, body } => ExprKind::Lambda {
+                params,
+                body: Box::new(self.expr(*body)),
+            },
+            ExprKind::Unary(op, x) => ExprKind::Unary(op, Box::new(self.atom(*x, binds))),
+            ExprKind::Binary(BinOp::And, l, r) => ExprKind::If {
+                cond: Box::new(self.atom(*l, binds)),
+                t: Box::new(self.expr(*r)),
+                f: Box::new(Expr::new(ExprKind::Lit(Lit::Bool(false)), ty.clone(), span)),
+            },
+            ExprKind::Binary(BinOp::Or, l, r) => ExprKind::If {
+                cond: Box::new(self.atom(*l, binds)),
+                t: Box::new(Expr::new(ExprKind::Lit(Lit::Bool(true)), ty.clone(), span)),
+                f: Box::new(self.expr(*r)),
+            },
+            ExprKind::Binary(op, l, r) => {
+                let l = self.atom(*l, binds);
+                let r = self.atom(*r, binds);
+                ExprKind::Binary(op, Box::new(l), Box::new(r))
+            }
+            ExprKind::Call { func, args } => {
+                let func = self.atom(*func, binds);
+                let args = args.into_iter().map(|a| self.atom(a, binds)).collect();
+                ExprKind::Call { func: Box::new(func), args }
+            }
+            ExprKind::If { cond, t, f } => ExprKind::If {
+                cond: Box::new(self.atom(*cond, binds)),
+                t: Box::new(self.expr(*t)),
+                f: Box::new(self.expr(*f)),
+            },
+            ExprKind::Let { var, value, body } => {
+                let value = self.cexp(*value, binds);
+                binds.push((var, value));
+                return self.cexp(*body, binds);
+            }
+            ExprKind::Define { var, value } => ExprKind::Define {
+                var,
+                value: Box::new(self.expr(*value)),
+            },
+            ExprKind::Block { exprs, void } => ExprKind::Block {
+                exprs: exprs.into_iter().map(|e| self.expr(e)).collect(),
+                void,
+            },
+            ExprKind::Closure { func, env } => ExprKind::Closure {
+                func,
+                env: env.into_iter().map(|e| self.atom(e, binds)).collect(),
+            },
+            ExprKind::EnvGet { closure, index } => ExprKind::EnvGet {
+                closure: Box::new(self.atom(*closure, binds)),
+                index,
+            },
+        };
+        Expr::new(kind, ty, span)
+    }
+
+    /// Convert an expression to an atom, naming it if it isn't one
+    fn atom(&mut self, e: Expr<'src>, binds: &mut Binds<'src>) -> Expr<'src> {
+        let e = self.cexp(e, binds);
+        if is_atom(&e) {
+            return e;
+        }
+        let var = self.vars.fresh("tmp", e.ty.clone());
+        let atom = Expr::new(ExprKind::Var(var.clone()), e.ty.clone(), e.span);
+        binds.push((var, e));
+        atom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pretty::pretty_expr, testing::{assert_preserves, lower}};
+
+    #[test]
+    fn test_anf_preserves_semantics() {
+        for program in assert_preserves(anf) {
+            assert!(program.iter().all(is_anf));
+        }
+    }
+
+    #[test]
+    fn test_anf_order() {
+        let program = anf(lower("let f = fun (a Int, b Int) -> a - b; f(f(1, 2), 3 * 4);"));
+        assert_eq!(
+            pretty_expr(&program[1]),
+            "let tmp%3: Int = f%0(1, 2) in\nlet tmp%4: Int = 3 * 4 in\nf%0(tmp%3, tmp%4)",
+        );
+    }
+}
@@ -0,0 +1,366 @@
+//! Free variable analysis, lambda lifting and closure conversion.
+//!
+//! After [`closure_convert`] every lambda is the value of a top-level
+//! `define` and has no free variables other than globals:
+//!
+//! - a lambda bound to a name that is only ever called is lifted to a new
+//!   top-level function which takes the variables it captured as extra
+//!   leading parameters, and its calls pass them along,
+//! - any other lambda that captures variables becomes a
+//!   [`ExprKind::Closure`] record of a top-level function and the captured
+//!   values. The function takes the record as its first parameter and
+//!   reads the captured values from it with [`ExprKind::EnvGet`],
+//! - a lambda that captures nothing is lifted as is.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{Expr, ExprKind, Lit, Span, Type, Var, VarGen, VarId};
+
+/// Collect the variables an expression refers to without binding them
+/// itself, in order of appearance
+pub fn free_vars<'src>(e: &Expr<'src>) -> Vec<Var<'src>> {
+    fn walk<'src>(e: &Expr<'src>, bound: &mut HashSet<VarId>, used: &mut Vec<Var<'src>>) {
+        match &e.kind {
+            ExprKind::Var(v) | ExprKind::Closure { func: v, .. } if !used.contains(v) => {
+                used.push(v.clone())
+            }
+            ExprKind::Lambda { params, .. } => bound.extend(params.iter().map(|p| p.id)),
+            ExprKind::Let { var, .. } | ExprKind::Define { var, .. } => { bound.insert(var.id); }
+            _ => {},
+        }
+        e.children().into_iter().for_each(|c| walk(c, bound, used));
+    }
+    // Variables are unique, so anything bound anywhere inside is not free
+    let mut bound = HashSet::new();
+    let mut used = vec![];
+    walk(e, &mut bound, &mut used);
+    used.retain(|v| !bound.contains(&v.id));
+    used
+}
+
+/// The variables that are used other than by calling them
+fn escaping(program: &[Expr]) -> HashSet<VarId> {
+    fn walk(e: &Expr, out: &mut HashSet<VarId>) {
+        match &e.kind {
+            ExprKind::Var(v) => { out.insert(v.id); }
+            ExprKind::Call { func, args } if matches!(func.kind, ExprKind::Var(_)) => {
+                args.iter().for_each(|a| walk(a, out));
+            }
+            _ => e.children().into_iter().for_each(|c| walk(c, out)),
+        }
+    }
+    let mut out = HashSet::new();
+    program.iter().for_each(|e| walk(e, &mut out));
+    out
+}
+
+/// Lift every lambda of a program to the top level, see the module
+/// documentation
+pub fn closure_convert(program: Vec<Expr>) -> Vec<Expr> {
+    let mut cc = Converter {
+        vars: VarGen::after(&program),
+        globals: program.iter()
+            .filter_map(|e| match &e.kind {
+                ExprKind::Define { var, .. } => Some(var.id),
+                _ => None,
+            })
+            .collect(),
+        escaping: escaping(&program),
+        lifted: HashMap::new(),
+        defs: vec![],
+    };
+    let mut out = vec![];
+    for e in program {
+        let e = match e.kind {
+            // Top-level functions only refer to globals already
+            ExprKind::Define { var, value } if value.is_lambda() => {
+                let value = value.map_children(|e| cc.expr(e));
+                Expr::new(ExprKind::Define { var, value: Box::new(value) }, e.ty, e.span)
+            }
+            kind => cc.expr(Expr::new(kind, e.ty, e.span)),
+        };
+        out.append(&mut cc.defs);
+        out.push(e);
+    }
+    out
+}
+
+struct Converter<'src> {
+    vars: VarGen,
+    globals: HashSet<VarId>,
+    escaping: HashSet<VarId>,
+    // The top-level function each lifted lambda was replaced with, and the
+    // variables to pass it before the arguments
+    lifted: HashMap<VarId, (Var<'src>, Vec<Var<'src>>)>,
+    // Functions lifted out of the current top-level expression
+    defs: Vec<Expr<'src>>,
+}
+
+fn var_expr<'src>(var: &Var<'src>, span: Span) -> Expr<'src> {
+    Expr::new(ExprKind::Var(var.clone()), var.ty.clone(), span)
+}
+
+impl<'src> Converter<'src> {
+    fn expr(&mut self, e: Expr<'src>) -> Expr<'src> {
+        let Expr { kind, ty, span } = e;
+        match kind {
+            ExprKind::Let { var, value, body } if self.liftable(&var, &value) => {
+                self.lift(&var, *value);
+                self.expr(*body)
+            }
+            ExprKind::Let { var, value, body } if value.is_lambda() => {
+                let value = self.convert(*value, None);
+                let body = self.expr(*body);
+                Expr::new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty, span)
+            }
+            ExprKind::Define { var, value } if value.is_lambda() => {
+                let value = self.convert(*value, Some(&var));
+                Expr::new(ExprKind::Define { var, value: Box::new(value) }, ty, span)
+            }
+            ExprKind::Block { exprs, mut void } => {
+                let n = exprs.len();
+                let mut out = vec![];
+                for (i, e) in exprs.into_iter().enumerate() {
+                    match e.kind {
+                        ExprKind::Define { var, value } if self.liftable(&var, &value) => {
+                            self.lift(&var, *value);
+                            // The block evaluated to the `define`, that is ()
+                            void |= i + 1 == n;
+                        }
+                        kind => out.push(self.expr(Expr::new(kind, e.ty, e.span))),
+                    }
+                }
+                if out.is_empty() {
+                    out.push(Expr::new(ExprKind::Lit(Lit::Unit), Type::Unit, span));
+                }
+                Expr::new(ExprKind::Block { exprs: out, void }, ty, span)
+            }
+            ExprKind::Lambda { .. } => self.convert(Expr::new(kind, ty, span), None),
+            ExprKind::Call { func, args } => {
+                let extra = match &func.kind {
+                    ExprKind::Var(v) => self.lifted.get(&v.id).cloned(),
+                    _ => None,
+                };
+                let (func, args) = match extra {
+                    Some((lifted, captures)) => {
+                        let args = captures.iter()
+                            .map(|v| var_expr(v, span))
+                            .chain(args.into_iter().map(|a| self.expr(a)))
+                            .collect();
+                        (var_expr(&lifted, func.span), args)
+                    }
+                    None => (self.expr(*func), args.into_iter().map(|a| self.expr(a)).collect()),
+                };
+                Expr::new(ExprKind::Call { func: Box::new(func), args }, ty, span)
+            }
+            kind => Expr::new(kind, ty, span).map_children(|e| self.expr(e)),
+        }
+    }
+
+    /// Whether a binding is a lambda that is only ever called
+    fn liftable(&self, var: &Var<'src>, value: &Expr<'src>) -> bool {
+        value.is_lambda() && !self.escaping.contains(&var.id) && !self.globals.contains(&var.id)
+    }
+
+    /// The local variables a lambda needs from its environment, including
+    /// the ones to pass to the lifted functions it calls
+    fn captures(&self, lambda: &Expr<'src>, this: Option<&Var<'src>>) -> Vec<Var<'src>> {
+        let mut out: Vec<Var<'src>> = vec![];
+        for v in free_vars(lambda) {
+            let vs = match self.lifted.get(&v.id) {
+                Some((_, captures)) => captures.clone(),
+                None if self.globals.contains(&v.id) || Some(&v) == this => vec![],
+                None => vec![v],
+            };
+            for v in vs {
+                if !out.contains(&v) {
+                    out.push(v);
+                }
+            }
+        }
+        out
+    }
+
+    /// Define a new top-level function and return the variable naming it
+    fn define(&mut self, name: &'src str, params: Vec<Var<'src>>, body: Expr<'src>) -> Var<'src> {
+        let span = body.span;
+        let ty = Type::Func(params.iter().map(|p| p.ty.clone()).collect(), Box::new(body.ty.clone()));
+        let var = self.vars.fresh(name, ty.clone());
+        self.globals.insert(var.id);
+        let lambda = Expr::new(ExprKind::Lambda { params, body: Box::new(body) }, ty, span);
+        self.defs.push(Expr::new(
+            ExprKind::Define { var: var.clone(), value: Box::new(lambda) },
+            Type::Unit,
+            span,
+        ));
+        var
+    }
+
+    /// Lift a lambda that is only ever called, passing it what it captured
+    /// as extra parameters
+    fn lift(&mut self, var: &Var<'src>, lambda: Expr<'src>) {
+        let captures = self.captures(&lambda, Some(var));
+        let ExprKind::Lambda { params, body } = lambda.kind else { unreachable!() };
+
+        // Register the function first so that recursive calls get the
+        // extra arguments too
+        let ty = Type::Func(
+            captures.iter().chain(&params).map(|p| p.ty.clone()).collect(),
+            Box::new(body.ty.clone()),
+        );
+        let lifted = self.vars.fresh(var.name, ty);
+        self.globals.insert(lifted.id);
+        self.lifted.insert(var.id, (lifted.clone(), captures.clone()));
+
+        let mut body = self.expr(*body);
+        let renames = captures.iter()
+            .map(|v| (v.id, self.vars.fresh(v.name, v.ty.clone())))
+            .collect::<HashMap<_, _>>();
+        body.rename(&renames);
+
+        let params = captures.iter().map(|v| renames[&v.id].clone()).chain(params).collect();
+        let span = body.span;
+        let lambda = Expr::new(ExprKind::Lambda { params, body: Box::new(body) }, lifted.ty.clone(), span);
+        self.defs.push(Expr::new(
+            ExprKind::Define { var: lifted, value: Box::new(lambda) },
+            Type::Unit,
+            span,
+        ));
+    }
+
+    /// Turn a lambda into a closure record, or a reference to a top-level
+    /// function if it captures nothing. `this` is the variable the lambda
+    /// is defined as, which it may refer to recursively
+    fn convert(&mut self, lambda: Expr<'src>, this: Option<&Var<'src>>) -> Expr<'src> {
+        let captures = self.captures(&lambda, this);
+        let Expr { kind: ExprKind::Lambda { params, body }, ty, span } = lambda else { unreachable!() };
+        let name = this.map_or("lambda", |v| v.name);
+        let mut body = self.expr(*body);
+
+        let recursive = this.is_some_and(|this| free_vars(&body).contains(this));
+        if captures.is_empty() && !recursive {
+            let func = self.define(name, params, body);
+            return var_expr(&func, span);
+        }
+
+        // Read the captured values from the record, which also stands for
+        // the function itself
+        let record = self.vars.fresh(name, ty.clone());
+        let mut renames = HashMap::new();
+        if let Some(this) = this {
+            renames.insert(this.id, record.clone());
+        }
+        let fields = captures.iter()
+            .map(|v| {
+                let field = self.vars.fresh(v.name, v.ty.clone());
+                renames.insert(v.id, field.clone());
+                field
+            })
+            .collect::<Vec<_>>();
+        body.rename(&renames);
+        let body = fields.into_iter().enumerate().rev().fold(body, |body, (index, field)| {
+            let get = ExprKind::EnvGet { closure: Box::new(var_expr(&record, span)), index };
+            let value = Expr::new(get, field.ty.clone(), span);
+            let ty = body.ty.clone();
+            Expr::new(ExprKind::Let { var: field, value: Box::new(value), body: Box::new(body) }, ty, span)
+        });
+
+        let params = std::iter::once(record).chain(params).collect();
+        let func = self.define(name, params, body);
+        let env = captures.iter().map(|v| var_expr(v, span)).collect();
+        Expr::new(ExprKind::Closure { func, env }, ty, span)
+    }
+}
+
+/// Whether no lambda of a program has local free variables, which is what
+/// [`closure_convert`] produces
+pub fn is_closed(program: &[Expr]) -> bool {
+    let globals = program.iter()
+        .filter_map(|e| match &e.kind {
+            ExprKind::Define { var, .. } => Some(var.id),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let mut ok = true;
+    for e in program {
+        let top = match &e.kind {
+            ExprKind::Define { value, .. } if value.is_lambda() => &**value,
+            _ => e,
+        };
+        top.walk(&mut |e| {
+            if e.is_lambda() && !std::ptr::eq(e, top) {
+                ok = false;
+            }
+        });
+        if top.is_lambda() {
+            ok &= free_vars(top).iter().all(|v| globals.contains(&v.id));
+        }
+    }
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{anf::anf, pretty::pretty, testing::{assert_preserves, lower}};
+
+    #[test]
+    fn test_free_vars() {
+        let program = lower("let x = 1 in let y = 2 in fun (z Int) -> x + z + y + x;");
+        let ExprKind::Let { body, .. } = &program[0].kind else { panic!() };
+        let ExprKind::Let { body, .. } = &body.kind else { panic!() };
+        let names = free_vars(body).iter().map(|v| v.name).collect::<Vec<_>>();
+        assert_eq!(names, vec!["x", "y"]);
+    }
+
+    #[test]
+    fn test_closure_convert_preserves_semantics() {
+        for program in assert_preserves(closure_convert) {
+            assert!(is_closed(&program), "{}", pretty(&program));
+        }
+        assert_preserves(|p| closure_convert(anf(p)));
+    }
+
+    #[test]
+    fn test_lambda_lifting() {
+        let program = closure_convert(lower("
+            let x = 4 in { let f = fun (y Int) -> x * y; f(x) };
+        "));
+        assert_eq!(pretty(&program), "\
+let f%3: (Int Int) -> Int = fun (x%4: Int, y%2: Int) ->
+    x%4 * y%2;
+let x%0: Int = 4 in
+{
+    f%3(x%0, x%0)
+};
+");
+    }
+
+    #[test]
+    fn test_closure_records() {
+        let program = closure_convert(lower("
+            let adder = fun (n Int) -> fun (x Int) -> x + n;
+            let add = adder(5);
+            let k = 3 in { let down = fun (n Int) Int -> if n > 0 then down(n - k) else n; down };
+        "));
+        assert_eq!(pretty(&program), "\
+let lambda%9: ((Int) -> Int Int) -> Int = fun (lambda%7: (Int) -> Int, x%3: Int) ->
+    let n%8: Int = lambda%7.0 in
+    x%3 + n%8;
+let adder%0: (Int) -> (Int) -> Int = fun (n%2: Int) ->
+    closure lambda%9 [n%2];
+let add%1: (Int) -> Int = adder%0(5);
+let down%12: ((Int) -> Int Int) -> Int = fun (down%10: (Int) -> Int, n%6: Int) ->
+    let k%11: Int = down%10.0 in
+    if n%6 > 0 then
+        down%10(n%6 - k%11)
+    else
+        n%6;
+let k%4: Int = 3 in
+{
+    let down%5: (Int) -> Int = closure down%12 [k%4];
+    down%5
+};
+");
+    }
+}
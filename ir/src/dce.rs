@@ -0,0 +1,180 @@
+//! Dead code elimination.
+//!
+//! Removes the `let`s and local `define`s whose variable is never used,
+//! the elements of blocks whose value is discarded, the branches of `if`s
+//! that can't be taken, and the top-level `define`s that the top-level
+//! expressions don't need. Only pure expressions are removed, so a program
+//! that fails at runtime still does.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{closure::free_vars, BinOp, Expr, ExprKind, Lit, Type, VarId};
+
+/// How many times each variable is used
+pub fn usage_counts(program: &[Expr]) -> HashMap<VarId, usize> {
+    let mut counts = HashMap::new();
+    for e in program {
+        e.walk(&mut |e| match &e.kind {
+            ExprKind::Var(v) | ExprKind::Closure { func: v, .. } => {
+                *counts.entry(v.id).or_default() += 1;
+            }
+            _ => {}
+        });
+    }
+    counts
+}
+
+/// Whether evaluating an expression can't fail, loop or be observed
+pub fn is_pure(e: &Expr) -> bool {
+    match &e.kind {
+        ExprKind::Lit(_) | ExprKind::Var(_) | ExprKind::Lambda { .. } => true,
+        ExprKind::Call { .. } => false,
+        ExprKind::Binary(BinOp::Div | BinOp::Rem, l, r) => {
+            is_pure(l) && matches!(r.kind, ExprKind::Lit(Lit::Int(i)) if i != 0)
+        }
+        _ => e.children().into_iter().all(is_pure),
+    }
+}
+
+/// Remove the dead code of a program. With `keep_exports`, top-level
+/// `define`s are kept even if the program doesn't use them, as a library
+/// would need them
+pub fn dce(mut program: Vec<Expr>, keep_exports: bool) -> Vec<Expr> {
+    // Removing code can make more bindings unused
+    loop {
+        let before = size(&program);
+        let counts = usage_counts(&program);
+        program = program.into_iter().map(|e| expr(e, &counts)).collect();
+        if !keep_exports {
+            program = remove_unreachable(program);
+        }
+        if size(&program) == before {
+            return program;
+        }
+    }
+}
+
+fn size(program: &[Expr]) -> usize {
+    let mut n = 0;
+    program.iter().for_each(|e| e.walk(&mut |_| n += 1));
+    n
+}
+
+/// Whether a binding of `var` to `value` is never used, not counting the
+/// recursive uses in its own value
+fn unused(var: VarId, value: &Expr, counts: &HashMap<VarId, usize>) -> bool {
+    let own = usage_counts(std::slice::from_ref(value)).get(&var).copied().unwrap_or(0);
+    counts.get(&var).copied().unwrap_or(0) == own
+}
+
+fn expr<'src>(e: Expr<'src>, counts: &HashMap<VarId, usize>) -> Expr<'src> {
+    let Expr { kind, ty, span } = e;
+    match kind {
+        ExprKind::Let { var, value, body } if is_pure(&value) && unused(var.id, &value, counts) => {
+            expr(*body, counts)
+        }
+        ExprKind::If { cond, t, f } => match cond.kind {
+            ExprKind::Lit(Lit::Bool(true)) => expr(*t, counts),
+            ExprKind::Lit(Lit::Bool(false)) => expr(*f, counts),
+            kind => {
+                let cond = Expr::new(kind, cond.ty, cond.span);
+                let e = ExprKind::If { cond: Box::new(cond), t, f };
+                Expr::new(e, ty, span).map_children(|e| expr(e, counts))
+            }
+        },
+        ExprKind::Block { exprs, void } => {
+            let n = exprs.len();
+            let exprs = exprs.into_iter()
+                .enumerate()
+                .filter(|(i, e)| match &e.kind {
+                    ExprKind::Define { var, value } => {
+                        !(is_pure(value) && unused(var.id, value, counts))
+                    }
+                    // Only the value of the last element of a block is used
+                    _ => !is_pure(e) || (i + 1 == n && !void),
+                })
+                .map(|(_, e)| expr(e, counts))
+                .collect::<Vec<_>>();
+            if exprs.is_empty() {
+                Expr::new(ExprKind::Lit(Lit::Unit), Type::Unit, span)
+            } else {
+                Expr::new(ExprKind::Block { exprs, void }, ty, span)
+            }
+        }
+        kind => Expr::new(kind, ty, span).map_children(|e| expr(e, counts)),
+    }
+}
+
+/// Remove the pure top-level `define`s that no top-level expression needs
+fn remove_unreachable(program: Vec<Expr>) -> Vec<Expr> {
+    let mut needed = HashSet::new();
+    let mut todo = vec![];
+    for e in &program {
+        match &e.kind {
+            ExprKind::Define { value, .. } if is_pure(value) => {}
+            _ => todo.push(e),
+        }
+    }
+    while let Some(e) = todo.pop() {
+        for v in free_vars(e) {
+            if needed.insert(v.id) {
+                todo.extend(program.iter().filter(|e| match &e.kind {
+                    ExprKind::Define { var, .. } => var.id == v.id,
+                    _ => false,
+                }));
+            }
+        }
+    }
+    program.into_iter()
+        .filter(|e| match &e.kind {
+            ExprKind::Define { var, value } => needed.contains(&var.id) || !is_pure(value),
+            _ => true,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fold::fold,
+        inline::{inline, MAX_INLINE_SIZE},
+        pretty::pretty,
+        testing::{assert_preserves, lower},
+    };
+
+    #[test]
+    fn test_dce_preserves_semantics() {
+        assert_preserves(|p| dce(p, false));
+        assert_preserves(|p| dce(fold(inline(p, MAX_INLINE_SIZE)).0, false));
+    }
+
+    #[test]
+    fn test_dce_bindings() {
+        let program = dce(lower("
+            let unused = fun (x Int) -> x;
+            let f = fun (x Int) -> x / 0;
+            let x = 1 in let y = x + 1 in { 1 + 2; f(3); let z = 4; let g = fun (n Int) Int -> g(n); x };
+            { 2; };
+        "), false);
+        assert_eq!(pretty(&program), "\
+let f%1: (Int) -> Int = fun (x%3: Int) ->
+    x%3 / 0;
+let x%4: Int = 1 in
+{
+    f%1(3);
+    x%4
+};
+();
+");
+    }
+
+    #[test]
+    fn test_dce_keeps_exports() {
+        let src = "let a = 1; let b = fun (x Int) -> x * a; let c = 1 / 0; 7;";
+        let program = dce(lower(src), false);
+        assert_eq!(pretty(&program), "let c%2: Int = 1 / 0;\n7;\n");
+        let program = dce(lower(src), true);
+        assert_eq!(program.len(), 4);
+    }
+}
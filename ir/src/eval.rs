@@ -0,0 +1,406 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{Debug, Display, Formatter, Result as FmtResult},
+    rc::Rc,
+};
+
+use super::{BinOp, Expr, ExprKind, Lit, Span, UnOp, VarId};
+
+/// A runtime value of the evaluator.
+#[derive(Clone, Debug)]
+pub enum Value<'src> {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    // Owned, as strings can come from native functions
+    Str(Rc<str>),
+    // Only made by native functions, as there are no array literals
+    Array(Rc<[Value<'src>]>),
+    Closure(Rc<Closure<'src>>),
+    Native(Rc<Native<'src>>),
+}
+
+impl Display for Value<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Value::Unit       => write!(f, "()"),
+            Value::Bool(b)    => write!(f, "{}", b),
+            Value::Int(i)     => write!(f, "{}", i),
+            Value::Str(s)     => write!(f, "{}", s),
+            Value::Array(vs)  => {
+                write!(f, "[")?;
+                for (i, v) in vs.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, v)?;
+                }
+                write!(f, "]")
+            }
+            Value::Closure(_) | Value::Native(_) => write!(f, "<function>"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Closure<'src> {
+    params: Vec<VarId>,
+    body: Expr<'src>,
+    env: Env<'src>,
+    // The captured values of a closure record built by closure conversion,
+    // which is passed to its function as the first argument
+    captured: Option<Vec<Value<'src>>>,
+}
+
+/// A function of the program running the evaluator, which programs call
+/// like their own
+pub struct Native<'src> {
+    name: String,
+    arity: usize,
+    #[allow(clippy::type_complexity)]
+    f: Box<dyn Fn(Vec<Value<'src>>) -> Result<Value<'src>, EvalError>>,
+}
+
+impl<'src> Native<'src> {
+    pub fn new(
+        name: impl Into<String>,
+        arity: usize,
+        f: impl Fn(Vec<Value<'src>>) -> Result<Value<'src>, EvalError> + 'static,
+    ) -> Self {
+        Self { name: name.into(), arity, f: Box::new(f) }
+    }
+}
+
+impl Debug for Native<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "Native({}/{})", self.name, self.arity)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EvalError {
+    pub message: String,
+    /// The span of the expression that failed
+    pub span: Option<Span>,
+}
+
+impl EvalError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        Self { message: message.into(), span: None }
+    }
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.message)
+    }
+}
+
+type Env<'src> = Rc<Scope<'src>>;
+
+// A scope is mutable so that `define` can bind into the enclosing block,
+// which is what makes (mutually) recursive definitions work.
+#[derive(Debug, Default)]
+struct Scope<'src> {
+    vars: RefCell<HashMap<VarId, Value<'src>>>,
+    parent: Option<Env<'src>>,
+}
+
+impl<'src> Scope<'src> {
+    fn child(parent: &Env<'src>) -> Env<'src> {
+        Rc::new(Scope {
+            vars: RefCell::new(HashMap::new()),
+            parent: Some(parent.clone()),
+        })
+    }
+
+    fn get(&self, id: VarId) -> Option<Value<'src>> {
+        match self.vars.borrow().get(&id) {
+            Some(v) => Some(v.clone()),
+            None => self.parent.as_ref().and_then(|p| p.get(id)),
+        }
+    }
+
+    fn set(&self, id: VarId, v: Value<'src>) {
+        self.vars.borrow_mut().insert(id, v);
+    }
+}
+
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(EvalError::new(format!($($arg)*)))
+    };
+}
+
+/// A tree-walking evaluator over the IR, used as the reference semantics
+/// the backends are tested against.
+pub struct Evaluator<'src> {
+    globals: Env<'src>,
+}
+
+impl Default for Evaluator<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'src> Evaluator<'src> {
+    pub fn new() -> Self {
+        Self { globals: Rc::new(Scope::default()) }
+    }
+
+    /// Evaluate a top-level expression, `define`s bind into the global scope
+    pub fn eval(&mut self, e: &Expr<'src>) -> Result<Value<'src>, EvalError> {
+        let env = self.globals.clone();
+        eval_expr(e, &env)
+    }
+
+    /// Bind a global variable to a value, as if it was defined
+    pub fn define(&mut self, var: VarId, value: Value<'src>) {
+        self.globals.set(var, value);
+    }
+
+    /// The value of a global variable, if it was defined
+    pub fn get(&self, var: VarId) -> Option<Value<'src>> {
+        self.globals.get(var)
+    }
+}
+
+fn int<'src>(v: Value<'src>) -> Result<i64, EvalError> {
+    match v {
+        Value::Int(i) => Ok(i),
+        v => bail!("expected an integer, found {}", v),
+    }
+}
+
+fn bool<'src>(v: Value<'src>) -> Result<bool, EvalError> {
+    match v {
+        Value::Bool(b) => Ok(b),
+        v => bail!("expected a boolean, found {}", v),
+    }
+}
+
+fn compare<'src>(op: BinOp, l: &Value<'src>, r: &Value<'src>) -> Result<bool, EvalError> {
+    use std::cmp::Ordering;
+    let ord = match (l, r) {
+        (Value::Unit, Value::Unit)       => Ordering::Equal,
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Int(a), Value::Int(b))   => a.cmp(b),
+        (Value::Str(a), Value::Str(b))   => a.cmp(b),
+        (Value::Closure(a), Value::Closure(b)) => match op {
+            BinOp::Eq => return Ok(Rc::ptr_eq(a, b)),
+            BinOp::Ne => return Ok(!Rc::ptr_eq(a, b)),
+            _ => bail!("functions can only be compared for equality"),
+        },
+        (Value::Array(a), Value::Array(b)) => match op {
+            BinOp::Eq | BinOp::Ne => {
+                let mut eq = a.len() == b.len();
+                for (a, b) in a.iter().zip(b.iter()) {
+                    if !eq {
+                        break;
+                    }
+                    eq = compare(BinOp::Eq, a, b)?;
+                }
+                return Ok(eq == (op == BinOp::Eq));
+            }
+            _ => bail!("arrays can only be compared for equality"),
+        },
+        (l, r) => bail!("cannot compare {} with {}", l, r),
+    };
+    Ok(match op {
+        BinOp::Eq => ord.is_eq(),
+        BinOp::Ne => ord.is_ne(),
+        BinOp::Lt => ord.is_lt(),
+        BinOp::Le => ord.is_le(),
+        BinOp::Gt => ord.is_gt(),
+        BinOp::Ge => ord.is_ge(),
+        _ => unreachable!(),
+    })
+}
+
+/// Apply an arithmetic operator with the wrapping semantics of the language
+pub fn arith(op: BinOp, l: i64, r: i64) -> Result<i64, EvalError> {
+    Ok(match op {
+        BinOp::Add => l.wrapping_add(r),
+        BinOp::Sub => l.wrapping_sub(r),
+        BinOp::Mul => l.wrapping_mul(r),
+        BinOp::Div if r == 0 => bail!("division by zero"),
+        BinOp::Div => l.wrapping_div(r),
+        BinOp::Rem if r == 0 => bail!("division by zero"),
+        BinOp::Rem => l.wrapping_rem(r),
+        _ => unreachable!(),
+    })
+}
+
+fn eval_expr<'src>(e: &Expr<'src>, env: &Env<'src>) -> Result<Value<'src>, EvalError> {
+    // The innermost expression that fails is the one the error points to
+    eval_kind(e, env).map_err(|err| EvalError { span: err.span.or(Some(e.span)), ..err })
+}
+
+fn eval_kind<'src>(e: &Expr<'src>, env: &Env<'src>) -> Result<Value<'src>, EvalError> {
+    match &e.kind {
+        ExprKind::Lit(l) => Ok(match l {
+            Lit::Unit    => Value::Unit,
+            Lit::Bool(b) => Value::Bool(*b),
+            Lit::Int(i)  => Value::Int(*i),
+            Lit::Str(s)  => Value::Str(Rc::from(*s)),
+        }),
+        ExprKind::Var(v) => match env.get(v.id) {
+            Some(v) => Ok(v),
+            None => bail!("`{}` is not defined", v.name),
+        },
+        ExprKind::If { cond, t, f } => {
+            if bool(eval_expr(cond, env)?)? {
+                eval_expr(t, env)
+            } else {
+                eval_expr(f, env)
+            }
+        }
+        ExprKind::Let { var, value, body } => {
+            let value = eval_expr(value, env)?;
+            let scope = Scope::child(env);
+            scope.set(var.id, value);
+            eval_expr(body, &scope)
+        }
+        ExprKind::Define { var, value } => {
+            let value = eval_expr(value, env)?;
+            env.set(var.id, value);
+            Ok(Value::Unit)
+        }
+        ExprKind::Lambda { params, body } => Ok(Value::Closure(Rc::new(Closure {
+            params: params.iter().map(|p| p.id).collect(),
+            body: (**body).clone(),
+            env: env.clone(),
+            captured: None,
+        }))),
+        ExprKind::Closure { func, env: captured } => {
+            let Some(Value::Closure(c)) = env.get(func.id) else {
+                bail!("`{}` is not a function", func.name);
+            };
+            let captured = captured.iter()
+                .map(|e| eval_expr(e, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Value::Closure(Rc::new(Closure {
+                params: c.params.clone(),
+                body: c.body.clone(),
+                env: c.env.clone(),
+                captured: Some(captured),
+            })))
+        }
+        ExprKind::EnvGet { closure, index } => match eval_expr(closure, env)? {
+            Value::Closure(c) => match c.captured.as_ref().and_then(|vs| vs.get(*index)) {
+                Some(v) => Ok(v.clone()),
+                None => bail!("closure has no captured value {}", index),
+            },
+            v => bail!("{} is not a closure", v),
+        },
+        ExprKind::Block { exprs, void } => {
+            let scope = Scope::child(env);
+            let mut last = Value::Unit;
+            for e in exprs {
+                last = eval_expr(e, &scope)?;
+            }
+            Ok(if *void { Value::Unit } else { last })
+        }
+        ExprKind::Unary(UnOp::Neg, x) => Ok(Value::Int(int(eval_expr(x, env)?)?.wrapping_neg())),
+        ExprKind::Unary(UnOp::Not, x) => Ok(Value::Bool(!bool(eval_expr(x, env)?)?)),
+        ExprKind::Binary(BinOp::And, l, r) => Ok(Value::Bool(
+            bool(eval_expr(l, env)?)? && bool(eval_expr(r, env)?)?
+        )),
+        ExprKind::Binary(BinOp::Or, l, r) => Ok(Value::Bool(
+            bool(eval_expr(l, env)?)? || bool(eval_expr(r, env)?)?
+        )),
+        ExprKind::Binary(op, l, r) if op.is_comparison() => {
+            let l = eval_expr(l, env)?;
+            let r = eval_expr(r, env)?;
+            Ok(Value::Bool(compare(*op, &l, &r)?))
+        }
+        ExprKind::Binary(op, l, r) => {
+            let l = int(eval_expr(l, env)?)?;
+            let r = int(eval_expr(r, env)?)?;
+            Ok(Value::Int(arith(*op, l, r)?))
+        }
+        ExprKind::Call { func, args } => {
+            let f = eval_expr(func, env)?;
+            let args = args.iter()
+                .map(|a| eval_expr(a, env))
+                .collect::<Result<Vec<_>, _>>()?;
+            apply(f, args)
+        }
+    }
+}
+
+/// Call a function with arguments
+pub fn apply<'src>(f: Value<'src>, mut args: Vec<Value<'src>>) -> Result<Value<'src>, EvalError> {
+    match f {
+        Value::Native(n) => {
+            if n.arity != args.len() {
+                bail!("expected {} arguments, found {}", n.arity, args.len());
+            }
+            (n.f)(args)
+        }
+        Value::Closure(c) => {
+            if c.captured.is_some() {
+                args.insert(0, Value::Closure(c.clone()));
+            }
+            if c.params.len() != args.len() {
+                bail!("expected {} arguments, found {}", c.params.len(), args.len());
+            }
+            let scope = Scope::child(&c.env);
+            c.params.iter()
+                .zip(args)
+                .for_each(|(p, a)| scope.set(*p, a));
+            eval_expr(&c.body, &scope)
+        }
+        v => bail!("{} is not a function", v),
+    }
+}
+
+/// Evaluate a whole program and return the values of its top-level
+/// expressions (everything except `define`s), in order
+pub fn eval_exprs<'src>(es: &[Expr<'src>]) -> Result<Vec<Value<'src>>, EvalError> {
+    let mut ev = Evaluator::new();
+    let mut values = vec![];
+    for e in es {
+        let v = ev.eval(e)?;
+        if !e.is_define() {
+            values.push(v);
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntax::source::FileId;
+
+    use crate::testing::lower;
+
+    fn run(src: &str) -> Result<Vec<String>, EvalError> {
+        Ok(eval_exprs(&lower(src))?.iter().map(|v| v.to_string()).collect())
+    }
+
+    #[test]
+    fn test_eval_recursion() {
+        let values = run("
+            let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1;
+            fact(5);
+        ").unwrap();
+        assert_eq!(values, vec!["120"]);
+    }
+
+    #[test]
+    fn test_eval_shadowing() {
+        let values = run("let x = 4 in { let x = x + 1; let f = fun (y Int) -> x * y; f(x) };").unwrap();
+        assert_eq!(values, vec!["25"]);
+    }
+
+    #[test]
+    fn test_eval_division_by_zero() {
+        assert!(run("1 / 0;").is_err());
+        let src = "let f = fun (n Int) -> 10 / n; 1 + f(0);";
+        let err = run(src).unwrap_err();
+        let start = src.find("10 / n").unwrap();
+        assert_eq!(err.message, "division by zero");
+        assert_eq!(err.span, Some(Span::new(FileId::default(), start, start + 6)));
+    }
+}
@@ -0,0 +1,212 @@
+//! Constant folding and propagation.
+//!
+//! Operators applied to literals are evaluated, `if`s on a literal condition
+//! are replaced by the branch taken, `&&`/`||` with a literal left operand
+//! are simplified, and variables bound to a literal by a `let` or a local
+//! `define` are replaced by it. The bindings themselves are left for dead
+//! code elimination.
+//!
+//! Operations that would fail or overflow at runtime are not folded, and
+//! are reported as warnings instead.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{BinOp, Expr, ExprKind, Lit, Span, UnOp, VarId};
+
+/// Something suspicious found while folding, left for runtime
+#[derive(Clone, Debug, PartialEq)]
+pub struct FoldWarning {
+    pub message: String,
+    pub span: Span,
+}
+
+/// Fold the constant expressions of a program
+pub fn fold(program: Vec<Expr>) -> (Vec<Expr>, Vec<FoldWarning>) {
+    let mut folder = Folder {
+        // Top-level names can be redefined, so they aren't constants
+        globals: program.iter()
+            .filter_map(|e| match &e.kind {
+                ExprKind::Define { var, .. } => Some(var.id),
+                _ => None,
+            })
+            .collect(),
+        consts: HashMap::new(),
+        warnings: vec![],
+    };
+    let program = program.into_iter().map(|e| folder.expr(e)).collect();
+    (program, folder.warnings)
+}
+
+struct Folder<'src> {
+    globals: HashSet<VarId>,
+    consts: HashMap<VarId, Lit<'src>>,
+    warnings: Vec<FoldWarning>,
+}
+
+fn compare(op: BinOp, l: &Lit, r: &Lit) -> Option<bool> {
+    let ord = match (l, r) {
+        (Lit::Unit, Lit::Unit)       => std::cmp::Ordering::Equal,
+        (Lit::Bool(a), Lit::Bool(b)) => a.cmp(b),
+        (Lit::Int(a), Lit::Int(b))   => a.cmp(b),
+        (Lit::Str(a), Lit::Str(b))   => a.cmp(b),
+        _ => return None,
+    };
+    Some(match op {
+        BinOp::Eq => ord.is_eq(),
+        BinOp::Ne => ord.is_ne(),
+        BinOp::Lt => ord.is_lt(),
+        BinOp::Le => ord.is_le(),
+        BinOp::Gt => ord.is_gt(),
+        BinOp::Ge => ord.is_ge(),
+        _ => return None,
+    })
+}
+
+impl<'src> Folder<'src> {
+    fn warn(&mut self, message: &str, span: Span) {
+        self.warnings.push(FoldWarning { message: message.to_string(), span });
+    }
+
+    fn arith(&mut self, op: BinOp, l: i64, r: i64, span: Span) -> Option<i64> {
+        let v = match op {
+            BinOp::Add => l.checked_add(r),
+            BinOp::Sub => l.checked_sub(r),
+            BinOp::Mul => l.checked_mul(r),
+            BinOp::Div | BinOp::Rem if r == 0 => {
+                self.warn("this division by zero will fail at runtime", span);
+                return None;
+            }
+            BinOp::Div => l.checked_div(r),
+            BinOp::Rem => l.checked_rem(r),
+            _ => return None,
+        };
+        if v.is_none() {
+            self.warn("this arithmetic overflows and will wrap around at runtime", span);
+        }
+        v
+    }
+
+    fn expr(&mut self, e: Expr<'src>) -> Expr<'src> {
+        let Expr { kind, ty, span } = e;
+        let lit = |l| Expr::new(ExprKind::Lit(l), ty.clone(), span);
+        match kind {
+            ExprKind::Var(v) => match self.consts.get(&v.id) {
+                Some(l) => lit(l.clone()),
+                None => Expr::new(ExprKind::Var(v), ty, span),
+            },
+            ExprKind::Unary(op, x) => {
+                let x = self.expr(*x);
+                match (op, &x.kind) {
+                    (UnOp::Not, ExprKind::Lit(Lit::Bool(b))) => lit(Lit::Bool(!b)),
+                    (UnOp::Neg, ExprKind::Lit(Lit::Int(i))) if *i != i64::MIN => lit(Lit::Int(-i)),
+                    (UnOp::Neg, ExprKind::Lit(Lit::Int(_))) => {
+                        self.warn("this negation overflows and will wrap around at runtime", span);
+                        Expr::new(ExprKind::Unary(op, Box::new(x)), ty, span)
+                    }
+                    _ => Expr::new(ExprKind::Unary(op, Box::new(x)), ty, span),
+                }
+            }
+            ExprKind::Binary(op @ (BinOp::And | BinOp::Or), l, r) => {
+                let l = self.expr(*l);
+                match (op, &l.kind) {
+                    // The right operand is only evaluated if it decides
+                    (BinOp::And, ExprKind::Lit(Lit::Bool(true)))
+                    | (BinOp::Or, ExprKind::Lit(Lit::Bool(false))) => self.expr(*r),
+                    (_, ExprKind::Lit(Lit::Bool(_))) => l,
+                    _ => {
+                        let r = self.expr(*r);
+                        Expr::new(ExprKind::Binary(op, Box::new(l), Box::new(r)), ty, span)
+                    }
+                }
+            }
+            ExprKind::Binary(op, l, r) => {
+                let l = self.expr(*l);
+                let r = self.expr(*r);
+                let folded = match (&l.kind, &r.kind) {
+                    (ExprKind::Lit(a), ExprKind::Lit(b)) if op.is_comparison() => {
+                        compare(op, a, b).map(Lit::Bool)
+                    }
+                    (ExprKind::Lit(Lit::Int(a)), ExprKind::Lit(Lit::Int(b))) => {
+                        self.arith(op, *a, *b, span).map(Lit::Int)
+                    }
+                    _ => None,
+                };
+                match folded {
+                    Some(v) => lit(v),
+                    None => Expr::new(ExprKind::Binary(op, Box::new(l), Box::new(r)), ty, span),
+                }
+            }
+            ExprKind::If { cond, t, f } => {
+                let cond = self.expr(*cond);
+                match cond.kind {
+                    ExprKind::Lit(Lit::Bool(true)) => self.expr(*t),
+                    ExprKind::Lit(Lit::Bool(false)) => self.expr(*f),
+                    kind => {
+                        let cond = Expr::new(kind, cond.ty, cond.span);
+                        let (t, f) = (self.expr(*t), self.expr(*f));
+                        Expr::new(ExprKind::If { cond: Box::new(cond), t: Box::new(t), f: Box::new(f) }, ty, span)
+                    }
+                }
+            }
+            ExprKind::Let { var, value, body } => {
+                let value = self.expr(*value);
+                if let ExprKind::Lit(l) = &value.kind {
+                    self.consts.insert(var.id, l.clone());
+                }
+                let body = self.expr(*body);
+                Expr::new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty, span)
+            }
+            ExprKind::Define { var, value } => {
+                let value = self.expr(*value);
+                match &value.kind {
+                    ExprKind::Lit(l) if !self.globals.contains(&var.id) => {
+                        self.consts.insert(var.id, l.clone());
+                    }
+                    _ => {}
+                }
+                Expr::new(ExprKind::Define { var, value: Box::new(value) }, ty, span)
+            }
+            kind => Expr::new(kind, ty, span).map_children(|e| self.expr(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{pretty::pretty, testing::{assert_preserves, lower}};
+
+    #[test]
+    fn test_fold_preserves_semantics() {
+        assert_preserves(|p| fold(p).0);
+    }
+
+    #[test]
+    fn test_fold_propagates() {
+        let (program, warnings) = fold(lower("
+            let x = 2 * 3 in if x > 5 && !false then x + 1 else 0;
+            { let s = \"a\"; s < \"b\" || 1 / 0 == 0 };
+        "));
+        assert!(warnings.is_empty());
+        assert_eq!(pretty(&program), "\
+let x%0: Int = 6 in
+7;
+{
+    let s%1: Str = \"a\";
+    true
+};
+");
+    }
+
+    #[test]
+    fn test_fold_leaves_runtime_errors() {
+        let src = "let f = fun (x Int) -> x; f(1 / 0); 9223372036854775807 + 1;";
+        let (program, warnings) = fold(lower(src));
+        assert_eq!(pretty(&program[1..]), "\
+f%0(1 / 0);
+9223372036854775807 + 1;
+");
+        let spans = warnings.iter().map(|w| &src[w.span.into_range()]).collect::<Vec<_>>();
+        assert_eq!(spans, vec!["1 / 0", "9223372036854775807 + 1"]);
+    }
+}
@@ -0,0 +1,173 @@
+//! Function inlining and beta reduction.
+//!
+//! Calls of lambdas are reduced to `let`s binding the parameters to the
+//! arguments, so every argument is still evaluated exactly once and in
+//! order. Arguments that are local variables are substituted instead; a
+//! global is still bound, as a later definition would change what its
+//! name refers to. Calls of small top-level functions are first replaced by a copy
+//! of the function, which is then reduced the same way.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{closure::free_vars, Expr, ExprKind, VarGen, VarId};
+
+/// The largest function body, in number of expressions, that is inlined
+pub const MAX_INLINE_SIZE: usize = 16;
+
+/// Inline the calls of lambdas and of the top-level functions whose bodies
+/// have at most `max_size` expressions
+pub fn inline(program: Vec<Expr>, max_size: usize) -> Vec<Expr> {
+    let mut defines = HashMap::<VarId, usize>::new();
+    for e in &program {
+        if let ExprKind::Define { var, .. } = &e.kind {
+            *defines.entry(var.id).or_default() += 1;
+        }
+    }
+    let recursive = recursive(&program);
+    let globals = defines.keys().copied().collect();
+    let mut inliner = Inliner { vars: VarGen::after(&program), functions: HashMap::new(), globals };
+
+    let mut out = vec![];
+    for e in program {
+        let e = inliner.expr(e);
+        // Only calls after the definition can be inlined, before it they
+        // fail at runtime
+        if let ExprKind::Define { var, value } = &e.kind {
+            if let ExprKind::Lambda { body, .. } = &value.kind {
+                let mut size = 0;
+                body.walk(&mut |_| size += 1);
+                if defines[&var.id] == 1 && !recursive.contains(&var.id) && size <= max_size {
+                    inliner.functions.insert(var.id, (**value).clone());
+                }
+            }
+        }
+        out.push(e);
+    }
+    out
+}
+
+/// The top-level functions that can end up calling themselves
+fn recursive(program: &[Expr]) -> HashSet<VarId> {
+    let calls = program.iter()
+        .filter_map(|e| match &e.kind {
+            ExprKind::Define { var, value } if value.is_lambda() => {
+                Some((var.id, free_vars(value).into_iter().map(|v| v.id).collect::<Vec<_>>()))
+            }
+            _ => None,
+        })
+        .fold(HashMap::<VarId, Vec<VarId>>::new(), |mut calls, (f, callees)| {
+            calls.entry(f).or_default().extend(callees);
+            calls
+        });
+
+    calls.keys()
+        .copied()
+        .filter(|&f| {
+            let mut seen = HashSet::new();
+            let mut todo = calls[&f].clone();
+            while let Some(g) = todo.pop() {
+                if g == f {
+                    return true;
+                }
+                if seen.insert(g) {
+                    todo.extend(calls.get(&g).into_iter().flatten());
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+struct Inliner<'src> {
+    vars: VarGen,
+    // The top-level functions to inline, by the variable they define
+    functions: HashMap<VarId, Expr<'src>>,
+    // The variables defined at the top level, which redefinitions change
+    globals: HashSet<VarId>,
+}
+
+impl<'src> Inliner<'src> {
+    fn expr(&mut self, e: Expr<'src>) -> Expr<'src> {
+        let Expr { kind, ty, span } = e;
+        let ExprKind::Call { func, args } = kind else {
+            return Expr::new(kind, ty, span).map_children(|e| self.expr(e));
+        };
+        let func = self.expr(*func);
+        let args = args.into_iter().map(|a| self.expr(a)).collect::<Vec<_>>();
+        let lambda = match &func.kind {
+            ExprKind::Var(v) if self.functions.contains_key(&v.id) => {
+                let mut copy = self.functions[&v.id].clone();
+                copy.freshen(&mut self.vars);
+                copy
+            }
+            ExprKind::Lambda { .. } => func,
+            _ => return Expr::new(ExprKind::Call { func: Box::new(func), args }, ty, span),
+        };
+        let ExprKind::Lambda { params, mut body } = lambda.kind else { unreachable!() };
+
+        // Arguments that are local variables are substituted, the others
+        // are bound in order
+        let mut renames = HashMap::new();
+        let mut binds = vec![];
+        for (param, arg) in params.into_iter().zip(args) {
+            match arg.kind {
+                ExprKind::Var(v) if !self.globals.contains(&v.id) => { renames.insert(param.id, v); }
+                _ => binds.push((param, arg)),
+            }
+        }
+        body.rename(&renames);
+        // The call's type is the more precise one when the function is
+        // polymorphic, as those imported from other modules can be
+        body.ty = ty.clone();
+        binds.into_iter().rev().fold(*body, |body, (var, value)| {
+            Expr::new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty.clone(), span)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fold::fold, pretty::pretty, testing::{assert_preserves, lower}};
+
+    #[test]
+    fn test_inline_preserves_semantics() {
+        assert_preserves(|p| inline(p, MAX_INLINE_SIZE));
+        assert_preserves(|p| fold(inline(p, MAX_INLINE_SIZE)).0);
+    }
+
+    #[test]
+    fn test_inline_pipes() {
+        let program = inline(lower("
+            let succ = fun (x Int) -> x + 1;
+            let twice = fun (x Int) -> x + x;
+            5 |> fun (y Int) -> succ(y);
+            twice(succ(2));
+        "), MAX_INLINE_SIZE);
+        assert_eq!(pretty(&program[2..]), "\
+let y%4: Int = 5 in
+y%4 + 1;
+let x%7: Int = let x%6: Int = 2 in
+x%6 + 1 in
+x%7 + x%7;
+");
+    }
+
+    #[test]
+    fn test_inline_skips_recursive_and_large() {
+        let src = "
+            let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1;
+            let big = fun (n Int) -> n * n * n * n * n * n * n * n;
+            fact(10);
+            big(2);
+        ";
+        let program = inline(lower(src), MAX_INLINE_SIZE);
+        assert_eq!(pretty(&program[2..]), "\
+fact%0(10);
+let n%4: Int = 2 in
+((((((n%4 * n%4) * n%4) * n%4) * n%4) * n%4) * n%4) * n%4;
+");
+        let program = inline(lower(src), 4);
+        assert_eq!(pretty(&program[2..]), "fact%0(10);\nbig%1(2);\n");
+    }
+}
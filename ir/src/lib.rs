@@ -1,9 +1,28 @@
-use typing::typed::TExpr;
-use syntax::expr::{Lit as ExprLit, UnaryOp, BinaryOp};
+pub mod anf;
+pub mod closure;
+pub mod dce;
+pub mod eval;
+pub mod fold;
+pub mod inline;
+pub mod matching;
+pub mod pass;
+pub mod pretty;
+pub mod text;
+pub mod verify;
 
+#[cfg(test)]
+mod testing;
+
+use typing::{infer::Interface, typed::TExpr};
+use syntax::expr::{Lit as ExprLit, Pattern, UnaryOp, BinaryOp};
+
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use text::{Binder, SexprType};
 
-#[derive(Clone, Debug)]
+pub use syntax::{expr::Span, ty::Type};
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Lit<'src> {
     Unit,
     Bool(bool),
@@ -11,6 +30,17 @@ pub enum Lit<'src> {
     Str(&'src str),
 }
 
+impl Lit<'_> {
+    pub fn ty(&self) -> Type {
+        match self {
+            Lit::Unit    => Type::Unit,
+            Lit::Bool(_) => Type::Bool,
+            Lit::Int(_)  => Type::Int,
+            Lit::Str(_)  => Type::Str,
+        }
+    }
+}
+
 impl Display for Lit<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
@@ -22,56 +52,325 @@ impl Display for Lit<'_> {
     }
 }
 
+/// Identifies a variable uniquely within a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VarId(pub usize);
+
+impl Display for VarId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "%{}", self.0)
+    }
+}
+
+/// A variable, the name is only kept for printing and diagnostics
 #[derive(Clone, Debug)]
-pub enum Expr<'src> {
+pub struct Var<'src> {
+    pub id: VarId,
+    pub name: &'src str,
+    pub ty: Type,
+}
+
+impl PartialEq for Var<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Var<'_> {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+impl Display for UnOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            UnOp::Neg => write!(f, "neg"),
+            UnOp::Not => write!(f, "not"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add, Sub, Mul, Div, Rem,
+    Eq, Ne, Lt, Le, Gt, Ge,
+    And, Or,
+}
+
+impl BinOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Rem => "%",
+            BinOp::Eq  => "==",
+            BinOp::Ne  => "!=",
+            BinOp::Lt  => "<",
+            BinOp::Le  => "<=",
+            BinOp::Gt  => ">",
+            BinOp::Ge  => ">=",
+            BinOp::And => "&&",
+            BinOp::Or  => "||",
+        }
+    }
+
+    /// Whether the operator compares its operands and results in a boolean
+    pub fn is_comparison(&self) -> bool {
+        matches!(self, BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge)
+    }
+}
+
+impl Display for BinOp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.symbol())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind<'src> {
     Lit(Lit<'src>),
-    // v0
-    Var(&'src str),
-    // f(v0, v1, ...)
-    Call(Vec<Self>),
+    Var(Var<'src>),
+    Unary(UnOp, Box<Expr<'src>>),
+    Binary(BinOp, Box<Expr<'src>>, Box<Expr<'src>>),
+    Lambda {
+        params: Vec<Var<'src>>,
+        body: Box<Expr<'src>>,
+    },
+    Call {
+        func: Box<Expr<'src>>,
+        args: Vec<Expr<'src>>,
+    },
+    If {
+        cond: Box<Expr<'src>>,
+        t: Box<Expr<'src>>,
+        f: Box<Expr<'src>>,
+    },
+    // Binds `var` in `body` only
+    Let {
+        var: Var<'src>,
+        value: Box<Expr<'src>>,
+        body: Box<Expr<'src>>,
+    },
+    // Binds `var` until the end of the enclosing block, or globally at the
+    // top level. Only a lambda value can refer to the variable itself
+    Define {
+        var: Var<'src>,
+        value: Box<Expr<'src>>,
+    },
+    // A void block discards the value of its last expression
+    Block {
+        exprs: Vec<Expr<'src>>,
+        void: bool,
+    },
+    // Only produced by closure conversion: a record pairing the top-level
+    // function `func` with the values of the variables it captured. Calling
+    // the record calls `func` with the record itself as the first argument
+    Closure {
+        func: Var<'src>,
+        env: Vec<Expr<'src>>,
+    },
+    // The `index`th captured value of a closure record
+    EnvGet {
+        closure: Box<Expr<'src>>,
+        index: usize,
+    },
+}
+
+/// An expression together with the type of its value and its source
+#[derive(Clone, Debug)]
+pub struct Expr<'src> {
+    pub kind: ExprKind<'src>,
+    pub ty: Type,
+    pub span: Span,
+}
+
+impl<'src> Expr<'src> {
+    pub fn new(kind: ExprKind<'src>, ty: Type, span: Span) -> Self {
+        Self { kind, ty, span }
+    }
+
+    pub fn is_define(&self) -> bool {
+        matches!(self.kind, ExprKind::Define { .. })
+    }
+
+    pub fn is_lambda(&self) -> bool {
+        matches!(self.kind, ExprKind::Lambda { .. })
+    }
+
+    /// Call `f` on this expression and every subexpression, parents first
+    pub fn walk(&self, f: &mut impl FnMut(&Expr<'src>)) {
+        f(self);
+        self.children().into_iter().for_each(|c| c.walk(f));
+    }
+
+    /// The direct subexpressions, in evaluation order
+    pub fn children(&self) -> Vec<&Expr<'src>> {
+        match &self.kind {
+            ExprKind::Lit(_) | ExprKind::Var(_) => vec![],
+            ExprKind::Unary(_, x) => vec![x],
+            ExprKind::Binary(_, l, r) => vec![l, r],
+            ExprKind::Lambda { body, .. } => vec![body],
+            ExprKind::Call { func, args } => std::iter::once(&**func).chain(args).collect(),
+            ExprKind::If { cond, t, f } => vec![cond, t, f],
+            ExprKind::Let { value, body, .. } => vec![value, body],
+            ExprKind::Define { value, .. } => vec![value],
+            ExprKind::Block { exprs, .. } => exprs.iter().collect(),
+            ExprKind::Closure { env, .. } => env.iter().collect(),
+            ExprKind::EnvGet { closure, .. } => vec![closure],
+        }
+    }
+
+    /// The direct subexpressions, mutably, in evaluation order
+    pub fn children_mut(&mut self) -> Vec<&mut Expr<'src>> {
+        match &mut self.kind {
+            ExprKind::Lit(_) | ExprKind::Var(_) => vec![],
+            ExprKind::Unary(_, x) => vec![x],
+            ExprKind::Binary(_, l, r) => vec![l, r],
+            ExprKind::Lambda { body, .. } => vec![body],
+            ExprKind::Call { func, args } => std::iter::once(&mut **func).chain(args).collect(),
+            ExprKind::If { cond, t, f } => vec![cond, t, f],
+            ExprKind::Let { value, body, .. } => vec![value, body],
+            ExprKind::Define { value, .. } => vec![value],
+            ExprKind::Block { exprs, .. } => exprs.iter_mut().collect(),
+            ExprKind::Closure { env, .. } => env.iter_mut().collect(),
+            ExprKind::EnvGet { closure, .. } => vec![closure],
+        }
+    }
+
+    /// Replace every direct subexpression with the result of `f`
+    pub fn map_children(mut self, mut f: impl FnMut(Expr<'src>) -> Expr<'src>) -> Self {
+        for c in self.children_mut() {
+            let placeholder = Expr::new(ExprKind::Lit(Lit::Unit), Type::Unit, c.span);
+            *c = f(std::mem::replace(c, placeholder));
+        }
+        self
+    }
+
+    /// Give fresh variables to everything bound inside the expression, so
+    /// that copies of it don't bind the same variables
+    pub fn freshen(&mut self, vars: &mut VarGen) {
+        let mut renames = HashMap::new();
+        self.walk(&mut |e| {
+            let bound = match &e.kind {
+                ExprKind::Let { var, .. } | ExprKind::Define { var, .. } => std::slice::from_ref(var),
+                ExprKind::Lambda { params, .. } => params.as_slice(),
+                _ => &[],
+            };
+            for v in bound {
+                renames.entry(v.id).or_insert_with(|| vars.fresh(v.name, v.ty.clone()));
+            }
+        });
+        self.rename(&renames);
+    }
+
+    /// Replace the uses and bindings of the variables in `map`
+    pub fn rename(&mut self, map: &HashMap<VarId, Var<'src>>) {
+        let vars = match &mut self.kind {
+            ExprKind::Var(v)
+            | ExprKind::Let { var: v, .. }
+            | ExprKind::Define { var: v, .. }
+            | ExprKind::Closure { func: v, .. } => std::slice::from_mut(v),
+            ExprKind::Lambda { params, .. } => params.as_mut_slice(),
+            _ => &mut [],
+        };
+        for v in vars {
+            if let Some(new) = map.get(&v.id) {
+                *v = new.clone();
+            }
+        }
+        self.children_mut().into_iter().for_each(|c| c.rename(map));
+    }
 }
 
+/// The S-expression view of the IR
 impl Display for Expr<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        match self {
-            Expr::Lit(l) => write!(f, "{}", l),
-            Expr::Var(s) => write!(f, "{}", s),
-            Expr::Call(v) => {
-                write!(f, "(")?;
-                for (i, e) in v.iter().enumerate() {
+        match &self.kind {
+            ExprKind::Lit(l) => write!(f, "{}", l),
+            ExprKind::Var(v) => write!(f, "{}{}", v.name, v.id),
+            ExprKind::Unary(op, x) => write!(f, "({} {})", op, x),
+            ExprKind::Binary(op, l, r) => write!(f, "({} {} {})", op, l, r),
+            ExprKind::Lambda { params, body } => {
+                write!(f, "(lambda (")?;
+                for (i, p) in params.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", Binder(p))?;
+                }
+                write!(f, ") {})", body)
+            }
+            ExprKind::Call { func, args } => {
+                write!(f, "({}", func)?;
+                args.iter().try_for_each(|a| write!(f, " {}", a))?;
+                write!(f, ")")
+            }
+            ExprKind::If { cond, t, f: e } => write!(f, "(if {} {} {})", cond, t, e),
+            ExprKind::Let { var, value, body } => {
+                write!(f, "(let {} {} {})", Binder(var), value, body)
+            }
+            ExprKind::Define { var, value } => write!(f, "(define {} {})", Binder(var), value),
+            ExprKind::Block { exprs, void } => {
+                write!(f, "(block (")?;
+                for (i, e) in exprs.iter().enumerate() {
                     if i != 0 {
                         write!(f, " ")?;
                     }
                     write!(f, "{}", e)?;
                 }
+                write!(f, ")")?;
+                if *void {
+                    write!(f, " ()")?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Closure { func, env } => {
+                write!(f, "(closure {}{}", func.name, func.id)?;
+                env.iter().try_for_each(|e| write!(f, " {}", e))?;
                 write!(f, ")")
             }
+            ExprKind::EnvGet { closure, index } => {
+                write!(f, "(env {} {} {})", closure, index, SexprType(&self.ty))
+            }
         }
     }
 }
 
-macro_rules! unbox {
-    ($e:expr) => {
-        *(($e).0)
-    };
-}
-
-macro_rules! str {
-    ($e:expr) => {
-        Expr::Lit(Lit::Str($e))
-    };
+/// Hands out variables with ids that are not in use yet
+#[derive(Clone, Debug, Default)]
+pub struct VarGen {
+    next: usize,
 }
 
-macro_rules! var {
-    ($e:expr) => {
-        Expr::Var($e)
-    };
-}
+impl VarGen {
+    /// Start after every variable of a program
+    pub fn after(program: &[Expr]) -> Self {
+        let mut next = 0;
+        for e in program {
+            e.walk(&mut |e| {
+                let vars = match &e.kind {
+                    ExprKind::Var(v)
+                    | ExprKind::Let { var: v, .. }
+                    | ExprKind::Define { var: v, .. }
+                    | ExprKind::Closure { func: v, .. } => std::slice::from_ref(v),
+                    ExprKind::Lambda { params, .. } => params.as_slice(),
+                    _ => &[],
+                };
+                vars.iter().for_each(|v| next = next.max(v.id.0 + 1));
+            });
+        }
+        Self { next }
+    }
 
-macro_rules! call {
-    ($e:expr) => {
-        Expr::Call($e)
-    };
+    pub fn fresh<'src>(&mut self, name: &'src str, ty: Type) -> Var<'src> {
+        self.next += 1;
+        Var { id: VarId(self.next - 1), name, ty }
+    }
 }
 
 pub fn lower_lit(lit: ExprLit) -> Lit {
@@ -83,81 +382,303 @@ pub fn lower_lit(lit: ExprLit) -> Lit {
     }
 }
 
+/// Lower an expression on its own. Only the expression itself is given an
+/// empty span, its subexpressions keep theirs
+#[deprecated(note = "use `Lowerer::lower`, or `Lowerer::lower_program` for a whole program, which keep spans")]
 pub fn lower_expr(e: TExpr) -> Expr {
-    match e {
-        TExpr::Lit(l)   => Expr::Lit(lower_lit(l)),
-        TExpr::Ident(s) => var!(s),
-        TExpr::Unary { op, expr, .. } => {
-            let expr = lower_expr(unbox!(expr));
-            match op {
-                UnaryOp::Neg => call!(vec![var!("neg"), expr]),
-                UnaryOp::Not => call!(vec![var!("not"), expr]),
-            }
+    Lowerer::new().lower(e, Span::default())
+}
+
+/// Lowers typed expressions into the IR, resolving every name to a
+/// variable. Top-level definitions of the same name share a variable, so
+/// later definitions replace earlier ones for everything referring to them
+#[derive(Clone, Debug, Default)]
+pub struct Lowerer<'src> {
+    scopes: Vec<Vec<Var<'src>>>,
+    globals: HashMap<&'src str, Var<'src>>,
+    // The globals of the modules imported, by the name they are referred
+    // to with
+    imports: HashMap<&'src str, HashMap<&'src str, Var<'src>>>,
+    vars: VarGen,
+}
+
+impl<'src> Lowerer<'src> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start lowering another module of the same program, whose variables
+    /// are all distinct from those of the modules lowered before
+    pub fn next_module(self) -> Self {
+        Self { vars: self.vars, ..Self::default() }
+    }
+
+    /// The public global variables of the module lowered so far, those in
+    /// its `interface`, to be given to the modules importing it
+    pub fn exports(&self, interface: &Interface) -> HashMap<&'src str, Var<'src>> {
+        self.globals.iter()
+            .filter(|(name, _)| interface.public.contains_key(*name))
+            .map(|(name, v)| (*name, v.clone()))
+            .collect()
+    }
+
+    /// Make the globals of a module available as `alias.name`. Every
+    /// `alias.name` lowered afterwards must be one of them, which the type
+    /// checker makes sure of
+    pub fn import(&mut self, alias: &'src str, exports: HashMap<&'src str, Var<'src>>) {
+        self.imports.insert(alias, exports);
+    }
+
+    /// The variable of a global defined so far
+    pub fn var(&self, name: &str) -> Option<&Var<'src>> {
+        self.globals.get(name)
+    }
+
+    /// Go back to lowering in the scope of `scope` after lowering other
+    /// modules with this lowerer, its variables still distinct from theirs
+    pub fn resume(self, scope: Self) -> Self {
+        Self { vars: self.vars, ..scope }
+    }
+
+    /// The variable of a global, made if nothing defined it yet, e.g. for
+    /// a value the evaluator is given from elsewhere
+    pub fn global(&mut self, name: &'src str, ty: Type) -> Var<'src> {
+        if let Some(v) = self.globals.get(name) {
+            return Var { ty, ..v.clone() };
         }
-        TExpr::Binary { op: BinaryOp::Pipe, lhs, rhs, .. } => {
-            let lhs = lower_expr(unbox!(lhs)); // arguments
-            let rhs = lower_expr(unbox!(rhs)); // function
-            call!(vec![rhs, lhs])
+        let v = self.vars.fresh(name, ty);
+        self.globals.insert(name, v.clone());
+        v
+    }
+
+    fn bind(&mut self, name: &'src str, ty: Type) -> Var<'src> {
+        let v = self.vars.fresh(name, ty);
+        self.scopes.last_mut().unwrap().push(v.clone());
+        v
+    }
+
+    fn lookup(&mut self, name: &'src str, ty: Type) -> Var<'src> {
+        let local = self.scopes.iter().rev()
+            .flat_map(|s| s.iter().rev())
+            .find(|v| v.name == name);
+        match local {
+            Some(v) => Var { ty, ..v.clone() },
+            // Also where names that were never defined end up, so that
+            // using them is an error when the program runs
+            None => self.global(name, ty),
         }
-        TExpr::Binary { op, lhs, rhs, .. } => {
-            let lhs = lower_expr(unbox!(lhs));
-            let rhs = lower_expr(unbox!(rhs));
-            match op {
-                BinaryOp::Add => call!(vec![var!("+"), lhs, rhs]),
-                BinaryOp::Sub => call!(vec![var!("-"), lhs, rhs]),
-                BinaryOp::Mul => call!(vec![var!("*"), lhs, rhs]),
-                BinaryOp::Div => call!(vec![var!("/"), lhs, rhs]),
-                BinaryOp::Rem => call!(vec![var!("%"), lhs, rhs]),
-                BinaryOp::Eq  => call!(vec![var!("=="), lhs, rhs]),
-                BinaryOp::Ne  => call!(vec![var!("!="), lhs, rhs]),
-                BinaryOp::Lt  => call!(vec![var!("<"), lhs, rhs]),
-                BinaryOp::Le  => call!(vec![var!("<="), lhs, rhs]),
-                BinaryOp::Gt  => call!(vec![var!(">"), lhs, rhs]),
-                BinaryOp::Ge  => call!(vec![var!(">="), lhs, rhs]),
-                BinaryOp::And => call!(vec![var!("&&"), lhs, rhs]),
-                BinaryOp::Or  => call!(vec![var!("||"), lhs, rhs]),
-                BinaryOp::Pipe => unreachable!("pipe operator is handled separately"),
+    }
+
+    /// Lower a whole program. Top-level definitions are known up front so
+    /// that functions can refer to ones that come later
+    pub fn lower_program(&mut self, program: Vec<(TExpr<'src>, Span)>) -> Vec<Expr<'src>> {
+        for (e, _) in &program {
+            if let TExpr::Define { name, ty, .. } = e {
+                self.global(name, ty.clone());
             }
         }
-        TExpr::Lambda { params, body, .. } => {
-            let body = lower_expr(unbox!(body));
-            call!(vec![
-                var!("lambda"),
-                call!(params.into_iter().map(|(p, _)| var!(p)).collect()),
-                body,
-            ])
-        }
-        TExpr::Call { func, args } => {
-            let func = lower_expr(unbox!(func));
-            let args = args.into_iter()
-                .map(|(a, _)| lower_expr(a))
-                .collect::<Vec<_>>();
-            call!(vec![func].into_iter().chain(args).collect())
-        }
-        TExpr::If { cond, t, f, .. } => {
-            let cond = lower_expr(unbox!(cond));
-            let t = lower_expr(unbox!(t));
-            let f = lower_expr(unbox!(f));
-            call!(vec![var!("if"), cond, t, f])
-        }
-        TExpr::Let { name, value, body, .. } => {
-            let value = lower_expr(unbox!(value));
-            let body = lower_expr(unbox!(body));
-            call!(vec![var!("let"), str!(name), value, body])
-        }
-        TExpr::Define { name, value, .. } => {
-            let value = lower_expr(unbox!(value));
-            call!(vec![var!("define"), var!(name), value])
-        }
-        TExpr::Block { exprs, void, .. } => {
-            let exprs = exprs.into_iter()
-                .map(|(e, _)| lower_expr(e))
-                .collect::<Vec<_>>();
-            if void {
-                call!(vec![var!("block"), call!(exprs)])
-            } else {
-                call!(vec![var!("block"), call!(exprs), var!("()")])
+        program.into_iter().map(|(e, span)| self.lower(e, span)).collect()
+    }
+
+    /// Lower a top-level expression
+    pub fn lower(&mut self, e: TExpr<'src>, span: Span) -> Expr<'src> {
+        let ty = e.ty();
+        let kind = match e {
+            TExpr::Lit(l)   => ExprKind::Lit(lower_lit(l)),
+            TExpr::Ident(s, ty) => ExprKind::Var(self.lookup(s, ty)),
+            TExpr::Qualified { module, name, ty } => {
+                // Type checking only lets through the names that imported
+                // modules define
+                let v = self.imports.get(module).and_then(|m| m.get(name));
+                debug_assert!(v.is_some(), "`{}.{}` was not imported", module, name);
+                match v {
+                    Some(v) => ExprKind::Var(Var { ty, ..v.clone() }),
+                    // Otherwise, like other undefined names, an error when
+                    // the program runs
+                    None => ExprKind::Var(self.vars.fresh(name, ty)),
+                }
             }
-        }
+            TExpr::Unary { op, expr: (x, xspan), .. } => {
+                let op = match op {
+                    UnaryOp::Neg => UnOp::Neg,
+                    UnaryOp::Not => UnOp::Not,
+                };
+                ExprKind::Unary(op, Box::new(self.lower(*x, xspan)))
+            }
+            TExpr::Binary { op: BinaryOp::Pipe, lhs: (lhs, lspan), rhs: (rhs, rspan), .. } => {
+                let arg = self.lower(*lhs, lspan);
+                let func = self.lower(*rhs, rspan);
+                ExprKind::Call { func: Box::new(func), args: vec![arg] }
+            }
+            TExpr::Binary { op, lhs: (lhs, lspan), rhs: (rhs, rspan), .. } => {
+                let op = match op {
+                    BinaryOp::Add => BinOp::Add,
+                    BinaryOp::Sub => BinOp::Sub,
+                    BinaryOp::Mul => BinOp::Mul,
+                    BinaryOp::Div => BinOp::Div,
+                    BinaryOp::Rem => BinOp::Rem,
+                    BinaryOp::Eq  => BinOp::Eq,
+                    BinaryOp::Ne  => BinOp::Ne,
+                    BinaryOp::Lt  => BinOp::Lt,
+                    BinaryOp::Le  => BinOp::Le,
+                    BinaryOp::Gt  => BinOp::Gt,
+                    BinaryOp::Ge  => BinOp::Ge,
+                    BinaryOp::And => BinOp::And,
+                    BinaryOp::Or  => BinOp::Or,
+                    BinaryOp::Pipe => unreachable!("pipe operator is handled separately"),
+                };
+                let lhs = self.lower(*lhs, lspan);
+                let rhs = self.lower(*rhs, rspan);
+                ExprKind::Binary(op, Box::new(lhs), Box::new(rhs))
+            }
+            TExpr::Lambda { params, body: (body, bspan), .. } => {
+                self.scopes.push(vec![]);
+                let params = params.into_iter()
+                    .map(|(p, ty)| self.bind(p, ty))
+                    .collect();
+                let body = self.lower(*body, bspan);
+                self.scopes.pop();
+                ExprKind::Lambda { params, body: Box::new(body) }
+            }
+            TExpr::Call { func: (func, fspan), args, .. } => {
+                let func = self.lower(*func, fspan);
+                let args = args.into_iter()
+                    .map(|(a, aspan)| self.lower(a, aspan))
+                    .collect();
+                ExprKind::Call { func: Box::new(func), args }
+            }
+            TExpr::If { cond: (cond, cspan), t: (t, tspan), f: (f, fspan), .. } => {
+                let cond = self.lower(*cond, cspan);
+                let t = self.lower(*t, tspan);
+                let f = self.lower(*f, fspan);
+                ExprKind::If { cond: Box::new(cond), t: Box::new(t), f: Box::new(f) }
+            }
+            TExpr::Let { name, ty, value: (value, vspan), body: (body, bspan) } => {
+                let value = self.lower(*value, vspan);
+                self.scopes.push(vec![]);
+                let var = self.bind(name, ty);
+                let body = self.lower(*body, bspan);
+                self.scopes.pop();
+                ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }
+            }
+            TExpr::Define { name, ty, value: (value, vspan) } => {
+                let lambda = matches!(*value, TExpr::Lambda { .. });
+                let (var, value) = if self.scopes.is_empty() {
+                    let var = self.global(name, ty);
+                    (var, self.lower(*value, vspan))
+                } else if lambda {
+                    let var = self.bind(name, ty);
+                    (var, self.lower(*value, vspan))
+                } else {
+                    let value = self.lower(*value, vspan);
+                    (self.bind(name, ty), value)
+                };
+                ExprKind::Define { var, value: Box::new(value) }
+            }
+            TExpr::Block { exprs, void, .. } => {
+                self.scopes.push(vec![]);
+                let exprs = exprs.into_iter()
+                    .map(|(e, espan)| self.lower(e, espan))
+                    .collect();
+                self.scopes.pop();
+                ExprKind::Block { exprs, void }
+            }
+            TExpr::Match { scrutinees, arms, .. } => {
+                let scrutinees = scrutinees.into_iter()
+                    .map(|(s, sspan)| self.lower(s, sspan))
+                    .collect::<Vec<_>>();
+                let arms = arms.into_iter()
+                    .map(|(patterns, (body, bspan))| {
+                        // A name takes the type of the scrutinee it is
+                        // matched against. Or-patterns don't bind any
+                        self.scopes.push(vec![]);
+                        let vars = patterns.iter()
+                            .zip(&scrutinees)
+                            .filter_map(|((p, _), s)| match p {
+                                Pattern::Bind(name) => Some(self.bind(name, s.ty.clone())),
+                                _ => None,
+                            })
+                            .collect();
+                        let body = self.lower(body, bspan);
+                        self.scopes.pop();
+                        let patterns = patterns.into_iter().map(|(p, _)| p).collect();
+                        matching::Arm { patterns, vars, body }
+                    })
+                    .collect();
+                matching::compile_match(&mut self.vars, scrutinees, arms, ty.clone(), span).kind
+            }
+        };
+        Expr::new(kind, ty, span)
+    }
+}
+
+/// Lower a whole program
+pub fn lower_program<'src>(program: Vec<(TExpr<'src>, Span)>) -> Vec<Expr<'src>> {
+    Lowerer::new().lower_program(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lower;
+
+    #[test]
+    fn test_lower_shadowing() {
+        let irs = lower("let x = 1 in let x = x + 1 in x;");
+        assert_eq!(irs[0].to_string(), "(let (x%0 Int) 1 (let (x%1 Int) (+ x%0 1) x%1))");
+        let ExprKind::Let { var: outer, body, .. } = &irs[0].kind else { panic!() };
+        let ExprKind::Let { var: inner, value, body } = &body.kind else { panic!() };
+        let ExprKind::Binary(_, l, _) = &value.kind else { panic!() };
+        let ExprKind::Var(use_value) = &l.kind else { panic!() };
+        let ExprKind::Var(use_body) = &body.kind else { panic!() };
+        assert_ne!(outer.id, inner.id);
+        assert_eq!(use_value.id, outer.id);
+        assert_eq!(use_body.id, inner.id);
+        assert_eq!(irs[0].ty, Type::Int);
+    }
+
+    #[test]
+    fn test_lower_globals() {
+        let irs = lower("let a = 1; let f = fun (x Int) -> x + a; let a = 2; f(1);");
+        let ids = irs.iter()
+            .filter_map(|e| match &e.kind {
+                ExprKind::Define { var, .. } => Some(var.id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Redefinitions replace the global
+        assert_eq!(ids[0], ids[2]);
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(irs[3].ty, Type::Int);
+        assert_eq!(irs[3].span.into_range(), 52..56);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lower_modules() {
+        use syntax::module::Module;
+        use typing::infer::Infer;
+
+        let module = |src: &'static str| Module { name: None, imports: vec![], items: crate::testing::parse(src) };
+        let math = module("pub let inc = fun (x Int) -> x + 1; let x = 2;");
+        let main = module("let x = math.inc(1);");
+
+        let mut infer = Infer::new();
+        let (math, interface, errs) = infer.infer_module(math);
+        assert!(errs.is_empty());
+        let mut infer = Infer::new();
+        infer.import("math", interface.clone());
+        let (main, _, errs) = infer.infer_module(main);
+        assert!(errs.is_empty());
+
+        let mut lowerer = Lowerer::new();
+        let math = lowerer.lower_program(math);
+        let exports = lowerer.exports(&interface);
+        // Only what the module makes public
+        assert_eq!(exports.keys().collect::<Vec<_>>(), [&"inc"]);
+        let mut lowerer = lowerer.next_module();
+        lowerer.import("math", exports);
+        let main = lowerer.lower_program(main);
+        assert_eq!(math[1].to_string(), "(define (x%1 Int) 2)");
+        assert_eq!(main[0].to_string(), "(define (x%3 Int) (inc%0 1))");
+    }
+}
@@ -0,0 +1,294 @@
+//! Compiling pattern matches into decision trees.
+//!
+//! A match tests a row of scrutinees against the patterns of its arms, and
+//! takes the first arm whose patterns all match. The patterns form a matrix
+//! that is split one column at a time, as the checks of the type checker
+//! in [`typing::usefulness`] split it: each value a column can be is tested
+//! once, and after the test only the arms that can still match are left, so
+//! no test is repeated on the way to an arm. Arms reached on several paths
+//! are copied.
+
+use std::collections::HashMap;
+
+use syntax::expr::{Lit as ExprLit, Pattern};
+use typing::usefulness::{complete, expand, heads, is_exhaustive, keeps};
+
+use crate::{lower_lit, BinOp, Expr, ExprKind, Lit, Span, Type, UnOp, Var, VarGen};
+
+#[derive(Clone, Debug)]
+pub struct Arm<'src> {
+    /// One pattern for each scrutinee
+    pub patterns: Vec<Pattern<'src>>,
+    /// The variables of the names the patterns bind
+    pub vars: Vec<Var<'src>>,
+    pub body: Expr<'src>,
+}
+
+/// A row of the matrix being compiled
+#[derive(Clone)]
+struct Row<'src> {
+    patterns: Vec<Pattern<'src>>,
+    arm: usize,
+    // The variables bound by the columns already tested, and the columns
+    binds: Vec<(Var<'src>, Var<'src>)>,
+}
+
+struct Compiler<'a, 'src> {
+    gen: &'a mut VarGen,
+    // The bodies of the arms, taken by the first path to reach them
+    bodies: Vec<(Expr<'src>, bool)>,
+    // The variables each arm binds
+    vars: Vec<Vec<Var<'src>>>,
+    ty: Type,
+    span: Span,
+}
+
+impl<'src> Compiler<'_, 'src> {
+    fn expr(&self, kind: ExprKind<'src>, ty: Type) -> Expr<'src> {
+        Expr::new(kind, ty, self.span)
+    }
+
+    fn var(&self, v: &Var<'src>) -> Expr<'src> {
+        self.expr(ExprKind::Var(v.clone()), v.ty.clone())
+    }
+
+    fn leaf(&mut self, cols: &[Var<'src>], row: Row<'src>) -> Expr<'src> {
+        let binds = row.patterns.into_iter()
+            .zip(cols)
+            .filter_map(|(p, col)| match p {
+                Pattern::Bind(name) => Some((self.bound(row.arm, name), col.clone())),
+                _ => None,
+            });
+        let mut binds = row.binds.into_iter().chain(binds).collect::<Vec<_>>();
+        let mut body = self.bodies[row.arm].0.clone();
+        if std::mem::replace(&mut self.bodies[row.arm].1, true) {
+            // A copy binds variables of its own, including those of the
+            // patterns
+            body.freshen(self.gen);
+            let renames = self.vars[row.arm].iter()
+                .map(|v| (v.id, self.gen.fresh(v.name, v.ty.clone())))
+                .collect::<HashMap<_, _>>();
+            body.rename(&renames);
+            binds.iter_mut().for_each(|(v, _)| *v = renames[&v.id].clone());
+        }
+        binds.into_iter().rev().fold(body, |body, (var, col)| {
+            let ty = body.ty.clone();
+            let value = Box::new(self.var(&col));
+            self.expr(ExprKind::Let { var, value, body: Box::new(body) }, ty)
+        })
+    }
+
+    /// The variable an arm binds a name to
+    fn bound(&self, arm: usize, name: &str) -> Var<'src> {
+        self.vars[arm].iter().find(|v| v.name == name).expect("the arm binds the name").clone()
+    }
+
+    /// The expression testing whether a column is a literal
+    fn test(&self, col: &Var<'src>, lit: &ExprLit<'src>) -> Expr<'src> {
+        match lower_lit(lit.clone()) {
+            Lit::Bool(true) => self.var(col),
+            Lit::Bool(false) => self.expr(ExprKind::Unary(UnOp::Not, Box::new(self.var(col))), Type::Bool),
+            lit => {
+                let lit = self.expr(ExprKind::Lit(lit.clone()), lit.ty());
+                self.expr(ExprKind::Binary(BinOp::Eq, Box::new(self.var(col)), Box::new(lit)), Type::Bool)
+            }
+        }
+    }
+
+    fn tree(&mut self, mut cols: Vec<Var<'src>>, mut rows: Vec<Row<'src>>) -> Expr<'src> {
+        // Test the first column the first row needs tested
+        let Some(i) = rows[0].patterns.iter().position(|p| matches!(p, Pattern::Lit(_) | Pattern::Or(_))) else {
+            let row = rows.swap_remove(0);
+            return self.leaf(&cols, row);
+        };
+        cols.swap(0, i);
+        let rows = rows.into_iter()
+            .flat_map(|mut row| {
+                row.patterns.swap(0, i);
+                expand(&row.patterns).into_iter().map(move |patterns| Row { patterns, ..row.clone() })
+            })
+            .collect::<Vec<_>>();
+
+        let col = cols.remove(0);
+        let mut specialize = |lit: Option<&ExprLit>| {
+            let rows = rows.iter()
+                .filter(|r| keeps(&r.patterns[0], lit))
+                .map(|r| {
+                    let mut r = r.clone();
+                    if let Pattern::Bind(name) = r.patterns.remove(0) {
+                        r.binds.push((self.bound(r.arm, name), col.clone()));
+                    }
+                    r
+                })
+                .collect::<Vec<_>>();
+            self.tree(cols.clone(), rows)
+        };
+        let heads = heads(rows.iter().map(|r| r.patterns.as_slice()));
+        let branches = heads.iter().map(|l| (l, specialize(Some(l)))).collect::<Vec<_>>();
+        let mut branches = branches.into_iter().rev();
+        // When the literals are every value, the last one needs no test
+        let last = if complete(&heads, &col.ty) {
+            branches.next().unwrap().1
+        } else {
+            specialize(None)
+        };
+        branches.fold(last, |f, (lit, t)| {
+            let kind = ExprKind::If { cond: Box::new(self.test(&col, lit)), t: Box::new(t), f: Box::new(f) };
+            self.expr(kind, self.ty.clone())
+        })
+    }
+}
+
+/// Compile a match of `scrutinees` against `arms` into a decision tree of
+/// `if`s, of type `ty`. The arms must match every value, which the type
+/// checker makes sure of
+pub fn compile_match<'src>(
+    gen: &mut VarGen,
+    scrutinees: Vec<Expr<'src>>,
+    arms: Vec<Arm<'src>>,
+    ty: Type,
+    span: Span,
+) -> Expr<'src> {
+    let patterns = arms.iter().map(|a| a.patterns.clone()).collect::<Vec<_>>();
+    let tys = scrutinees.iter().map(|s| s.ty.clone()).collect::<Vec<_>>();
+    debug_assert!(is_exhaustive(&patterns, &tys), "the match doesn't cover every value");
+
+    // The scrutinees are evaluated once, in order
+    let mut binds = vec![];
+    let cols = scrutinees.into_iter()
+        .map(|s| match s.kind {
+            ExprKind::Var(v) => v,
+            _ => {
+                let v = gen.fresh("scrutinee", s.ty.clone());
+                binds.push((v.clone(), s));
+                v
+            }
+        })
+        .collect();
+    let rows = patterns.into_iter()
+        .enumerate()
+        .map(|(arm, patterns)| Row { patterns, arm, binds: vec![] })
+        .collect();
+    let (bodies, vars) = arms.into_iter().map(|a| ((a.body, false), a.vars)).unzip();
+    let tree = Compiler { gen, bodies, vars, ty, span }.tree(cols, rows);
+    binds.into_iter().rev().fold(tree, |body, (var, value)| {
+        let ty = body.ty.clone();
+        Expr::new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty, span)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{testing::run, verify::verify};
+
+    fn lit(l: Lit) -> Expr {
+        let ty = l.ty();
+        Expr::new(ExprKind::Lit(l), ty, Span::default())
+    }
+
+    fn var<'src>(v: &Var<'src>) -> Expr<'src> {
+        Expr::new(ExprKind::Var(v.clone()), v.ty.clone(), Span::default())
+    }
+
+    /// The obvious compilation, testing each arm in turn. The last arm has
+    /// to match everything
+    fn naive<'src>(cols: &[Var<'src>], arms: Vec<Arm<'src>>) -> Expr<'src> {
+        fn test<'src>(col: &Var<'src>, p: &Pattern<'src>) -> Option<Expr<'src>> {
+            let bool = |kind| Expr::new(kind, Type::Bool, Span::default());
+            match p {
+                Pattern::Wild | Pattern::Bind(_) => None,
+                Pattern::Lit(l) => Some(bool(ExprKind::Binary(BinOp::Eq, Box::new(var(col)), Box::new(lit(lower_lit(l.clone())))))),
+                Pattern::Or(ps) => ps.iter()
+                    .map(|p| test(col, p).unwrap())
+                    .reduce(|a, b| bool(ExprKind::Binary(BinOp::Or, Box::new(a), Box::new(b)))),
+            }
+        }
+        arms.into_iter().rev().fold(None, |rest: Option<Expr>, arm| {
+            let mut body = arm.body;
+            for (p, col) in arm.patterns.iter().zip(cols).rev() {
+                if let Pattern::Bind(name) = p {
+                    let v = arm.vars.iter().find(|v| v.name == *name).unwrap();
+                    let ty = body.ty.clone();
+                    let kind = ExprKind::Let { var: v.clone(), value: Box::new(var(col)), body: Box::new(body) };
+                    body = Expr::new(kind, ty, Span::default());
+                }
+            }
+            let cond = arm.patterns.iter()
+                .zip(cols)
+                .filter_map(|(p, col)| test(col, p))
+                .reduce(|a, b| Expr::new(ExprKind::Binary(BinOp::And, Box::new(a), Box::new(b)), Type::Bool, Span::default()));
+            Some(match (cond, rest) {
+                (Some(cond), Some(rest)) => {
+                    let ty = body.ty.clone();
+                    Expr::new(ExprKind::If { cond: Box::new(cond), t: Box::new(body), f: Box::new(rest) }, ty, Span::default())
+                }
+                _ => body,
+            })
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_matches_like_naive_lowering() {
+        let mut vars = VarGen::default();
+        let (a, b) = (vars.fresh("a", Type::Int), vars.fresh("b", Type::Bool));
+        let x = vars.fresh("x", Type::Int);
+        let int = |i| Pattern::Lit(ExprLit::Int(i));
+        let bool = |b| Pattern::Lit(ExprLit::Bool(b));
+        let add = |l, r| Expr::new(ExprKind::Binary(BinOp::Add, Box::new(l), Box::new(r)), Type::Int, Span::default());
+        let arms = vec![
+            Arm { patterns: vec![int(1), bool(true)], vars: vec![], body: lit(Lit::Int(10)) },
+            Arm { patterns: vec![Pattern::Or(vec![int(1), int(2)]), Pattern::Wild], vars: vec![], body: lit(Lit::Int(20)) },
+            Arm { patterns: vec![Pattern::Bind("x"), bool(false)], vars: vec![x.clone()], body: add(var(&x), lit(Lit::Int(100))) },
+            Arm { patterns: vec![Pattern::Wild, Pattern::Wild], vars: vec![], body: lit(Lit::Int(0)) },
+        ];
+
+        for i in 0..4 {
+            for flag in [true, false] {
+                let define = |v: &Var<'static>, value| Expr::new(
+                    ExprKind::Define { var: v.clone(), value: Box::new(value) }, Type::Unit, Span::default(),
+                );
+                let program = |e| vec![define(&a, lit(Lit::Int(i))), define(&b, lit(Lit::Bool(flag))), e];
+                let tree = compile_match(&mut vars, vec![var(&a), var(&b)], arms.clone(), Type::Int, Span::default());
+                let tree = program(tree);
+                assert_eq!(verify(&tree), vec![]);
+                assert_eq!(run(&tree), run(&program(naive(&[a.clone(), b.clone()], arms.clone()))));
+            }
+        }
+    }
+
+    #[test]
+    fn test_shares_tests() {
+        let mut vars = VarGen::default();
+        let (a, b) = (vars.fresh("a", Type::Int), vars.fresh("b", Type::Int));
+        let int = |i| Pattern::Lit(ExprLit::Int(i));
+        let str = |s| lit(Lit::Str(s));
+        let arms = vec![
+            Arm { patterns: vec![int(1), int(1)], vars: vec![], body: str("a") },
+            Arm { patterns: vec![int(1), int(2)], vars: vec![], body: str("b") },
+            Arm { patterns: vec![int(2), Pattern::Wild], vars: vec![], body: str("c") },
+            Arm { patterns: vec![Pattern::Wild, Pattern::Wild], vars: vec![], body: str("d") },
+        ];
+        let tree = compile_match(&mut vars, vec![var(&a), var(&b)], arms, Type::Str, Span::default());
+        assert_eq!(
+            tree.to_string(),
+            "(if (== a%0 1) (if (== b%1 1) \"a\" (if (== b%1 2) \"b\" \"d\")) (if (== a%0 2) \"c\" \"d\"))",
+        );
+    }
+
+    #[test]
+    fn test_copies_bind_fresh_variables() {
+        let mut vars = VarGen::default();
+        let (a, b) = (vars.fresh("a", Type::Int), vars.fresh("b", Type::Bool));
+        let x = vars.fresh("x", Type::Int);
+        let arms = vec![
+            Arm { patterns: vec![Pattern::Lit(ExprLit::Int(1)), Pattern::Lit(ExprLit::Bool(true))], vars: vec![], body: lit(Lit::Int(0)) },
+            Arm { patterns: vec![Pattern::Bind("x"), Pattern::Wild], vars: vec![x.clone()], body: var(&x) },
+        ];
+        // The last arm is reached whether `a` is 1 or not, and its copy
+        // binds `x` anew
+        let tree = compile_match(&mut vars, vec![var(&a), var(&b)], arms, Type::Int, Span::default());
+        assert_eq!(tree.to_string(), "(if (== a%0 1) (if b%1 0 (let (x%2 Int) a%0 x%2)) (let (x%3 Int) a%0 x%3))");
+    }
+}
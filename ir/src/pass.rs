@@ -0,0 +1,131 @@
+//! Running passes over the IR by name.
+
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    anf::anf,
+    closure::closure_convert,
+    dce::dce,
+    fold::{fold, FoldWarning},
+    inline::{inline, MAX_INLINE_SIZE},
+    verify::verify,
+    Expr,
+};
+
+/// A transformation of a whole program
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pass {
+    Anf,
+    Closure,
+    Inline,
+    Fold,
+    Dce,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 5] = [Pass::Anf, Pass::Closure, Pass::Inline, Pass::Fold, Pass::Dce];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::Anf     => "anf",
+            Pass::Closure => "closure",
+            Pass::Inline  => "inline",
+            Pass::Fold    => "fold",
+            Pass::Dce     => "dce",
+        }
+    }
+}
+
+impl Display for Pass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Pass::ALL.into_iter()
+            .find(|p| p.name() == s)
+            .ok_or_else(|| format!("unknown pass `{}`", s))
+    }
+}
+
+/// A sequence of passes and their options
+#[derive(Clone, Debug)]
+pub struct PassManager {
+    pub passes: Vec<Pass>,
+    /// See [`inline`]
+    pub max_inline_size: usize,
+    /// Keep unused top-level definitions, see [`dce`]
+    pub keep_exports: bool,
+    /// Panic if a pass turns a valid program into an invalid one, see
+    /// [`verify`]. On by default in debug builds
+    pub verify: bool,
+}
+
+impl PassManager {
+    pub fn new(passes: Vec<Pass>) -> Self {
+        Self { passes, max_inline_size: MAX_INLINE_SIZE, keep_exports: false, verify: cfg!(debug_assertions) }
+    }
+
+    /// The passes run before handing a program to a backend
+    pub fn optimize() -> Self {
+        Self::new(vec![Pass::Inline, Pass::Fold, Pass::Dce])
+    }
+
+    /// Run the passes in order, calling `after` with the result and the
+    /// duration of each. Returns the warnings of the passes too
+    pub fn run<'src>(
+        &self,
+        mut program: Vec<Expr<'src>>,
+        mut after: impl FnMut(Pass, &[Expr<'src>], Duration),
+    ) -> (Vec<Expr<'src>>, Vec<FoldWarning>) {
+        let mut warnings = vec![];
+        // Passes can't be blamed for a program that was invalid to begin with
+        let check = self.verify && verify(&program).is_empty();
+        for &pass in &self.passes {
+            let start = Instant::now();
+            program = match pass {
+                Pass::Anf     => anf(program),
+                Pass::Closure => closure_convert(program),
+                Pass::Inline  => inline(program, self.max_inline_size),
+                Pass::Fold    => {
+                    let (program, ws) = fold(program);
+                    warnings.extend(ws);
+                    program
+                }
+                Pass::Dce     => dce(program, self.keep_exports),
+            };
+            if check {
+                if let Some(e) = verify(&program).first() {
+                    panic!("invalid IR after the {} pass at {:?}: {}", pass, e.span, e.message);
+                }
+            }
+            after(pass, &program, start.elapsed());
+        }
+        (program, warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::assert_preserves;
+
+    #[test]
+    fn test_pass_manager() {
+        let pm = PassManager::new(Pass::ALL.to_vec());
+        let mut ran = vec![];
+        let programs = assert_preserves(|p| pm.run(p, |pass, _, _| ran.push(pass)).0);
+        assert_eq!(ran.len(), Pass::ALL.len() * programs.len());
+        assert_eq!(ran[..5], Pass::ALL);
+        assert!(programs.iter().all(|p| crate::closure::is_closed(p)));
+        assert_eq!("fold".parse(), Ok(Pass::Fold));
+    }
+}
@@ -0,0 +1,172 @@
+//! A readable, indented view of the IR in a syntax close to the source
+//! language. Variables are printed with their ids, as in `x%3`, since
+//! passes can introduce distinct variables with the same name.
+
+use std::fmt::Write;
+
+use crate::{Expr, ExprKind, UnOp, Var};
+
+const INDENT: &str = "    ";
+
+/// Pretty-print a program, one top-level expression per line
+pub fn pretty(program: &[Expr]) -> String {
+    let mut out = String::new();
+    for e in program {
+        expr(&mut out, e, 0);
+        out.push_str(";\n");
+    }
+    out
+}
+
+/// Pretty-print a single expression
+pub fn pretty_expr(e: &Expr) -> String {
+    let mut out = String::new();
+    expr(&mut out, e, 0);
+    out
+}
+
+fn var(out: &mut String, v: &Var) {
+    let _ = write!(out, "{}{}", v.name, v.id);
+}
+
+fn newline(out: &mut String, depth: usize) {
+    out.push('\n');
+    (0..depth).for_each(|_| out.push_str(INDENT));
+}
+
+/// Operands that would be ambiguous without parentheses
+fn operand(out: &mut String, e: &Expr, depth: usize) {
+    match e.kind {
+        ExprKind::Lit(_) | ExprKind::Var(_) | ExprKind::Call { .. } | ExprKind::Block { .. } => {
+            expr(out, e, depth)
+        }
+        _ => {
+            out.push('(');
+            expr(out, e, depth);
+            out.push(')');
+        }
+    }
+}
+
+fn expr(out: &mut String, e: &Expr, depth: usize) {
+    match &e.kind {
+        ExprKind::Lit(l) => { let _ = write!(out, "{}", l); }
+        ExprKind::Var(v) => var(out, v),
+        ExprKind::Unary(op, x) => {
+            out.push_str(match op {
+                UnOp::Neg => "-",
+                UnOp::Not => "!",
+            });
+            operand(out, x, depth);
+        }
+        ExprKind::Binary(op, l, r) => {
+            operand(out, l, depth);
+            let _ = write!(out, " {} ", op.symbol());
+            operand(out, r, depth);
+        }
+        ExprKind::Lambda { params, body } => {
+            out.push_str("fun (");
+            for (i, p) in params.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                var(out, p);
+                let _ = write!(out, ": {}", p.ty);
+            }
+            out.push_str(") ->");
+            newline(out, depth + 1);
+            expr(out, body, depth + 1);
+        }
+        ExprKind::Call { func, args } => {
+            operand(out, func, depth);
+            out.push('(');
+            for (i, a) in args.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                expr(out, a, depth);
+            }
+            out.push(')');
+        }
+        ExprKind::If { cond, t, f } => {
+            out.push_str("if ");
+            expr(out, cond, depth);
+            out.push_str(" then");
+            newline(out, depth + 1);
+            expr(out, t, depth + 1);
+            newline(out, depth);
+            out.push_str("else");
+            newline(out, depth + 1);
+            expr(out, f, depth + 1);
+        }
+        ExprKind::Let { var: v, value, body } => {
+            out.push_str("let ");
+            var(out, v);
+            let _ = write!(out, ": {} = ", v.ty);
+            expr(out, value, depth);
+            out.push_str(" in");
+            newline(out, depth);
+            expr(out, body, depth);
+        }
+        ExprKind::Define { var: v, value } => {
+            out.push_str("let ");
+            var(out, v);
+            let _ = write!(out, ": {} = ", v.ty);
+            expr(out, value, depth);
+        }
+        ExprKind::Block { exprs, void } => {
+            out.push('{');
+            for (i, e) in exprs.iter().enumerate() {
+                newline(out, depth + 1);
+                expr(out, e, depth + 1);
+                if *void || i + 1 != exprs.len() {
+                    out.push(';');
+                }
+            }
+            newline(out, depth);
+            out.push('}');
+        }
+        ExprKind::Closure { func, env } => {
+            out.push_str("closure ");
+            var(out, func);
+            out.push_str(" [");
+            for (i, e) in env.iter().enumerate() {
+                if i != 0 {
+                    out.push_str(", ");
+                }
+                expr(out, e, depth);
+            }
+            out.push(']');
+        }
+        ExprKind::EnvGet { closure, index } => {
+            operand(out, closure, depth);
+            let _ = write!(out, ".{}", index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::lower;
+
+    #[test]
+    fn test_pretty() {
+        let program = lower("
+            let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1;
+            let x = 2 in { let y = -x; fact(y + 5); };
+        ");
+        assert_eq!(pretty(&program), "\
+let fact%0: (Int) -> Int = fun (n%1: Int) ->
+    if n%1 > 1 then
+        n%1 * fact%0(n%1 - 1)
+    else
+        1;
+let x%2: Int = 2 in
+{
+    let y%3: Int = -x%2;
+    fact%0(y%3 + 5);
+};
+");
+    }
+}
@@ -0,0 +1,66 @@
+//! Helpers shared by the tests of the passes.
+
+use chumsky::{Parser, prelude::Input};
+use syntax::{
+    expr::{Expr as SExpr, Span, Spanned},
+    parser::{lexer, exprs_parser},
+    source::FileId,
+};
+use typing::infer::infer_exprs;
+
+use crate::{eval::eval_exprs, lower_program, Expr};
+
+/// Lex and parse a program that is expected to be valid
+pub fn parse(src: &str) -> Vec<Spanned<SExpr<'_>>> {
+    let tokens = lexer(FileId::default()).parse(src).into_result().expect("lexing failed");
+    let ast = exprs_parser()
+        .parse(tokens.as_slice().spanned(Span::new(FileId::default(), src.len(), src.len())))
+        .into_result()
+        .expect("parsing failed");
+    ast
+}
+
+/// Run the front end on a program that is expected to be well-typed and
+/// lower it
+pub fn lower(src: &str) -> Vec<Expr<'_>> {
+    let (typed, errs) = infer_exprs(parse(src));
+    assert!(errs.is_empty(), "type errors: {:?}", errs);
+    lower_program(typed)
+}
+
+/// Programs covering every kind of expression, used to check that passes
+/// keep the meaning of programs
+pub const PROGRAMS: [&str; 10] = [
+    "let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1; fact(5);",
+    "let adder = fun (n Int) -> fun (x Int) -> x + n; let addfive = adder(5); addfive(10);",
+    "let x = 4 in { let x = x + 1; let f = fun (y Int) -> x * y; f(x) };",
+    "{ let fib = fun (n Int) Int -> if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15) };",
+    "let f = fun (a Int, b Int, c Int) -> a * b - c; f(f(1, 2, 3), { 4; 5 }, let y = 6 in y * y);",
+    "!(1 == 2) && true || false; \"hello\" < \"world\"; -7 / 2; -7 % 2; 9223372036854775807 + 1;",
+    "let k = 10 in { let y = k + 1; let g = fun (u Int) -> y * k + u; g(1) }; { 1; 2; };",
+    "let big = fun (x Int) -> if x > 1 && x < 10 then \"mid\" else \"out\"; big(5) |> fun (s Str) -> if s == \"mid\" then \"yes\" else s;",
+    "let m = fun (n Int, b Bool) -> match n, b { 0, _ -> \"zero\", 1 | 2, true -> \"small\", k, false -> if k > 5 then \"big\" else \"off\", _, _ -> \"on\" }; m(0, true); m(2, true); m(2, false); m(9, false); m(9, true); match \"a\" { \"b\" -> 1, s -> 2 };",
+    "let a = 1; let mk = fun (x Int) -> fun (u Int) -> x; let g = mk(a); let a = a + 1; g(0); let f = fun (u Int) -> a * u; a; f(3);",
+];
+
+/// The printed values of a program according to the evaluator
+pub fn run(program: &[Expr]) -> Vec<String> {
+    eval_exprs(program).expect("evaluation failed")
+        .iter()
+        .map(|v| v.to_string())
+        .collect()
+}
+
+/// Check that a pass doesn't change what any of [`PROGRAMS`] evaluate to,
+/// and return the transformed programs for further checks
+pub fn assert_preserves<'a>(mut pass: impl FnMut(Vec<Expr<'a>>) -> Vec<Expr<'a>>) -> Vec<Vec<Expr<'a>>> {
+    PROGRAMS.iter()
+        .map(|src| {
+            let before = lower(src);
+            let expected = run(&before);
+            let after = pass(before);
+            assert_eq!(run(&after), expected, "the pass changed the meaning of {}", src);
+            after
+        })
+        .collect()
+}
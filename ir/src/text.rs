@@ -0,0 +1,411 @@
+//! The textual form of the IR: the S-expressions printed by the `Display`
+//! implementation of [`Expr`], and [`parse`] to read them back.
+//!
+//! Variables are written `name%id`, binders carry their type, as in
+//! `(let (x%1 Int) 1 (+ x%1 1))`, and the types of the other expressions
+//! follow from them. Types are written `Int`, `(fun (Int Str) Bool)`,
+//! `(tuple Int Int)`, `(array Int)` or `(var 0)`. `;` starts a comment.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+use syntax::source::FileId;
+
+use crate::{BinOp, Expr, ExprKind, Lit, Span, Type, UnOp, Var, VarId};
+
+/// Displays a type in the textual IR
+pub struct SexprType<'a>(pub &'a Type);
+
+impl Display for SexprType<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.0 {
+            Type::Unit => write!(f, "Unit"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Int  => write!(f, "Int"),
+            Type::Str  => write!(f, "Str"),
+            Type::Var(id) => write!(f, "(var {})", id),
+            Type::Func(args, ret) => {
+                write!(f, "(fun (")?;
+                for (i, a) in args.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", SexprType(a))?;
+                }
+                write!(f, ") {})", SexprType(ret))
+            }
+            Type::Tuple(tys) => {
+                write!(f, "(tuple")?;
+                tys.iter().try_for_each(|t| write!(f, " {}", SexprType(t)))?;
+                write!(f, ")")
+            }
+            Type::Array(ty) => write!(f, "(array {})", SexprType(ty)),
+        }
+    }
+}
+
+/// Displays a variable with its type, as it is written where it is bound
+pub struct Binder<'a, 'src>(pub &'a Var<'src>);
+
+impl Display for Binder<'_, '_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "({}{} {})", self.0.name, self.0.id, SexprType(&self.0.ty))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+macro_rules! bail {
+    ($span:expr, $($arg:tt)*) => {
+        return Err(ParseError { message: format!($($arg)*), span: $span })
+    };
+}
+
+#[derive(Debug)]
+enum Sexpr<'src> {
+    Atom(&'src str, Span),
+    Str(&'src str, Span),
+    List(Vec<Sexpr<'src>>, Span),
+}
+
+impl<'src> Sexpr<'src> {
+    fn span(&self) -> Span {
+        match self {
+            Sexpr::Atom(_, s) | Sexpr::Str(_, s) | Sexpr::List(_, s) => *s,
+        }
+    }
+
+    fn list(&self, what: &str) -> Result<&[Sexpr<'src>], ParseError> {
+        match self {
+            Sexpr::List(items, _) => Ok(items),
+            e => bail!(e.span(), "expected {}", what),
+        }
+    }
+}
+
+/// Split the source into S-expressions
+fn read(file: FileId, src: &str) -> Result<Vec<Sexpr<'_>>, ParseError> {
+    let span = |start, end| Span::new(file, start, end);
+    let bytes = src.as_bytes();
+    let mut stack: Vec<(usize, Vec<Sexpr>)> = vec![(0, vec![])];
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b if b.is_ascii_whitespace() => i += 1,
+            b';' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                stack.push((i, vec![]));
+                i += 1;
+            }
+            b')' => {
+                if stack.len() == 1 {
+                    bail!(span(i, i + 1), "unexpected `)`");
+                }
+                let (open, items) = stack.pop().unwrap();
+                i += 1;
+                stack.last_mut().unwrap().1.push(Sexpr::List(items, span(open, i)));
+            }
+            b'"' => {
+                // String literals of the language can't contain quotes
+                let Some(len) = src[i + 1..].find('"') else {
+                    bail!(span(i, src.len()), "unterminated string");
+                };
+                i += len + 2;
+                let s = Sexpr::Str(&src[start + 1..i - 1], span(start, i));
+                stack.last_mut().unwrap().1.push(s);
+            }
+            _ => {
+                while i < bytes.len() && !matches!(bytes[i], b'(' | b')' | b'"' | b';')
+                    && !bytes[i].is_ascii_whitespace() {
+                    i += 1;
+                }
+                let atom = Sexpr::Atom(&src[start..i], span(start, i));
+                stack.last_mut().unwrap().1.push(atom);
+            }
+        }
+    }
+    if stack.len() > 1 {
+        let open = stack.last().unwrap().0;
+        bail!(span(open, open + 1), "unclosed `(`");
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+fn binop(s: &str) -> Option<BinOp> {
+    use BinOp::*;
+    [Add, Sub, Mul, Div, Rem, Eq, Ne, Lt, Le, Gt, Ge, And, Or].into_iter().find(|op| op.symbol() == s)
+}
+
+/// Split `name%id` into its parts
+fn var_name(s: &str) -> Option<(&str, VarId)> {
+    let (name, id) = s.rsplit_once('%')?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, VarId(id.parse().ok()?)))
+}
+
+struct Parser {
+    // The types of the variables, from where they are bound
+    types: HashMap<VarId, Type>,
+}
+
+impl Parser {
+    fn ty(&self, e: &Sexpr) -> Result<Type, ParseError> {
+        Ok(match e {
+            Sexpr::Atom("Unit", _) => Type::Unit,
+            Sexpr::Atom("Bool", _) => Type::Bool,
+            Sexpr::Atom("Int", _)  => Type::Int,
+            Sexpr::Atom("Str", _)  => Type::Str,
+            Sexpr::List(items, span) => match items.as_slice() {
+                [Sexpr::Atom("fun", _), args, ret] => Type::Func(
+                    args.list("argument types")?.iter().map(|a| self.ty(a)).collect::<Result<_, _>>()?,
+                    Box::new(self.ty(ret)?),
+                ),
+                [Sexpr::Atom("tuple", _), tys @ ..] => {
+                    Type::Tuple(tys.iter().map(|t| self.ty(t)).collect::<Result<_, _>>()?)
+                }
+                [Sexpr::Atom("array", _), ty] => Type::Array(Box::new(self.ty(ty)?)),
+                [Sexpr::Atom("var", _), Sexpr::Atom(n, s)] => match n.parse() {
+                    Ok(n) => Type::Var(n),
+                    Err(_) => bail!(*s, "expected a type variable number"),
+                },
+                _ => bail!(*span, "expected a type"),
+            },
+            e => bail!(e.span(), "expected a type"),
+        })
+    }
+
+    /// Parse a `(name%id type)` binder
+    fn binder<'src>(&self, e: &Sexpr<'src>) -> Result<Var<'src>, ParseError> {
+        match e.list("a binder")? {
+            [Sexpr::Atom(v, span), ty] => match var_name(v) {
+                Some((name, id)) => Ok(Var { id, name, ty: self.ty(ty)? }),
+                None => bail!(*span, "expected a variable"),
+            },
+            _ => bail!(e.span(), "expected a binder"),
+        }
+    }
+
+    /// Record the types of all the variables bound in an expression
+    fn collect(&mut self, e: &Sexpr) -> Result<(), ParseError> {
+        if let Sexpr::List(items, _) = e {
+            let binders = match items.as_slice() {
+                [Sexpr::Atom("lambda", _), Sexpr::List(params, _), _] => params.iter().collect(),
+                [Sexpr::Atom("let" | "define", _), binder, ..] => vec![binder],
+                _ => vec![],
+            };
+            for b in binders {
+                let var = self.binder(b)?;
+                self.types.insert(var.id, var.ty);
+            }
+            items.iter().try_for_each(|e| self.collect(e))?;
+        }
+        Ok(())
+    }
+
+    fn expr<'src>(&self, e: &Sexpr<'src>) -> Result<Expr<'src>, ParseError> {
+        let span = e.span();
+        let new = |kind, ty| Ok(Expr::new(kind, ty, span));
+        let items = match e {
+            Sexpr::Str(s, _) => return new(ExprKind::Lit(Lit::Str(s)), Type::Str),
+            Sexpr::Atom("true", _) => return new(ExprKind::Lit(Lit::Bool(true)), Type::Bool),
+            Sexpr::Atom("false", _) => return new(ExprKind::Lit(Lit::Bool(false)), Type::Bool),
+            Sexpr::Atom(a, _) => {
+                if let Ok(i) = a.parse() {
+                    return new(ExprKind::Lit(Lit::Int(i)), Type::Int);
+                }
+                let var = self.var(a, span)?;
+                let ty = var.ty.clone();
+                return new(ExprKind::Var(var), ty);
+            }
+            Sexpr::List(items, _) => items.as_slice(),
+        };
+
+        match items {
+            [] => new(ExprKind::Lit(Lit::Unit), Type::Unit),
+            [Sexpr::Atom("lambda", _), params, body] => {
+                let params = params.list("parameters")?
+                    .iter()
+                    .map(|p| self.binder(p))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let body = self.expr(body)?;
+                let ty = Type::Func(params.iter().map(|p| p.ty.clone()).collect(), Box::new(body.ty.clone()));
+                new(ExprKind::Lambda { params, body: Box::new(body) }, ty)
+            }
+            [Sexpr::Atom("let", _), var, value, body] => {
+                let (var, value, body) = (self.binder(var)?, self.expr(value)?, self.expr(body)?);
+                let ty = body.ty.clone();
+                new(ExprKind::Let { var, value: Box::new(value), body: Box::new(body) }, ty)
+            }
+            [Sexpr::Atom("define", _), var, value] => {
+                let (var, value) = (self.binder(var)?, self.expr(value)?);
+                new(ExprKind::Define { var, value: Box::new(value) }, Type::Unit)
+            }
+            [Sexpr::Atom("if", _), cond, t, f] => {
+                let (cond, t, f) = (self.expr(cond)?, self.expr(t)?, self.expr(f)?);
+                let ty = t.ty.clone();
+                new(ExprKind::If { cond: Box::new(cond), t: Box::new(t), f: Box::new(f) }, ty)
+            }
+            [Sexpr::Atom("block", _), exprs, rest @ ..] => {
+                let void = match rest {
+                    [] => false,
+                    [Sexpr::List(unit, _)] if unit.is_empty() => true,
+                    _ => bail!(span, "expected `(block (...))` or `(block (...) ())`"),
+                };
+                let exprs = exprs.list("the expressions of a block")?
+                    .iter()
+                    .map(|e| self.expr(e))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let ty = match exprs.last() {
+                    Some(e) if !void => e.ty.clone(),
+                    _ => Type::Unit,
+                };
+                new(ExprKind::Block { exprs, void }, ty)
+            }
+            [Sexpr::Atom("closure", _), Sexpr::Atom(func, fspan), env @ ..] => {
+                let func = self.var(func, *fspan)?;
+                let ty = match &func.ty {
+                    Type::Func(params, _) if !params.is_empty() => params[0].clone(),
+                    _ => bail!(*fspan, "`{}` doesn't take a closure record", func.name),
+                };
+                let env = env.iter().map(|e| self.expr(e)).collect::<Result<_, _>>()?;
+                new(ExprKind::Closure { func, env }, ty)
+            }
+            [Sexpr::Atom("env", _), closure, Sexpr::Atom(index, ispan), ty] => {
+                let Ok(index) = index.parse() else { bail!(*ispan, "expected an index") };
+                let closure = Box::new(self.expr(closure)?);
+                new(ExprKind::EnvGet { closure, index }, self.ty(ty)?)
+            }
+            [Sexpr::Atom(op @ ("neg" | "not"), _), x] => {
+                let (op, ty) = if *op == "neg" { (UnOp::Neg, Type::Int) } else { (UnOp::Not, Type::Bool) };
+                new(ExprKind::Unary(op, Box::new(self.expr(x)?)), ty)
+            }
+            [Sexpr::Atom(op, _), l, r] if binop(op).is_some() => {
+                let op = binop(op).unwrap();
+                let ty = match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => Type::Int,
+                    _ => Type::Bool,
+                };
+                new(ExprKind::Binary(op, Box::new(self.expr(l)?), Box::new(self.expr(r)?)), ty)
+            }
+            [Sexpr::Atom(kw @ ("lambda" | "let" | "define" | "if" | "block" | "closure" | "env" | "neg" | "not"), _), ..] => {
+                bail!(span, "malformed `{}`", kw)
+            }
+            [func, args @ ..] => {
+                let func = self.expr(func)?;
+                let ty = match &func.ty {
+                    Type::Func(_, ret) => (**ret).clone(),
+                    ty => bail!(func.span, "calling a value of type {}", ty),
+                };
+                let args = args.iter().map(|a| self.expr(a)).collect::<Result<_, _>>()?;
+                new(ExprKind::Call { func: Box::new(func), args }, ty)
+            }
+        }
+    }
+
+    fn var<'src>(&self, s: &'src str, span: Span) -> Result<Var<'src>, ParseError> {
+        let Some((name, id)) = var_name(s) else { bail!(span, "unexpected `{}`", s) };
+        match self.types.get(&id) {
+            Some(ty) => Ok(Var { id, name, ty: ty.clone() }),
+            None => bail!(span, "`{}` is not bound anywhere", s),
+        }
+    }
+}
+
+/// Read a program in the textual IR, the source of `file`
+pub fn parse(file: FileId, src: &str) -> Result<Vec<Expr<'_>>, ParseError> {
+    let sexprs = read(file, src)?;
+    let mut parser = Parser { types: HashMap::new() };
+    sexprs.iter().try_for_each(|e| parser.collect(e))?;
+    sexprs.iter().map(|e| parser.expr(e)).collect()
+}
+
+/// Read types separated by spaces, as [`SexprType`] writes them
+pub fn parse_types(file: FileId, src: &str) -> Result<Vec<Type>, ParseError> {
+    let parser = Parser { types: HashMap::new() };
+    read(file, src)?.iter().map(|t| parser.ty(t)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pass::{Pass, PassManager},
+        testing::{lower, PROGRAMS},
+    };
+
+    fn print(program: &[Expr]) -> String {
+        program.iter().map(|e| format!("{}\n", e)).collect()
+    }
+
+    // Every expression, with its type
+    fn types(program: &[Expr]) -> Vec<(String, Type)> {
+        let mut out = vec![];
+        program.iter().for_each(|e| e.walk(&mut |e| out.push((e.to_string(), e.ty.clone()))));
+        out
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for src in PROGRAMS {
+            let before = PassManager::new(Pass::ALL.to_vec()).run(lower(src), |_, _, _| {}).0;
+            for program in [lower(src), before] {
+                let text = print(&program);
+                let after = parse(FileId::default(), &text).unwrap_or_else(|e| panic!("{:?} in\n{}", e, text));
+                assert_eq!(print(&after), text);
+                assert_eq!(types(&after), types(&program));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = |src| parse(FileId::default(), src).unwrap_err();
+        assert_eq!(err("(+ 1 2").message, "unclosed `(`");
+        assert_eq!(err("(let (x%0 Int) 1 y%1)").span, Span::new(FileId::default(), 17, 20));
+        assert_eq!(err("(lambda ((x%0 Int)))").message, "malformed `lambda`");
+        assert_eq!(err("(1 2)").message, "calling a value of type Int");
+        assert_eq!(parse_types(FileId::default(), "Int (fun ((var 0)) (array Str))").unwrap(), [
+            Type::Int,
+            Type::Func(vec![Type::Var(0)], Box::new(Type::Array(Box::new(Type::Str)))),
+        ]);
+        assert!(parse_types(FileId::default(), "(fun Int)").is_err());
+    }
+
+    #[test]
+    fn test_pass_pairs() {
+        // testdata/<pass>/<name>.ir is the input of the pass, and
+        // <name>.out.ir what it should produce
+        let root = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata");
+        let mut n = 0;
+        for dir in std::fs::read_dir(root).unwrap() {
+            let dir = dir.unwrap().path();
+            let pass = dir.file_name().unwrap().to_str().unwrap().parse::<Pass>().unwrap();
+            for file in std::fs::read_dir(&dir).unwrap() {
+                let path = file.unwrap().path();
+                let name = path.to_str().unwrap();
+                if !name.ends_with(".ir") || name.ends_with(".out.ir") {
+                    continue;
+                }
+                let input = std::fs::read_to_string(&path).unwrap();
+                let expected = std::fs::read_to_string(name.replace(".ir", ".out.ir")).unwrap();
+                let output = PassManager::new(vec![pass]).run(parse(FileId::default(), &input).unwrap(), |_, _, _| {}).0;
+                assert_eq!(print(&output), print(&parse(FileId::default(), &expected).unwrap()), "{}", name);
+                n += 1;
+            }
+        }
+        assert!(n > 0);
+    }
+}
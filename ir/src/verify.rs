@@ -0,0 +1,282 @@
+//! Checking that the IR is well formed.
+//!
+//! Every variable must be used where it is bound, calls of known functions
+//! must pass as many arguments as the functions take, and the type of each
+//! expression must agree with the types of its parts. Types that still
+//! contain type variables are not checked. There are no forms that only
+//! exist before lowering, as `|>` is already lowered to calls.
+
+use std::collections::HashMap;
+
+use crate::{BinOp, Expr, ExprKind, Span, Type, UnOp, Var, VarId};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VerifyError {
+    pub message: String,
+    pub span: Span,
+}
+
+/// Check a program, returning everything that is wrong with it
+pub fn verify(program: &[Expr]) -> Vec<VerifyError> {
+    let mut v = Verifier { globals: HashMap::new(), locals: vec![], errors: vec![] };
+    for e in program {
+        if let ExprKind::Define { var, value } = &e.kind {
+            let arity = arity(value, &v);
+            v.globals.entry(var.id).or_default().push((&var.ty, arity));
+        }
+    }
+    for e in program {
+        match &e.kind {
+            ExprKind::Define { var, value } => v.define(e, var, value),
+            _ => v.expr(e),
+        }
+    }
+    v.errors
+}
+
+fn has_type_var(ty: &Type) -> bool {
+    match ty {
+        Type::Var(_) => true,
+        Type::Func(args, ret) => args.iter().any(has_type_var) || has_type_var(ret),
+        Type::Tuple(tys) => tys.iter().any(has_type_var),
+        Type::Array(ty) => has_type_var(ty),
+        _ => false,
+    }
+}
+
+/// The number of arguments a value takes, if it is a known function
+fn arity(value: &Expr, v: &Verifier) -> Option<usize> {
+    match &value.kind {
+        ExprKind::Lambda { params, .. } => Some(params.len()),
+        // Calling a closure record passes the record too
+        ExprKind::Closure { func, .. } => v.arity(func.id).and_then(|n| n.checked_sub(1)),
+        _ => None,
+    }
+}
+
+struct Verifier<'a> {
+    // The type and arity of every definition of the top-level variables,
+    // which can be defined more than once
+    globals: HashMap<VarId, Vec<(&'a Type, Option<usize>)>>,
+    locals: Vec<(VarId, &'a Type, Option<usize>)>,
+    errors: Vec<VerifyError>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&mut self, span: Span, message: String) {
+        self.errors.push(VerifyError { message, span });
+    }
+
+    /// Check that an expression has the type it should
+    fn expect(&mut self, e: &Expr, ty: &Type, what: &str) {
+        if !has_type_var(&e.ty) && !has_type_var(ty) && e.ty != *ty {
+            self.error(e.span, format!("{} should have type {}, but has type {}", what, ty, e.ty));
+        }
+    }
+
+    fn arity(&self, id: VarId) -> Option<usize> {
+        if let Some((_, _, arity)) = self.locals.iter().rev().find(|(v, _, _)| *v == id) {
+            return *arity;
+        }
+        let defs = self.globals.get(&id)?;
+        defs.iter().all(|(_, a)| *a == defs[0].1).then_some(defs[0].1).flatten()
+    }
+
+    /// Check that a variable is bound, with the type it is used with
+    fn var(&mut self, var: &Var, span: Span) {
+        let types = match self.locals.iter().rev().find(|(v, _, _)| *v == var.id) {
+            Some((_, ty, _)) => vec![*ty],
+            None => match self.globals.get(&var.id) {
+                Some(defs) => defs.iter().map(|(ty, _)| *ty).collect(),
+                None => {
+                    self.error(span, format!("`{}{}` is not bound here", var.name, var.id));
+                    return;
+                }
+            },
+        };
+        if !has_type_var(&var.ty) && !types.iter().any(|ty| has_type_var(ty) || **ty == var.ty) {
+            self.error(span, format!(
+                "`{}{}` is used with type {}, but is bound with type {}",
+                var.name, var.id, var.ty, types[0],
+            ));
+        }
+    }
+
+    fn define(&mut self, e: &'a Expr, var: &'a Var, value: &'a Expr) {
+        self.expr(value);
+        self.expect(value, &var.ty, &format!("the value of `{}{}`", var.name, var.id));
+        self.expect(e, &Type::Unit, "a definition");
+    }
+
+    fn expr(&mut self, e: &'a Expr) {
+        match &e.kind {
+            ExprKind::Lit(l) => self.expect(e, &l.ty(), "a literal"),
+            ExprKind::Var(v) => self.var(v, e.span),
+            ExprKind::Unary(op, x) => {
+                self.expr(x);
+                let ty = match op {
+                    UnOp::Neg => Type::Int,
+                    UnOp::Not => Type::Bool,
+                };
+                self.expect(x, &ty, "the operand");
+                self.expect(e, &ty, "the operation");
+            }
+            ExprKind::Binary(op, l, r) => {
+                self.expr(l);
+                self.expr(r);
+                let ty = match op {
+                    BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => {
+                        self.expect(l, &Type::Int, "the operand");
+                        self.expect(r, &Type::Int, "the operand");
+                        Type::Int
+                    }
+                    BinOp::And | BinOp::Or => {
+                        self.expect(l, &Type::Bool, "the operand");
+                        self.expect(r, &Type::Bool, "the operand");
+                        Type::Bool
+                    }
+                    _ => {
+                        self.expect(r, &l.ty, "the right operand");
+                        Type::Bool
+                    }
+                };
+                self.expect(e, &ty, "the operation");
+            }
+            ExprKind::Lambda { params, body } => {
+                let n = self.locals.len();
+                self.locals.extend(params.iter().map(|p| (p.id, &p.ty, None)));
+                self.expr(body);
+                self.locals.truncate(n);
+                let ty = Type::Func(params.iter().map(|p| p.ty.clone()).collect(), Box::new(body.ty.clone()));
+                self.expect(e, &ty, "the function");
+            }
+            ExprKind::Call { func, args } => {
+                self.expr(func);
+                args.iter().for_each(|a| self.expr(a));
+                let arity = match &func.kind {
+                    ExprKind::Var(v) => self.arity(v.id),
+                    ExprKind::Lambda { params, .. } => Some(params.len()),
+                    _ => None,
+                };
+                match (&func.ty, arity) {
+                    (_, Some(n)) if n != args.len() => {
+                        self.error(e.span, format!("the function takes {} arguments, but is given {}", n, args.len()));
+                    }
+                    (Type::Func(params, ret), _) => {
+                        if params.len() != args.len() {
+                            self.error(e.span, format!(
+                                "the function's type takes {} arguments, but it is given {}",
+                                params.len(), args.len(),
+                            ));
+                        } else {
+                            params.iter().zip(args).for_each(|(p, a)| self.expect(a, p, "the argument"));
+                        }
+                        self.expect(e, ret, "the call");
+                    }
+                    (ty, _) if !has_type_var(ty) => {
+                        self.error(func.span, format!("calling a value of type {}", ty));
+                    }
+                    _ => {}
+                }
+            }
+            ExprKind::If { cond, t, f } => {
+                self.expr(cond);
+                self.expr(t);
+                self.expr(f);
+                self.expect(cond, &Type::Bool, "the condition");
+                self.expect(f, &t.ty, "the else branch");
+                self.expect(e, &t.ty, "the `if`");
+            }
+            ExprKind::Let { var, value, body } => {
+                self.expr(value);
+                self.expect(value, &var.ty, &format!("the value of `{}{}`", var.name, var.id));
+                let arity = arity(value, self);
+                self.locals.push((var.id, &var.ty, arity));
+                self.expr(body);
+                self.locals.pop();
+                self.expect(e, &body.ty, "the `let`");
+            }
+            ExprKind::Define { var, value } => {
+                // Bound for the rest of the enclosing block, and in its own
+                // value so that functions can be recursive
+                let arity = arity(value, self);
+                self.locals.push((var.id, &var.ty, arity));
+                self.define(e, var, value);
+            }
+            ExprKind::Block { exprs, void } => {
+                let n = self.locals.len();
+                exprs.iter().for_each(|e| self.expr(e));
+                self.locals.truncate(n);
+                let ty = match exprs.last() {
+                    Some(last) if !void => last.ty.clone(),
+                    _ => Type::Unit,
+                };
+                self.expect(e, &ty, "the block");
+            }
+            ExprKind::Closure { func, env } => {
+                self.var(func, e.span);
+                env.iter().for_each(|e| self.expr(e));
+                match &func.ty {
+                    Type::Func(params, ret) if !params.is_empty() => {
+                        let ty = Type::Func(params[1..].to_vec(), ret.clone());
+                        self.expect(e, &params[0], "the closure");
+                        self.expect(e, &ty, "the closure");
+                    }
+                    ty => {
+                        let message = format!("the code of a closure should take the record, but has type {}", ty);
+                        self.error(e.span, message);
+                    }
+                }
+            }
+            ExprKind::EnvGet { closure, .. } => self.expr(closure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pass::{Pass, PassManager},
+        testing::{lower, PROGRAMS},
+        text::parse,
+    };
+    use syntax::source::FileId;
+
+    #[test]
+    fn test_passes_produce_valid_ir() {
+        for src in PROGRAMS {
+            assert_eq!(verify(&lower(src)), vec![], "{}", src);
+            let mut pm = PassManager::new(Pass::ALL.to_vec());
+            pm.verify = false;
+            pm.run(lower(src), |pass, program, _| {
+                assert_eq!(verify(program), vec![], "after {} in {}", pass, src);
+            });
+        }
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let errors = |src| verify(&parse(FileId::default(), src).unwrap()).into_iter().map(|e| e.message).collect::<Vec<_>>();
+        assert_eq!(errors("(let (x%0 Int) 1 2) x%0"), ["`x%0` is not bound here"]);
+        assert_eq!(errors("
+            (define (f%0 (fun (Int) Int)) (lambda ((x%1 Int)) x%1))
+            (f%0 1 2)
+            (f%0 \"one\")
+        "), [
+            "the function takes 1 arguments, but is given 2",
+            "the argument should have type Int, but has type Str",
+        ]);
+        assert_eq!(errors("(if 1 2 \"two\")"), [
+            "the condition should have type Bool, but has type Int",
+            "the else branch should have type Int, but has type Str",
+        ]);
+
+        let mut program = parse(FileId::default(), "(let (x%0 Int) 1 (+ x%0 x%0))").unwrap();
+        let ExprKind::Let { body, .. } = &mut program[0].kind else { panic!() };
+        body.ty = Type::Str;
+        let errors = verify(&program);
+        assert_eq!(errors[0].message, "the operation should have type Int, but has type Str");
+        assert_eq!(errors[0].span, Span::new(FileId::default(), 17, 28));
+    }
+}
@@ -1,7 +1,6 @@
-use std::fmt::{ Display, Formatter, self };
-use chumsky::span::SimpleSpan;
+use std::{fmt::{ Display, Formatter, self }, ops::Range};
 
-use super::ty::Type;
+use super::{source::FileId, ty::Type};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Delim { Paren, Brack, Brace }
@@ -16,13 +15,14 @@ pub enum Token<'src> {
     Add, Sub, Mul, Div, Rem,
     Eq, Ne, Lt, Gt, Le, Ge,
     And, Or, Not,
-    Pipe,
+    Pipe, Bar,
 
-    Assign, Comma, Colon, Semicolon,
+    Assign, Comma, Colon, Semicolon, Dot,
     Open(Delim), Close(Delim),
     Lambda, Arrow,
 
-    Let, In, Func, Return, If, Then, Else,
+    Let, In, Func, Return, If, Then, Else, Match,
+    Module, Import, Pub,
 }
 
 impl<'src> Display for Token<'src> {
@@ -49,11 +49,13 @@ impl<'src> Display for Token<'src> {
             Token::Or  => write!(f, "||"),
             Token::Not => write!(f, "!"),
             Token::Pipe => write!(f, "|>"),
+            Token::Bar  => write!(f, "|"),
 
             Token::Assign    => write!(f, "="),
             Token::Comma     => write!(f, ","),
             Token::Colon     => write!(f, ":"),
             Token::Semicolon => write!(f, ";"),
+            Token::Dot       => write!(f, "."),
             Token::Open(d) => write!(f, "{}", match d {
                 Delim::Paren => "(",
                 Delim::Brack => "[",
@@ -74,11 +76,71 @@ impl<'src> Display for Token<'src> {
             Token::If     => write!(f, "if"),
             Token::Then   => write!(f, "then"),
             Token::Else   => write!(f, "else"),
+            Token::Match  => write!(f, "match"),
+            Token::Module => write!(f, "module"),
+            Token::Import => write!(f, "import"),
+            Token::Pub    => write!(f, "pub"),
         }
     }
 }
 
-pub type Span = SimpleSpan<usize>;
+/// Where something is in the source, a range of bytes of one of its files
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: FileId,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(file: FileId, start: usize, end: usize) -> Self {
+        Self { file, start, end }
+    }
+
+    /// The span from the start of this one to the end of `other`, which is
+    /// in the same file
+    pub fn to(self, other: Span) -> Self {
+        Self { end: other.end, ..self }
+    }
+
+    pub fn into_range(self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+impl chumsky::span::Span for Span {
+    type Context = FileId;
+    type Offset = usize;
+
+    fn new(file: FileId, range: Range<usize>) -> Self {
+        Self::new(file, range.start, range.end)
+    }
+
+    fn context(&self) -> FileId {
+        self.file
+    }
+
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    fn end(&self) -> usize {
+        self.end
+    }
+}
+
+// The file is left out, as it is the same for everything printed together
+impl Display for Span {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Lit<'src> {
@@ -131,12 +193,29 @@ impl Display for BinaryOp {
 
 pub type Spanned<T> = (T, Span);
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern<'src> {
+    // `_`, matches anything
+    Wild,
+    // Matches anything, binding it to the name
+    Bind(&'src str),
+    // Matches the values equal to the literal
+    Lit(Lit<'src>),
+    // `p | q`, matches what any of the patterns match. They can't bind names
+    Or(Vec<Self>),
+}
+
 // Clone is needed for type checking since the type checking
 // algorithm is recursive and sometimes consume the AST.
 #[derive(Clone, Debug)]
 pub enum Expr<'src> {
     Lit(Lit<'src>),
     Ident(&'src str),
+    // A name defined by an imported module, `math.square`
+    Qualified {
+        module: &'src str,
+        name: &'src str,
+    },
 
     Unary(UnaryOp, Spanned<Box<Self>>),
     Binary(BinaryOp, Spanned<Box<Self>>, Spanned<Box<Self>>),
@@ -159,9 +238,16 @@ pub enum Expr<'src> {
         name: &'src str,
         ty: Option<Type>,
         value: Spanned<Box<Self>>,
+        public: bool, // True if other modules can use it (`pub let`).
     },
     Block {
         exprs: Vec<Spanned<Box<Self>>>,
         void: bool, // True if last expression is discarded (ends with semicolon).
     },
+    // `match a, b { p, q -> e, ... }`, each arm has a pattern per scrutinee
+    // and the first arm they all match is taken
+    Match {
+        scrutinees: Vec<Spanned<Self>>,
+        arms: Vec<(Vec<Spanned<Pattern<'src>>>, Spanned<Self>)>,
+    },
 }
\ No newline at end of file
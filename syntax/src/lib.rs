@@ -1,3 +1,6 @@
 pub mod expr;
+pub mod module;
 pub mod parser;
+pub mod pretty;
+pub mod source;
 pub mod ty;
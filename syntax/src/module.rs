@@ -0,0 +1,99 @@
+//! Modules, one per source file. A file can name its module and import
+//! others by their path, `import util.math;`, and then refer to what they
+//! define with `pub let` as `math.cube`.
+
+use super::expr::{Expr, Spanned};
+
+/// The dotted path of a module, `util.math` being `["util", "math"]`
+pub type ModulePath<'src> = Vec<&'src str>;
+
+#[derive(Clone, Debug)]
+pub struct Module<'src> {
+    /// The `module` declaration
+    pub name: Option<Spanned<ModulePath<'src>>>,
+    pub imports: Vec<Spanned<ModulePath<'src>>>,
+    pub items: Vec<Spanned<Expr<'src>>>,
+}
+
+/// The name an imported module is referred to by, the last part of its path
+pub fn alias<'src>(path: &[&'src str]) -> &'src str {
+    path.last().expect("module paths are never empty")
+}
+
+/// Print a module path the way it is written
+pub fn path_name(path: &[&str]) -> String {
+    path.join(".")
+}
+
+/// Order modules so that every module comes after the ones it imports,
+/// given the indices of the modules each of them imports. If there is an
+/// import cycle, returns the modules that are part of it instead, each one
+/// importing the next and the last one the first
+pub fn sort(imports: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State { New, Visiting, Done }
+
+    fn visit(
+        m: usize,
+        imports: &[Vec<usize>],
+        states: &mut [State],
+        stack: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+    ) -> Result<(), Vec<usize>> {
+        match states[m] {
+            State::Done => return Ok(()),
+            State::Visiting => {
+                let start = stack.iter().position(|&s| s == m).unwrap();
+                return Err(stack[start..].to_vec());
+            }
+            State::New => {}
+        }
+        states[m] = State::Visiting;
+        stack.push(m);
+        for &i in &imports[m] {
+            visit(i, imports, states, stack, order)?;
+        }
+        stack.pop();
+        states[m] = State::Done;
+        order.push(m);
+        Ok(())
+    }
+
+    let mut states = vec![State::New; imports.len()];
+    let mut order = vec![];
+    for m in 0..imports.len() {
+        visit(m, imports, &mut states, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{prelude::Input, Parser};
+
+    use super::*;
+    use crate::{expr::Span, parser::{lexer, module_parser}, source::FileId};
+
+    #[test]
+    fn test_module_parser() {
+        let src = "module app; import util.math; import io; pub let x = math.cube(2); io.print(x);";
+        let tokens = lexer(FileId::default()).parse(src).into_result().unwrap();
+        let m = module_parser()
+            .parse(tokens.as_slice().spanned(Span::new(FileId::default(), src.len(), src.len())))
+            .into_result()
+            .unwrap();
+        assert_eq!(m.name.unwrap().0, ["app"]);
+        assert_eq!(m.imports.iter().map(|(p, _)| path_name(p)).collect::<Vec<_>>(), ["util.math", "io"]);
+        assert_eq!(alias(&m.imports[0].0), "math");
+        assert!(matches!(m.items[0].0, Expr::Define { name: "x", public: true, .. }));
+    }
+
+    #[test]
+    fn test_sort() {
+        // 0 imports 1 and 2, 1 imports 2
+        assert_eq!(sort(&[vec![1, 2], vec![2], vec![]]), Ok(vec![2, 1, 0]));
+        // 1 -> 2 -> 3 -> 1
+        assert_eq!(sort(&[vec![1], vec![2], vec![3], vec![1]]), Err(vec![1, 2, 3]));
+        assert_eq!(sort(&[vec![0]]), Err(vec![0]));
+    }
+}
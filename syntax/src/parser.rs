@@ -1,8 +1,10 @@
 use chumsky::prelude::*;
 
-use super::{ expr::*, ty::Type };
+use super::{ expr::*, module::Module, source::FileId, ty::Type };
 
-pub fn lexer<'src>() -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, extra::Err<Rich<'src, char, Span>>> {
+/// Lex a file. The errors have spans in it without its id, as the source
+/// is only text
+pub fn lexer<'src>(file: FileId) -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, extra::Err<Rich<'src, char>>> {
     // let num = text::int(10)
     //     .then(just('.').then(text::digits(10)).or_not())
     //     .slice()
@@ -16,9 +18,9 @@ pub fn lexer<'src>() -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, e
         .map(Token::Int);
 
     let strn = just('"')
-        .ignore_then(none_of('"').repeated())
+        .ignore_then(none_of('"').repeated().slice())
         .then_ignore(just('"'))
-        .map_slice(Token::Str);
+        .map(Token::Str);
 
     fn id_filter<C>(c: &C) -> bool where C: text::Char {
         c.to_char().is_ascii_alphabetic()
@@ -41,6 +43,10 @@ pub fn lexer<'src>() -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, e
         "if"     => Token::If,
         "then"   => Token::Then,
         "else"   => Token::Else,
+        "match"  => Token::Match,
+        "module" => Token::Module,
+        "import" => Token::Import,
+        "pub"    => Token::Pub,
         _        => Token::Ident(s),
     });
 
@@ -63,12 +69,14 @@ pub fn lexer<'src>() -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, e
         just('>').to(Token::Gt),
         just("&&").to(Token::And),
         just("||").to(Token::Or),
+        just('|').to(Token::Bar),
         just('!').to(Token::Not),
 
         just('=').to(Token::Assign),
         just(',').to(Token::Comma),
         just(':').to(Token::Colon),
         just(';').to(Token::Semicolon),
+        just('.').to(Token::Dot),
     ));
 
     let delim = choice((
@@ -93,7 +101,7 @@ pub fn lexer<'src>() -> impl Parser<'src, &'src str, Vec<(Token<'src>, Span)>, e
         .padded();
 
     token
-        .map_with_span(move |tok, span| (tok, span))
+        .map_with_span(move |tok, span: SimpleSpan| (tok, Span::new(file, span.start, span.end)))
         .padded_by(comment.repeated())
         .padded()
         // If we get an error, skip to the next character and try again.
@@ -136,6 +144,12 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
         let ident = symbol
             .map(Expr::Ident);
 
+        // module.name
+        let qualified = symbol
+            .then_ignore(just(Token::Dot))
+            .then(symbol)
+            .map(|(module, name)| Expr::Qualified { module, name });
+
         let paren_expr = expr.clone()
             .delimited_by(
                 just(Token::Open(Delim::Paren)),
@@ -171,16 +185,25 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .then(expr.clone())
             .map(|((name, ty), expr)| (name, ty, boxspan(expr)));
 
-        let let_or_define = just(Token::Let)
-            .ignore_then(bind)
+        let let_or_define = just(Token::Pub)
+            .or_not()
+            .then_ignore(just(Token::Let))
+            .then(bind)
             .then(
                 just(Token::In)
                     .ignore_then(expr.clone())
                     .or_not()
             )
-            .map(|((name, ty, expr), body)| match body {
-                Some(body) => Expr::Let { name, ty, value: expr, body: boxspan(body) },
-                None => Expr::Define { name, ty, value: expr },
+            .validate(|((public, (name, ty, expr)), body), span, emitter| match body {
+                Some(body) => {
+                    if public.is_some() {
+                        emitter.emit(Rich::custom(span,
+                            "Only definitions can be public, not `let ... in`.".to_string()
+                        ));
+                    }
+                    Expr::Let { name, ty, value: expr, body: boxspan(body) }
+                }
+                None => Expr::Define { name, ty, value: expr, public: public.is_some() },
             });
 
         let if_ = just(Token::If)
@@ -195,6 +218,39 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
                 f: boxspan(f)
             });
 
+        // match a, b { p, q -> e, ... }
+        let match_ = just(Token::Match)
+            .ignore_then(expr.clone()
+                .separated_by(just(Token::Comma))
+                .at_least(1)
+                .collect::<Vec<_>>())
+            .then(pattern_parser()
+                .separated_by(just(Token::Comma))
+                .at_least(1)
+                .collect::<Vec<_>>()
+                .then_ignore(just(Token::Arrow))
+                .then(expr.clone())
+                .separated_by(just(Token::Comma))
+                .allow_trailing()
+                .at_least(1)
+                .collect::<Vec<_>>()
+                .delimited_by(
+                    just(Token::Open(Delim::Brace)),
+                    just(Token::Close(Delim::Brace)),
+                ))
+            .validate(|(scrutinees, arms), _, emitter| {
+                for (patterns, _) in &arms {
+                    if patterns.len() != scrutinees.len() {
+                        let (first, last) = (patterns[0].1, patterns[patterns.len() - 1].1);
+                        emitter.emit(Rich::custom(first.to(last), format!(
+                            "Expected {} patterns, one for each value matched, found {}.",
+                            scrutinees.len(), patterns.len()
+                        )));
+                    }
+                }
+                Expr::Match { scrutinees, arms }
+            });
+
         let block = expr.clone()
             .map(boxspan)
             .then_ignore(just(Token::Semicolon))
@@ -219,11 +275,13 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             });
 
         let atom = lit
+            .or(qualified)
             .or(ident)
             .or(paren_expr)
             .or(lambda)
             .or(let_or_define)
             .or(if_)
+            .or(match_)
             .or(block)
             .map_with_span(|e, s| (e, s))
             .boxed()
@@ -256,8 +314,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .foldr(
                 call,
                 |op, expr| {
-                let span = op.1.start..expr.1.end;
-                (Expr::Unary(op.0, boxspan(expr)), span.into())
+                let span = op.1.to(expr.1);
+                (Expr::Unary(op.0, boxspan(expr)), span)
             });
 
         let op = choice((
@@ -269,8 +327,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .foldl(
                 op.then(unary).repeated(),
                 |a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(op, boxspan(a), boxspan(b)), span.into())
+                    let span = a.1.to(b.1);
+                    (Expr::Binary(op, boxspan(a), boxspan(b)), span)
                 }
             );
 
@@ -282,8 +340,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .foldl(
                 op.then(product).repeated(),
                 |a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(op, boxspan(a), boxspan(b)), span.into())
+                    let span = a.1.to(b.1);
+                    (Expr::Binary(op, boxspan(a), boxspan(b)), span)
                 }
             );
 
@@ -299,8 +357,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .foldl(
                 op.then(sum).repeated(),
                 |a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(op, boxspan(a), boxspan(b)), span.into())
+                    let span = a.1.to(b.1);
+                    (Expr::Binary(op, boxspan(a), boxspan(b)), span)
                 }
             );
 
@@ -312,8 +370,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
             .foldl(
                 op.then(comparison).repeated(),
                 |a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(op, boxspan(a), boxspan(b)), span.into())
+                    let span = a.1.to(b.1);
+                    (Expr::Binary(op, boxspan(a), boxspan(b)), span)
                 }
             );
 
@@ -322,8 +380,8 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
                 just(Token::Pipe).to(BinaryOp::Pipe)
                 .then(logical).repeated(),
                 |a, (op, b)| {
-                    let span = a.1.start..b.1.end;
-                    (Expr::Binary(op, boxspan(a), boxspan(b)), span.into())
+                    let span = a.1.to(b.1);
+                    (Expr::Binary(op, boxspan(a), boxspan(b)), span)
                 }
             );
 
@@ -332,6 +390,39 @@ pub fn expr_parser<'tokens, 'src: 'tokens>() -> impl Parser<
     })
 }
 
+/// A pattern of a `match` arm: `_`, a name, a literal, or several of them
+/// separated by `|`
+pub fn pattern_parser<'tokens, 'src: 'tokens>() -> impl Parser<
+    'tokens,
+    ParserInput<'tokens, 'src>,
+    Spanned<Pattern<'src>>,
+    extra::Err<Rich<'tokens, Token<'src>, Span>>,
+> + Clone {
+    let lit = select! {
+        Token::Unit    => Lit::Unit,
+        Token::Bool(b) => Lit::Bool(b),
+        Token::Int(n)  => Lit::Int(n),
+        Token::Str(s)  => Lit::Str(s),
+    };
+    let neg = just(Token::Sub)
+        .ignore_then(select! { Token::Int(n) => Lit::Int(n.wrapping_neg()) });
+    let single = lit
+        .or(neg)
+        .map(Pattern::Lit)
+        .or(select! {
+            Token::Ident("_") => Pattern::Wild,
+            Token::Ident(s)   => Pattern::Bind(s),
+        });
+
+    single
+        .separated_by(just(Token::Bar))
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .map(|mut ps| if ps.len() == 1 { ps.remove(0) } else { Pattern::Or(ps) })
+        .map_with_span(|p, s| (p, s))
+        .labelled("pattern")
+}
+
 pub fn type_parser<'tokens, 'src: 'tokens>() -> impl Parser<
     'tokens,
     ParserInput<'tokens, 'src>,
@@ -409,6 +500,38 @@ pub fn exprs_parser<'tokens, 'src: 'tokens>() -> impl Parser<
         .collect::<Vec<_>>()
 }
 
+/// A source file: its `module` declaration if it has one, its imports and
+/// then its expressions
+pub fn module_parser<'tokens, 'src: 'tokens>() -> impl Parser<
+    'tokens,
+    ParserInput<'tokens, 'src>,
+    Module<'src>,
+    extra::Err<Rich<'tokens, Token<'src>, Span>>,
+> + Clone {
+    // a.b.c
+    let path = select! { Token::Ident(s) => s }
+        .separated_by(just(Token::Dot))
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .map_with_span(|p, s| (p, s));
+
+    let name = just(Token::Module)
+        .ignore_then(path.clone())
+        .then_ignore(just(Token::Semicolon))
+        .or_not();
+
+    let imports = just(Token::Import)
+        .ignore_then(path)
+        .then_ignore(just(Token::Semicolon))
+        .repeated()
+        .collect::<Vec<_>>();
+
+    name
+        .then(imports)
+        .then(exprs_parser())
+        .map(|((name, imports), items)| Module { name, imports, items })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -416,7 +539,7 @@ mod tests {
     #[test]
     fn test_type_parser() {
         let input = "(() -> () -> () -> (num)) -> bool";
-        let (ts, errs) = lexer().parse(input).into_output_errors();
+        let (ts, errs) = lexer(FileId::default()).parse(input).into_output_errors();
 
         assert!(ts.is_some());
         assert!(errs.is_empty());
@@ -424,7 +547,7 @@ mod tests {
         if let Some(ts) = ts {
             let (ast, parse_errs) = type_parser()
                 .map_with_span(|ty, span| (ty, span))
-                .parse(ts.as_slice().spanned((input.len()..input.len()).into()))
+                .parse(ts.as_slice().spanned(Span::new(FileId::default(), input.len(), input.len())))
                 .into_output_errors();
 
             println!("{:?}", ast);
@@ -437,11 +560,11 @@ mod tests {
         let input = "
             let id : (A) -> A = (\\x -> x) in {
                 if false
-                    then id(3.14)
+                    then id(3)
                     else id(true);
             }
         ";
-        let (ast, errs) = lexer().parse(input).into_output_errors();
+        let (ast, errs) = lexer(FileId::default()).parse(input).into_output_errors();
 
         assert!(ast.is_some());
         assert!(errs.is_empty());
@@ -449,7 +572,7 @@ mod tests {
         if let Some(ast) = ast {
             let (ast, parse_errs) = expr_parser()
                 .map_with_span(|ty, span| (ty, span))
-                .parse(ast.as_slice().spanned((input.len()..input.len()).into()))
+                .parse(ast.as_slice().spanned(Span::new(FileId::default(), input.len(), input.len())))
                 .into_output_errors();
 
             println!("{:?}", ast);
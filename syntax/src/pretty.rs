@@ -0,0 +1,310 @@
+//! Printing syntax trees back as source code, for `hc fmt`.
+//!
+//! The layout only depends on the tree, so formatting is idempotent.
+//! Expressions are kept on one line when they fit, and broken the way the
+//! examples are written otherwise. Comments are not part of the tree, see
+//! [`comments`].
+
+use super::{
+    expr::{BinaryOp, Expr, Lit, Pattern, Span, Token},
+    module::{path_name, Module},
+    source::FileId,
+    ty::{itoa, Type},
+};
+
+const WIDTH: usize = 80;
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+fn fits(s: &str, depth: usize) -> bool {
+    !s.contains('\n') && depth * 4 + s.len() <= WIDTH
+}
+
+/// Print a type the way it is written
+pub fn pretty_type(ty: &Type) -> String {
+    let list = |tys: &[Type]| tys.iter().map(pretty_type).collect::<Vec<_>>().join(", ");
+    match ty {
+        Type::Unit => "()".to_string(),
+        Type::Bool => "Bool".to_string(),
+        Type::Int  => "Int".to_string(),
+        Type::Str  => "Str".to_string(),
+        // Only in inferred types, which have no syntax for them
+        Type::Var(id) => itoa(*id),
+        Type::Func(args, ret) => format!("({}) -> {}", list(args), pretty_type(ret)),
+        Type::Tuple(tys) => format!("({})", list(tys)),
+        Type::Array(ty) => format!("[{}]", pretty_type(ty)),
+    }
+}
+
+fn lit(l: &Lit) -> String {
+    match l {
+        Lit::Unit    => "()".to_string(),
+        Lit::Bool(b) => b.to_string(),
+        Lit::Int(i)  => i.to_string(),
+        Lit::Str(s)  => format!("\"{}\"", s),
+    }
+}
+
+fn pattern(p: &Pattern) -> String {
+    match p {
+        Pattern::Wild => "_".to_string(),
+        Pattern::Bind(name) => name.to_string(),
+        Pattern::Lit(l) => lit(l),
+        Pattern::Or(ps) => ps.iter().map(pattern).collect::<Vec<_>>().join(" | "),
+    }
+}
+
+fn prec(op: &BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Pipe => 0,
+        BinaryOp::And | BinaryOp::Or => 1,
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 2,
+        BinaryOp::Add | BinaryOp::Sub => 3,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 4,
+    }
+}
+
+/// Whether an expression extends as far to the right as it can, so that
+/// it needs parentheses when something follows it
+fn open_ended(e: &Expr) -> bool {
+    matches!(e, Expr::Lambda(..) | Expr::Let { .. } | Expr::Define { .. } | Expr::If { .. })
+}
+
+fn parens(s: String, parens: bool) -> String {
+    if parens { format!("({})", s) } else { s }
+}
+
+/// Print an expression starting at `depth` levels of indentation. `tail`
+/// is whether nothing follows the expression before a delimiter
+fn expr(e: &Expr, depth: usize, tail: bool) -> String {
+    match e {
+        Expr::Lit(l) => lit(l),
+        Expr::Ident(s) => s.to_string(),
+        Expr::Qualified { module, name } => format!("{}.{}", module, name),
+        Expr::Unary(op, (x, _)) => {
+            let p = matches!(**x, Expr::Binary(..)) || (open_ended(x) && !tail);
+            format!("{}{}", op, parens(expr(x, depth, tail || p), p))
+        }
+        Expr::Binary(op, (l, _), (r, _)) => {
+            let lp = matches!(&**l, Expr::Binary(o, ..) if prec(o) < prec(op)) || open_ended(l);
+            let rp = matches!(&**r, Expr::Binary(o, ..) if prec(o) <= prec(op)) || (open_ended(r) && !tail);
+            let l = parens(expr(l, depth, lp), lp);
+            let r = parens(expr(r, depth + 1, tail || rp), rp);
+            let flat = format!("{} {} {}", l, op, r);
+            if fits(&flat, depth) {
+                flat
+            } else {
+                format!("{}\n{}{} {}", l, indent(depth + 1), op, r)
+            }
+        }
+        Expr::Lambda(params, ret, (body, _)) => {
+            let params = params.iter()
+                .map(|(name, ty)| match ty {
+                    Some(ty) => format!("{} {}", name, pretty_type(ty)),
+                    None => name.to_string(),
+                })
+                .collect::<Vec<_>>();
+            let mut head = format!("fun ({})", params.join(", "));
+            if let Some(ret) = ret {
+                head = format!("{} {}", head, pretty_type(ret));
+            }
+            let flat = format!("{} -> {}", head, expr(body, depth, tail));
+            if fits(&flat, depth) || matches!(**body, Expr::Block { .. }) {
+                flat
+            } else {
+                format!("{} ->\n{}{}", head, indent(depth + 1), expr(body, depth + 1, tail))
+            }
+        }
+        Expr::Call((f, _), args) => {
+            let p = matches!(**f, Expr::Binary(..) | Expr::Unary(..) | Expr::Call(..)) || open_ended(f);
+            let f = parens(expr(f, depth, p), p);
+            let flat = format!("{}({})", f, args.iter().map(|(a, _)| expr(a, depth, true)).collect::<Vec<_>>().join(", "));
+            if fits(&flat, depth) || args.is_empty() {
+                flat
+            } else {
+                let args = args.iter()
+                    .map(|(a, _)| format!("{}{},\n", indent(depth + 1), expr(a, depth + 1, true)))
+                    .collect::<String>();
+                format!("{}(\n{}{})", f, args, indent(depth))
+            }
+        }
+        Expr::If { cond: (cond, _), t: (t, _), f: (f, _) } => {
+            let flat = format!("if {} then {} else {}", expr(cond, depth, true), expr(t, depth, true), expr(f, depth, tail));
+            if fits(&flat, depth) {
+                flat
+            } else {
+                format!(
+                    "if {}\n{ind}then {}\n{ind}else {}",
+                    expr(cond, depth + 1, true), expr(t, depth + 1, true), expr(f, depth + 1, tail),
+                    ind = indent(depth + 1),
+                )
+            }
+        }
+        Expr::Let { name, ty, value: (value, _), body: (body, _) } => {
+            let head = format!("{} in", binding(name, ty, value, depth));
+            let flat = format!("{} {}", head, expr(body, depth, tail));
+            if fits(&flat, depth) {
+                flat
+            } else {
+                format!("{}\n{}{}", head, indent(depth), expr(body, depth, tail))
+            }
+        }
+        Expr::Define { name, ty, value: (value, _), public: false } => binding(name, ty, value, depth),
+        Expr::Define { name, ty, value: (value, _), public: true } => format!("pub {}", binding(name, ty, value, depth)),
+        Expr::Block { exprs, void } => {
+            let items = exprs.iter()
+                .enumerate()
+                .map(|(i, (e, _))| {
+                    let semi = if i + 1 < exprs.len() || *void { ";" } else { "" };
+                    (expr(e, depth + 1, true), semi)
+                })
+                .collect::<Vec<_>>();
+            match items.as_slice() {
+                [] => "{}".to_string(),
+                [(e, semi)] if fits(&format!("{{ {}{} }}", e, semi), depth) => format!("{{ {}{} }}", e, semi),
+                _ => {
+                    let items = items.iter()
+                        .map(|(e, semi)| format!("{}{}{}\n", indent(depth + 1), e, semi))
+                        .collect::<String>();
+                    format!("{{\n{}{}}}", items, indent(depth))
+                }
+            }
+        }
+        Expr::Match { scrutinees, arms } => {
+            let scrutinees = scrutinees.iter().map(|(e, _)| expr(e, depth, true)).collect::<Vec<_>>();
+            let head = format!("match {}", scrutinees.join(", "));
+            let arms = arms.iter()
+                .map(|(patterns, (body, _))| {
+                    let patterns = patterns.iter().map(|(p, _)| pattern(p)).collect::<Vec<_>>();
+                    format!("{} -> {}", patterns.join(", "), expr(body, depth + 1, true))
+                })
+                .collect::<Vec<_>>();
+            let flat = format!("{} {{ {} }}", head, arms.join(", "));
+            if fits(&flat, depth) {
+                flat
+            } else {
+                let arms = arms.iter()
+                    .map(|a| format!("{}{},\n", indent(depth + 1), a))
+                    .collect::<String>();
+                format!("{} {{\n{}{}}}", head, arms, indent(depth))
+            }
+        }
+    }
+}
+
+fn binding(name: &str, ty: &Option<Type>, value: &Expr, depth: usize) -> String {
+    let ty = ty.as_ref().map(|ty| format!(": {}", pretty_type(ty))).unwrap_or_default();
+    format!("let {}{} = {}", name, ty, expr(value, depth, true))
+}
+
+/// Print a module. Blank lines between top-level expressions are kept, and
+/// the `module` and `import` declarations are separated from them by one
+pub fn pretty(module: &Module, src: &str) -> String {
+    let mut out = String::new();
+    if let Some((name, _)) = &module.name {
+        out.push_str(&format!("module {};\n", path_name(name)));
+    }
+    for (path, _) in &module.imports {
+        out.push_str(&format!("import {};\n", path_name(path)));
+    }
+    if !out.is_empty() && !module.items.is_empty() {
+        out.push('\n');
+    }
+    let program = &module.items;
+    for (i, (e, span)) in program.iter().enumerate() {
+        if i > 0 && src[program[i - 1].1.end..span.start].matches('\n').count() > 1 {
+            out.push('\n');
+        }
+        out.push_str(&expr(e, 0, true));
+        out.push_str(";\n");
+    }
+    out
+}
+
+/// The spans of the comments in a file, which are between its tokens
+pub fn comments(file: FileId, src: &str, tokens: &[(Token, Span)]) -> Vec<Span> {
+    let mut gaps = vec![];
+    let mut prev = 0;
+    for (_, span) in tokens.iter().chain([&(Token::Unit, Span::new(file, src.len(), src.len()))]) {
+        let gap = &src[prev..span.start];
+        let mut offset = 0;
+        while let Some(start) = gap[offset..].find("//") {
+            let start = prev + offset + start;
+            let end = src[start..span.start].find('\n').map_or(span.start, |n| start + n);
+            gaps.push(Span::new(file, start, end));
+            offset = end - prev;
+        }
+        prev = span.end;
+    }
+    gaps
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{prelude::Input, Parser};
+
+    use super::*;
+    use crate::parser::{lexer, module_parser};
+
+    fn format(src: &str) -> String {
+        let tokens = lexer(FileId::default()).parse(src).into_result().unwrap();
+        let ast = module_parser()
+            .parse(tokens.as_slice().spanned(Span::new(FileId::default(), src.len(), src.len())))
+            .into_result()
+            .unwrap();
+        pretty(&ast, src)
+    }
+
+    #[test]
+    fn test_pretty() {
+        let src = "
+            let   add = fun(x Int,y Int) Int->x+y;
+            let f = fun (a (Int, Int) -> Int, y Int) -> a(y, 1);
+
+            add(33, 35) |> fun (x) -> x * (2 + 1) |> fun (y) -> { let z = y in -z; };
+            (fun (x) -> x)(1) - (2 - 3);
+            let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1;
+            let long = fun (n Int) -> if n > 100000 then \"this is a rather large number\" else \"this is a rather small number\";
+            match x,y{0|-1,_->true,_,z->false};
+            let sign = fun (n Int) -> match n { 0 -> \"zero\", 1 | 2 | 3 -> \"a few\", _ -> \"more than a few, or fewer\" };
+        ";
+        let out = format(src);
+        assert_eq!(out, "\
+let add = fun (x Int, y Int) Int -> x + y;
+let f = fun (a (Int, Int) -> Int, y Int) -> a(y, 1);
+
+add(33, 35) |> fun (x) -> x * (2 + 1) |> fun (y) -> { let z = y in -z; };
+(fun (x) -> x)(1) - (2 - 3);
+let fact = fun (n Int) Int -> if n > 1 then n * fact(n - 1) else 1;
+let long = fun (n Int) ->
+    if n > 100000
+        then \"this is a rather large number\"
+        else \"this is a rather small number\";
+match x, y { 0 | -1, _ -> true, _, z -> false };
+let sign = fun (n Int) ->
+    match n {
+        0 -> \"zero\",
+        1 | 2 | 3 -> \"a few\",
+        _ -> \"more than a few, or fewer\",
+    };
+");
+        assert_eq!(format(&out), out);
+    }
+
+    #[test]
+    fn test_pretty_module() {
+        let out = format("module app ; import util . math;pub let x = math.cube(2);x;");
+        assert_eq!(out, "module app;\nimport util.math;\n\npub let x = math.cube(2);\nx;\n");
+        assert_eq!(format(&out), out);
+    }
+
+    #[test]
+    fn test_comments() {
+        let src = "1; // one\n// two\n\"//\";";
+        let tokens = lexer(FileId::default()).parse(src).into_result().unwrap();
+        let spans = comments(FileId::default(), src, &tokens);
+        assert_eq!(spans.iter().map(|s| &src[s.into_range()]).collect::<Vec<_>>(), ["// one", "// two"]);
+    }
+}
@@ -0,0 +1,97 @@
+//! The files a program is made of. Spans say which file they are in with a
+//! [`FileId`], which is looked up here for the path to show in reports and
+//! the lines they are on. Their text is kept in [`Texts`], which syntax
+//! trees and what is made from them borrow.
+
+use typed_arena::Arena;
+
+/// A file of [`Sources`], by its position among them in the order they
+/// were added
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub u32);
+
+pub struct SourceFile<'src> {
+    /// What the file is called in reports
+    pub path: String,
+    pub text: &'src str,
+    // The offset each line starts at
+    lines: Vec<usize>,
+}
+
+impl<'src> SourceFile<'src> {
+    fn new(path: String, text: &'src str) -> Self {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { path, text, lines }
+    }
+
+    /// The line an offset is on, counted from 0, and the part of that line
+    /// before it. Offsets past the end are at the end
+    pub fn locate(&self, offset: usize) -> (usize, &'src str) {
+        let offset = offset.min(self.text.len());
+        let line = self.lines.partition_point(|&start| start <= offset) - 1;
+        (line, &self.text[self.lines[line]..offset])
+    }
+}
+
+#[derive(Default)]
+pub struct Sources<'src> {
+    files: Vec<SourceFile<'src>>,
+}
+
+impl<'src> Sources<'src> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, path: impl Into<String>, text: &'src str) -> FileId {
+        self.files.push(SourceFile::new(path.into(), text));
+        FileId(self.files.len() as u32 - 1)
+    }
+
+    pub fn get(&self, file: FileId) -> &SourceFile<'src> {
+        &self.files[file.0 as usize]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (FileId, &SourceFile<'src>)> {
+        self.files.iter().enumerate().map(|(i, f)| (FileId(i as u32), f))
+    }
+}
+
+/// Where the text of sources is kept for as long as what refers to it, and
+/// freed all at once when dropped. Start over with new `Texts` to free the
+/// text of sources that are no longer needed
+#[derive(Default)]
+pub struct Texts {
+    arena: Arena<u8>,
+}
+
+impl Texts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, text: &str) -> &str {
+        self.arena.alloc_str(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let mut sources = Sources::new();
+        let a = sources.add("a.hlm", "ab\nλcd\n");
+        let b = sources.add("b.hlm", "x");
+        let file = sources.get(a);
+        assert_eq!(file.locate(0), (0, ""));
+        assert_eq!(file.locate(3), (1, ""));
+        assert_eq!(file.locate(6), (1, "λc"));
+        assert_eq!(file.locate(100), (2, ""));
+        assert_eq!(sources.get(b).path, "b.hlm");
+        assert_eq!(sources.iter().map(|(id, _)| id).collect::<Vec<_>>(), [a, b]);
+    }
+}
@@ -1,14 +1,17 @@
 use std::collections::HashMap;
-use chumsky::span::SimpleSpan;
 use syntax::{
     expr::{
         Lit, UnaryOp, BinaryOp,
-        Expr,
+        Expr, Pattern, Span,
     },
+    module::Module,
     ty::*,
 };
 
-use crate::rename::{rename_exprs, rename_type};
+use crate::{
+    rename::{rename_exprs, rename_type},
+    usefulness::check_match,
+};
 
 use super::typed::TExpr;
 
@@ -33,12 +36,12 @@ pub enum InferErrorKind {
 #[derive(Clone, Debug)]
 pub struct InferError {
     pub title: String,
-    pub labels: Vec<(String, InferErrorKind, SimpleSpan)>,
-    pub span: SimpleSpan,
+    pub labels: Vec<(String, InferErrorKind, Span)>,
+    pub span: Span,
 }
 
 impl InferError {
-    pub fn new<S: Into<String>>(title: S, span: SimpleSpan) -> Self {
+    pub fn new<S: Into<String>>(title: S, span: Span) -> Self {
         Self {
             title: title.into(),
             labels: Vec::new(),
@@ -46,12 +49,12 @@ impl InferError {
         }
     }
 
-    pub fn add_error<S: Into<String>>(mut self, reason: S, span: SimpleSpan) -> Self {
+    pub fn add_error<S: Into<String>>(mut self, reason: S, span: Span) -> Self {
         self.labels.push((reason.into(), InferErrorKind::Error, span));
         self
     }
 
-    pub fn add_hint<S: Into<String>>(mut self, reason: S, span: SimpleSpan) -> Self {
+    pub fn add_hint<S: Into<String>>(mut self, reason: S, span: Span) -> Self {
         self.labels.push((reason.into(), InferErrorKind::Hint, span));
         self
     }
@@ -62,11 +65,11 @@ struct Constraint {
     t1: Type,
     t2: Type,
     // Where the constraint was generated, for error reporting
-    span: SimpleSpan,
+    span: Span,
 }
 
 impl Constraint {
-    fn new(t1: Type, t2: Type, span: SimpleSpan) -> Self {
+    fn new(t1: Type, t2: Type, span: Span) -> Self {
         Self {
             t1,
             t2,
@@ -75,17 +78,37 @@ impl Constraint {
     }
 }
 
+/// What a module gives the modules that import it: the types of its public
+/// definitions, generalized over their type variables, and where the others
+/// are so that using them can be told apart from using undefined ones
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Interface<'src> {
+    pub public: HashMap<&'src str, Type>,
+    pub private: HashMap<&'src str, Span>,
+}
+
+/// The environment of the type checker. It outlives a list of expressions
+/// when they come one after the other, like the inputs of a REPL
 #[derive(Clone, Debug)]
-struct Infer<'src> {
+pub struct Infer<'src> {
     env: HashMap<&'src str, Type>,
+    // The modules imported, by the name they are referred to with
+    imports: HashMap<&'src str, Interface<'src>>,
     subst: Vec<Type>,
     constraints: Vec<Constraint>,
 }
 
+impl Default for Infer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'src> Infer<'src> {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Infer {
             env: HashMap::new(),
+            imports: HashMap::new(),
             subst: Vec::new(),
             constraints: Vec::new(),
         }
@@ -98,6 +121,22 @@ impl<'src> Infer<'src> {
         Type::Var(i)
     }
 
+    /// Replace the type variables of a generalized type with fresh ones, the
+    /// same variable with the same fresh one
+    fn instantiate(&mut self, t: Type, vars: &mut HashMap<usize, Type>) -> Type {
+        use Type::*;
+        match t {
+            Var(i) => vars.entry(i).or_insert_with(|| self.fresh()).clone(),
+            Func(args, ret) => Func(
+                args.into_iter().map(|t| self.instantiate(t, vars)).collect(),
+                Box::new(self.instantiate(*ret, vars)),
+            ),
+            Tuple(tys) => Tuple(tys.into_iter().map(|t| self.instantiate(t, vars)).collect()),
+            Array(ty) => Array(Box::new(self.instantiate(*ty, vars))),
+            _ => t,
+        }
+    }
+
     /// Get a substitution for a type variable
     fn subst(&self, i: usize) -> Option<Type> {
         self.subst.get(i).cloned()
@@ -195,7 +234,7 @@ impl<'src> Infer<'src> {
                     return Err(e);
                 }
                 // Unify the arguments
-                for (a1, a2) in a1.into_iter().zip(a2.into_iter()) {
+                for (a1, a2) in a1.into_iter().zip(a2) {
                     self.unify(constraint!(a1, a2))?;
                 }
                 // Unify the return types
@@ -213,7 +252,7 @@ impl<'src> Infer<'src> {
                         ), c.span));
                 }
                 // Unify the elements
-                for (t1, t2) in t1.into_iter().zip(t2.into_iter()) {
+                for (t1, t2) in t1.into_iter().zip(t2) {
                     self.unify(constraint!(t1, t2))?;
                 }
                 Ok(())
@@ -272,7 +311,9 @@ impl<'src> Infer<'src> {
     fn substitute_texp(&mut self, e: TExpr<'src>) -> TExpr<'src> {
         use TExpr::*;
         match e {
-            Lit(_) | Ident(_) => e,
+            Lit(_) => e,
+            Ident(x, ty) => Ident(x, self.substitute(ty)),
+            Qualified { module, name, ty } => Qualified { module, name, ty: self.substitute(ty) },
             Unary { op, expr: (e, lspan), ret_ty } => {
                 Unary {
                     op,
@@ -301,7 +342,7 @@ impl<'src> Infer<'src> {
                     ret_ty: self.substitute(ret_ty),
                 }
             },
-            Call { func: (func, fspan), args } => {
+            Call { func: (func, fspan), args, ret_ty } => {
                 let funct = self.substitute_texp(*func);
                 let argst = args.into_iter()
                     .map(|(arg, span)| (self.substitute_texp(arg), span))
@@ -309,6 +350,7 @@ impl<'src> Infer<'src> {
                 Call {
                     func: (Box::new(funct), fspan),
                     args: argst,
+                    ret_ty: self.substitute(ret_ty),
                 }
             },
             If { cond: (cond, cspan), t: (t, tspan), f: (f, fspan), br_ty } => {
@@ -319,7 +361,7 @@ impl<'src> Infer<'src> {
                     cond: (Box::new(condt), cspan),
                     t: (Box::new(tt), tspan),
                     f: (Box::new(ft), fspan),
-                    br_ty,
+                    br_ty: self.substitute(br_ty),
                 }
             },
             Let { name, ty, value: (v, vspan), body: (b, bspan) } => {
@@ -350,12 +392,63 @@ impl<'src> Infer<'src> {
                     ret_ty: self.substitute(ret_ty),
                 }
             },
+            Match { scrutinees, arms, ret_ty } => {
+                let scrutineest = scrutinees.into_iter()
+                    .map(|(e, span)| (self.substitute_texp(e), span))
+                    .collect::<Vec<_>>();
+                let armst = arms.into_iter()
+                    .map(|(ps, (body, bspan))| (ps, (self.substitute_texp(body), bspan)))
+                    .collect::<Vec<_>>();
+                Match {
+                    scrutinees: scrutineest,
+                    arms: armst,
+                    ret_ty: self.substitute(ret_ty),
+                }
+            },
+        }
+    }
+
+    /// Check a pattern against the type of the value it matches, adding the
+    /// names it binds to `bound`
+    fn pattern(
+        &mut self, p: &Pattern<'src>, span: Span, ty: &Type, bound: &mut Vec<(&'src str, Type)>,
+    ) -> Vec<InferError> {
+        match p {
+            Pattern::Wild => vec![],
+            Pattern::Bind(name) => {
+                if bound.iter().any(|(n, _)| n == name) {
+                    return vec![InferError::new("Name bound twice", span)
+                        .add_error(format!("`{}` is already bound by this arm", name), span)];
+                }
+                bound.push((name, ty.clone()));
+                vec![]
+            },
+            // The value matched has to be of the literal's type
+            Pattern::Lit(l) => {
+                let lt = match l {
+                    Lit::Unit    => Type::Unit,
+                    Lit::Bool(_) => Type::Bool,
+                    Lit::Int(_)  => Type::Int,
+                    Lit::Str(_)  => Type::Str,
+                };
+                self.add_constraint(Constraint::new(ty.clone(), lt, span));
+                vec![]
+            },
+            // Only some of the alternatives could bind a name
+            Pattern::Or(ps) => ps.iter()
+                .flat_map(|p| match p {
+                    Pattern::Bind(name) => vec![InferError::new("Name bound in an or-pattern", span)
+                        .add_error(format!("`{}` can't be bound by one of several patterns", name), span)
+                        .add_hint("Use `_` to match anything without binding it", span)],
+                    p => self.pattern(p, span, ty, bound),
+                })
+                .collect(),
         }
     }
 
     /// Infer the type of an expression
     fn infer(
-        &mut self, e: (Expr<'src>, SimpleSpan), expected: Type
+        &mut self, e: (Expr<'src>, Span), expected: Type
     ) -> (TExpr<'src>, Vec<InferError>) {
         let span = e.1;
         macro_rules! constraint {
@@ -391,20 +484,50 @@ impl<'src> Infer<'src> {
             // The same as literals but the type is looked up in the environment
             Expr::Ident(ref x) => {
                 if let Some(t) = self.env.get(x) {
+                    let t = t.clone();
                     constraint!(t.clone());
-                    ok!(TExpr::Ident(x))
+                    ok!(TExpr::Ident(x, t))
                 } else {
                     let kind = match &expected {
                         Type::Func(_, _) => "function",
                         _ => "value",
                     };
-                    (TExpr::Ident(x), vec![
+                    (TExpr::Ident(x, expected), vec![
                         InferError::new(format!("Undefined {}", kind), span)
                             .add_error(format!("`{}` is not defined", x), span)
                     ])
                 }
             }
 
+            // Qualified names
+            // Looked up in the interface of the module, with fresh type
+            // variables for each use
+            Expr::Qualified { module, name } => {
+                let found = self.imports.get(module)
+                    .map(|i| (i.public.get(name).cloned(), i.private.get(name).copied()));
+                match found {
+                    Some((Some(t), _)) => {
+                        let t = self.instantiate(t, &mut HashMap::new());
+                        constraint!(t.clone());
+                        ok!(TExpr::Qualified { module, name, ty: t })
+                    }
+                    found => {
+                        let (title, reason) = match found {
+                            None => ("Undefined module", format!("`{}` is not imported", module)),
+                            Some((_, Some(_))) => ("Private definition", format!(
+                                "`{}` is not `pub` in `{}`", name, module
+                            )),
+                            Some(_) => ("Undefined value", format!("`{}` has no `{}`", module, name)),
+                        };
+                        let mut err = InferError::new(title, span).add_error(reason, span);
+                        if let Some((_, Some(def))) = found {
+                            err = err.add_hint(format!("`{}` is defined here", name), def);
+                        }
+                        (TExpr::Qualified { module, name, ty: expected }, vec![err])
+                    }
+                }
+            }
+
             // Unary & binary operators
             // The type of the left and right hand side are inferred and
             // the expected type is determined by the operator
@@ -564,13 +687,13 @@ impl<'src> Infer<'src> {
                 // Create a function type
                 let fsig = Type::Func(
                     freshes.clone(),
-                    Box::new(expected),
+                    Box::new(expected.clone()),
                 );
                 // Expect the function to have the function type
                 let (ft, mut errs) = self.infer(unbox!(f), fsig);
                 // Infer the arguments
                 let (xs, xerrs) = args.into_iter()
-                    .zip(freshes.into_iter())
+                    .zip(freshes)
                     .map(|(x, t)| {
                         let span = x.1;
                         let (xt, err) = self.infer(x, t);
@@ -587,6 +710,7 @@ impl<'src> Infer<'src> {
                 (TExpr::Call {
                     func: (Box::new(ft), f.1),
                     args: xs,
+                    ret_ty: expected,
                 }, errs)
             },
 
@@ -618,8 +742,8 @@ impl<'src> Infer<'src> {
                 // Create a new environment and add the binding to it
                 // and then use the new environment to infer the body
                 let mut env = self.env.clone();
-                env.insert(name.clone(), ty.clone());
-                let mut inf = Infer::new();
+                env.insert(name, ty.clone());
+                let mut inf = self.clone();
                 inf.env = env;
                 let (bt, berrs) = inf.infer(unbox!(body), expected.clone());
                 errs.extend(berrs);
@@ -641,9 +765,9 @@ impl<'src> Infer<'src> {
                     body: (Box::new(bt), body.1),
                 }, errs)
             },
-            Expr::Define { name, ty, value } => {
+            Expr::Define { name, ty, value, .. } => {
                 let ty = ty.unwrap_or(self.fresh());
-                self.env.insert(name.clone(), ty.clone());
+                self.env.insert(name, ty.clone());
                 let (val_ty, errs) = self.infer(unbox!(value), ty.clone());
 
                 constraint!(Type::Unit);
@@ -678,16 +802,18 @@ impl<'src> Infer<'src> {
                         (xs, errs)
                     });
 
-                let rt = if void || last.is_none() {
+                let rt = match last.filter(|_| !void) {
                     // If the block is void or there is no expression,
                     // the return type is unit
-                    constraint!(Type::Unit);
-                    Type::Unit
-                } else {
+                    None => {
+                        constraint!(Type::Unit);
+                        Type::Unit
+                    },
                     // Otherwise, the return type is the same as the expected type
-                    // constraint!(last.unwrap());
-                    self.add_constraint(Constraint::new(expected.clone(), last.unwrap(), span));
-                    expected
+                    Some(last) => {
+                        self.add_constraint(Constraint::new(expected.clone(), last, span));
+                        expected
+                    },
                 };
 
                 (TExpr::Block {
@@ -696,41 +822,239 @@ impl<'src> Infer<'src> {
                     ret_ty: rt,
                 }, errs)
             },
+
+            // Match
+            Expr::Match { scrutinees, arms } => {
+                // The patterns of a column have the type of its scrutinee
+                let tys = scrutinees.iter()
+                    .map(|_| self.fresh())
+                    .collect::<Vec<_>>();
+                let mut errs = vec![];
+                let mut ts = vec![];
+                for (x, t) in scrutinees.into_iter().zip(&tys) {
+                    let span = x.1;
+                    let (xt, err) = self.infer(x, t.clone());
+                    ts.push((xt, span));
+                    errs.extend(err);
+                }
+
+                let mut tarms = vec![];
+                for (patterns, body) in arms {
+                    let mut bound = vec![];
+                    for ((p, pspan), t) in patterns.iter().zip(&tys) {
+                        errs.extend(self.pattern(p, *pspan, t, &mut bound));
+                    }
+                    // The names bound are only in scope of the arm's body,
+                    // which is inferred like the body of a let
+                    let mut env = self.env.clone();
+                    env.extend(bound);
+                    let mut inf = self.clone();
+                    inf.env = env;
+                    let bspan = body.1;
+                    let (bt, berrs) = inf.infer(body, expected.clone());
+                    errs.extend(berrs);
+
+                    for s in inf.subst {
+                        if !self.subst.contains(&s) {
+                            self.subst.push(s);
+                        }
+                    }
+                    for c in inf.constraints {
+                        if !self.constraints.contains(&c) {
+                            self.constraints.push(c);
+                        }
+                    }
+                    tarms.push((patterns, (bt, bspan)));
+                }
+
+                (TExpr::Match {
+                    scrutinees: ts,
+                    arms: tarms,
+                    ret_ty: expected,
+                }, errs)
+            },
         }
     }
 }
 
-/// Infer a list of expressions
-pub fn infer_exprs(es: Vec<(Expr, SimpleSpan)>) -> (Vec<(TExpr, SimpleSpan)>, Vec<InferError>) {
-    let mut inf = Infer::new();
-    // Type expressions
-    let mut tes = vec![];
-    // Unsubstituted typed expressions
-    let mut errors = vec![];
-
-    for e in es {
-        let span = e.1;
-        let fresh = inf.fresh();
-        // Infer the types
-        let (te, err) = inf.infer(e, fresh);
+impl<'src> Infer<'src> {
+    /// Infer a list of expressions after the ones inferred before, which
+    /// they can refer to. Nothing is kept from a list with errors
+    pub fn infer_more(&mut self, es: Vec<(Expr<'src>, Span)>) -> (Vec<(TExpr<'src>, Span)>, Vec<InferError>) {
+        let before = self.clone();
+        // Type expressions
+        let mut tes = vec![];
+        // Unsubstituted typed expressions
+        let mut errors = vec![];
+
+        for e in es {
+            let span = e.1;
+            let fresh = self.fresh();
+            // Infer the types
+            let (te, err) = self.infer(e, fresh);
 
-        // Push the expression to the list
-        tes.push((te.clone(), span));
+            // Push the expression to the list
+            tes.push((te.clone(), span));
+
+            if !err.is_empty() {
+                errors.extend(err);
+            }
+        }
+
+        let solve_errors = self.solve();
+        if !solve_errors.is_empty() {
+            errors.extend(solve_errors);
+        } else {
+            // Substitute the types
+            tes = tes.into_iter()
+                .map(|(te, s)| (self.substitute_texp(te), s))
+                .collect();
+            // Whether the arms of a match cover every value depends on the
+            // types of its scrutinees, which are only known now
+            for (te, span) in &tes {
+                te.walk(*span, &mut |e, span| if let TExpr::Match { scrutinees, arms, .. } = e {
+                    errors.extend(check_match(scrutinees, arms, span));
+                });
+            }
+        }
 
-        if !err.is_empty() {
-            errors.extend(err);
+        if errors.is_empty() {
+            // Already part of the substitution
+            self.constraints.clear();
+        } else {
+            *self = before;
         }
+        (rename_exprs(tes), errors)
+    }
+}
+
+impl<'src> Infer<'src> {
+    /// Make a module's public definitions available as `alias.name`
+    pub fn import(&mut self, alias: &'src str, interface: Interface<'src>) {
+        self.imports.insert(alias, interface);
+    }
+
+    /// Define a name whose value comes from elsewhere, like the functions
+    /// an embedder gives programs. Its type can't have type variables
+    pub fn define(&mut self, name: &'src str, ty: Type) {
+        self.env.insert(name, ty);
     }
 
-    let solve_errors = inf.solve();
-    if !solve_errors.is_empty() {
-        errors.extend(solve_errors);
-    } else {
-        // Substitute the types
-        tes = tes.into_iter()
-            .map(|(te, s)| (inf.substitute_texp(te), s))
-            .collect();
+    /// The type of a definition of the expressions inferred so far
+    pub fn type_of(&mut self, name: &str) -> Option<Type> {
+        let t = self.env.get(name)?.clone();
+        Some(rename_type(self.substitute(t)))
     }
 
-    (rename_exprs(tes), errors)
-}
\ No newline at end of file
+    /// The type of a public definition of an imported module, `None` if it
+    /// has no such definition and nothing if it isn't imported
+    pub(crate) fn imported(&self, module: &str, name: &str) -> Option<Option<&Type>> {
+        self.imports.get(module).map(|i| i.public.get(name))
+    }
+
+    /// Infer a module after importing what it imports, returning its
+    /// interface. The interface is empty if there were errors
+    pub fn infer_module(
+        &mut self,
+        module: Module<'src>,
+    ) -> (Vec<(TExpr<'src>, Span)>, Interface<'src>, Vec<InferError>) {
+        let defines = module.items.iter()
+            .filter_map(|(e, span)| match e {
+                Expr::Define { name, public, .. } => Some((*name, *public, *span)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let (tes, errors) = self.infer_more(module.items);
+
+        let mut interface = Interface::default();
+        if errors.is_empty() {
+            for (name, public, span) in defines {
+                if public {
+                    let t = self.substitute(self.env[name].clone());
+                    // Every type variable left is free to be anything
+                    interface.public.insert(name, rename_type(t));
+                } else {
+                    interface.private.insert(name, span);
+                }
+            }
+        }
+        (tes, interface, errors)
+    }
+}
+
+/// Whether a type without type variables, like those of Rust values, is an
+/// instance of `scheme`, whose type variables stand for any type each
+pub fn instance_of(ty: &Type, scheme: &Type) -> bool {
+    let mut infer = Infer::new();
+    let scheme = infer.instantiate(scheme.clone(), &mut HashMap::new());
+    infer.unify(Constraint::new(scheme, ty.clone(), Span::default())).is_ok()
+}
+
+/// Infer a list of expressions
+pub fn infer_exprs(es: Vec<(Expr, Span)>) -> (Vec<(TExpr, Span)>, Vec<InferError>) {
+    Infer::new().infer_more(es)
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{prelude::Input, Parser};
+    use syntax::{parser::{lexer, module_parser}, source::FileId};
+
+    use super::*;
+
+    fn module(src: &str) -> Module<'_> {
+        let tokens = lexer(FileId::default()).parse(src).into_result().unwrap();
+        let module = module_parser()
+            .parse(tokens.as_slice().spanned(Span::new(FileId::default(), src.len(), src.len())))
+            .into_result()
+            .unwrap();
+        module
+    }
+
+    #[test]
+    fn test_infer_module() {
+        let (_, math, errs) = Infer::new().infer_module(module("pub let id = fun (x) -> x; let one = 1;"));
+        assert!(errs.is_empty());
+        assert_eq!(math.public["id"], Type::Func(vec![Type::Var(0)], Box::new(Type::Var(0))));
+        assert!(math.private.contains_key("one"));
+
+        // Each use of a generalized type gets its own type variables
+        let mut infer = Infer::new();
+        infer.import("math", math.clone());
+        let (typed, _, errs) = infer.infer_module(module("import math; math.id(1); math.id(true);"));
+        assert!(errs.is_empty());
+        assert_eq!(typed.iter().map(|(e, _)| e.ty()).collect::<Vec<_>>(), [Type::Int, Type::Bool]);
+
+        let mut infer = Infer::new();
+        infer.import("math", math);
+        let (_, _, errs) = infer.infer_module(module("math.one; math.two; util.one;"));
+        let titles = errs.iter().map(|e| e.title.as_str()).collect::<Vec<_>>();
+        assert_eq!(titles, ["Private definition", "Undefined value", "Undefined module"]);
+    }
+
+    #[test]
+    fn test_infer_match() {
+        let (typed, _, errs) = Infer::new().infer_module(module("match 1, true { 0, _ -> \"a\", n, b -> if b then \"b\" else \"c\" };"));
+        assert!(errs.is_empty());
+        assert_eq!(typed[0].0.ty(), Type::Str);
+
+        let titles = |src| Infer::new().infer_module(module(src)).2.into_iter().map(|e| e.title).collect::<Vec<_>>();
+        assert_eq!(titles("match 1 { 0 -> 1, 1 -> 2 };"), ["Non-exhaustive match"]);
+        assert_eq!(titles("match true { true -> 1, false -> 2, _ -> 3 };"), ["Unreachable arm"]);
+        assert_eq!(titles("match 1 { \"a\" -> 1, _ -> 2 };"), ["Type mismatch"]);
+        assert_eq!(titles("match 1, 2 { x, x -> 1 };"), ["Name bound twice"]);
+        assert_eq!(titles("match 1 { x | 2 -> 1, _ -> 2 };")[0], "Name bound in an or-pattern");
+    }
+
+    #[test]
+    fn test_instance_of() {
+        use Type::*;
+        let id = Func(vec![Var(0)], Box::new(Var(0)));
+        assert!(instance_of(&Func(vec![Int], Box::new(Int)), &id));
+        assert!(instance_of(&Func(vec![Str], Box::new(Str)), &id));
+        assert!(!instance_of(&Func(vec![Int], Box::new(Bool)), &id));
+        let pair = Func(vec![Var(0), Var(1)], Box::new(Tuple(vec![Var(0), Var(1)])));
+        assert!(instance_of(&Func(vec![Int, Bool], Box::new(Tuple(vec![Int, Bool]))), &pair));
+        assert!(!instance_of(&Int, &Bool));
+    }
+}
@@ -1,3 +1,5 @@
 pub mod infer;
+pub mod memo;
 pub mod rename;
-pub mod typed;
\ No newline at end of file
+pub mod typed;
+pub mod usefulness;
\ No newline at end of file
@@ -0,0 +1,372 @@
+//! Type checking a module a group of items at a time, keeping the types of
+//! each group so that only those that changed are checked again.
+//!
+//! The top-level definitions of a module aren't generalized, so the uses of
+//! a definition decide its type as much as the definition itself. The items
+//! are thus split into [`groups`] that share no definitions, which can be
+//! inferred on their own, and the types of a group are kept by a fingerprint
+//! of its text and of what it uses from the modules it imports. Editing a
+//! definition checks again the definition, the items that use it and the
+//! definitions they use, and nothing else.
+//!
+//! Only type checking is memoized this way. Lexing, parsing and lowering
+//! are redone for every file each time, `hc --watch` included, which keeps
+//! nothing but the [`Memo`] from one run to the next.
+
+use std::collections::{HashMap, HashSet};
+
+use syntax::{
+    expr::{Expr, Span, Spanned},
+    module::Module,
+    ty::Type,
+};
+
+use crate::{
+    infer::{Infer, InferError, Interface},
+    rename::{rename_exprs, rename_type},
+    typed::TExpr,
+};
+
+/// FNV-1a over an encoding of its own, so that fingerprints stay the same
+/// from one build of the compiler to the next, unlike `Hash` and the hasher
+/// of the standard library
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn len(&mut self, n: usize) {
+        self.bytes(&(n as u64).to_le_bytes());
+    }
+
+    /// A string, after its length so that it can't run into the next one
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.bytes(s.as_bytes());
+    }
+
+    /// A type, each part after a byte telling what it is
+    fn ty(&mut self, t: &Type) {
+        match t {
+            Type::Unit => self.bytes(&[0]),
+            Type::Bool => self.bytes(&[1]),
+            Type::Int  => self.bytes(&[2]),
+            Type::Str  => self.bytes(&[3]),
+            Type::Var(i) => {
+                self.bytes(&[4]);
+                self.len(*i);
+            },
+            Type::Func(args, ret) => {
+                self.bytes(&[5]);
+                self.len(args.len());
+                args.iter().for_each(|t| self.ty(t));
+                self.ty(ret);
+            },
+            Type::Tuple(tys) => {
+                self.bytes(&[6]);
+                self.len(tys.len());
+                tys.iter().for_each(|t| self.ty(t));
+            },
+            Type::Array(t) => {
+                self.bytes(&[7]);
+                self.ty(t);
+            },
+        }
+    }
+}
+
+/// The types of the groups of items checked before, by their fingerprint,
+/// as [`TExpr::types`] gives them
+#[derive(Default)]
+pub struct Memo {
+    groups: HashMap<u64, Vec<Type>>,
+    // The groups looked up since `clear`
+    used: HashSet<u64>,
+    /// How many items were type checked since `clear`, and how many of them
+    /// kept their types instead of being inferred again
+    pub checked: usize,
+    pub reused: usize,
+}
+
+impl Memo {
+    /// Add the types of a group, e.g. as they were saved by another run
+    pub fn insert(&mut self, fingerprint: u64, types: Vec<Type>) {
+        self.groups.insert(fingerprint, types);
+    }
+
+    /// Start counting again
+    pub fn clear(&mut self) {
+        self.used.clear();
+        self.checked = 0;
+        self.reused = 0;
+    }
+
+    /// The groups looked up since `clear` that have types, which are those
+    /// worth saving
+    pub fn used(&self) -> impl Iterator<Item = (u64, &[Type])> {
+        self.used.iter().filter_map(|f| Some((*f, self.groups.get(f)?.as_slice())))
+    }
+}
+
+/// Call `f` on an expression and every expression in it
+fn walk<'src>(e: &Expr<'src>, f: &mut impl FnMut(&Expr<'src>)) {
+    f(e);
+    match e {
+        Expr::Lit(_) | Expr::Ident(_) | Expr::Qualified { .. } => {},
+        Expr::Unary(_, e) => walk(&e.0, f),
+        Expr::Binary(_, lhs, rhs) => {
+            walk(&lhs.0, f);
+            walk(&rhs.0, f);
+        },
+        Expr::Lambda(_, _, body) => walk(&body.0, f),
+        Expr::Call(func, args) => {
+            walk(&func.0, f);
+            args.iter().for_each(|a| walk(&a.0, f));
+        },
+        Expr::If { cond, t, f: e } => {
+            walk(&cond.0, f);
+            walk(&t.0, f);
+            walk(&e.0, f);
+        },
+        Expr::Let { value, body, .. } => {
+            walk(&value.0, f);
+            walk(&body.0, f);
+        },
+        Expr::Define { value, .. } => walk(&value.0, f),
+        Expr::Block { exprs, .. } => exprs.iter().for_each(|e| walk(&e.0, f)),
+        Expr::Match { scrutinees, arms } => {
+            scrutinees.iter().for_each(|e| walk(&e.0, f));
+            arms.iter().for_each(|(_, body)| walk(&body.0, f));
+        },
+    }
+}
+
+/// Split the items of a module into groups that share no definitions, each
+/// one in the order of the items. Definitions in blocks are counted too, as
+/// they are visible after the block
+pub fn groups(items: &[Spanned<Expr>]) -> Vec<Vec<usize>> {
+    let mut defined = HashSet::new();
+    items.iter().for_each(|(e, _)| walk(e, &mut |e| if let Expr::Define { name, .. } = e {
+        defined.insert(*name);
+    }));
+
+    // Every item is joined to the first one that mentions the same name
+    fn root(parents: &mut [usize], i: usize) -> usize {
+        if parents[i] != i {
+            parents[i] = root(parents, parents[i]);
+        }
+        parents[i]
+    }
+    let mut parents = (0..items.len()).collect::<Vec<_>>();
+    let mut first = HashMap::new();
+    for (i, (e, _)) in items.iter().enumerate() {
+        walk(e, &mut |e| {
+            let (Expr::Ident(name) | Expr::Define { name, .. }) = e else { return };
+            if !defined.contains(name) {
+                return;
+            }
+            let (a, b) = (root(&mut parents, i), root(&mut parents, *first.entry(*name).or_insert(i)));
+            parents[a.max(b)] = a.min(b);
+        });
+    }
+
+    let mut groups: Vec<Vec<usize>> = vec![];
+    let mut index = HashMap::new();
+    for i in 0..items.len() {
+        let g = *index.entry(root(&mut parents, i)).or_insert_with(|| {
+            groups.push(vec![]);
+            groups.len() - 1
+        });
+        groups[g].push(i);
+    }
+    groups
+}
+
+/// The largest type variable in a type, plus one
+fn vars(t: &Type) -> usize {
+    match t {
+        Type::Var(i) => i + 1,
+        Type::Func(args, ret) => args.iter().map(vars).max().unwrap_or(0).max(vars(ret)),
+        Type::Tuple(tys) => tys.iter().map(vars).max().unwrap_or(0),
+        Type::Array(ty) => vars(ty),
+        _ => 0,
+    }
+}
+
+/// Number the type variables of a type from `by` on
+fn shift(t: Type, by: usize) -> Type {
+    match t {
+        Type::Var(i) => Type::Var(i + by),
+        Type::Func(args, ret) => Type::Func(
+            args.into_iter().map(|t| shift(t, by)).collect(),
+            Box::new(shift(*ret, by)),
+        ),
+        Type::Tuple(tys) => Type::Tuple(tys.into_iter().map(|t| shift(t, by)).collect()),
+        Type::Array(ty) => Type::Array(Box::new(shift(*ty, by))),
+        _ => t,
+    }
+}
+
+impl<'src> Infer<'src> {
+    /// What a group of items is checked from: its text, and the types of
+    /// what it uses from the modules it imports
+    fn fingerprint(&self, items: &[Spanned<Expr<'src>>], group: &[usize], src: &str) -> u64 {
+        let mut h = Fnv::new();
+        for &i in group {
+            let (e, span) = &items[i];
+            h.str(&src[span.into_range()]);
+            walk(e, &mut |e| if let Expr::Qualified { module, name } = e {
+                h.str(module);
+                h.str(name);
+                match self.imported(module, name) {
+                    Some(Some(t)) => {
+                        h.bytes(&[2]);
+                        h.ty(t);
+                    },
+                    Some(None) => h.bytes(&[1]),
+                    None => h.bytes(&[0]),
+                }
+            });
+        }
+        h.finish()
+    }
+
+    /// Infer a module like [`Infer::infer_module`], but a group of items at
+    /// a time, and keeping the types of the groups that didn't change since
+    /// they were put in `memo`. `src` is the text of the module
+    pub fn infer_module_memo(
+        &self,
+        module: Module<'src>,
+        src: &str,
+        memo: &mut Memo,
+    ) -> (Vec<(TExpr<'src>, Span)>, Interface<'src>, Vec<InferError>) {
+        let items = module.items;
+        let mut typed = vec![];
+        let mut errors = vec![];
+        // Each group has type variables of its own
+        let mut vars_before = 0;
+        for group in groups(&items) {
+            let fingerprint = self.fingerprint(&items, &group, src);
+            memo.used.insert(fingerprint);
+            memo.checked += group.len();
+
+            let by = vars_before;
+            let retype = |types: &[Type]| {
+                let mut types = types.iter().map(|t| shift(t.clone(), by));
+                let tes = group.iter()
+                    .map(|&i| {
+                        let (e, span) = items[i].clone();
+                        Some((i, (TExpr::retype(e, span, &mut types)?, span)))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                types.next().is_none().then_some(tes)
+            };
+            // Types that don't fit were saved by a compiler that ordered
+            // them differently
+            let reused = memo.groups.get(&fingerprint).and_then(|types| Some((retype(types)?, types)));
+            let (tes, types) = match reused {
+                Some((tes, types)) => {
+                    memo.reused += group.len();
+                    (tes, types.clone())
+                },
+                None => {
+                    let (tes, errs) = self.clone().infer_more(group.iter().map(|&i| items[i].clone()).collect());
+                    let mut types = vec![];
+                    tes.iter().for_each(|(e, _)| e.types(&mut types));
+                    if errs.is_empty() {
+                        memo.groups.insert(fingerprint, types.clone());
+                    }
+                    errors.extend(errs);
+                    (retype(&types).expect("the types of an expression fit it"), types)
+                },
+            };
+            vars_before += types.iter().map(vars).max().unwrap_or(0);
+            typed.extend(tes);
+        }
+        typed.sort_by_key(|(i, _)| *i);
+        let typed = rename_exprs(typed.into_iter().map(|(_, te)| te).collect());
+
+        let mut interface = Interface::default();
+        if errors.is_empty() {
+            for ((te, span), (e, _)) in typed.iter().zip(&items) {
+                match (te, e) {
+                    (TExpr::Define { name, ty, .. }, Expr::Define { public: true, .. }) => {
+                        interface.public.insert(name, rename_type(ty.clone()));
+                    },
+                    (TExpr::Define { name, .. }, _) => {
+                        interface.private.insert(name, *span);
+                    },
+                    _ => {},
+                }
+            }
+        }
+        (typed, interface, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chumsky::{prelude::Input, Parser};
+    use syntax::{parser::{lexer, module_parser}, source::FileId};
+
+    use super::*;
+
+    fn module(src: &str) -> Module<'_> {
+        let tokens = lexer(FileId::default()).parse(src).into_result().unwrap();
+        let module = module_parser()
+            .parse(tokens.as_slice().spanned(Span::new(FileId::default(), src.len(), src.len())))
+            .into_result()
+            .unwrap();
+        module
+    }
+
+    #[test]
+    fn test_groups() {
+        let m = module("let a = 1; let b = a + 1; let c = 2; c; { let d = 3; }; d; 4;");
+        assert_eq!(groups(&m.items), [vec![0, 1], vec![2, 3], vec![4, 5], vec![6]]);
+    }
+
+    #[test]
+    fn test_memo() {
+        let before = "pub let id = fun (x) -> x; let n = id(1); pub let s = \"s\"; let t = s;";
+        let after = "pub let id = fun (x) -> x; let n = id(1); pub let s = true; let t = s;";
+        let mut memo = Memo::default();
+        let (_, interface, errs) = Infer::new().infer_module_memo(module(before), before, &mut memo);
+        assert!(errs.is_empty());
+        assert_eq!(interface.public["s"], Type::Str);
+        assert_eq!((memo.checked, memo.reused), (4, 0));
+
+        memo.clear();
+        let (typed, interface, errs) = Infer::new().infer_module_memo(module(after), after, &mut memo);
+        assert!(errs.is_empty());
+        assert_eq!((memo.checked, memo.reused), (4, 2));
+        assert_eq!(interface, Infer::new().infer_module(module(after)).1);
+        let expected = Infer::new().infer_module(module(after)).0;
+        assert_eq!(format!("{:?}", typed), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // Fingerprints are saved, so they must not change with the compiler
+        let src = "let a = m.f(1);";
+        let m = module(src);
+        let mut infer = Infer::new();
+        assert_eq!(infer.fingerprint(&m.items, &[0], src), 0x6b2c_52fd_8a8c_81b6);
+        let f = Type::Func(vec![Type::Int], Box::new(Type::Int));
+        let interface = Interface { public: HashMap::from([("f", f)]), ..Interface::default() };
+        infer.import("m", interface);
+        assert_eq!(infer.fingerprint(&m.items, &[0], src), 0x96f7_5c47_5837_7272);
+    }
+}
@@ -1,4 +1,4 @@
-use chumsky::span::SimpleSpan;
+use syntax::expr::Span;
 use syntax::ty::Type;
 
 use crate::typed::TExpr;
@@ -9,6 +9,12 @@ pub struct Renamer {
     vars: Vec<usize>,
 }
 
+impl Default for Renamer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'src> Renamer {
     pub fn new() -> Self {
         Self {
@@ -62,11 +68,21 @@ impl<'src> Renamer {
                 self.find_var(ret_ty);
                 self.traverse(*body.0);
             },
-            TExpr::Call { func, args } => {
+            TExpr::Ident(_, ty) | TExpr::Qualified { ty, .. } => {
+                self.find_var(ty);
+            },
+            TExpr::Call { func, args, ret_ty } => {
                 self.traverse(*func.0);
                 for arg in args {
                     self.traverse(arg.0);
                 }
+                self.find_var(ret_ty);
+            },
+            TExpr::If { cond, t, f, br_ty } => {
+                self.traverse(*cond.0);
+                self.traverse(*t.0);
+                self.traverse(*f.0);
+                self.find_var(br_ty);
             },
             TExpr::Let { ty, value, body, .. } => {
                 self.find_var(ty);
@@ -83,6 +99,15 @@ impl<'src> Renamer {
                 }
                 self.find_var(ret_ty);
             },
+            TExpr::Match { scrutinees, arms, ret_ty } => {
+                for scrutinee in scrutinees {
+                    self.traverse(scrutinee.0);
+                }
+                for (_, body) in arms {
+                    self.traverse(body.0);
+                }
+                self.find_var(ret_ty);
+            },
             _ => {},
         }
     }
@@ -132,12 +157,25 @@ impl<'src> Renamer {
                     ret_ty: self.rename_type(ret_ty)
                 }
             },
-            TExpr::Call { func, args } => {
+            TExpr::Ident(x, ty) => TExpr::Ident(x, self.rename_type(ty)),
+            TExpr::Qualified { module, name, ty } => {
+                TExpr::Qualified { module, name, ty: self.rename_type(ty) }
+            },
+            TExpr::Call { func, args, ret_ty } => {
                 TExpr::Call {
                     func: (Box::new(self.rename_texp(*func.0)), func.1),
                     args: args.into_iter()
                         .map(|x| (self.rename_texp(x.0), x.1))
-                        .collect()
+                        .collect(),
+                    ret_ty: self.rename_type(ret_ty)
+                }
+            },
+            TExpr::If { cond, t, f, br_ty } => {
+                TExpr::If {
+                    cond: (Box::new(self.rename_texp(*cond.0)), cond.1),
+                    t: (Box::new(self.rename_texp(*t.0)), t.1),
+                    f: (Box::new(self.rename_texp(*f.0)), f.1),
+                    br_ty: self.rename_type(br_ty)
                 }
             },
             TExpr::Let { name, ty, value, body } => {
@@ -164,6 +202,17 @@ impl<'src> Renamer {
                     ret_ty: self.rename_type(ret_ty)
                 }
             },
+            TExpr::Match { scrutinees, arms, ret_ty } => {
+                TExpr::Match {
+                    scrutinees: scrutinees.into_iter()
+                        .map(|x| (self.rename_texp(x.0), x.1))
+                        .collect(),
+                    arms: arms.into_iter()
+                        .map(|(ps, body)| (ps, (self.rename_texp(body.0), body.1)))
+                        .collect(),
+                    ret_ty: self.rename_type(ret_ty)
+                }
+            },
             _ => e,
         }
     }
@@ -175,7 +224,7 @@ pub fn rename_type(t: Type) -> Type {
     renamer.rename_type(t)
 }
 
-pub fn rename_exprs(es: Vec<(TExpr, SimpleSpan)>) -> Vec<(TExpr, SimpleSpan)> {
+pub fn rename_exprs(es: Vec<(TExpr, Span)>) -> Vec<(TExpr, Span)> {
     let mut renamer = Renamer::new();
     es.clone().into_iter()
         .for_each(|e| renamer.traverse(e.0));
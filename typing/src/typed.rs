@@ -2,7 +2,10 @@ use syntax::{
     expr::{
         BinaryOp,
         UnaryOp,
+        Expr,
         Lit,
+        Pattern,
+        Span,
         Spanned,
     },
     ty::Type,
@@ -12,7 +15,12 @@ use syntax::{
 #[derive(Clone, Debug)]
 pub enum TExpr<'src> {
     Lit(Lit<'src>),
-    Ident(&'src str),
+    Ident(&'src str, Type),
+    Qualified {
+        module: &'src str,
+        name: &'src str,
+        ty: Type,
+    },
 
     Unary {
         op: UnaryOp,
@@ -34,6 +42,7 @@ pub enum TExpr<'src> {
     Call {
         func: Spanned<Box<Self>>,
         args: Vec<Spanned<Self>>,
+        ret_ty: Type,
     },
     If {
         cond: Spanned<Box<Self>>,
@@ -57,4 +66,191 @@ pub enum TExpr<'src> {
         void: bool,
         ret_ty: Type,
     },
-}
\ No newline at end of file
+    // The names a pattern binds have the type of the scrutinee it matches
+    Match {
+        scrutinees: Vec<Spanned<Self>>,
+        arms: Vec<(Vec<Spanned<Pattern<'src>>>, Spanned<Self>)>,
+        ret_ty: Type,
+    },
+}
+
+impl<'src> TExpr<'src> {
+    /// The type of the value this expression evaluates to
+    pub fn ty(&self) -> Type {
+        match self {
+            TExpr::Lit(Lit::Unit)    => Type::Unit,
+            TExpr::Lit(Lit::Bool(_)) => Type::Bool,
+            TExpr::Lit(Lit::Int(_))  => Type::Int,
+            TExpr::Lit(Lit::Str(_))  => Type::Str,
+            TExpr::Ident(_, ty) | TExpr::Qualified { ty, .. } => ty.clone(),
+            TExpr::Unary { ret_ty, .. }
+            | TExpr::Binary { ret_ty, .. }
+            | TExpr::Call { ret_ty, .. }
+            | TExpr::Block { ret_ty, .. }
+            | TExpr::Match { ret_ty, .. } => ret_ty.clone(),
+            TExpr::Lambda { params, ret_ty, .. } => Type::Func(
+                params.iter().map(|(_, t)| t.clone()).collect(),
+                Box::new(ret_ty.clone()),
+            ),
+            TExpr::If { br_ty, .. } => br_ty.clone(),
+            TExpr::Let { body, .. } => body.0.ty(),
+            TExpr::Define { .. } => Type::Unit,
+        }
+    }
+
+    /// Every type in the expression, in the order [`TExpr::retype`] takes
+    /// them back
+    pub fn types(&self, out: &mut Vec<Type>) {
+        match self {
+            TExpr::Lit(_) => {},
+            TExpr::Ident(_, ty) | TExpr::Qualified { ty, .. } => out.push(ty.clone()),
+            TExpr::Unary { expr, ret_ty, .. } => {
+                out.push(ret_ty.clone());
+                expr.0.types(out);
+            },
+            TExpr::Binary { lhs, rhs, ret_ty, .. } => {
+                out.push(ret_ty.clone());
+                lhs.0.types(out);
+                rhs.0.types(out);
+            },
+            TExpr::Lambda { params, body, ret_ty } => {
+                out.extend(params.iter().map(|(_, t)| t.clone()));
+                out.push(ret_ty.clone());
+                body.0.types(out);
+            },
+            TExpr::Call { func, args, ret_ty } => {
+                out.push(ret_ty.clone());
+                func.0.types(out);
+                args.iter().for_each(|a| a.0.types(out));
+            },
+            TExpr::If { cond, t, f, br_ty } => {
+                out.push(br_ty.clone());
+                cond.0.types(out);
+                t.0.types(out);
+                f.0.types(out);
+            },
+            TExpr::Let { ty, value, body, .. } => {
+                out.push(ty.clone());
+                value.0.types(out);
+                body.0.types(out);
+            },
+            TExpr::Define { ty, value, .. } => {
+                out.push(ty.clone());
+                value.0.types(out);
+            },
+            TExpr::Block { exprs, ret_ty, .. } => {
+                out.push(ret_ty.clone());
+                exprs.iter().for_each(|e| e.0.types(out));
+            },
+            TExpr::Match { scrutinees, arms, ret_ty } => {
+                out.push(ret_ty.clone());
+                scrutinees.iter().for_each(|s| s.0.types(out));
+                arms.iter().for_each(|(_, body)| body.0.types(out));
+            },
+        }
+    }
+
+    /// Call `f` on this expression and every expression in it, with their
+    /// spans, this one at `span`
+    pub fn walk(&self, span: Span, f: &mut impl FnMut(&Self, Span)) {
+        f(self, span);
+        match self {
+            TExpr::Lit(_) | TExpr::Ident(..) | TExpr::Qualified { .. } => {},
+            TExpr::Unary { expr, .. } => expr.0.walk(expr.1, f),
+            TExpr::Binary { lhs, rhs, .. } => {
+                lhs.0.walk(lhs.1, f);
+                rhs.0.walk(rhs.1, f);
+            },
+            TExpr::Lambda { body, .. } => body.0.walk(body.1, f),
+            TExpr::Call { func, args, .. } => {
+                func.0.walk(func.1, f);
+                args.iter().for_each(|(a, s)| a.walk(*s, f));
+            },
+            TExpr::If { cond, t, f: e, .. } => {
+                cond.0.walk(cond.1, f);
+                t.0.walk(t.1, f);
+                e.0.walk(e.1, f);
+            },
+            TExpr::Let { value, body, .. } => {
+                value.0.walk(value.1, f);
+                body.0.walk(body.1, f);
+            },
+            TExpr::Define { value, .. } => value.0.walk(value.1, f),
+            TExpr::Block { exprs, .. } => exprs.iter().for_each(|(e, s)| e.walk(*s, f)),
+            TExpr::Match { scrutinees, arms, .. } => {
+                scrutinees.iter().for_each(|(e, s)| e.walk(*s, f));
+                arms.iter().for_each(|(_, (body, s))| body.walk(*s, f));
+            },
+        }
+    }
+
+    /// Type an expression again without inferring it, with the types that
+    /// [`TExpr::types`] gave for it. `None` if there aren't enough of them
+    pub fn retype(e: Expr<'src>, span: Span, types: &mut impl Iterator<Item = Type>) -> Option<Self> {
+        macro_rules! retype {
+            ($e:expr) => {
+                (Box::new(Self::retype(*$e.0, $e.1, types)?), $e.1)
+            };
+        }
+        Some(match e {
+            Expr::Lit(l) => TExpr::Lit(l),
+            Expr::Ident(x) => TExpr::Ident(x, types.next()?),
+            Expr::Qualified { module, name } => TExpr::Qualified { module, name, ty: types.next()? },
+            Expr::Unary(op, expr) => {
+                let ret_ty = types.next()?;
+                // The operand has the span of the whole expression, as
+                // when it is inferred
+                let expr = (retype!(expr).0, span);
+                TExpr::Unary { op, expr, ret_ty }
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let ret_ty = types.next()?;
+                TExpr::Binary { op, lhs: retype!(lhs), rhs: retype!(rhs), ret_ty }
+            },
+            Expr::Lambda(params, _, body) => {
+                let params = params.into_iter()
+                    .map(|(x, _)| Some((x, types.next()?)))
+                    .collect::<Option<_>>()?;
+                let ret_ty = types.next()?;
+                TExpr::Lambda { params, body: retype!(body), ret_ty }
+            },
+            Expr::Call(func, args) => {
+                let ret_ty = types.next()?;
+                let func = retype!(func);
+                let args = args.into_iter()
+                    .map(|(a, s)| Some((Self::retype(a, s, types)?, s)))
+                    .collect::<Option<_>>()?;
+                TExpr::Call { func, args, ret_ty }
+            },
+            Expr::If { cond, t, f } => {
+                let br_ty = types.next()?;
+                TExpr::If { cond: retype!(cond), t: retype!(t), f: retype!(f), br_ty }
+            },
+            Expr::Let { name, value, body, .. } => {
+                let ty = types.next()?;
+                TExpr::Let { name, ty, value: retype!(value), body: retype!(body) }
+            },
+            Expr::Define { name, value, .. } => {
+                let ty = types.next()?;
+                TExpr::Define { name, ty, value: retype!(value) }
+            },
+            Expr::Block { exprs, void } => {
+                let ret_ty = types.next()?;
+                let exprs = exprs.into_iter()
+                    .map(|(e, s)| Some((Self::retype(*e, s, types)?, s)))
+                    .collect::<Option<_>>()?;
+                TExpr::Block { exprs, void, ret_ty }
+            },
+            Expr::Match { scrutinees, arms } => {
+                let ret_ty = types.next()?;
+                let scrutinees = scrutinees.into_iter()
+                    .map(|(e, s)| Some((Self::retype(e, s, types)?, s)))
+                    .collect::<Option<_>>()?;
+                let arms = arms.into_iter()
+                    .map(|(ps, (body, s))| Some((ps, (Self::retype(body, s, types)?, s))))
+                    .collect::<Option<_>>()?;
+                TExpr::Match { scrutinees, arms, ret_ty }
+            },
+        })
+    }
+}
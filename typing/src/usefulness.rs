@@ -0,0 +1,151 @@
+//! Checking the arms of a `match`: whether they cover every value, and
+//! whether some of them can never be taken.
+//!
+//! The patterns of the arms form a matrix, with a row for each arm and a
+//! column for each value matched. Both checks come down to whether a row is
+//! useful, whether it matches values that none of the rows above it do,
+//! which is found by splitting the matrix one column at a time. The match
+//! compiler of the IR splits it the same way, so the operations on rows
+//! are public.
+
+use syntax::{
+    expr::{Lit, Pattern, Span, Spanned},
+    ty::Type,
+};
+
+use crate::{infer::InferError, typed::TExpr};
+
+/// Split the or-patterns of the first column into rows of their own
+pub fn expand<'src>(row: &[Pattern<'src>]) -> Vec<Vec<Pattern<'src>>> {
+    match row.split_first() {
+        Some((Pattern::Or(ps), rest)) => ps.iter()
+            .flat_map(|p| expand(&[std::slice::from_ref(p), rest].concat()))
+            .collect(),
+        _ => vec![row.to_vec()],
+    }
+}
+
+/// Whether a row is left when its first column is `lit`, or a literal not
+/// tested for when `lit` is `None`
+pub fn keeps(p: &Pattern, lit: Option<&Lit>) -> bool {
+    match p {
+        Pattern::Wild | Pattern::Bind(_) => true,
+        Pattern::Lit(l) => Some(l) == lit,
+        Pattern::Or(_) => unreachable!("or-patterns are expanded first"),
+    }
+}
+
+/// The distinct literals of the first column, in order
+pub fn heads<'a, 'src>(rows: impl IntoIterator<Item = &'a [Pattern<'src>]>) -> Vec<Lit<'src>>
+where
+    'src: 'a,
+{
+    let mut heads = vec![];
+    for row in rows {
+        if let Some(Pattern::Lit(l)) = row.first() {
+            if !heads.contains(l) {
+                heads.push(l.clone());
+            }
+        }
+    }
+    heads
+}
+
+/// Whether the literals are every value of a type
+pub fn complete(heads: &[Lit], ty: &Type) -> bool {
+    match ty {
+        Type::Unit => !heads.is_empty(),
+        Type::Bool => heads.contains(&Lit::Bool(true)) && heads.contains(&Lit::Bool(false)),
+        _ => false,
+    }
+}
+
+/// Whether a row of patterns matches some values that none of the rows
+/// match
+fn useful(rows: &[Vec<Pattern>], row: &[Pattern], tys: &[Type]) -> bool {
+    let Some((first, rest)) = row.split_first() else {
+        return rows.is_empty();
+    };
+    let rows = rows.iter().flat_map(|r| expand(r)).collect::<Vec<_>>();
+    let specialize = |lit: Option<&Lit>| rows.iter()
+        .filter(|r| keeps(&r[0], lit))
+        .map(|r| r[1..].to_vec())
+        .collect::<Vec<_>>();
+    match first {
+        Pattern::Or(ps) => ps.iter().any(|p| {
+            useful(&rows, &[std::slice::from_ref(p), rest].concat(), tys)
+        }),
+        Pattern::Lit(l) => useful(&specialize(Some(l)), rest, &tys[1..]),
+        Pattern::Wild | Pattern::Bind(_) => {
+            let heads = heads(rows.iter().map(Vec::as_slice));
+            if complete(&heads, &tys[0]) {
+                heads.iter().any(|l| useful(&specialize(Some(l)), rest, &tys[1..]))
+            } else {
+                useful(&specialize(None), rest, &tys[1..])
+            }
+        }
+    }
+}
+
+/// Whether the arms match every value of scrutinees of types `tys`
+pub fn is_exhaustive(arms: &[Vec<Pattern>], tys: &[Type]) -> bool {
+    !useful(arms, &vec![Pattern::Wild; tys.len()], tys)
+}
+
+/// The arms that can never be taken, as the arms before them match
+/// everything they do
+pub fn redundant_arms(arms: &[Vec<Pattern>], tys: &[Type]) -> Vec<usize> {
+    (0..arms.len()).filter(|&i| !useful(&arms[..i], &arms[i], tys)).collect()
+}
+
+/// The errors of a typed `match` at `span`, once the types of its
+/// scrutinees are known
+pub fn check_match(
+    scrutinees: &[Spanned<TExpr>],
+    arms: &[(Vec<Spanned<Pattern>>, Spanned<TExpr>)],
+    span: Span,
+) -> Vec<InferError> {
+    let tys = scrutinees.iter().map(|(s, _)| s.ty()).collect::<Vec<_>>();
+    let rows = arms.iter()
+        .map(|(ps, _)| ps.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+
+    let mut errors = redundant_arms(&rows, &tys).into_iter()
+        .map(|i| {
+            let ps = &arms[i].0;
+            let span = ps[0].1.to(ps[ps.len() - 1].1);
+            InferError::new("Unreachable arm", span)
+                .add_error("The arms before this one match everything it does", span)
+        })
+        .collect::<Vec<_>>();
+    if !is_exhaustive(&rows, &tys) {
+        errors.push(InferError::new("Non-exhaustive match", span)
+            .add_error("Some values are matched by none of the arms", span)
+            .add_hint("An arm of `_` patterns would match them", span));
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redundancy_and_exhaustiveness() {
+        let bool = |b| Pattern::Lit(Lit::Bool(b));
+        let int = |i| Pattern::Lit(Lit::Int(i));
+        let arms = [vec![bool(true)], vec![bool(false)], vec![Pattern::Wild]];
+        assert_eq!(redundant_arms(&arms, &[Type::Bool]), [2]);
+        assert!(is_exhaustive(&arms[..2], &[Type::Bool]));
+        assert!(!is_exhaustive(&arms[..1], &[Type::Bool]));
+
+        let arms = [vec![int(1)], vec![Pattern::Or(vec![int(1), int(2)])], vec![int(2)]];
+        assert_eq!(redundant_arms(&arms, &[Type::Int]), [2]);
+        assert!(!is_exhaustive(&arms, &[Type::Int]));
+
+        let arms = [vec![int(1), Pattern::Wild], vec![int(1), int(2)], vec![Pattern::Wild, int(2)]];
+        assert_eq!(redundant_arms(&arms, &[Type::Int, Type::Int]), [1]);
+        assert!(!is_exhaustive(&arms, &[Type::Int, Type::Int]));
+        assert!(is_exhaustive(&[vec![Pattern::Lit(Lit::Unit)]], &[Type::Unit]));
+    }
+}